@@ -0,0 +1,11 @@
+-- Session table for PostgresStore. `{table}` is replaced with the configured
+-- table name (default "sessions"); the store applies this on first use.
+CREATE TABLE IF NOT EXISTS {table} (
+    id         TEXT PRIMARY KEY,
+    user_id    TEXT,
+    data       JSONB NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS {table}_user_id_idx ON {table} (user_id);
+CREATE INDEX IF NOT EXISTS {table}_expires_at_idx ON {table} (expires_at);
@@ -0,0 +1,266 @@
+use axum::{
+    extract::{Request, State},
+    http::HeaderName,
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+use crate::proxy_protocol::ClientAddr;
+
+// On-call access that maintenance mode and rate limiting let through, e.g.
+//
+//   [admin_allowlist]
+//   ips = ["10.0.0.0/8", "203.0.113.7"]
+//   header = "X-Debug"
+//   secret = "long random string"
+//
+// A request matches by client IP or by carrying the header with the secret.
+// The header is removed before the request goes further, so backends never
+// see the secret.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AdminAllowlistConfig {
+    pub ips: Vec<IpNet>,
+    pub header: String,
+    // No secret, no header bypass
+    pub secret: Option<String>,
+}
+
+impl Default for AdminAllowlistConfig {
+    fn default() -> Self {
+        Self {
+            ips: Vec::new(),
+            header: "x-debug".to_string(),
+            secret: None,
+        }
+    }
+}
+
+impl AdminAllowlistConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if HeaderName::from_str(&self.header).is_err() {
+            anyhow::bail!("admin_allowlist header {:?} is not a valid header name", self.header);
+        }
+        if self.secret.as_deref().map_or(false, |secret| secret.trim().is_empty()) {
+            anyhow::bail!("admin_allowlist secret must not be empty");
+        }
+        Ok(())
+    }
+}
+
+// An address or CIDR block, e.g. "10.0.0.0/8" or "2001:db8::1"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| anyhow::anyhow!("invalid address {:?}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| anyhow::anyhow!("invalid prefix length in {:?}", s))?,
+            None => max,
+        };
+        Ok(Self { addr: addr.to_canonical(), prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for IpNet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Set on allowlisted requests; says why, for the bypass log lines
+#[derive(Debug, Clone)]
+pub struct AdminBypass(pub &'static str);
+
+// Starts from the config; reloads replace it
+#[derive(Debug)]
+pub struct AdminAllowlist {
+    config: RwLock<AdminAllowlistConfig>,
+}
+
+impl AdminAllowlist {
+    pub fn new(config: AdminAllowlistConfig) -> Self {
+        Self { config: RwLock::new(config) }
+    }
+
+    pub fn configure(&self, config: AdminAllowlistConfig) {
+        *self.config.write().unwrap() = config;
+    }
+}
+
+// Byte-by-byte without stopping early, so timing doesn't leak the secret
+fn secret_matches(given: &[u8], secret: &[u8]) -> bool {
+    given.len() == secret.len() && given.iter().zip(secret).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Marks allowlisted requests with AdminBypass; maintenance_middleware and
+// rate_limit_middleware, inside this one, let them through
+pub async fn admin_allowlist_middleware(
+    State(allowlist): State<Arc<AdminAllowlist>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let reason = {
+        let config = allowlist.config.read().unwrap();
+        let client_ip = req.extensions().get::<ClientAddr>().map(|ClientAddr(addr)| addr.ip());
+        let by_ip = client_ip.map_or(false, |ip| config.ips.iter().any(|net| net.contains(ip)));
+        let by_header = match (&config.secret, HeaderName::from_str(&config.header)) {
+            (Some(secret), Ok(name)) => req.headers_mut().remove(name)
+                .map_or(false, |given| secret_matches(given.as_bytes(), secret.as_bytes())),
+            _ => false,
+        };
+        if by_ip {
+            Some("allowlisted IP")
+        } else if by_header {
+            Some("debug header")
+        } else {
+            None
+        }
+    };
+    if let Some(reason) = reason {
+        info!("Admin allowlist matched {} {} ({})", req.method(), req.uri().path(), reason);
+        req.extensions_mut().insert(AdminBypass(reason));
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintenance::{maintenance_middleware, MaintenanceConfig, MaintenanceMode};
+    use crate::security::{rate_limit_middleware, RateLimiter, SecurityConfig};
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::Router;
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    fn allowlist() -> Arc<AdminAllowlist> {
+        Arc::new(AdminAllowlist::new(toml::from_str(r#"
+            ips = ["10.1.0.0/16", "2001:db8::1"]
+            header = "X-Debug"
+            secret = "open sesame"
+        "#).unwrap()))
+    }
+
+    fn request(ip: &str, debug: Option<&str>) -> Request {
+        let mut builder = Request::get("/page");
+        if let Some(value) = debug {
+            builder = builder.header("x-debug", value);
+        }
+        let mut req = builder.body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ClientAddr(SocketAddr::new(ip.parse().unwrap(), 40000)));
+        req
+    }
+
+    async fn status_of(app: &Router, req: Request) -> StatusCode {
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_allowlisted_requests_bypass_maintenance() {
+        let maintenance = Arc::new(MaintenanceMode::new(MaintenanceConfig { enabled: true, ..Default::default() }));
+        // The handler reports whether the secret header got through
+        let app = Router::new()
+            .route("/page", get(|req: Request| async move {
+                if req.headers().contains_key("x-debug") { "leaked" } else { "page" }
+            }))
+            .layer(axum::middleware::from_fn_with_state(maintenance, maintenance_middleware))
+            .layer(axum::middleware::from_fn_with_state(allowlist(), admin_allowlist_middleware));
+
+        let response = app.clone().oneshot(request("10.1.42.7", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(request("192.0.2.1", Some("open sesame"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"page");
+        assert_eq!(status_of(&app, request("::ffff:10.1.0.1", None)).await, StatusCode::OK);
+
+        assert_eq!(status_of(&app, request("10.2.0.1", None)).await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status_of(&app, request("192.0.2.1", Some("open sesame!"))).await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status_of(&app, request("2001:db8::2", None)).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_allowlisted_requests_skip_rate_limiting() {
+        let limiter = Arc::new(RateLimiter::new(SecurityConfig { rate_limit_requests: 1, ..Default::default() }));
+        let app = Router::new()
+            .route("/page", get(|| async { "page" }))
+            .layer(axum::middleware::from_fn_with_state(limiter, rate_limit_middleware))
+            .layer(axum::middleware::from_fn_with_state(allowlist(), admin_allowlist_middleware));
+
+        for _ in 0..3 {
+            assert_eq!(status_of(&app, request("10.1.0.9", None)).await, StatusCode::OK);
+        }
+        assert_eq!(status_of(&app, request("192.0.2.1", None)).await, StatusCode::OK);
+        assert_eq!(status_of(&app, request("192.0.2.1", None)).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.255.1".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.7".parse().unwrap()));
+        let single: IpNet = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.to_string(), "2001:db8::1/128");
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert!(toml::from_str::<AdminAllowlistConfig>("ips = [\"not an ip\"]").is_err());
+        let config: AdminAllowlistConfig = toml::from_str("secret = \" \"").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
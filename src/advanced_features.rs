@@ -65,8 +65,9 @@ pub async fn init_advanced_features(config: &Config) -> Result<AdvancedFeatures>
         circuit_breaker::Config {
             failure_threshold: 5,
             success_threshold: 2,
-            timeout: std::time::Duration::from_secs(30),
+            cooldown_seconds: 30,
             half_open_max_calls: 3,
+            ..Default::default()
         }
     ));
     
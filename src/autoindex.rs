@@ -0,0 +1,124 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::fmt::Write;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+// Characters escaped in listing links on top of control characters
+const LINK_ENCODE: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>')
+    .add(b'?').add(b'`').add(b'{').add(b'}');
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+}
+
+// nginx-style `autoindex on` listing for `dir`, which is served at
+// `uri_path`. `dir` must already be resolved inside the static root.
+// Dotfiles are left out; directories are listed first, then files, by name.
+pub async fn render_listing(dir: &Path, uri_path: &str) -> std::io::Result<String> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follows symlinks; broken links are skipped
+        let metadata = match tokio::fs::metadata(entry.path()).await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let modified = metadata.modified().ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified,
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let base = uri_path.trim_end_matches('/');
+    let title = escape_html(&percent_decode_str(uri_path).decode_utf8_lossy());
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+         <body>\n<h1>Index of {title}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Last modified</th><th>Size</th></tr>\n",
+    );
+    if !base.is_empty() {
+        let parent = match base.rfind('/') {
+            Some(i) => &base[..=i],
+            None => "/",
+        };
+        let _ = writeln!(html, "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>", parent);
+    }
+    for entry in &entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let href = format!("{}/{}{}", base, utf8_percent_encode(&entry.name, LINK_ENCODE), suffix);
+        let modified = chrono::DateTime::from_timestamp(entry.modified as i64, 0)
+            .unwrap_or_default()
+            .format("%Y-%m-%d %H:%M");
+        let size = if entry.is_dir { "-".to_string() } else { entry.size.to_string() };
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            escape_html(&href), escape_html(&entry.name), suffix, modified, size,
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    Ok(html)
+}
+
+fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render_listing() {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-autoindex-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("zeta")).unwrap();
+        std::fs::create_dir_all(dir.join("alpha")).unwrap();
+        std::fs::write(dir.join("b.txt"), "12345").unwrap();
+        std::fs::write(dir.join("a <b>.txt"), "").unwrap();
+        std::fs::write(dir.join(".env"), "SECRET=1").unwrap();
+
+        let html = render_listing(&dir, "/files/docs/").await.unwrap();
+
+        assert!(html.contains("<title>Index of /files/docs/</title>"));
+        assert!(html.contains("<a href=\"/files/\">../</a>"));
+        assert!(!html.contains(".env"));
+        assert!(html.contains("<a href=\"/files/docs/b.txt\">b.txt</a>"));
+        assert!(html.contains("<td>5</td>"));
+        // Names are escaped in text and encoded in links
+        assert!(html.contains("<a href=\"/files/docs/a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+
+        // Directories first, each group sorted by name
+        let order: Vec<usize> = ["alpha/", "zeta/", "a &lt;b&gt;.txt", "b.txt"].iter()
+            .map(|name| html.find(&format!(">{}</a>", name)).unwrap())
+            .collect();
+        assert!(order.windows(2).all(|w| w[0] < w[1]));
+
+        let root = render_listing(&dir, "/").await.unwrap();
+        assert!(!root.contains("../"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use axum::extract::Request;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Error yielded by a limited stream once more than `limit` bytes have passed
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimitExceeded {
+    pub limit: u64,
+}
+
+impl fmt::Display for BodyLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "body exceeds limit of {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for BodyLimitExceeded {}
+
+// Wrap a body so bytes are counted as they flow instead of being collected
+// up front. The body ends with BodyLimitExceeded as soon as the running
+// total goes past `limit`. Only data frames count; trailers pass through.
+pub struct LimitedBody<B> {
+    inner: B,
+    limit: u64,
+    seen: u64,
+}
+
+impl<B> LimitedBody<B> {
+    pub fn new(inner: B, limit: u64) -> Self {
+        Self { inner, limit, seen: 0 }
+    }
+}
+
+impl<B> Body for LimitedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        // The body ends after the limit error
+        if self.seen > self.limit {
+            return Poll::Ready(None);
+        }
+
+        let frame = match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+            None => return Poll::Ready(None),
+        };
+        if let Some(data) = frame.data_ref() {
+            self.seen += data.len() as u64;
+            if self.seen > self.limit {
+                return Poll::Ready(Some(Err(Box::new(BodyLimitExceeded { limit: self.limit }))));
+            }
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Apply a request size limit: a Content-Length over `limit` is answered
+// with a 413 right away, anything else gets its body wrapped so reading past
+// the limit fails with BodyLimitExceeded (answer that with payload_too_large)
+pub fn limit_request(req: Request, limit: u64) -> Result<Request, Response> {
+    let declared_length = req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared_length.map_or(false, |len| len > limit) {
+        return Err(payload_too_large());
+    }
+    Ok(req.map(|body| axum::body::Body::new(LimitedBody::new(body, limit))))
+}
+
+pub fn payload_too_large() -> Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response()
+}
+
+// Parse a size such as "512KB", "10MB" or "1GiB" into bytes. KB/MB/GB (and
+// K/M/G) are powers of 1000, KiB/MiB/GiB powers of 1024; a bare number or
+// "B" is bytes. Units are case-insensitive.
+pub fn parse_size(value: &str) -> anyhow::Result<u64> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse()
+        .map_err(|_| anyhow::anyhow!("invalid size {:?}: expected a number followed by a unit like \"10MB\"", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        other => anyhow::bail!("invalid size {:?}: unknown unit {:?} (use B, KB, MB, GB, KiB, MiB or GiB)", value, other),
+    };
+    number.checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("invalid size {:?}: too large", value))
+}
+
+// Size setting written either as a byte count or a string for parse_size
+struct ByteSize(u64);
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = ByteSize;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a byte count or a size like \"10MB\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<ByteSize, E> {
+                Ok(ByteSize(value))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<ByteSize, E> {
+                u64::try_from(value).map(ByteSize).map_err(|_| E::custom("size must not be negative"))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<ByteSize, E> {
+                parse_size(value).map(ByteSize).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+// For `#[serde(deserialize_with)]` on u64 size fields
+pub fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    ByteSize::deserialize(deserializer).map(|ByteSize(size)| size)
+}
+
+pub fn deserialize_optional_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Option::<ByteSize>::deserialize(deserializer).map(|size| size.map(|ByteSize(size)| size))
+}
+
+// Walk an error's source chain looking for a BodyLimitExceeded
+pub fn is_limit_exceeded(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if e.is::<BodyLimitExceeded>() {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    fn chunked_body(total: usize) -> axum::body::Body {
+        // Every chunk shares the same allocation, so the test itself never
+        // holds more than one chunk worth of memory
+        let chunk = Bytes::from(vec![0u8; CHUNK_SIZE]);
+        axum::body::Body::from_stream(stream::iter((0..total / CHUNK_SIZE).map(move |_| Ok::<_, std::io::Error>(chunk.clone()))))
+    }
+
+    async fn next_frame<B: Body + Unpin>(body: &mut B) -> Option<Result<Frame<B::Data>, B::Error>> {
+        futures::future::poll_fn(|cx| Pin::new(&mut *body).poll_frame(cx)).await
+    }
+
+    #[tokio::test]
+    async fn test_large_body_streams_through() {
+        let total = 200 * 1024 * 1024;
+        let mut limited = LimitedBody::new(chunked_body(total), 256 * 1024 * 1024);
+
+        let mut seen = 0usize;
+        let mut largest_chunk = 0usize;
+        while let Some(frame) = next_frame(&mut limited).await {
+            let chunk = frame.unwrap().into_data().unwrap();
+            largest_chunk = largest_chunk.max(chunk.len());
+            seen += chunk.len();
+        }
+
+        assert_eq!(seen, total);
+        assert_eq!(largest_chunk, CHUNK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_limit_exceeded_stops_stream() {
+        let limit = (CHUNK_SIZE * 3) as u64;
+        let mut limited = LimitedBody::new(chunked_body(CHUNK_SIZE * 10), limit);
+
+        let mut ok_chunks = 0;
+        let mut error = None;
+        while let Some(frame) = next_frame(&mut limited).await {
+            match frame {
+                Ok(_) => ok_chunks += 1,
+                Err(e) => error = Some(e),
+            }
+        }
+
+        assert_eq!(ok_chunks, 3);
+        let error = error.expect("expected limit error");
+        assert!(is_limit_exceeded(error.as_ref()));
+    }
+
+    // A data chunk followed by a trailers frame
+    struct TrailerBody(Vec<Frame<Bytes>>);
+
+    impl Body for TrailerBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_frame(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, std::io::Error>>> {
+            Poll::Ready((!self.0.is_empty()).then(|| Ok(self.0.remove(0))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trailers_pass_through() {
+        let mut trailers = axum::http::HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        let body = TrailerBody(vec![Frame::data(Bytes::from("hello")), Frame::trailers(trailers)]);
+        let mut limited = LimitedBody::new(body, 5);
+
+        assert_eq!(next_frame(&mut limited).await.unwrap().unwrap().into_data().unwrap(), "hello");
+        let frame = next_frame(&mut limited).await.unwrap().unwrap();
+        assert_eq!(frame.trailers_ref().expect("trailers frame")["grpc-status"], "0");
+        assert!(next_frame(&mut limited).await.is_none());
+    }
+
+    // Reads the whole body like the proxy does, 413 once the limit is hit
+    async fn upload(req: Request) -> Response {
+        match axum::body::to_bytes(req.into_body(), usize::MAX).await {
+            Ok(body) => format!("{} bytes", body.len()).into_response(),
+            Err(e) if is_limit_exceeded(&e) => payload_too_large(),
+            Err(_) => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+
+    async fn upload_status(body: axum::body::Body, content_length: Option<usize>, limit: u64) -> StatusCode {
+        let mut req = Request::builder().method("POST").uri("/upload");
+        if let Some(len) = content_length {
+            req = req.header(header::CONTENT_LENGTH, len);
+        }
+        match limit_request(req.body(body).unwrap(), limit) {
+            Ok(req) => upload(req).await.status(),
+            Err(response) => response.status(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_over_limit_is_413() {
+        let limit = (CHUNK_SIZE * 3) as u64;
+
+        // Declared up front
+        let status = upload_status(chunked_body(CHUNK_SIZE * 4), Some(CHUNK_SIZE * 3 + 1), limit).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Streamed without a length, one byte over
+        let body = axum::body::Body::from_stream(stream::iter([
+            Ok::<_, std::io::Error>(Bytes::from(vec![0u8; CHUNK_SIZE * 3])),
+            Ok(Bytes::from_static(b"x")),
+        ]));
+        assert_eq!(upload_status(body, None, limit).await, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Exactly at the limit is fine
+        assert_eq!(upload_status(chunked_body(CHUNK_SIZE * 3), None, limit).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512KB").unwrap(), 512_000);
+        assert_eq!(parse_size("10MB").unwrap(), 10_000_000);
+        assert_eq!(parse_size("10m").unwrap(), 10_000_000);
+        assert_eq!(parse_size("1GiB").unwrap(), 1 << 30);
+        assert_eq!(parse_size("64 KiB").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+
+        let error = parse_size("10XB").unwrap_err().to_string();
+        assert!(error.contains("unknown unit"), "{}", error);
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("99999999999GB").is_err());
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Limits {
+        #[serde(deserialize_with = "deserialize_size")]
+        max: u64,
+        #[serde(default, deserialize_with = "deserialize_optional_size")]
+        per_host: Option<u64>,
+    }
+
+    #[test]
+    fn test_size_settings_from_toml() {
+        let limits: Limits = toml::from_str(r#"max = "10MB""#).unwrap();
+        assert_eq!((limits.max, limits.per_host), (10_000_000, None));
+
+        let limits: Limits = toml::from_str("max = 2048\nper_host = \"1GiB\"").unwrap();
+        assert_eq!((limits.max, limits.per_host), (2048, Some(1 << 30)));
+
+        let error = toml::from_str::<Limits>(r#"max = "ten megs""#).unwrap_err().to_string();
+        assert!(error.contains("invalid size"), "{}", error);
+    }
+}
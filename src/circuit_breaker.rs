@@ -1,185 +1,398 @@
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
-use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
-#[derive(Debug, Clone)]
+// Per-upstream breaker thresholds, e.g. for a backend:
+//
+//   [backends."api.example.com".circuit_breaker]
+//   failure_threshold = 5
+//   error_rate_threshold = 0.5
+//   cooldown_seconds = 30
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Config {
+    // Consecutive failures that open the circuit
     pub failure_threshold: u32,
-    pub success_threshold: u32,
-    pub timeout: Duration,
+    // Share of failed requests (0.0-1.0) in a window that opens the circuit,
+    // once the window has seen min_requests
+    pub error_rate_threshold: Option<f64>,
+    pub min_requests: u32,
+    pub window_seconds: u64,
+    // How long the circuit stays open before probes are let through
+    pub cooldown_seconds: u64,
+    // Probes allowed at once while half-open
     pub half_open_max_calls: u32,
+    // Successful probes needed to close the circuit again
+    pub success_threshold: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            error_rate_threshold: None,
+            min_requests: 20,
+            window_seconds: 60,
+            cooldown_seconds: 30,
+            half_open_max_calls: 1,
+            success_threshold: 2,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.failure_threshold == 0 || self.success_threshold == 0 || self.half_open_max_calls == 0 {
+            anyhow::bail!("circuit_breaker thresholds must be greater than zero");
+        }
+        if let Some(rate) = self.error_rate_threshold {
+            if !(rate > 0.0 && rate <= 1.0) {
+                anyhow::bail!("circuit_breaker error_rate_threshold must be in (0, 1], not {}", rate);
+            }
+        }
+        if self.window_seconds == 0 {
+            anyhow::bail!("circuit_breaker window_seconds must be greater than zero");
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum State {
     Closed,
     Open,
     HalfOpen,
 }
 
+struct Inner {
+    state: State,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+    opened_at: Option<Instant>,
+    probes_in_flight: u32,
+    probe_successes: u32,
+}
+
 pub struct CircuitBreaker {
     config: Config,
-    state: Arc<RwLock<State>>,
-    failure_count: AtomicU32,
-    success_count: AtomicU32,
-    half_open_calls: AtomicU32,
-    last_failure_time: Arc<RwLock<Option<Instant>>>,
+    inner: Mutex<Inner>,
     total_requests: AtomicU64,
     total_failures: AtomicU64,
+    rejected: AtomicU64,
 }
 
 impl CircuitBreaker {
     pub fn new(config: Config) -> Self {
         Self {
             config,
-            state: Arc::new(RwLock::new(State::Closed)),
-            failure_count: AtomicU32::new(0),
-            success_count: AtomicU32::new(0),
-            half_open_calls: AtomicU32::new(0),
-            last_failure_time: Arc::new(RwLock::new(None)),
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                consecutive_failures: 0,
+                window_start: Instant::now(),
+                window_requests: 0,
+                window_failures: 0,
+                opened_at: None,
+                probes_in_flight: 0,
+                probe_successes: 0,
+            }),
             total_requests: AtomicU64::new(0),
             total_failures: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
         }
     }
-    
-    pub async fn call<F, T>(&self, f: F) -> Result<T>
-    where
-        F: FnOnce() -> Result<T>,
-    {
-        self.total_requests.fetch_add(1, Ordering::Relaxed);
-        
-        let state = self.get_state().await;
-        
-        match state {
-            State::Open => {
-                // Check if we should transition to half-open
-                if self.should_attempt_reset().await {
-                    self.transition_to_half_open().await;
-                } else {
-                    warn!("Circuit breaker is open, rejecting request");
-                    return Err(anyhow::anyhow!("Circuit breaker is open"));
-                }
-            }
-            State::HalfOpen => {
-                let calls = self.half_open_calls.fetch_add(1, Ordering::SeqCst);
-                if calls >= self.config.half_open_max_calls {
-                    warn!("Circuit breaker half-open limit reached");
-                    return Err(anyhow::anyhow!("Circuit breaker half-open limit reached"));
-                }
-            }
-            State::Closed => {
-                // Normal operation
+
+    // Let a request through, or None to fail it fast. An open circuit turns
+    // half-open once the cooldown is over; half-open only lets
+    // half_open_max_calls probes through at a time.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == State::Open {
+            if inner.opened_at.map_or(false, |at| at.elapsed() >= self.cooldown()) {
+                debug!("Circuit breaker half-open, probing");
+                inner.state = State::HalfOpen;
+                inner.probes_in_flight = 0;
+                inner.probe_successes = 0;
+            } else {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return None;
             }
         }
-        
-        // Execute the function
-        match f() {
-            Ok(result) => {
-                self.on_success().await;
-                Ok(result)
-            }
-            Err(e) => {
-                self.on_failure().await;
-                Err(e)
+        let probe = inner.state == State::HalfOpen;
+        if probe {
+            if inner.probes_in_flight >= self.config.half_open_max_calls {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return None;
             }
+            inner.probes_in_flight += 1;
         }
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        Some(Permit { breaker: self.clone(), probe, done: false })
     }
-    
-    async fn get_state(&self) -> State {
-        *self.state.read().await
+
+    pub fn state(&self) -> State {
+        self.inner.lock().unwrap().state
     }
-    
-    async fn on_success(&self) {
-        let state = self.get_state().await;
-        
-        match state {
-            State::Closed => {
-                self.failure_count.store(0, Ordering::Relaxed);
-            }
-            State::HalfOpen => {
-                let successes = self.success_count.fetch_add(1, Ordering::SeqCst) + 1;
-                if successes >= self.config.success_threshold {
-                    self.transition_to_closed().await;
-                }
-            }
-            State::Open => {
-                // Shouldn't happen
-            }
+
+    // Time left until an open circuit lets a probe through
+    pub fn retry_after(&self) -> Duration {
+        let inner = self.inner.lock().unwrap();
+        match (inner.state, inner.opened_at) {
+            (State::Open, Some(at)) => self.cooldown().saturating_sub(at.elapsed()),
+            _ => Duration::ZERO,
         }
     }
-    
-    async fn on_failure(&self) {
-        self.total_failures.fetch_add(1, Ordering::Relaxed);
-        let state = self.get_state().await;
-        
-        match state {
-            State::Closed => {
-                let failures = self.failure_count.fetch_add(1, Ordering::SeqCst) + 1;
-                if failures >= self.config.failure_threshold {
-                    self.transition_to_open().await;
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.config.cooldown_seconds)
+    }
+
+    fn record(&self, probe: bool, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if !success {
+            self.total_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        if probe {
+            inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+        }
+
+        match inner.state {
+            State::HalfOpen if success => {
+                inner.probe_successes += 1;
+                if inner.probe_successes >= self.config.success_threshold {
+                    debug!("Circuit breaker closed after {} successful probes", inner.probe_successes);
+                    self.close(&mut inner);
                 }
             }
             State::HalfOpen => {
-                self.transition_to_open().await;
+                warn!("Circuit breaker probe failed, opening again");
+                self.open(&mut inner);
             }
-            State::Open => {
-                // Already open
+            State::Closed => {
+                if inner.window_start.elapsed() >= Duration::from_secs(self.config.window_seconds) {
+                    inner.window_start = Instant::now();
+                    inner.window_requests = 0;
+                    inner.window_failures = 0;
+                }
+                inner.window_requests += 1;
+                if success {
+                    inner.consecutive_failures = 0;
+                    return;
+                }
+                inner.window_failures += 1;
+                inner.consecutive_failures += 1;
+
+                let error_rate = inner.window_failures as f64 / inner.window_requests as f64;
+                let rate_exceeded = self.config.error_rate_threshold
+                    .map_or(false, |threshold| inner.window_requests >= self.config.min_requests && error_rate >= threshold);
+                if inner.consecutive_failures >= self.config.failure_threshold || rate_exceeded {
+                    warn!(
+                        "Circuit breaker opened after {} consecutive failures ({:.0}% of {} requests failed)",
+                        inner.consecutive_failures, error_rate * 100.0, inner.window_requests
+                    );
+                    self.open(&mut inner);
+                }
             }
+            // Requests let through before the circuit opened
+            State::Open => {}
         }
-        
-        *self.last_failure_time.write().await = Some(Instant::now());
-    }
-    
-    async fn transition_to_open(&self) {
-        let mut state = self.state.write().await;
-        *state = State::Open;
-        self.failure_count.store(0, Ordering::Relaxed);
-        self.success_count.store(0, Ordering::Relaxed);
-        warn!("Circuit breaker transitioned to OPEN");
-    }
-    
-    async fn transition_to_closed(&self) {
-        let mut state = self.state.write().await;
-        *state = State::Closed;
-        self.failure_count.store(0, Ordering::Relaxed);
-        self.success_count.store(0, Ordering::Relaxed);
-        self.half_open_calls.store(0, Ordering::Relaxed);
-        debug!("Circuit breaker transitioned to CLOSED");
-    }
-    
-    async fn transition_to_half_open(&self) {
-        let mut state = self.state.write().await;
-        *state = State::HalfOpen;
-        self.success_count.store(0, Ordering::Relaxed);
-        self.half_open_calls.store(0, Ordering::Relaxed);
-        debug!("Circuit breaker transitioned to HALF-OPEN");
-    }
-    
-    async fn should_attempt_reset(&self) -> bool {
-        if let Some(last_failure) = *self.last_failure_time.read().await {
-            last_failure.elapsed() >= self.config.timeout
-        } else {
-            false
-        }
-    }
-    
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        inner.state = State::Open;
+        inner.opened_at = Some(Instant::now());
+        inner.probes_in_flight = 0;
+        inner.probe_successes = 0;
+    }
+
+    fn close(&self, inner: &mut Inner) {
+        inner.state = State::Closed;
+        inner.opened_at = None;
+        inner.consecutive_failures = 0;
+        inner.window_start = Instant::now();
+        inner.window_requests = 0;
+        inner.window_failures = 0;
+    }
+
     pub fn get_stats(&self) -> CircuitBreakerStats {
+        let inner = self.inner.lock().unwrap();
         CircuitBreakerStats {
+            state: inner.state,
             total_requests: self.total_requests.load(Ordering::Relaxed),
             total_failures: self.total_failures.load(Ordering::Relaxed),
-            current_failures: self.failure_count.load(Ordering::Relaxed),
-            current_successes: self.success_count.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            current_failures: inner.consecutive_failures,
+            current_successes: inner.probe_successes,
+        }
+    }
+}
+
+// A request let through by the breaker. Report how it went; a permit
+// dropped without an outcome (say, the client went away) counts as neither.
+pub struct Permit {
+    breaker: Arc<CircuitBreaker>,
+    probe: bool,
+    done: bool,
+}
+
+impl Permit {
+    pub fn success(mut self) {
+        self.done = true;
+        self.breaker.record(self.probe, true);
+    }
+
+    pub fn failure(mut self) {
+        self.done = true;
+        self.breaker.record(self.probe, false);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.done && self.probe {
+            let mut inner = self.breaker.inner.lock().unwrap();
+            inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
         }
     }
 }
 
 #[derive(Debug, serde::Serialize)]
 pub struct CircuitBreakerStats {
+    pub state: State,
     pub total_requests: u64,
     pub total_failures: u64,
+    // Requests failed fast while open or half-open
+    pub rejected: u64,
     pub current_failures: u32,
     pub current_successes: u32,
-}
\ No newline at end of file
+}
+
+// Breakers by backend, then upstream address, made on first use with the
+// backend's config at the time
+#[derive(Default)]
+pub struct CircuitBreakers {
+    breakers: RwLock<HashMap<String, HashMap<String, Arc<CircuitBreaker>>>>,
+}
+
+impl CircuitBreakers {
+    pub fn get(&self, backend: &str, upstream: &str, config: &Config) -> Arc<CircuitBreaker> {
+        if let Some(breaker) = self.breakers.read().unwrap().get(backend).and_then(|b| b.get(upstream)) {
+            return breaker.clone();
+        }
+        self.breakers.write().unwrap()
+            .entry(backend.to_string())
+            .or_default()
+            .entry(upstream.to_string())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(config.clone())))
+            .clone()
+    }
+
+    // Stats for a backend's upstreams
+    pub fn stats(&self, backend: &str) -> HashMap<String, CircuitBreakerStats> {
+        self.breakers.read().unwrap()
+            .get(backend)
+            .map(|upstreams| upstreams.iter().map(|(upstream, breaker)| (upstream.clone(), breaker.get_stats())).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(config: Config) -> Arc<CircuitBreaker> {
+        Arc::new(CircuitBreaker::new(config))
+    }
+
+    fn fail(breaker: &Arc<CircuitBreaker>, times: u32) {
+        for _ in 0..times {
+            breaker.try_acquire().expect("closed circuit lets requests through").failure();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_fast_fails_then_probes_close() {
+        let breaker = breaker(Config {
+            failure_threshold: 3,
+            cooldown_seconds: 1,
+            half_open_max_calls: 1,
+            success_threshold: 2,
+            ..Default::default()
+        });
+
+        // Successes in between reset the run of failures
+        fail(&breaker, 2);
+        breaker.try_acquire().unwrap().success();
+        fail(&breaker, 2);
+        assert_eq!(breaker.state(), State::Closed);
+        fail(&breaker, 1);
+        assert_eq!(breaker.state(), State::Open);
+
+        // Fast-fail for the cooldown
+        assert!(breaker.try_acquire().is_none());
+        assert!(breaker.retry_after() > Duration::ZERO);
+        assert_eq!(breaker.get_stats().rejected, 1);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        // One probe at a time; a dropped one frees its slot
+        let probe = breaker.try_acquire().expect("probe after cooldown");
+        assert_eq!(breaker.state(), State::HalfOpen);
+        assert!(breaker.try_acquire().is_none());
+        drop(probe);
+
+        breaker.try_acquire().unwrap().success();
+        assert_eq!(breaker.state(), State::HalfOpen);
+        breaker.try_acquire().unwrap().success();
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failed_probe_reopens() {
+        let breaker = breaker(Config { failure_threshold: 1, cooldown_seconds: 1, ..Default::default() });
+        fail(&breaker, 1);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        breaker.try_acquire().expect("probe").failure();
+        assert_eq!(breaker.state(), State::Open);
+        assert!(breaker.try_acquire().is_none());
+    }
+
+    #[test]
+    fn test_error_rate_opens_circuit() {
+        let breaker = breaker(Config {
+            failure_threshold: 100,
+            error_rate_threshold: Some(0.5),
+            min_requests: 10,
+            ..Default::default()
+        });
+
+        // Every other request fails: never two in a row, but half of them
+        for i in 0..9 {
+            let permit = breaker.try_acquire().unwrap();
+            if i % 2 == 0 { permit.failure() } else { permit.success() }
+        }
+        assert_eq!(breaker.state(), State::Closed);
+        fail(&breaker, 1);
+        assert_eq!(breaker.state(), State::Open);
+    }
+
+    #[test]
+    fn test_breakers_are_per_upstream() {
+        let breakers = CircuitBreakers::default();
+        let config = Config { failure_threshold: 1, ..Default::default() };
+        fail(&breakers.get("api", "10.0.0.1:80", &config), 1);
+        breakers.get("api", "10.0.0.2:80", &config).try_acquire().unwrap().success();
+
+        let stats = breakers.stats("api");
+        assert_eq!(stats["10.0.0.1:80"].state, State::Open);
+        assert_eq!(stats["10.0.0.2:80"].state, State::Closed);
+        assert!(breakers.stats("web").is_empty());
+    }
+}
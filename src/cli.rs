@@ -0,0 +1,69 @@
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tracing::Level;
+
+// Command-line options. Anything given here wins over the config file and
+// the built-in defaults, and keeps winning across config reloads.
+#[derive(Parser, Debug, Clone, Default)]
+#[command(name = "miwidothttp", version, about = "High-performance HTTP server and reverse proxy")]
+pub struct Cli {
+    /// Config file to load instead of searching /etc/miwidothttp/config.toml and ./config.toml
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Address for the HTTP listener; replaces bind_address, http_port and [[server.listeners]]
+    #[arg(short, long, value_name = "ADDR:PORT")]
+    pub bind: Option<SocketAddr>,
+
+    /// Directory to serve static files from
+    #[arg(long, value_name = "DIR")]
+    pub static_dir: Option<String>,
+
+    /// Log level for the server (error, warn, info, debug, trace); overrides RUST_LOG
+    #[arg(short, long, value_name = "LEVEL")]
+    pub log_level: Option<Level>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+impl Cli {
+    // Filter directives for tracing_subscriber's EnvFilter
+    pub fn log_filter(&self) -> Option<String> {
+        self.log_level.map(|level| {
+            let level = level.to_string().to_lowercase();
+            format!("miwidothttp={0},tower_http={0}", level)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let cli = Cli::try_parse_from([
+            "miwidothttp",
+            "--config", "/etc/site.toml",
+            "--bind", "127.0.0.1:9000",
+            "--static-dir", "/srv/www",
+            "--log-level", "debug",
+            "--check-config",
+        ]).unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("/etc/site.toml")));
+        assert_eq!(cli.bind, Some("127.0.0.1:9000".parse().unwrap()));
+        assert_eq!(cli.static_dir.as_deref(), Some("/srv/www"));
+        assert_eq!(cli.log_filter().as_deref(), Some("miwidothttp=debug,tower_http=debug"));
+        assert!(cli.check_config);
+
+        let cli = Cli::try_parse_from(["miwidothttp"]).unwrap();
+        assert!(cli.config.is_none() && cli.bind.is_none() && !cli.check_config);
+        assert!(cli.log_filter().is_none());
+
+        assert!(Cli::try_parse_from(["miwidothttp", "--bind", "localhost"]).is_err());
+        assert!(Cli::try_parse_from(["miwidothttp", "--log-level", "loud"]).is_err());
+    }
+}
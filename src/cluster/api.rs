@@ -0,0 +1,197 @@
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use super::{ClusterManager, ClusterStats, NodeRole, NodeState};
+
+// Keys used for /ring when the caller doesn't pass any
+const SAMPLE_KEYS: &[&str] = &[
+    "session:1", "session:2", "session:3", "session:4",
+    "cache:/", "cache:/index.html", "cache:/api", "cache:/static/app.js",
+];
+
+#[derive(Debug, Serialize)]
+pub struct ClusterStatus {
+    pub cluster_name: String,
+    pub node_id: String,
+    pub leader: Option<String>,
+    pub stats: ClusterStats,
+    pub members: Vec<MemberStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemberStatus {
+    pub id: String,
+    pub name: String,
+    pub addr: String,
+    pub grpc_addr: String,
+    pub state: NodeState,
+    pub role: NodeRole,
+    pub cpu_percent: f32,
+    pub memory_percent: f32,
+    pub active_connections: u32,
+    pub version: String,
+    // Unix seconds
+    pub last_seen: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RingStatus {
+    pub members: Vec<String>,
+    pub keys: Vec<KeyOwnership>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyOwnership {
+    pub key: String,
+    pub owner: Option<String>,
+    pub replicas: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RingQuery {
+    // Comma-separated keys to look up
+    keys: Option<String>,
+}
+
+// Mounted under /api/cluster
+pub fn router(manager: Arc<ClusterManager>) -> Router {
+    Router::new()
+        .route("/", get(cluster_status))
+        .route("/ring", get(ring_status))
+        .with_state(manager)
+}
+
+async fn cluster_status(State(manager): State<Arc<ClusterManager>>) -> Json<ClusterStatus> {
+    let members = manager.members().await
+        .into_iter()
+        .map(|node| MemberStatus {
+            id: node.id,
+            name: node.name,
+            addr: node.addr.to_string(),
+            grpc_addr: node.grpc_addr.to_string(),
+            state: node.state,
+            role: node.role,
+            cpu_percent: node.load.cpu_percent,
+            memory_percent: node.load.memory_percent,
+            active_connections: node.load.active_connections,
+            version: node.version,
+            last_seen: node.last_seen.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        })
+        .collect();
+
+    Json(ClusterStatus {
+        cluster_name: manager.cluster_name().to_string(),
+        node_id: manager.node_id().to_string(),
+        leader: manager.leader_id().await,
+        stats: manager.get_cluster_stats().await,
+        members,
+    })
+}
+
+async fn ring_status(
+    State(manager): State<Arc<ClusterManager>>,
+    Query(query): Query<RingQuery>,
+) -> Json<RingStatus> {
+    let keys: Vec<String> = match query.keys {
+        Some(keys) => keys.split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => SAMPLE_KEYS.iter().map(|key| key.to_string()).collect(),
+    };
+
+    let mut ownership = Vec::with_capacity(keys.len());
+    for key in keys {
+        let owner = manager.get_node_for_key(&key).await;
+        let replicas = manager.get_replicas_for_key(&key).await;
+        ownership.push(KeyOwnership { key, owner, replicas });
+    }
+    let members: BTreeSet<String> = manager.members().await
+        .into_iter()
+        .filter(|node| node.state == NodeState::Active)
+        .map(|node| node.id)
+        .collect();
+
+    Json(RingStatus { members: members.into_iter().collect(), keys: ownership })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::ClusterConfig;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+    use tower::ServiceExt;
+
+    async fn get_json(app: &Router, uri: &str) -> serde_json::Value {
+        let response = app.clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_single_node_cluster_status() {
+        let config = ClusterConfig {
+            enabled: true,
+            node_id: "node-api".to_string(),
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 17981)),
+            gossip_port: 17981,
+            grpc_port: 17982,
+            gossip_interval: Duration::from_millis(50),
+            heartbeat_interval: Duration::from_millis(50),
+            election_timeout: Duration::from_millis(200),
+            replication_factor: 1,
+            quorum_size: 1,
+            enable_auto_join: false,
+            etcd_endpoints: vec![],
+            ..Default::default()
+        };
+        let mut manager = ClusterManager::new(config).await.unwrap();
+        manager.start().await.unwrap();
+        let manager = Arc::new(manager);
+        let app = router(manager.clone());
+
+        // A lone node elects itself and owns the whole ring
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let status = loop {
+            let status = get_json(&app, "/").await;
+            let ring = get_json(&app, "/ring?keys=a").await;
+            if status["leader"] == "node-api" && ring["keys"][0]["owner"] == "node-api" {
+                break status;
+            }
+            assert!(Instant::now() < deadline, "single node never became leader");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+
+        assert_eq!(status["node_id"], "node-api");
+        assert_eq!(status["stats"]["total_nodes"], 1);
+        assert_eq!(status["stats"]["active_nodes"], 1);
+        assert_eq!(status["members"][0]["id"], "node-api");
+        assert_eq!(status["members"][0]["state"], "Active");
+        assert_eq!(status["members"][0]["role"], "Leader");
+
+        let ring = get_json(&app, "/ring").await;
+        assert_eq!(ring["members"], serde_json::json!(["node-api"]));
+        assert_eq!(ring["keys"].as_array().unwrap().len(), SAMPLE_KEYS.len());
+        for entry in ring["keys"].as_array().unwrap() {
+            assert_eq!(entry["owner"], "node-api");
+            assert_eq!(entry["replicas"], serde_json::json!(["node-api"]));
+        }
+
+        manager.shutdown().await.unwrap();
+    }
+}
@@ -1,13 +1,25 @@
 use anyhow::Result;
+use futures::future::join_all;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use chrono::{DateTime, TimeZone, Utc};
+use std::time::Duration;
 use tokio::sync::{RwLock, Mutex, broadcast};
+use tonic::transport::Channel;
+use tonic::{Request, Response, Status};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use super::{ClusterConfig, ClusterEvent, NodeRole};
+use super::grpc::{cluster_rpc as pb, lazy_channel};
+use super::grpc::cluster_rpc::raft_client::RaftClient;
+use super::grpc::cluster_rpc::raft_server::{Raft, RaftServer};
+use super::{ClusterConfig, ClusterEvent, NodeInfo, NodeRole, NodeState};
+
+// Peers that take longer than this to answer are treated as unreachable for
+// the current round; it has to stay well under the election timeout
+const RPC_TIMEOUT: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RaftState {
@@ -25,7 +37,8 @@ pub struct LogEntry {
     pub term: u64,
     pub index: u64,
     pub command: Command,
-    pub timestamp: Instant,
+    // Wall-clock time the leader created the entry; informational only
+    pub timestamp: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,6 +64,9 @@ pub struct StateUpdate {
 pub struct ConsensusManager {
     config: ClusterConfig,
     node_id: String,
+    // Cluster membership; every other member is a Raft peer
+    nodes: Arc<RwLock<HashMap<String, NodeInfo>>>,
+    clients: Arc<Mutex<HashMap<SocketAddr, RaftClient<Channel>>>>,
     role: Arc<RwLock<NodeRole>>,
     state: Arc<RwLock<RaftState>>,
     leader_id: Arc<RwLock<Option<String>>>,
@@ -61,7 +77,11 @@ pub struct ConsensusManager {
 }
 
 impl ConsensusManager {
-    pub async fn new(config: &ClusterConfig, event_tx: broadcast::Sender<ClusterEvent>) -> Result<Self> {
+    pub async fn new(
+        config: &ClusterConfig,
+        nodes: Arc<RwLock<HashMap<String, NodeInfo>>>,
+        event_tx: broadcast::Sender<ClusterEvent>,
+    ) -> Result<Self> {
         let state = RaftState {
             current_term: 0,
             voted_for: None,
@@ -69,7 +89,7 @@ impl ConsensusManager {
                 term: 0,
                 index: 0,
                 command: Command::NoOp,
-                timestamp: Instant::now(),
+                timestamp: Utc::now(),
             }],
             commit_index: 0,
             last_applied: 0,
@@ -80,6 +100,8 @@ impl ConsensusManager {
         Ok(ConsensusManager {
             config: config.clone(),
             node_id: config.node_id.clone(),
+            nodes,
+            clients: Arc::new(Mutex::new(HashMap::new())),
             role: Arc::new(RwLock::new(NodeRole::Follower)),
             state: Arc::new(RwLock::new(state)),
             leader_id: Arc::new(RwLock::new(None)),
@@ -105,6 +127,12 @@ impl ConsensusManager {
         Ok(())
     }
 
+    // RequestVote/AppendEntries for the other members; ClusterManager serves
+    // it on grpc_port
+    pub fn service(&self) -> RaftServer<RaftService> {
+        RaftServer::new(RaftService { consensus: self.clone() })
+    }
+
     async fn consensus_loop(&self) {
         let mut ticker = tokio::time::interval(Duration::from_millis(100));
         
@@ -174,25 +202,32 @@ impl ConsensusManager {
         self.reset_election_timer().await;
 
         // Request votes from other nodes
-        let votes_needed = (self.config.quorum_size + 1) / 2;
+        let votes_needed = self.quorum().await;
         let mut votes_received = 1; // Vote for self
 
-        // Simulate vote collection (would use gRPC in real implementation)
         let vote_responses = self.request_votes(current_term).await;
 
+        // A leader for this term (or a later one) may have shown up while
+        // the votes were outstanding
+        if *self.role.read().await != NodeRole::Candidate || self.state.read().await.current_term != current_term {
+            return;
+        }
+
         for response in vote_responses {
-            if response.vote_granted {
-                votes_received += 1;
-                if votes_received >= votes_needed {
-                    info!("Won election with {} votes", votes_received);
-                    self.become_leader().await;
-                    return;
-                }
-            } else if response.term > current_term {
+            if response.term > current_term {
                 // Found a node with higher term, become follower
                 self.become_follower(response.term).await;
                 return;
             }
+            if response.vote_granted {
+                votes_received += 1;
+            }
+        }
+
+        if votes_received >= votes_needed {
+            info!("Won election with {} votes", votes_received);
+            self.become_leader().await;
+            return;
         }
 
         // Not enough votes, remain candidate or become follower
@@ -207,15 +242,26 @@ impl ConsensusManager {
         *self.leader_id.write().await = Some(self.node_id.clone());
         
         // Initialize next_index and match_index for all nodes
+        let peers = self.peers().await;
         let mut state = self.state.write().await;
         let last_log_index = state.log.last().map(|e| e.index).unwrap_or(0);
         
-        // In real implementation, would get list of all nodes
-        let nodes = vec![]; // Placeholder
-        for node_id in nodes {
+        state.next_index.clear();
+        state.match_index.clear();
+        for (node_id, _) in peers {
             state.next_index.insert(node_id.clone(), last_log_index + 1);
             state.match_index.insert(node_id, 0);
         }
+
+        // Entries from earlier terms only commit once one from this term
+        // does, so start the term with a no-op
+        let entry = LogEntry {
+            term: state.current_term,
+            index: last_log_index + 1,
+            command: Command::NoOp,
+            timestamp: Utc::now(),
+        };
+        state.log.push(entry);
         
         drop(state);
         
@@ -241,9 +287,13 @@ impl ConsensusManager {
         
         *self.role.write().await = NodeRole::Follower;
         
+        // Keep our vote when staying in the same term, so we never vote
+        // twice in one term
         let mut state = self.state.write().await;
-        state.current_term = term;
-        state.voted_for = None;
+        if term > state.current_term {
+            state.current_term = term;
+            state.voted_for = None;
+        }
         
         self.reset_election_timer().await;
     }
@@ -251,20 +301,9 @@ impl ConsensusManager {
     async fn send_heartbeats(&self) {
         debug!("Sending heartbeats");
         
-        // In real implementation, send AppendEntries RPC to all nodes
-        // For now, we'll simulate this
-        let state = self.state.read().await;
-        let heartbeat = AppendEntriesRequest {
-            term: state.current_term,
-            leader_id: self.node_id.clone(),
-            prev_log_index: state.log.last().map(|e| e.index).unwrap_or(0),
-            prev_log_term: state.log.last().map(|e| e.term).unwrap_or(0),
-            entries: vec![],
-            leader_commit: state.commit_index,
-        };
-        
-        // Send to all followers (would use gRPC)
-        self.broadcast_append_entries(heartbeat).await;
+        // AppendEntries doubles as the heartbeat; followers that are behind
+        // get the entries they are missing in the same request
+        self.broadcast_append_entries().await;
     }
 
     async fn process_client_requests(&self) {
@@ -273,35 +312,152 @@ impl ConsensusManager {
     }
 
     async fn replicate_log_entries(&self) {
-        // Replicate any uncommitted log entries to followers
-        let state = self.state.read().await;
+        // Commit the newest entry from this term that a majority holds;
+        // everything before it commits with it
+        let quorum = self.quorum().await;
+        let mut state = self.state.write().await;
+        let last_log_index = state.log.len() as u64 - 1;
         
-        for (node_id, next_idx) in &state.next_index {
-            if *next_idx <= state.log.len() as u64 {
-                // Send log entries from next_idx onwards
-                let entries: Vec<LogEntry> = state.log
+        for index in (state.commit_index + 1..=last_log_index).rev() {
+            if state.log[index as usize].term != state.current_term {
+                break;
+            }
+            let replicas = 1 + state.match_index.values().filter(|m| **m >= index).count();
+            if replicas >= quorum {
+                debug!("Committing log up to {}", index);
+                state.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    async fn request_votes(&self, term: u64) -> Vec<VoteResponse> {
+        let request = {
+            let state = self.state.read().await;
+            pb::ElectionRequest {
+                term,
+                candidate_id: self.node_id.clone(),
+                last_log_index: state.log.last().map(|e| e.index).unwrap_or(0),
+                last_log_term: state.log.last().map(|e| e.term).unwrap_or(0),
+            }
+        };
+
+        let calls = self.peers().await.into_iter().map(|(node_id, addr)| {
+            let request = request.clone();
+            async move {
+                let mut client = self.connect_to_node(addr).await.ok()?;
+                match tokio::time::timeout(RPC_TIMEOUT, client.request_vote(request)).await {
+                    Ok(Ok(response)) => {
+                        let response = response.into_inner();
+                        Some(VoteResponse { term: response.term, vote_granted: response.vote_granted })
+                    }
+                    Ok(Err(status)) => {
+                        debug!("RequestVote to {} failed: {}", node_id, status);
+                        None
+                    }
+                    Err(_) => {
+                        debug!("RequestVote to {} timed out", node_id);
+                        None
+                    }
+                }
+            }
+        });
+
+        join_all(calls).await.into_iter().flatten().collect()
+    }
+
+    async fn broadcast_append_entries(&self) {
+        let peers = self.peers().await;
+        let (term, requests) = {
+            let mut state = self.state.write().await;
+            let term = state.current_term;
+            let last_log_index = state.log.len() as u64 - 1;
+            let requests: Vec<_> = peers.into_iter().map(|(node_id, addr)| {
+                let next_index = *state.next_index.entry(node_id.clone()).or_insert(last_log_index + 1);
+                let prev_log_index = next_index.clamp(1, last_log_index + 1) - 1;
+                let entries: Vec<pb::RaftLogEntry> = state.log[prev_log_index as usize + 1..]
                     .iter()
-                    .skip(*next_idx as usize - 1)
-                    .cloned()
+                    .filter_map(|entry| entry.try_into().ok())
                     .collect();
-                
-                if !entries.is_empty() {
-                    debug!("Replicating {} entries to {}", entries.len(), node_id);
-                    // Send AppendEntries RPC with entries
+                let sent_up_to = prev_log_index + entries.len() as u64;
+                let request = pb::AppendEntriesRequest {
+                    term,
+                    leader_id: self.node_id.clone(),
+                    prev_log_index,
+                    prev_log_term: state.log[prev_log_index as usize].term,
+                    entries,
+                    leader_commit: state.commit_index,
+                };
+                (node_id, addr, sent_up_to, request)
+            }).collect();
+            (term, requests)
+        };
+
+        let calls = requests.into_iter().map(|(node_id, addr, sent_up_to, request)| async move {
+            let mut client = self.connect_to_node(addr).await.ok()?;
+            match tokio::time::timeout(RPC_TIMEOUT, client.append_entries(request)).await {
+                Ok(Ok(response)) => Some((node_id, sent_up_to, response.into_inner())),
+                Ok(Err(status)) => {
+                    debug!("AppendEntries to {} failed: {}", node_id, status);
+                    None
+                }
+                Err(_) => {
+                    debug!("AppendEntries to {} timed out", node_id);
+                    None
                 }
             }
+        });
+        let responses: Vec<_> = join_all(calls).await.into_iter().flatten().collect();
+
+        let mut state = self.state.write().await;
+        if state.current_term != term {
+            return;
+        }
+        for (node_id, sent_up_to, response) in responses {
+            if response.term > term {
+                drop(state);
+                self.become_follower(response.term).await;
+                return;
+            }
+            if response.success {
+                state.match_index.insert(node_id.clone(), sent_up_to);
+                state.next_index.insert(node_id, sent_up_to + 1);
+            } else {
+                // Follower's log diverges before next_index; back up one
+                let next_index = state.next_index.entry(node_id).or_insert(1);
+                *next_index = next_index.saturating_sub(1).max(1);
+            }
         }
     }
 
-    async fn request_votes(&self, term: u64) -> Vec<VoteResponse> {
-        // In real implementation, send RequestVote RPC to all nodes
-        // For simulation, return empty vec
-        vec![]
+    // Other cluster members and where their Raft service listens
+    async fn peers(&self) -> Vec<(String, SocketAddr)> {
+        self.nodes.read().await
+            .values()
+            .filter(|node| node.id != self.node_id && node.state != NodeState::Leaving)
+            .map(|node| (node.id.clone(), node.grpc_addr))
+            .collect()
     }
 
-    async fn broadcast_append_entries(&self, request: AppendEntriesRequest) {
-        // In real implementation, send to all nodes via gRPC
-        debug!("Broadcasting append entries for term {}", request.term);
+    // Votes or replicas needed: a majority of the current voting members,
+    // ourselves included. Observers get the log but don't count.
+    async fn quorum(&self) -> usize {
+        let voting_peers = self.nodes.read().await
+            .values()
+            .filter(|node| node.id != self.node_id && node.state != NodeState::Leaving && node.role != NodeRole::Observer)
+            .count();
+        majority(voting_peers + 1)
+    }
+
+    async fn connect_to_node(&self, addr: SocketAddr) -> Result<RaftClient<Channel>> {
+        let mut clients = self.clients.lock().await;
+        if let Some(client) = clients.get(&addr) {
+            return Ok(client.clone());
+        }
+
+        let client = RaftClient::new(lazy_channel(addr, RPC_TIMEOUT)?);
+        clients.insert(addr, client.clone());
+        Ok(client)
     }
 
     async fn reset_election_timer(&self) {
@@ -336,7 +492,7 @@ impl ConsensusManager {
             term: state.current_term,
             index,
             command,
-            timestamp: Instant::now(),
+            timestamp: Utc::now(),
         };
         
         state.log.push(entry);
@@ -368,14 +524,13 @@ impl ConsensusManager {
         }
 
         // If RPC request or response contains term T > currentTerm:
-        // set currentTerm = T, convert to follower
+        // set currentTerm = T, convert to follower. A candidate that hears
+        // from the leader of its own term steps down too.
         if request.term > state.current_term {
             state.current_term = request.term;
             state.voted_for = None;
-            drop(state);
-            self.become_follower(request.term).await;
-            state = self.state.write().await;
         }
+        *self.role.write().await = NodeRole::Follower;
 
         // Reset election timer
         self.reset_election_timer().await;
@@ -384,17 +539,11 @@ impl ConsensusManager {
         *self.leader_id.write().await = Some(request.leader_id.clone());
 
         // Reply false if log doesn't contain an entry at prevLogIndex
-        // whose term matches prevLogTerm
-        if request.prev_log_index > 0 {
-            if state.log.len() < request.prev_log_index as usize {
-                return AppendEntriesResponse {
-                    term: state.current_term,
-                    success: false,
-                };
-            }
-            
-            let prev_entry = &state.log[request.prev_log_index as usize - 1];
-            if prev_entry.term != request.prev_log_term {
+        // whose term matches prevLogTerm. log[0] is the initial no-op, so
+        // an entry's index is its position in the log.
+        match state.log.get(request.prev_log_index as usize) {
+            Some(prev_entry) if prev_entry.term == request.prev_log_term => {}
+            _ => {
                 return AppendEntriesResponse {
                     term: state.current_term,
                     success: false,
@@ -402,14 +551,21 @@ impl ConsensusManager {
             }
         }
 
-        // Append new entries
+        // Append new entries, dropping any conflicting suffix first
+        let last_new_index = request.prev_log_index + request.entries.len() as u64;
         for entry in request.entries {
+            let position = entry.index as usize;
+            match state.log.get(position) {
+                Some(existing) if existing.term == entry.term => continue,
+                Some(_) => state.log.truncate(position),
+                None => {}
+            }
             state.log.push(entry);
         }
 
         // Update commit index
         if request.leader_commit > state.commit_index {
-            state.commit_index = request.leader_commit.min(state.log.len() as u64 - 1);
+            state.commit_index = request.leader_commit.min(last_new_index);
         }
 
         AppendEntriesResponse {
@@ -434,6 +590,7 @@ impl ConsensusManager {
         if request.term > state.current_term {
             state.current_term = request.term;
             state.voted_for = None;
+            *self.role.write().await = NodeRole::Follower;
         }
 
         // Check if we can vote for this candidate
@@ -472,6 +629,8 @@ impl Clone for ConsensusManager {
         ConsensusManager {
             config: self.config.clone(),
             node_id: self.node_id.clone(),
+            nodes: self.nodes.clone(),
+            clients: self.clients.clone(),
             role: self.role.clone(),
             state: self.state.clone(),
             leader_id: self.leader_id.clone(),
@@ -484,31 +643,271 @@ impl Clone for ConsensusManager {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct AppendEntriesRequest {
-    term: u64,
-    leader_id: String,
-    prev_log_index: u64,
-    prev_log_term: u64,
-    entries: Vec<LogEntry>,
-    leader_commit: u64,
+pub struct AppendEntriesRequest {
+    pub term: u64,
+    pub leader_id: String,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct AppendEntriesResponse {
-    term: u64,
-    success: bool,
+pub struct AppendEntriesResponse {
+    pub term: u64,
+    pub success: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct VoteRequest {
-    term: u64,
-    candidate_id: String,
-    last_log_index: u64,
-    last_log_term: u64,
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate_id: String,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct VoteResponse {
-    term: u64,
-    vote_granted: bool,
-}
\ No newline at end of file
+pub struct VoteResponse {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+fn majority(voting_members: usize) -> usize {
+    voting_members / 2 + 1
+}
+
+impl TryFrom<&LogEntry> for pb::RaftLogEntry {
+    type Error = serde_json::Error;
+
+    fn try_from(entry: &LogEntry) -> Result<Self, Self::Error> {
+        Ok(pb::RaftLogEntry {
+            term: entry.term,
+            index: entry.index,
+            command: serde_json::to_vec(&entry.command)?,
+            timestamp_ms: entry.timestamp.timestamp_millis(),
+        })
+    }
+}
+
+impl TryFrom<pb::AppendEntriesRequest> for AppendEntriesRequest {
+    type Error = serde_json::Error;
+
+    fn try_from(request: pb::AppendEntriesRequest) -> Result<Self, Self::Error> {
+        let entries = request.entries.into_iter()
+            .map(|entry| Ok(LogEntry {
+                term: entry.term,
+                index: entry.index,
+                command: serde_json::from_slice(&entry.command)?,
+                timestamp: Utc.timestamp_millis_opt(entry.timestamp_ms).single().unwrap_or_default(),
+            }))
+            .collect::<Result<_, Self::Error>>()?;
+
+        Ok(AppendEntriesRequest {
+            term: request.term,
+            leader_id: request.leader_id,
+            prev_log_index: request.prev_log_index,
+            prev_log_term: request.prev_log_term,
+            entries,
+            leader_commit: request.leader_commit,
+        })
+    }
+}
+
+// gRPC front end for a node's ConsensusManager
+pub struct RaftService {
+    consensus: ConsensusManager,
+}
+
+#[tonic::async_trait]
+impl Raft for RaftService {
+    async fn request_vote(
+        &self,
+        request: Request<pb::ElectionRequest>,
+    ) -> Result<Response<pb::ElectionResponse>, Status> {
+        let req = request.into_inner();
+        debug!("Vote requested by {} for term {}", req.candidate_id, req.term);
+
+        let response = self.consensus.handle_request_vote(VoteRequest {
+            term: req.term,
+            candidate_id: req.candidate_id,
+            last_log_index: req.last_log_index,
+            last_log_term: req.last_log_term,
+        }).await;
+
+        Ok(Response::new(pb::ElectionResponse {
+            term: response.term,
+            vote_granted: response.vote_granted,
+            voter_id: self.consensus.node_id.clone(),
+        }))
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<pb::AppendEntriesRequest>,
+    ) -> Result<Response<pb::AppendEntriesResponse>, Status> {
+        let request = AppendEntriesRequest::try_from(request.into_inner())
+            .map_err(|e| Status::invalid_argument(format!("Invalid log entry: {}", e)))?;
+
+        let response = self.consensus.handle_append_entries(request).await;
+
+        Ok(Response::new(pb::AppendEntriesResponse {
+            term: response.term,
+            success: response.success,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::ClusterManager;
+
+    fn node_config(node_id: &str, grpc_port: u16) -> ClusterConfig {
+        ClusterConfig {
+            node_id: node_id.to_string(),
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], grpc_port - 1)),
+            grpc_port,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_majority() {
+        assert_eq!(majority(1), 1);
+        assert_eq!(majority(2), 2);
+        assert_eq!(majority(3), 2);
+        assert_eq!(majority(4), 3);
+        assert_eq!(majority(5), 3);
+    }
+
+    #[tokio::test]
+    async fn test_three_nodes_elect_single_leader() {
+        let configs: Vec<_> = [("node-1", 17961), ("node-2", 17963), ("node-3", 17965)]
+            .iter()
+            .map(|(id, port)| node_config(id, *port))
+            .collect();
+
+        // Every node knows the full membership, as it would from gossip
+        let membership: HashMap<String, NodeInfo> = configs.iter()
+            .map(|config| {
+                let mut node = ClusterManager::local_node_info(config).unwrap();
+                node.state = NodeState::Active;
+                (node.id.clone(), node)
+            })
+            .collect();
+
+        let mut managers = Vec::new();
+        for config in &configs {
+            let (event_tx, _) = broadcast::channel(16);
+            let nodes = Arc::new(RwLock::new(membership.clone()));
+            let manager = ConsensusManager::new(config, nodes, event_tx).await.unwrap();
+            let addr = SocketAddr::new(config.bind_addr.ip(), config.grpc_port);
+            tokio::spawn(tonic::transport::Server::builder().add_service(manager.service()).serve(addr));
+            manager.start().await.unwrap();
+            managers.push(manager);
+        }
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        let leader = loop {
+            assert!(tokio::time::Instant::now() < deadline, "no leader elected");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut leaders = Vec::new();
+            for manager in &managers {
+                if manager.is_leader().await {
+                    leaders.push(manager.node_id.clone());
+                }
+            }
+            if leaders.len() != 1 {
+                continue;
+            }
+            let leader = leaders.remove(0);
+            let mut agreed = true;
+            for manager in &managers {
+                agreed &= manager.get_leader().await.as_deref() == Some(leader.as_str());
+            }
+            if agreed {
+                break leader;
+            }
+        };
+
+        // The leader's commands reach the followers and commit
+        let leader_manager = managers.iter().find(|m| m.node_id == leader).unwrap();
+        let index = leader_manager.propose_command(Command::Custom(b"hello".to_vec())).await.unwrap();
+        loop {
+            assert!(tokio::time::Instant::now() < deadline, "command was not replicated");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut committed = true;
+            for manager in &managers {
+                committed &= manager.get_state().await.commit_index >= index;
+            }
+            if committed {
+                break;
+            }
+        }
+
+        let terms: Vec<u64> = join_all(managers.iter().map(|m| async { m.get_state().await.current_term })).await;
+        assert!(terms.iter().all(|term| *term == terms[0]));
+        let leaders = join_all(managers.iter().map(|m| m.is_leader())).await;
+        assert_eq!(leaders.iter().filter(|leader| **leader).count(), 1);
+
+        for manager in &managers {
+            manager.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follower_rejects_mismatched_log_and_keeps_its_vote() {
+        let (event_tx, _) = broadcast::channel(16);
+        let nodes = Arc::new(RwLock::new(HashMap::new()));
+        let manager = ConsensusManager::new(&node_config("node-1", 17967), nodes, event_tx).await.unwrap();
+
+        let vote = |candidate: &str| VoteRequest {
+            term: 1,
+            candidate_id: candidate.to_string(),
+            last_log_index: 0,
+            last_log_term: 0,
+        };
+        assert!(manager.handle_request_vote(vote("node-2")).await.vote_granted);
+        // Losing interest in the election must not free up the vote
+        manager.become_follower(1).await;
+        assert!(!manager.handle_request_vote(vote("node-3")).await.vote_granted);
+
+        let append = |prev_log_index, prev_log_term| AppendEntriesRequest {
+            term: 1,
+            leader_id: "node-2".to_string(),
+            prev_log_index,
+            prev_log_term,
+            entries: vec![LogEntry { term: 1, index: prev_log_index + 1, command: Command::NoOp, timestamp: Utc::now() }],
+            leader_commit: 1,
+        };
+        assert!(!manager.handle_append_entries(append(3, 1)).await.success);
+        assert!(manager.handle_append_entries(append(0, 0)).await.success);
+
+        let state = manager.get_state().await;
+        assert_eq!(state.log.len(), 2);
+        assert_eq!(state.commit_index, 1);
+        assert_eq!(manager.get_leader().await.as_deref(), Some("node-2"));
+    }
+
+    #[test]
+    fn test_log_entry_keeps_leader_timestamp_over_grpc() {
+        let timestamp = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        let entry = LogEntry { term: 2, index: 7, command: Command::NoOp, timestamp };
+
+        let request = pb::AppendEntriesRequest {
+            term: 2,
+            leader_id: "node-1".to_string(),
+            prev_log_index: 6,
+            prev_log_term: 2,
+            entries: vec![pb::RaftLogEntry::try_from(&entry).unwrap()],
+            leader_commit: 6,
+        };
+        let request = AppendEntriesRequest::try_from(request).unwrap();
+
+        let received = &request.entries[0];
+        assert_eq!((received.term, received.index), (2, 7));
+        assert!(matches!(received.command, Command::NoOp));
+        assert_eq!(received.timestamp, timestamp);
+    }
+}
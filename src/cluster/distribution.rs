@@ -1,8 +1,9 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use hashring::HashRing;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::{debug, info, warn};
@@ -40,6 +41,8 @@ pub struct DistributionManager {
     hash_ring: Arc<RwLock<HashRing<String>>>,
     node_weights: Arc<RwLock<HashMap<String, f32>>>,
     key_mappings: Arc<RwLock<HashMap<String, String>>>,
+    // Rebuilt whenever the set of nodes changes
+    maglev_table: Arc<RwLock<MaglevTable>>,
     migration_state: Arc<RwLock<MigrationState>>,
 }
 
@@ -63,12 +66,27 @@ impl DistributionManager {
             weights: HashMap::new(),
         };
 
+        // Mappings a previous run left behind; a file that can't be read is
+        // an error rather than silently forgetting them
+        let key_mappings = match &config.key_mappings_path {
+            Some(path) => {
+                let mappings = load_key_mappings(path).await
+                    .with_context(|| format!("failed to load key mappings from {}", path.display()))?;
+                if !mappings.is_empty() {
+                    info!("Restored {} key mappings from {}", mappings.len(), path.display());
+                }
+                mappings
+            }
+            None => HashMap::new(),
+        };
+
         Ok(DistributionManager {
             config: config.clone(),
             strategy,
             hash_ring: Arc::new(RwLock::new(HashRing::new())),
             node_weights: Arc::new(RwLock::new(HashMap::new())),
-            key_mappings: Arc::new(RwLock::new(HashMap::new())),
+            key_mappings: Arc::new(RwLock::new(key_mappings)),
+            maglev_table: Arc::new(RwLock::new(MaglevTable::default())),
             migration_state: Arc::new(RwLock::new(MigrationState {
                 active: false,
                 source_node: None,
@@ -96,15 +114,17 @@ impl DistributionManager {
                 weights.insert(node.id.clone(), weight);
 
                 // Add virtual nodes for better distribution
-                let virtual_node_count = (self.strategy.virtual_nodes as f32 * weight) as u32;
+                let virtual_node_count = self.virtual_node_count(weight);
                 for i in 0..virtual_node_count {
                     ring.add(format!("{}:{}", node.id, i));
                 }
 
-                info!("Added node {} with weight {} ({} vnodes)", 
+                // Runs on every heartbeat, so only at debug level
+                debug!("Added node {} with weight {} ({} vnodes)", 
                     node.id, weight, virtual_node_count);
             }
         }
+        *self.maglev_table.write().await = MaglevTable::new(weights.keys().cloned().collect());
 
         Ok(())
     }
@@ -115,12 +135,15 @@ impl DistributionManager {
             + (node.capacity.memory_mb as f32 / 1024.0 * 0.3)
             + (node.capacity.max_connections as f32 / 1000.0 * 0.4);
 
-        // Adjust for current load (higher load = lower weight)
-        let load_factor = 1.0 - (
-            (node.load.cpu_percent / 100.0 * 0.3)
-            + (node.load.memory_percent / 100.0 * 0.3)
-            + (node.load.active_connections as f32 / node.capacity.max_connections as f32 * 0.4)
-        );
+        // Adjust for current load (higher load = lower weight). Load averages
+        // and connection counts can run past capacity, so a saturated node
+        // keeps a small share instead of dropping off the ring.
+        let connection_load = node.load.active_connections as f32 / node.capacity.max_connections.max(1) as f32;
+        let load_factor = (1.0 - (
+            (node.load.cpu_percent / 100.0).min(1.0) * 0.3
+            + (node.load.memory_percent / 100.0).min(1.0) * 0.3
+            + connection_load.min(1.0) * 0.4
+        )).max(0.05);
 
         // Check for custom weight override
         let custom_weight = self.strategy.weights.get(&node.id).copied().unwrap_or(1.0);
@@ -128,18 +151,37 @@ impl DistributionManager {
         capacity_weight * load_factor * custom_weight
     }
 
+    fn virtual_node_count(&self, weight: f32) -> u32 {
+        (self.strategy.virtual_nodes as f32 * weight).max(1.0) as u32
+    }
+
     pub async fn get_node_for_key(&self, key: &str) -> Option<String> {
         // Check affinity rules first
-        if let Some(node) = self.check_affinity_rules(key).await {
-            return Some(node);
-        }
+        let excluded = match self.check_affinity_rules(key).await {
+            Ok(Affinity::Pinned(node)) => return Some(node),
+            Ok(Affinity::Unpinned { excluded }) => excluded,
+            Err(e) => {
+                warn!("No node for key {}: {}", key, e);
+                return None;
+            }
+        };
 
         // Check if key has a specific mapping (during migration)
         let mappings = self.key_mappings.read().await;
-        if let Some(node) = mappings.get(key) {
+        if let Some(node) = mappings.get(key).filter(|node| !excluded.contains(*node)) {
             return Some(node.clone());
         }
+        drop(mappings);
+
+        let node = self.hash_key(key).await;
+        match node {
+            // An excluded node is never chosen, whatever the algorithm says
+            Some(node) if excluded.contains(&node) => self.rendezvous_hash(key, &excluded).await,
+            node => node,
+        }
+    }
 
+    async fn hash_key(&self, key: &str) -> Option<String> {
         // Use hash ring for distribution
         let ring = self.hash_ring.read().await;
         match self.strategy.algorithm {
@@ -148,7 +190,7 @@ impl DistributionManager {
                     .map(|vnode| vnode.split(':').next().unwrap().to_string())
             }
             HashingAlgorithm::RendezvousHash => {
-                self.rendezvous_hash(key).await
+                self.rendezvous_hash(key, &HashSet::new()).await
             }
             HashingAlgorithm::JumpHash => {
                 self.jump_hash(key).await
@@ -184,32 +226,55 @@ impl DistributionManager {
         replicas.into_iter().collect()
     }
 
-    async fn check_affinity_rules(&self, key: &str) -> Option<String> {
-        for rule in &self.strategy.affinity_rules {
-            if key.contains(&rule.key_pattern) {
-                // Check required nodes first
-                if !rule.required_nodes.is_empty() {
-                    // TODO: Check if required nodes are available
-                    return rule.required_nodes.first().cloned();
-                }
-                
-                // Check preferred nodes
-                if !rule.preferred_nodes.is_empty() {
-                    // TODO: Check if preferred nodes are available
-                    return rule.preferred_nodes.first().cloned();
-                }
+    // Rules matching `key`, in order: the first available required node pins
+    // it (none up is an error), then the first available preferred node.
+    // Nodes are available while they are active members; excluded nodes from
+    // every matching rule are never picked.
+    async fn check_affinity_rules(&self, key: &str) -> Result<Affinity> {
+        let rules: Vec<_> = self.strategy.affinity_rules.iter()
+            .filter(|rule| key.contains(&rule.key_pattern))
+            .collect();
+        if rules.is_empty() {
+            return Ok(Affinity::Unpinned { excluded: HashSet::new() });
+        }
+        
+        let excluded: HashSet<String> = rules.iter()
+            .flat_map(|rule| rule.excluded_nodes.iter().cloned())
+            .collect();
+        let weights = self.node_weights.read().await;
+        let available = |node: &&String| weights.contains_key(*node) && !excluded.contains(*node);
+        
+        for rule in &rules {
+            if rule.required_nodes.is_empty() {
+                continue;
             }
+            return match rule.required_nodes.iter().find(available) {
+                Some(node) => Ok(Affinity::Pinned(node.clone())),
+                None => Err(anyhow::anyhow!(
+                    "none of the required nodes {:?} for {:?} are available",
+                    rule.required_nodes, rule.key_pattern
+                )),
+            };
         }
-        None
+        for rule in &rules {
+            if let Some(node) = rule.preferred_nodes.iter().find(available) {
+                return Ok(Affinity::Pinned(node.clone()));
+            }
+        }
+        if rules.iter().any(|rule| !rule.preferred_nodes.is_empty()) {
+            debug!("No preferred node for {} is available, hashing it", key);
+        }
+        
+        Ok(Affinity::Unpinned { excluded })
     }
 
-    async fn rendezvous_hash(&self, key: &str) -> Option<String> {
+    async fn rendezvous_hash(&self, key: &str, excluded: &HashSet<String>) -> Option<String> {
         let weights = self.node_weights.read().await;
         
         let mut best_node = None;
         let mut best_score = 0u64;
         
-        for (node_id, weight) in weights.iter() {
+        for (node_id, weight) in weights.iter().filter(|(node_id, _)| !excluded.contains(*node_id)) {
             let mut hasher = std::collections::hash_map::DefaultHasher::new();
             key.hash(&mut hasher);
             node_id.hash(&mut hasher);
@@ -233,26 +298,18 @@ impl DistributionManager {
         
         let mut hasher = std::collections::hash_map::DefaultHasher::new();
         key.hash(&mut hasher);
-        let mut hash = hasher.finish();
         
-        let num_buckets = weights.len() as u64;
-        let mut b = 0u64;
-        let mut j = 0u64;
-        
-        while j < num_buckets {
-            b = j;
-            hash = hash.wrapping_mul(2862933555777941757).wrapping_add(1);
-            j = ((b + 1) as f64 * (1u64 << 31) as f64 / ((hash >> 33) + 1) as f64) as u64;
-        }
-        
-        let nodes: Vec<_> = weights.keys().collect();
-        nodes.get(b as usize).map(|s| (*s).clone())
+        // Buckets are indices into the node list, so it needs a stable order;
+        // HashMap iteration order isn't one. Sorted by id, keys only move to a
+        // new node when its id sorts after the existing ones.
+        let mut nodes: Vec<_> = weights.keys().collect();
+        nodes.sort();
+        let bucket = jump_consistent_hash(hasher.finish(), nodes.len() as u32);
+        nodes.get(bucket as usize).map(|s| (*s).clone())
     }
 
-    async fn maglev_hash(&self, _key: &str) -> Option<String> {
-        // Simplified Maglev hashing implementation
-        // In production, would use full Maglev lookup table
-        self.rendezvous_hash(_key).await
+    async fn maglev_hash(&self, key: &str) -> Option<String> {
+        self.maglev_table.read().await.get(key).map(str::to_string)
     }
 
     pub async fn redistribute_load(&self, failed_node: &str) -> Result<()> {
@@ -279,6 +336,7 @@ impl DistributionManager {
             }
         }
         
+        self.persist_key_mappings(&mappings).await?;
         info!("Load redistribution completed");
         Ok(())
     }
@@ -367,6 +425,7 @@ impl DistributionManager {
         }
         
         state.active = false;
+        self.persist_key_mappings(&mappings).await?;
         info!("Migration completed: {} keys moved", total_migrated);
         
         Ok(())
@@ -398,6 +457,43 @@ impl DistributionManager {
         }
     }
 
+    // Pin `key` to `node_id` until unpinned, ahead of the hashing algorithm
+    pub async fn pin_key(&self, key: &str, node_id: &str) -> Result<()> {
+        let mut mappings = self.key_mappings.write().await;
+        mappings.insert(key.to_string(), node_id.to_string());
+        self.persist_key_mappings(&mappings).await
+    }
+
+    pub async fn unpin_key(&self, key: &str) -> Result<()> {
+        let mut mappings = self.key_mappings.write().await;
+        if mappings.remove(key).is_some() {
+            self.persist_key_mappings(&mappings).await?;
+        }
+        Ok(())
+    }
+
+    // Written to a temporary file and renamed over the old one, so a crash
+    // never leaves a half-written file. Callers hold the mappings lock,
+    // which keeps writers from racing on the temporary file.
+    async fn persist_key_mappings(&self, mappings: &HashMap<String, String>) -> Result<()> {
+        let Some(path) = &self.config.key_mappings_path else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec(mappings)?;
+        let tmp_path = path.with_file_name(format!(
+            ".{}.tmp",
+            path.file_name().and_then(|name| name.to_str()).unwrap_or("key_mappings"),
+        ));
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&tmp_path, data).await
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        tokio::fs::rename(&tmp_path, path).await
+            .with_context(|| format!("failed to replace {}", path.display()))?;
+        Ok(())
+    }
+
     pub async fn add_affinity_rule(&self, rule: AffinityRule) -> Result<()> {
         // In real implementation, would persist to config
         info!("Added affinity rule for pattern: {}", rule.key_pattern);
@@ -406,12 +502,115 @@ impl DistributionManager {
 
     pub async fn set_node_weight(&self, node_id: &str, weight: f32) -> Result<()> {
         let mut weights = self.node_weights.write().await;
-        weights.insert(node_id.to_string(), weight);
+        if weights.insert(node_id.to_string(), weight).is_none() {
+            *self.maglev_table.write().await = MaglevTable::new(weights.keys().cloned().collect());
+        }
         info!("Set weight for node {} to {}", node_id, weight);
         Ok(())
     }
 }
 
+// Lamping & Veach, "A Fast, Minimal Memory, Consistent Hash Algorithm"
+// (2014): growing from n to n + 1 buckets moves only 1/(n + 1) of the keys,
+// all of them into the new bucket
+fn jump_consistent_hash(mut key: u64, num_buckets: u32) -> u32 {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < num_buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b.max(0) as u32
+}
+
+async fn load_key_mappings(path: &Path) -> Result<HashMap<String, String>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+enum Affinity {
+    Pinned(String),
+    // Left to the hashing algorithm, minus these nodes
+    Unpinned { excluded: HashSet<String> },
+}
+
+// Maglev lookup table (Eisenbud et al., NSDI 2016). Each node walks its own
+// permutation of the slots, derived from offset and skip hashes of its id,
+// and the nodes take turns claiming their next free slot until the table is
+// full. Lookups are one hash and one index; every node ends up with an equal
+// share of slots, and removing a node barely moves the others' slots.
+#[derive(Debug, Default)]
+struct MaglevTable {
+    nodes: Vec<String>,
+    // Index into `nodes` for each slot
+    slots: Vec<u32>,
+}
+
+// Prime, and well above 100x any expected cluster size
+const MAGLEV_TABLE_SIZE: u64 = 65_537;
+
+impl MaglevTable {
+    fn new(mut nodes: Vec<String>) -> Self {
+        // Turn order decides ties, so it can't depend on HashMap order
+        nodes.sort();
+        nodes.dedup();
+        if nodes.is_empty() {
+            return Self::default();
+        }
+
+        let size = MAGLEV_TABLE_SIZE;
+        let permutations: Vec<(u64, u64)> = nodes.iter()
+            .map(|node| {
+                let offset = maglev_hash_of(node, 0) % size;
+                let skip = maglev_hash_of(node, 1) % (size - 1) + 1;
+                (offset, skip)
+            })
+            .collect();
+
+        let mut next = vec![0u64; nodes.len()];
+        let mut slots = vec![u32::MAX; size as usize];
+        let mut filled = 0;
+        'fill: loop {
+            for (i, (offset, skip)) in permutations.iter().enumerate() {
+                let mut slot = (offset + next[i] * skip) % size;
+                while slots[slot as usize] != u32::MAX {
+                    next[i] += 1;
+                    slot = (offset + next[i] * skip) % size;
+                }
+                slots[slot as usize] = i as u32;
+                next[i] += 1;
+                filled += 1;
+                if filled == size {
+                    break 'fill;
+                }
+            }
+        }
+
+        Self { nodes, slots }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        let slot = self.slots[(hasher.finish() % self.slots.len() as u64) as usize];
+        self.nodes.get(slot as usize).map(String::as_str)
+    }
+}
+
+fn maglev_hash_of(node: &str, seed: u8) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    seed.hash(&mut hasher);
+    node.hash(&mut hasher);
+    hasher.finish()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DistributionStats {
     pub total_nodes: usize,
@@ -421,4 +620,258 @@ pub struct DistributionStats {
     pub node_distribution: HashMap<String, usize>,
     pub migration_active: bool,
     pub migration_progress: f32,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::ClusterManager;
+
+    async fn manager_with(algorithm: HashingAlgorithm, nodes: &[&str]) -> DistributionManager {
+        let mut manager = DistributionManager::new(&ClusterConfig::default()).await.unwrap();
+        manager.strategy.algorithm = algorithm;
+        for node in nodes {
+            manager.set_node_weight(node, 1.0).await.unwrap();
+        }
+        manager
+    }
+
+    fn node_ids(ids: &[u32]) -> Vec<String> {
+        ids.iter().map(|i| format!("node-{}", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_loaded_node_gets_fewer_virtual_nodes() {
+        let manager = DistributionManager::new(&ClusterConfig::default()).await.unwrap();
+        let config = ClusterConfig::default();
+        let mut idle = ClusterManager::local_node_info(&config).unwrap();
+        idle.id = "node-idle".to_string();
+        idle.state = NodeState::Active;
+        idle.load.cpu_percent = 10.0;
+        idle.load.memory_percent = 30.0;
+        let mut busy = idle.clone();
+        busy.id = "node-busy".to_string();
+        busy.load.cpu_percent = 80.0;
+        busy.load.memory_percent = 80.0;
+        busy.load.active_connections = idle.capacity.max_connections * 9 / 10;
+        busy.load.requests_per_second = 5_000.0;
+        busy.load.response_time_ms = 250.0;
+
+        manager.update_nodes(&[idle.clone(), busy.clone()]).await.unwrap();
+        let weights = manager.node_weights.read().await.clone();
+        let idle_vnodes = manager.virtual_node_count(weights["node-idle"]);
+        let busy_vnodes = manager.virtual_node_count(weights["node-busy"]);
+        assert!(busy_vnodes < idle_vnodes / 2, "busy {} vs idle {}", busy_vnodes, idle_vnodes);
+
+        // The ring follows: most keys land on the idle node
+        let mut on_idle = 0;
+        for i in 0..1_000 {
+            let key = format!("key-{}", i);
+            if manager.get_node_for_key(&key).await.as_deref() == Some("node-idle") {
+                on_idle += 1;
+            }
+        }
+        assert!(on_idle > 600, "{} of 1000 keys on the idle node", on_idle);
+
+        // Overload past capacity still leaves the node on the ring
+        busy.load.cpu_percent = 400.0;
+        busy.load.active_connections = busy.capacity.max_connections * 3;
+        assert!(manager.calculate_node_weight(&busy) > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_key_mappings_survive_restart() {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-mappings-{}", uuid::Uuid::new_v4()));
+        let config = ClusterConfig {
+            key_mappings_path: Some(dir.join("key_mappings.json")),
+            ..Default::default()
+        };
+
+        let manager = DistributionManager::new(&config).await.unwrap();
+        manager.set_node_weight("node-1", 1.0).await.unwrap();
+        manager.pin_key("session:42", "node-2").await.unwrap();
+        manager.pin_key("session:43", "node-3").await.unwrap();
+        manager.unpin_key("session:43").await.unwrap();
+        drop(manager);
+
+        let manager = DistributionManager::new(&config).await.unwrap();
+        assert_eq!(manager.get_node_for_key("session:42").await.as_deref(), Some("node-2"));
+        let mappings = manager.key_mappings.read().await.clone();
+        assert_eq!(mappings, HashMap::from([("session:42".to_string(), "node-2".to_string())]));
+        // Only the renamed file is left
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A corrupt file stops startup instead of dropping the mappings
+        std::fs::write(dir.join("key_mappings.json"), "{not json").unwrap();
+        assert!(DistributionManager::new(&config).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn rule(pattern: &str, required: &[&str], preferred: &[&str], excluded: &[&str]) -> AffinityRule {
+        let ids = |nodes: &[&str]| nodes.iter().map(|n| n.to_string()).collect();
+        AffinityRule {
+            key_pattern: pattern.to_string(),
+            preferred_nodes: ids(preferred),
+            required_nodes: ids(required),
+            excluded_nodes: ids(excluded),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_affinity_skips_unavailable_nodes() {
+        // node-1 is down: it isn't among the live members
+        let mut manager = manager_with(HashingAlgorithm::RendezvousHash, &["node-2", "node-3"]).await;
+        manager.strategy.affinity_rules = vec![
+            rule("tenant-a:", &[], &["node-1", "node-3"], &[]),
+            rule("tenant-b:", &["node-1", "node-2"], &[], &[]),
+            rule("tenant-c:", &["node-1"], &[], &[]),
+            rule("tenant-d:", &[], &["node-1"], &[]),
+        ];
+
+        assert_eq!(manager.get_node_for_key("tenant-a:cart").await.as_deref(), Some("node-3"));
+        assert_eq!(manager.get_node_for_key("tenant-b:cart").await.as_deref(), Some("node-2"));
+        // A required node that's down leaves the key without a home
+        assert_eq!(manager.get_node_for_key("tenant-c:cart").await, None);
+        // No preferred node up: hashed to a live one
+        let node = manager.get_node_for_key("tenant-d:cart").await.unwrap();
+        assert!(node == "node-2" || node == "node-3");
+
+        // Once node-1 is back it's used again
+        manager.set_node_weight("node-1", 1.0).await.unwrap();
+        assert_eq!(manager.get_node_for_key("tenant-a:cart").await.as_deref(), Some("node-1"));
+        assert_eq!(manager.get_node_for_key("tenant-c:cart").await.as_deref(), Some("node-1"));
+    }
+
+    #[tokio::test]
+    async fn test_affinity_excluded_nodes_are_avoided() {
+        for algorithm in [HashingAlgorithm::RendezvousHash, HashingAlgorithm::JumpHash, HashingAlgorithm::Maglev] {
+            let mut manager = manager_with(algorithm, &["node-1", "node-2", "node-3"]).await;
+            manager.strategy.affinity_rules = vec![
+                rule("pii:", &[], &["node-2"], &["node-2", "node-3"]),
+            ];
+            for i in 0..200 {
+                let key = format!("pii:{}", i);
+                assert_eq!(manager.get_node_for_key(&key).await.as_deref(), Some("node-1"), "{}", key);
+            }
+
+            // Keys outside the rule still use every node
+            let mut owners = HashSet::new();
+            for i in 0..200 {
+                owners.insert(manager.get_node_for_key(&format!("other:{}", i)).await.unwrap());
+            }
+            assert_eq!(owners.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_maglev_table_is_even() {
+        let table = MaglevTable::new(node_ids(&[1, 2, 3, 4, 5]));
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for slot in &table.slots {
+            *counts.entry(*slot).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 5);
+        let min = counts.values().min().unwrap();
+        let max = counts.values().max().unwrap();
+        assert!(max - min <= 1, "slot counts {:?}", counts);
+
+        // Keys spread the same way
+        let mut owners: HashMap<&str, usize> = HashMap::new();
+        for i in 0..50_000 {
+            *owners.entry(table.get(&format!("key-{}", i)).unwrap()).or_insert(0) += 1;
+        }
+        assert!(owners.values().all(|&n| (9_000..11_000).contains(&n)), "{:?}", owners);
+
+        assert!(MaglevTable::new(Vec::new()).get("key").is_none());
+    }
+
+    #[test]
+    fn test_maglev_table_removal_moves_few_keys() {
+        let before = MaglevTable::new(node_ids(&[1, 2, 3, 4, 5]));
+        let after = MaglevTable::new(node_ids(&[1, 2, 4, 5]));
+
+        let mut kept = 0;
+        let mut moved = 0;
+        for i in 0..50_000 {
+            let key = format!("key-{}", i);
+            let old = before.get(&key).unwrap();
+            let new = after.get(&key).unwrap();
+            assert_ne!(new, "node-3");
+            if old != "node-3" {
+                kept += 1;
+                if old != new {
+                    moved += 1;
+                }
+            }
+        }
+        // Only node-3's keys need a new home; the rest stay nearly put
+        assert!(moved * 100 < kept * 2, "{} of {} keys moved", moved, kept);
+    }
+
+    #[tokio::test]
+    async fn test_maglev_lookup_follows_membership() {
+        let manager = manager_with(HashingAlgorithm::Maglev, &["node-1", "node-2"]).await;
+        let mut owners = HashSet::new();
+        for i in 0..100 {
+            owners.insert(manager.get_node_for_key(&format!("key-{}", i)).await.unwrap());
+        }
+        assert_eq!(owners, HashSet::from(["node-1".to_string(), "node-2".to_string()]));
+
+        manager.set_node_weight("node-3", 1.0).await.unwrap();
+        let mut owners = HashSet::new();
+        for i in 0..100 {
+            owners.insert(manager.get_node_for_key(&format!("key-{}", i)).await.unwrap());
+        }
+        assert!(owners.contains("node-3"));
+    }
+
+    #[test]
+    fn test_jump_consistent_hash() {
+        assert_eq!(jump_consistent_hash(0, 1), 0);
+        assert_eq!(jump_consistent_hash(u64::MAX, 1), 0);
+        assert_eq!(jump_consistent_hash(12345, 0), 0);
+
+        // Going from n to n + 1 buckets a key either stays put or moves to
+        // the new bucket, never between old ones
+        for key in 0..10_000u64 {
+            let key = key.wrapping_mul(0x9E3779B97F4A7C15);
+            for n in 1..20 {
+                let before = jump_consistent_hash(key, n);
+                let after = jump_consistent_hash(key, n + 1);
+                assert!(before < n);
+                assert!(after == before || after == n, "key {} moved from {} to {}", key, before, after);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jump_hash_is_stable_and_moves_few_keys() {
+        let manager = manager_with(HashingAlgorithm::JumpHash, &["node-1", "node-2", "node-3", "node-4"]).await;
+        let keys: Vec<String> = (0..10_000).map(|i| format!("session:{}", i)).collect();
+
+        let mut before = Vec::new();
+        for key in &keys {
+            let node = manager.get_node_for_key(key).await.unwrap();
+            for _ in 0..5 {
+                assert_eq!(manager.get_node_for_key(key).await.unwrap(), node);
+            }
+            before.push(node);
+        }
+        // Every node gets a share
+        let owners: HashSet<_> = before.iter().collect();
+        assert_eq!(owners.len(), 4);
+
+        // A fifth node should take about 1/5 of the keys, and only those
+        manager.set_node_weight("node-5", 1.0).await.unwrap();
+        let mut moved = 0;
+        for (key, old) in keys.iter().zip(&before) {
+            let new = manager.get_node_for_key(key).await.unwrap();
+            if &new != old {
+                assert_eq!(new, "node-5");
+                moved += 1;
+            }
+        }
+        assert!((1_500..2_500).contains(&moved), "{} of 10000 keys moved", moved);
+    }
+}
@@ -1,11 +1,15 @@
 use anyhow::Result;
-use chitchat::{ChitchatConfig, ChitchatHandle, FailureDetectorConfig};
-use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::time::Duration;
-use tracing::{info, debug};
+use chitchat::transport::Transport;
+use chitchat::{Chitchat, ChitchatConfig, ChitchatHandle, ChitchatId, FailureDetectorConfig};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tracing::{info, debug, warn};
 
-use super::ClusterConfig;
+use super::{ClusterConfig, NodeInfo};
+
+// Chitchat key holding each node's JSON-encoded NodeInfo
+pub const NODE_INFO_KEY: &str = "node_info";
 
 pub struct GossipManager {
     config: ClusterConfig,
@@ -23,29 +27,10 @@ impl GossipManager {
     pub async fn start(&mut self) -> Result<()> {
         info!("Starting gossip protocol on {}", self.config.bind_addr);
         
-        let chitchat_config = ChitchatConfig {
-            node_id: self.config.node_id.clone().into(),
-            cluster_id: self.config.cluster_name.clone(),
-            gossip_addr: self.config.bind_addr,
-            gossip_interval: self.config.gossip_interval,
-            listen_addr: self.config.advertise_addr.unwrap_or(self.config.bind_addr),
-            seed_nodes: self.config.seed_nodes
-                .iter()
-                .filter_map(|s| s.parse().ok())
-                .collect(),
-            failure_detector_config: FailureDetectorConfig {
-                phi_threshold: 8.0,
-                sampling_window: Duration::from_secs(60),
-                min_std_deviation: Duration::from_millis(100),
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-        
-        let (handle, _stream) = chitchat::spawn_chitchat(
-            chitchat_config,
+        let handle = chitchat::spawn_chitchat(
+            chitchat_config(&self.config),
             vec![],
-            &tokio::runtime::Handle::current(),
+            &chitchat::transport::UdpTransport,
         ).await?;
         
         self.handle = Some(handle);
@@ -67,10 +52,70 @@ impl GossipManager {
         Ok(())
     }
     
-    pub async fn shutdown(&self) -> Result<()> {
-        if let Some(handle) = &self.handle {
+    pub async fn shutdown(&mut self) -> Result<()> {
+        if let Some(handle) = self.handle.take() {
             handle.shutdown().await?;
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+pub fn chitchat_config(config: &ClusterConfig) -> ChitchatConfig {
+    // A restarted node gets a new generation so peers drop its old state
+    let generation = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    ChitchatConfig {
+        chitchat_id: ChitchatId::new(
+            config.node_id.clone(),
+            generation,
+            config.advertise_addr.unwrap_or(config.bind_addr),
+        ),
+        cluster_id: config.cluster_name.clone(),
+        gossip_interval: config.gossip_interval,
+        listen_addr: config.bind_addr,
+        seed_nodes: config.seed_nodes.clone(),
+        failure_detector_config: FailureDetectorConfig {
+            phi_threshold: 8.0,
+            ..Default::default()
+        },
+        marked_for_deletion_grace_period: 10_000,
+    }
+}
+
+// Start gossiping with this node's NodeInfo already in its key-value state
+pub async fn spawn_gossip(
+    config: &ClusterConfig,
+    node_info: &NodeInfo,
+    transport: &dyn Transport,
+) -> Result<ChitchatHandle> {
+    let initial_state = vec![(NODE_INFO_KEY.to_string(), serde_json::to_string(node_info)?)];
+    chitchat::spawn_chitchat(chitchat_config(config), initial_state, transport).await
+}
+
+// Replace this node's NodeInfo in the gossip state; peers pick it up on
+// their next gossip round
+pub async fn publish_node_info(chitchat: &Mutex<Chitchat>, node_info: &NodeInfo) -> Result<()> {
+    let value = serde_json::to_string(node_info)?;
+    chitchat.lock().await.self_node_state().set(NODE_INFO_KEY, value);
+    Ok(())
+}
+
+// Decode the NodeInfo every live node has published. Nodes that have not
+// published one yet (or publish garbage) are left out.
+pub fn collect_nodes(live_nodes: &BTreeMap<ChitchatId, chitchat::NodeState>) -> HashMap<String, NodeInfo> {
+    live_nodes.iter()
+        .filter_map(|(id, state)| {
+            let value = state.get(NODE_INFO_KEY)?;
+            match serde_json::from_str::<NodeInfo>(value) {
+                Ok(node) => Some((node.id.clone(), node)),
+                Err(e) => {
+                    warn!("Ignoring invalid node info from {}: {}", id.node_id, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
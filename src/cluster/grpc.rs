@@ -1,8 +1,11 @@
 use anyhow::Result;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{Request, Response, Status};
 use tracing::{debug, error, info};
 
 use super::NodeInfo;
@@ -141,6 +144,14 @@ impl ClusterRpc for ClusterService {
     }
 }
 
+// Channel to another node's gRPC port. It connects on first use and
+// reconnects by itself, so peers that start later are picked up.
+pub fn lazy_channel(addr: SocketAddr, connect_timeout: Duration) -> Result<Channel> {
+    Ok(Endpoint::from_shared(format!("http://{}", addr))?
+        .connect_timeout(connect_timeout)
+        .connect_lazy())
+}
+
 // gRPC client for cluster communication
 pub struct ClusterClient {
     clients: HashMap<String, cluster_rpc::cluster_rpc_client::ClusterRpcClient<tonic::transport::Channel>>,
@@ -202,6 +213,17 @@ service ClusterRpc {
     rpc GetData(DataRequest) returns (DataResponse);
 }
 
+// Raft consensus between cluster members, served on grpc_port
+service Raft {
+    rpc RequestVote(ElectionRequest) returns (ElectionResponse);
+    rpc AppendEntries(AppendEntriesRequest) returns (AppendEntriesResponse);
+}
+
+// Key/value replication to the replica nodes for a key, served on grpc_port
+service Replication {
+    rpc Push(ReplicaUpdate) returns (ReplicaAck);
+}
+
 message Empty {}
 
 message HeartbeatRequest {
@@ -258,6 +280,44 @@ message ElectionResponse {
     string voter_id = 3;
 }
 
+message RaftLogEntry {
+    uint64 term = 1;
+    uint64 index = 2;
+    // JSON-encoded consensus::Command
+    bytes command = 3;
+    // When the leader created the entry, Unix milliseconds
+    int64 timestamp_ms = 4;
+}
+
+message AppendEntriesRequest {
+    uint64 term = 1;
+    string leader_id = 2;
+    uint64 prev_log_index = 3;
+    uint64 prev_log_term = 4;
+    repeated RaftLogEntry entries = 5;
+    uint64 leader_commit = 6;
+}
+
+message AppendEntriesResponse {
+    uint64 term = 1;
+    bool success = 2;
+}
+
+message ReplicaUpdate {
+    string key = 1;
+    bytes value = 2;
+    // Per-key logical version; ties go to the higher origin_node
+    uint64 version = 3;
+    string origin_node = 4;
+    int64 updated_at_ms = 5;
+}
+
+message ReplicaAck {
+    // Version the replica holds after the push, which may be newer
+    uint64 version = 1;
+    string origin_node = 2;
+}
+
 message ReplicationRequest {
     string key = 1;
     bytes data = 2;
@@ -1,24 +1,35 @@
 use anyhow::{anyhow, Result};
-use chitchat::{ChitchatConfig, ChitchatHandle, FailureDetectorConfig};
+use chitchat::transport::UdpTransport;
+use chitchat::{Chitchat, ChitchatHandle};
+use tonic::transport::Server;
 use hashring::HashRing;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::sync::{RwLock, Mutex, broadcast};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
+use crate::metrics::MetricsCollector;
+
+pub mod api;
 pub mod gossip;
+pub mod grpc;
 pub mod consensus;
 pub mod distribution;
 pub mod replication;
 pub mod health;
 
-use crate::config::Config;
+// Ring entries per node, so keys spread evenly across members
+const VIRTUAL_NODES: usize = 150;
 
+// The [cluster] section; see config-cluster.toml. Intervals are given in
+// milliseconds (gossip_interval_ms and so on).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ClusterConfig {
     pub enabled: bool,
     pub node_id: String,
@@ -26,17 +37,36 @@ pub struct ClusterConfig {
     pub bind_addr: SocketAddr,
     pub advertise_addr: Option<SocketAddr>,
     pub seed_nodes: Vec<String>,
+    #[serde(rename = "gossip_interval_ms", with = "duration_ms")]
     pub gossip_interval: Duration,
     pub gossip_port: u16,
     pub grpc_port: u16,
+    #[serde(rename = "heartbeat_interval_ms", with = "duration_ms")]
     pub heartbeat_interval: Duration,
+    #[serde(rename = "election_timeout_ms", with = "duration_ms")]
     pub election_timeout: Duration,
     pub replication_factor: usize,
     pub quorum_size: usize,
     pub enable_auto_join: bool,
     pub enable_auto_failover: bool,
+    #[serde(rename = "data_sync_interval_ms", with = "duration_ms")]
     pub data_sync_interval: Duration,
     pub etcd_endpoints: Vec<String>,
+    // JSON file that keeps pinned and migrated key mappings across restarts
+    pub key_mappings_path: Option<PathBuf>,
+}
+
+mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
 }
 
 impl Default for ClusterConfig {
@@ -59,6 +89,7 @@ impl Default for ClusterConfig {
             enable_auto_failover: true,
             data_sync_interval: Duration::from_secs(10),
             etcd_endpoints: vec!["http://localhost:2379".to_string()],
+            key_mappings_path: None,
         }
     }
 }
@@ -120,11 +151,15 @@ pub struct ClusterManager {
     node_info: Arc<RwLock<NodeInfo>>,
     nodes: Arc<RwLock<HashMap<String, NodeInfo>>>,
     hash_ring: Arc<RwLock<HashRing<String>>>,
-    gossip_handle: Option<ChitchatHandle>,
+    gossip_handle: Mutex<Option<ChitchatHandle>>,
+    // Gossip state, used to publish this node's NodeInfo
+    chitchat: Option<Arc<Mutex<Chitchat>>>,
     consensus_manager: Arc<consensus::ConsensusManager>,
     health_monitor: Arc<health::HealthMonitor>,
     distribution_manager: Arc<distribution::DistributionManager>,
     replication_manager: Arc<replication::ReplicationManager>,
+    // Request metrics reported as this node's load on each heartbeat
+    metrics: Option<Arc<MetricsCollector>>,
     event_tx: broadcast::Sender<ClusterEvent>,
     shutdown: Arc<Mutex<bool>>,
 }
@@ -143,32 +178,13 @@ pub enum ClusterEvent {
 
 impl ClusterManager {
     pub async fn new(config: ClusterConfig) -> Result<Self> {
-        let node_info = NodeInfo {
-            id: config.node_id.clone(),
-            name: hostname::get()?.to_string_lossy().to_string(),
-            addr: config.bind_addr,
-            grpc_addr: SocketAddr::new(config.bind_addr.ip(), config.grpc_port),
-            state: NodeState::Joining,
-            role: NodeRole::Follower,
-            capacity: Self::detect_capacity(),
-            load: NodeLoad {
-                cpu_percent: 0.0,
-                memory_percent: 0.0,
-                disk_percent: 0.0,
-                active_connections: 0,
-                requests_per_second: 0.0,
-                response_time_ms: 0.0,
-            },
-            version: env!("CARGO_PKG_VERSION").to_string(),
-            started_at: SystemTime::now(),
-            last_seen: SystemTime::now(),
-            metadata: HashMap::new(),
-        };
+        let node_info = Self::local_node_info(&config)?;
 
         let (event_tx, _) = broadcast::channel(1000);
+        let nodes = Arc::new(RwLock::new(HashMap::new()));
 
         let consensus_manager = Arc::new(
-            consensus::ConsensusManager::new(&config, event_tx.clone()).await?
+            consensus::ConsensusManager::new(&config, nodes.clone(), event_tx.clone()).await?
         );
 
         let health_monitor = Arc::new(
@@ -180,30 +196,67 @@ impl ClusterManager {
         );
 
         let replication_manager = Arc::new(
-            replication::ReplicationManager::new(&config).await?
+            replication::ReplicationManager::new(&config, nodes.clone()).await?
         );
 
         Ok(ClusterManager {
             config: config.clone(),
             node_info: Arc::new(RwLock::new(node_info)),
-            nodes: Arc::new(RwLock::new(HashMap::new())),
+            nodes,
             hash_ring: Arc::new(RwLock::new(HashRing::new())),
-            gossip_handle: None,
+            gossip_handle: Mutex::new(None),
+            chitchat: None,
             consensus_manager,
             health_monitor,
             distribution_manager,
             replication_manager,
+            metrics: None,
             event_tx,
             shutdown: Arc::new(Mutex::new(false)),
         })
     }
 
+    // Call before start(); without it connections, request rate and latency
+    // are reported as zero
+    pub fn set_metrics(&mut self, metrics: Arc<MetricsCollector>) {
+        self.metrics = Some(metrics);
+    }
+
+    fn local_node_info(config: &ClusterConfig) -> Result<NodeInfo> {
+        Ok(NodeInfo {
+            id: config.node_id.clone(),
+            name: hostname::get()?.to_string_lossy().to_string(),
+            addr: config.bind_addr,
+            // Peers dial this, so use the advertised address when bound to
+            // a wildcard one
+            grpc_addr: SocketAddr::new(config.advertise_addr.unwrap_or(config.bind_addr).ip(), config.grpc_port),
+            state: NodeState::Joining,
+            role: NodeRole::Follower,
+            capacity: Self::detect_capacity(),
+            load: NodeLoad {
+                cpu_percent: 0.0,
+                memory_percent: 0.0,
+                disk_percent: 0.0,
+                active_connections: 0,
+                requests_per_second: 0.0,
+                response_time_ms: 0.0,
+            },
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at: SystemTime::now(),
+            last_seen: SystemTime::now(),
+            metadata: HashMap::new(),
+        })
+    }
+
     pub async fn start(&mut self) -> Result<()> {
         info!("Starting cluster manager for node: {}", self.config.node_id);
 
         // Start gossip protocol
         self.start_gossip().await?;
 
+        // Serve Raft and replication traffic from the other members
+        self.start_rpc_server();
+
         // Start consensus manager
         self.consensus_manager.start().await?;
 
@@ -219,77 +272,120 @@ impl ClusterManager {
         self.start_background_tasks().await?;
 
         // Update node state
-        {
+        let node = {
             let mut node = self.node_info.write().await;
             node.state = NodeState::Active;
-        }
+            node.clone()
+        };
+        self.publish_node_info(&node).await;
 
         info!("Cluster manager started successfully");
         Ok(())
     }
 
-    async fn start_gossip(&mut self) -> Result<()> {
-        let chitchat_config = ChitchatConfig {
-            node_id: self.config.node_id.clone().into(),
-            cluster_id: self.config.cluster_name.clone(),
-            gossip_addr: self.config.bind_addr,
-            gossip_interval: self.config.gossip_interval,
-            listen_addr: self.config.advertise_addr.unwrap_or(self.config.bind_addr),
-            seed_nodes: self.config.seed_nodes
-                .iter()
-                .map(|s| s.parse().unwrap())
-                .collect(),
-            failure_detector_config: FailureDetectorConfig {
-                phi_threshold: 8.0,
-                sampling_window: Duration::from_secs(60),
-                min_std_deviation: Duration::from_millis(100),
-                ..Default::default()
-            },
-            ..Default::default()
-        };
+    fn start_rpc_server(&self) {
+        let addr = SocketAddr::new(self.config.bind_addr.ip(), self.config.grpc_port);
+        let router = Server::builder()
+            .add_service(self.consensus_manager.service())
+            .add_service(self.replication_manager.service());
+        let shutdown = self.shutdown.clone();
 
-        let (gossip_handle, gossip_stream) = chitchat::spawn_chitchat(
-            chitchat_config,
-            vec![],
-            &tokio::runtime::Handle::current(),
-        ).await?;
+        tokio::spawn(async move {
+            let stopped = async move {
+                while !*shutdown.lock().await {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            };
+            if let Err(e) = router.serve_with_shutdown(addr, stopped).await {
+                error!("Cluster RPC server on {} failed: {}", addr, e);
+            }
+        });
+    }
+
+    async fn start_gossip(&mut self) -> Result<()> {
+        let node_info = self.node_info.read().await.clone();
+        let handle = gossip::spawn_gossip(&self.config, &node_info, &UdpTransport).await?;
+        let chitchat = handle.chitchat();
 
-        self.gossip_handle = Some(gossip_handle);
+        self.chitchat = Some(chitchat.clone());
+        *self.gossip_handle.lock().await = Some(handle);
 
         // Process gossip events
         let nodes = self.nodes.clone();
         let event_tx = self.event_tx.clone();
+        let interval = self.config.gossip_interval;
+        let local_id = self.config.node_id.clone();
         tokio::spawn(async move {
-            Self::process_gossip_events(gossip_stream, nodes, event_tx).await;
+            Self::process_gossip_events(chitchat, interval, local_id, nodes, event_tx).await;
         });
 
         Ok(())
     }
 
+    // Mirror the NodeInfo that live peers publish into `nodes`, once per
+    // gossip round
     async fn process_gossip_events(
-        mut stream: chitchat::ChitchatStream,
+        chitchat: Arc<Mutex<Chitchat>>,
+        interval: Duration,
+        local_id: String,
         nodes: Arc<RwLock<HashMap<String, NodeInfo>>>,
         event_tx: broadcast::Sender<ClusterEvent>,
     ) {
-        while let Some(event) = stream.recv().await {
-            match event {
-                chitchat::ChitchatEvent::NodeJoined(node_id) => {
-                    info!("Node joined cluster: {}", node_id);
-                    let _ = event_tx.send(ClusterEvent::NodeJoined(node_id.to_string()));
-                }
-                chitchat::ChitchatEvent::NodeLeft(node_id) => {
-                    info!("Node left cluster: {}", node_id);
-                    nodes.write().await.remove(&node_id.to_string());
-                    let _ = event_tx.send(ClusterEvent::NodeLeft(node_id.to_string()));
-                }
-                chitchat::ChitchatEvent::NodeFailed(node_id) => {
-                    warn!("Node failed: {}", node_id);
-                    if let Some(mut node) = nodes.write().await.get_mut(&node_id.to_string()) {
-                        node.state = NodeState::Failed;
-                    }
-                    let _ = event_tx.send(ClusterEvent::NodeFailed(node_id.to_string()));
-                }
-                _ => {}
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let live = {
+                let chitchat = chitchat.lock().await;
+                let live_nodes = chitchat.live_nodes()
+                    .filter_map(|id| Some((id.clone(), chitchat.node_state(id)?.clone())))
+                    .collect();
+                gossip::collect_nodes(&live_nodes)
+            };
+            Self::apply_gossip_state(&mut *nodes.write().await, live, &local_id, &event_tx);
+        }
+    }
+
+    fn apply_gossip_state(
+        nodes: &mut HashMap<String, NodeInfo>,
+        live: HashMap<String, NodeInfo>,
+        local_id: &str,
+        event_tx: &broadcast::Sender<ClusterEvent>,
+    ) {
+        // Known nodes that dropped out of the live set either left on
+        // purpose (they announced Leaving first) or failed
+        let mut left = Vec::new();
+        for (id, node) in nodes.iter_mut() {
+            if live.contains_key(id) || node.state == NodeState::Failed {
+                continue;
+            }
+            if node.state == NodeState::Leaving {
+                left.push(id.clone());
+            } else {
+                warn!("Node failed: {}", id);
+                node.state = NodeState::Failed;
+                let _ = event_tx.send(ClusterEvent::NodeFailed(id.clone()));
+            }
+        }
+        for id in left {
+            info!("Node left cluster: {}", id);
+            nodes.remove(&id);
+            let _ = event_tx.send(ClusterEvent::NodeLeft(id));
+        }
+
+        for (id, node) in live {
+            let joined = nodes.get(&id).map_or(true, |known| known.state == NodeState::Failed);
+            if joined && id != local_id {
+                info!("Node joined cluster: {}", id);
+                let _ = event_tx.send(ClusterEvent::NodeJoined(id.clone()));
+            }
+            nodes.insert(id, node);
+        }
+    }
+
+    async fn publish_node_info(&self, node: &NodeInfo) {
+        if let Some(chitchat) = &self.chitchat {
+            if let Err(e) = gossip::publish_node_info(chitchat, node).await {
+                warn!("Failed to publish node info: {}", e);
             }
         }
     }
@@ -310,9 +406,11 @@ impl ClusterManager {
             }
         }
 
-        // Register with etcd if configured
+        // Register with etcd if configured; gossip works without it
         if !self.config.etcd_endpoints.is_empty() {
-            self.register_with_etcd().await?;
+            if let Err(e) = self.register_with_etcd().await {
+                warn!("Failed to register with etcd: {}", e);
+            }
         }
 
         Ok(())
@@ -352,24 +450,60 @@ impl ClusterManager {
         // Heartbeat task
         let node_info = self.node_info.clone();
         let interval = self.config.heartbeat_interval;
+        let chitchat = self.chitchat.clone();
+        let metrics = self.metrics.clone();
         tokio::spawn(async move {
             let mut ticker = tokio::time::interval(interval);
+            let mut last_sample = None;
             loop {
                 ticker.tick().await;
-                let mut node = node_info.write().await;
-                node.last_seen = SystemTime::now();
-                node.load = Self::measure_load().await;
+                let load = Self::measure_load(metrics.as_deref(), &mut last_sample).await;
+                let node = {
+                    let mut node = node_info.write().await;
+                    node.last_seen = SystemTime::now();
+                    node.load = load;
+                    node.clone()
+                };
+                // Peers see the new load on their next gossip round
+                if let Some(chitchat) = &chitchat {
+                    if let Err(e) = gossip::publish_node_info(chitchat, &node).await {
+                        warn!("Failed to publish node info: {}", e);
+                    }
+                }
             }
         });
 
-        // Hash ring update task
+        // Hash ring update task; rebuilds once per gossip round when the
+        // set of active members has changed. The load-weighted distribution
+        // is also refreshed once per heartbeat, as members report new load.
         let nodes = self.nodes.clone();
         let hash_ring = self.hash_ring.clone();
+        let distribution = self.distribution_manager.clone();
+        let interval = self.config.gossip_interval;
+        let weights_interval = self.config.heartbeat_interval;
         tokio::spawn(async move {
-            let mut ticker = tokio::time::interval(Duration::from_secs(5));
+            let mut ticker = tokio::time::interval(interval);
+            let mut members = HashSet::new();
+            let mut weights_updated: Option<std::time::Instant> = None;
             loop {
                 ticker.tick().await;
-                Self::update_hash_ring(nodes.clone(), hash_ring.clone()).await;
+                let active: HashSet<String> = nodes.read().await
+                    .values()
+                    .filter(|node| node.state == NodeState::Active)
+                    .map(|node| node.id.clone())
+                    .collect();
+                let membership_changed = active != members;
+                if membership_changed {
+                    Self::update_hash_ring(nodes.clone(), hash_ring.clone()).await;
+                    members = active;
+                }
+                if membership_changed || weights_updated.map_or(true, |at| at.elapsed() >= weights_interval) {
+                    let snapshot: Vec<NodeInfo> = nodes.read().await.values().cloned().collect();
+                    if let Err(e) = distribution.update_nodes(&snapshot).await {
+                        warn!("Failed to update node weights: {}", e);
+                    }
+                    weights_updated = Some(std::time::Instant::now());
+                }
             }
         });
 
@@ -391,7 +525,7 @@ impl ClusterManager {
 
     pub async fn get_node_for_key(&self, key: &str) -> Option<String> {
         let ring = self.hash_ring.read().await;
-        ring.get(&key.to_string()).cloned()
+        ring.get(&key.to_string()).map(|vnode| ring_node_id(vnode).to_string())
     }
 
     pub async fn get_replicas_for_key(&self, key: &str) -> Vec<String> {
@@ -399,12 +533,12 @@ impl ClusterManager {
         let nodes = self.nodes.read().await;
         
         let mut replicas = Vec::new();
-        if let Some(primary) = ring.get(&key.to_string()) {
-            replicas.push(primary.clone());
+        if let Some(primary) = ring.get(&key.to_string()).map(|vnode| ring_node_id(vnode)) {
+            replicas.push(primary.to_string());
             
             // Get additional replicas based on replication factor
             let active_nodes: Vec<_> = nodes.values()
-                .filter(|n| n.state == NodeState::Active && n.id != *primary)
+                .filter(|n| n.state == NodeState::Active && n.id != primary)
                 .map(|n| n.id.clone())
                 .collect();
             
@@ -418,6 +552,50 @@ impl ClusterManager {
         replicas
     }
 
+    // Write a replicated value; it goes to the replica nodes for the key
+    pub async fn put_replicated(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        let replicas = self.get_replicas_for_key(key).await;
+        self.replication_manager.replicate(key.to_string(), value, replicas).await
+    }
+
+    pub async fn get_replicated(&self, key: &str) -> Option<Vec<u8>> {
+        self.replication_manager.get(key).await
+    }
+
+    // Set once start() has joined the cluster, until shutdown
+    pub async fn is_joined(&self) -> bool {
+        self.node_info.read().await.state == NodeState::Active
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.config.node_id
+    }
+
+    pub fn cluster_name(&self) -> &str {
+        &self.config.cluster_name
+    }
+
+    // Known members, with roles taken from the current Raft leadership
+    pub async fn members(&self) -> Vec<NodeInfo> {
+        let leader = self.consensus_manager.get_leader().await;
+        let mut members: Vec<NodeInfo> = self.nodes.read().await.values().cloned().collect();
+        for node in &mut members {
+            if node.role != NodeRole::Observer {
+                node.role = if leader.as_deref() == Some(node.id.as_str()) {
+                    NodeRole::Leader
+                } else {
+                    NodeRole::Follower
+                };
+            }
+        }
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        members
+    }
+
+    pub async fn leader_id(&self) -> Option<String> {
+        self.consensus_manager.get_leader().await
+    }
+
     pub async fn is_leader(&self) -> bool {
         let node = self.node_info.read().await;
         node.role == NodeRole::Leader
@@ -443,7 +621,9 @@ impl ClusterManager {
 
         // Update hash ring
         let mut ring = self.hash_ring.write().await;
-        ring.remove(&failed_node_id.to_string());
+        for i in 0..VIRTUAL_NODES {
+            ring.remove(&format!("{}:{}", failed_node_id, i));
+        }
 
         Ok(())
     }
@@ -472,6 +652,7 @@ impl ClusterManager {
     }
 
     pub async fn get_cluster_stats(&self) -> ClusterStats {
+        let replication_lag = self.replication_manager.lag().await;
         let nodes = self.nodes.read().await;
         
         let total_nodes = nodes.len();
@@ -481,8 +662,9 @@ impl ClusterManager {
         let total_capacity = nodes.values().fold(0, |acc, n| acc + n.capacity.max_connections);
         let total_load = nodes.values().fold(0, |acc, n| acc + n.load.active_connections);
         
-        let avg_cpu = nodes.values().map(|n| n.load.cpu_percent).sum::<f32>() / nodes.len() as f32;
-        let avg_memory = nodes.values().map(|n| n.load.memory_percent).sum::<f32>() / nodes.len() as f32;
+        let count = nodes.len().max(1) as f32;
+        let avg_cpu = nodes.values().map(|n| n.load.cpu_percent).sum::<f32>() / count;
+        let avg_memory = nodes.values().map(|n| n.load.memory_percent).sum::<f32>() / count;
         
         ClusterStats {
             total_nodes,
@@ -494,6 +676,8 @@ impl ClusterManager {
             avg_memory_percent: avg_memory,
             replication_factor: self.config.replication_factor,
             quorum_size: self.config.quorum_size,
+            replication_pending_entries: replication_lag.pending_entries,
+            replication_lag_ms: replication_lag.max_lag_ms,
         }
     }
 
@@ -504,13 +688,17 @@ impl ClusterManager {
         *self.shutdown.lock().await = true;
         
         // Leave cluster gracefully
-        {
+        let node = {
             let mut node = self.node_info.write().await;
             node.state = NodeState::Leaving;
-        }
+            node.clone()
+        };
         
-        // Notify other nodes
-        if let Some(handle) = &self.gossip_handle {
+        // Notify other nodes, giving them a couple of gossip rounds to see
+        // Leaving before we disappear
+        if let Some(handle) = self.gossip_handle.lock().await.take() {
+            self.publish_node_info(&node).await;
+            tokio::time::sleep(self.config.gossip_interval * 2).await;
             handle.shutdown().await?;
         }
         
@@ -536,7 +724,28 @@ impl ClusterManager {
         }
     }
 
-    async fn measure_load() -> NodeLoad {
+    // System load, plus connections, request rate and p95 latency from the
+    // request metrics. The rate covers the time since `last_sample`.
+    async fn measure_load(
+        metrics: Option<&MetricsCollector>,
+        last_sample: &mut Option<(std::time::Instant, u64)>,
+    ) -> NodeLoad {
+        let (active_connections, requests_per_second, response_time_ms) = match metrics {
+            Some(metrics) => {
+                let sample = metrics.load_sample().await;
+                let now = std::time::Instant::now();
+                let rate = match *last_sample {
+                    Some((at, total)) if now > at => {
+                        sample.requests_total.saturating_sub(total) as f64 / (now - at).as_secs_f64()
+                    }
+                    _ => 0.0,
+                };
+                *last_sample = Some((now, sample.requests_total));
+                (sample.active_connections as u32, rate, sample.p95_ms)
+            }
+            None => (0, 0.0, 0.0),
+        };
+        
         NodeLoad {
             cpu_percent: sys_info::loadavg()
                 .map(|l| (l.one * 100.0) as f32)
@@ -547,9 +756,9 @@ impl ClusterManager {
             disk_percent: sys_info::disk_info()
                 .map(|d| ((d.total - d.free) as f32 / d.total as f32) * 100.0)
                 .unwrap_or(0.0),
-            active_connections: 0, // Would be updated by connection tracking
-            requests_per_second: 0.0, // Would be calculated from metrics
-            response_time_ms: 0.0, // Would be calculated from metrics
+            active_connections,
+            requests_per_second,
+            response_time_ms,
         }
     }
 
@@ -565,7 +774,7 @@ impl ClusterManager {
         for (id, node) in nodes.iter() {
             if node.state == NodeState::Active {
                 // Add node multiple times for better distribution
-                for i in 0..150 {
+                for i in 0..VIRTUAL_NODES {
                     ring.add(format!("{}:{}", id, i));
                 }
             }
@@ -577,6 +786,12 @@ impl ClusterManager {
     }
 }
 
+// Ring entries are "<node id>:<n>"; node ids are UUIDs, so the last colon
+// separates the suffix
+fn ring_node_id(vnode: &str) -> &str {
+    vnode.rsplit_once(':').map_or(vnode, |(id, _)| id)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClusterStats {
     pub total_nodes: usize,
@@ -588,9 +803,146 @@ pub struct ClusterStats {
     pub avg_memory_percent: f32,
     pub replication_factor: usize,
     pub quorum_size: usize,
+    // Writes from this node that some replica hasn't confirmed yet, and the
+    // age of the oldest one
+    pub replication_pending_entries: usize,
+    pub replication_lag_ms: u64,
 }
 
 // External dependencies for capacity detection
 use hostname;
 use num_cpus;
-use sys_info;
\ No newline at end of file
+use sys_info;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chitchat::transport::ChannelTransport;
+    use std::time::Instant;
+
+    fn node_config(node_id: &str, port: u16, seeds: &[&str]) -> ClusterConfig {
+        ClusterConfig {
+            node_id: node_id.to_string(),
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            seed_nodes: seeds.iter().map(|s| s.to_string()).collect(),
+            gossip_interval: Duration::from_millis(50),
+            ..Default::default()
+        }
+    }
+
+    fn active_node(config: &ClusterConfig) -> NodeInfo {
+        let mut node = ClusterManager::local_node_info(config).unwrap();
+        node.state = NodeState::Active;
+        node
+    }
+
+    async fn wait_for(nodes: &RwLock<HashMap<String, NodeInfo>>, check: impl Fn(&HashMap<String, NodeInfo>) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !check(&*nodes.read().await) {
+            assert!(Instant::now() < deadline, "gossip did not converge");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gossip_propagates_node_info() {
+        let transport = ChannelTransport::with_mtu(65_507);
+        let config_a = node_config("node-a", 17946, &[]);
+        let config_b = node_config("node-b", 17947, &["127.0.0.1:17946"]);
+
+        let info_a = active_node(&config_a);
+        let mut info_b = active_node(&config_b);
+        info_b.capacity.max_connections = 4321;
+
+        let handle_a = gossip::spawn_gossip(&config_a, &info_a, &transport).await.unwrap();
+        let handle_b = gossip::spawn_gossip(&config_b, &info_b, &transport).await.unwrap();
+
+        let nodes = Arc::new(RwLock::new(HashMap::new()));
+        let (event_tx, mut events) = broadcast::channel(16);
+        tokio::spawn(ClusterManager::process_gossip_events(
+            handle_a.chitchat(),
+            config_a.gossip_interval,
+            config_a.node_id.clone(),
+            nodes.clone(),
+            event_tx,
+        ));
+
+        // Node A learns B's capacity through gossip
+        wait_for(&nodes, |nodes| nodes.contains_key("node-b")).await;
+        {
+            let nodes = nodes.read().await;
+            assert_eq!(nodes["node-b"].capacity.max_connections, 4321);
+            assert!(nodes.contains_key("node-a"));
+        }
+        assert!(matches!(events.recv().await.unwrap(), ClusterEvent::NodeJoined(id) if id == "node-b"));
+
+        // Later updates to B's load follow
+        info_b.load.active_connections = 7;
+        gossip::publish_node_info(&handle_b.chitchat(), &info_b).await.unwrap();
+        wait_for(&nodes, |nodes| nodes["node-b"].load.active_connections == 7).await;
+
+        // Both members end up on the hash ring
+        let ring = Arc::new(RwLock::new(HashRing::new()));
+        ClusterManager::update_hash_ring(nodes.clone(), ring.clone()).await;
+        let ring = ring.read().await;
+        let owners: HashSet<String> = (0..100)
+            .filter_map(|i| ring.get(&format!("key-{}", i)).map(|vnode| ring_node_id(vnode).to_string()))
+            .collect();
+        assert_eq!(owners, HashSet::from(["node-a".to_string(), "node-b".to_string()]));
+
+        handle_a.shutdown().await.unwrap();
+        handle_b.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_measure_load_reports_request_metrics() {
+        let metrics = MetricsCollector::new();
+        let mut last_sample = None;
+
+        let load = ClusterManager::measure_load(Some(&metrics), &mut last_sample).await;
+        assert_eq!(load.active_connections, 0);
+        assert_eq!(load.requests_per_second, 0.0);
+
+        metrics.increment_connections();
+        for ms in 1..=50 {
+            metrics.record_request("GET", 200, Duration::from_millis(ms), 0, 0).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let load = ClusterManager::measure_load(Some(&metrics), &mut last_sample).await;
+        assert_eq!(load.active_connections, 1);
+        // 50 requests since the last heartbeat, a little over 100ms ago
+        assert!(load.requests_per_second > 50.0 && load.requests_per_second <= 500.0, "{}", load.requests_per_second);
+        assert!((47.0..=48.1).contains(&load.response_time_ms), "{}", load.response_time_ms);
+
+        let load = ClusterManager::measure_load(None, &mut None).await;
+        assert_eq!(load.active_connections, 0);
+    }
+
+    #[test]
+    fn test_apply_gossip_state_tracks_failed_and_left_nodes() {
+        let (event_tx, mut events) = broadcast::channel(16);
+        let mut nodes = HashMap::new();
+        let config_b = node_config("node-b", 17948, &[]);
+        let config_c = node_config("node-c", 17949, &[]);
+
+        let live = HashMap::from([
+            ("node-b".to_string(), active_node(&config_b)),
+            ("node-c".to_string(), active_node(&config_c)),
+        ]);
+        ClusterManager::apply_gossip_state(&mut nodes, live, "node-a", &event_tx);
+        assert_eq!(nodes.len(), 2);
+
+        // C announces it is leaving, then both drop out of the live set
+        let mut leaving = active_node(&config_c);
+        leaving.state = NodeState::Leaving;
+        ClusterManager::apply_gossip_state(&mut nodes, HashMap::from([("node-c".to_string(), leaving)]), "node-a", &event_tx);
+        ClusterManager::apply_gossip_state(&mut nodes, HashMap::new(), "node-a", &event_tx);
+
+        assert_eq!(nodes["node-b"].state, NodeState::Failed);
+        assert!(!nodes.contains_key("node-c"));
+
+        let events: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(events.iter().filter(|e| matches!(e, ClusterEvent::NodeJoined(_))).count(), 2);
+        assert!(events.iter().any(|e| matches!(e, ClusterEvent::NodeFailed(id) if id == "node-b")));
+        assert!(events.iter().any(|e| matches!(e, ClusterEvent::NodeLeft(id) if id == "node-c")));
+    }
+}
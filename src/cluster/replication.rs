@@ -1,89 +1,373 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, RwLock};
+use tonic::transport::Channel;
+use tonic::{Request, Response, Status};
 use tracing::{info, warn, debug};
 
-use super::ClusterConfig;
+use super::grpc::cluster_rpc::replication_client::ReplicationClient;
+use super::grpc::cluster_rpc::replication_server::{Replication, ReplicationServer};
+use super::grpc::cluster_rpc::{ReplicaAck, ReplicaUpdate};
+use super::grpc::lazy_channel;
+use super::{ClusterConfig, NodeInfo};
 
+// A push that takes longer than this is retried by the next sync_data
+const PUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Replicated key/value data (sessions, cache entries and other state that
+// has to survive losing a node). Each key is written on its owner and pushed
+// to the replica nodes; conflicting writes are resolved last-writer-wins on
+// (version, origin node).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReplicationEntry {
     pub key: String,
     pub value: Vec<u8>,
+    // Per-key logical clock: a local write goes one past the newest version
+    // seen, so it wins over anything it could have read
     pub version: u64,
+    // Node that made the write; breaks ties between concurrent writes
+    pub origin: String,
     pub timestamp: std::time::SystemTime,
     pub replicas: Vec<String>,
+    // Highest version each replica has confirmed
+    #[serde(skip)]
+    pub acked: HashMap<String, u64>,
+}
+
+impl ReplicationEntry {
+    // Same write as (version, origin), or one that beats it
+    fn covers(&self, version: u64, origin: &str) -> bool {
+        (self.version, self.origin.as_str()) >= (version, origin)
+    }
+
+    fn pending_replicas(&self) -> impl Iterator<Item = &String> {
+        self.replicas.iter().filter(|replica| self.acked.get(*replica).map_or(true, |v| *v < self.version))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplicationLag {
+    // Entries with at least one replica behind
+    pub pending_entries: usize,
+    // Age of the oldest write not yet on all of its replicas
+    pub max_lag_ms: u64,
 }
 
 pub struct ReplicationManager {
     config: ClusterConfig,
+    node_id: String,
+    // Cluster membership, to find replicas' gRPC addresses
+    nodes: Arc<RwLock<HashMap<String, NodeInfo>>>,
     data: Arc<RwLock<HashMap<String, ReplicationEntry>>>,
+    clients: Arc<Mutex<HashMap<SocketAddr, ReplicationClient<Channel>>>>,
 }
 
 impl ReplicationManager {
-    pub async fn new(config: &ClusterConfig) -> Result<Self> {
+    pub async fn new(config: &ClusterConfig, nodes: Arc<RwLock<HashMap<String, NodeInfo>>>) -> Result<Self> {
         Ok(Self {
             config: config.clone(),
+            node_id: config.node_id.clone(),
+            nodes,
             data: Arc::new(RwLock::new(HashMap::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
         })
     }
-    
+
+    // Push updates from other nodes; ClusterManager serves it on grpc_port
+    pub fn service(&self) -> ReplicationServer<ReplicationService> {
+        ReplicationServer::new(ReplicationService { data: self.data.clone() })
+    }
+
+    // Store `value` locally and push it to `replicas`. Replicas that can't be
+    // reached now are retried by sync_data.
     pub async fn replicate(&self, key: String, value: Vec<u8>, replicas: Vec<String>) -> Result<()> {
-        let entry = ReplicationEntry {
-            key: key.clone(),
-            value: value.clone(),
-            version: 1,
-            timestamp: std::time::SystemTime::now(),
-            replicas: replicas.clone(),
+        let replicas: Vec<String> = replicas.into_iter().filter(|r| *r != self.node_id).collect();
+        let update = {
+            let mut data = self.data.write().await;
+            let version = data.get(&key).map_or(0, |e| e.version) + 1;
+            let entry = ReplicationEntry {
+                key: key.clone(),
+                value,
+                version,
+                origin: self.node_id.clone(),
+                timestamp: std::time::SystemTime::now(),
+                replicas: replicas.clone(),
+                acked: HashMap::new(),
+            };
+            let update = to_update(&entry);
+            data.insert(key.clone(), entry);
+            update
         };
-        
-        let mut data = self.data.write().await;
-        data.insert(key.clone(), entry);
-        
+
         // Send to replicas
-        for replica in replicas {
-            debug!("Replicating {} to {}", key, replica);
-            // TODO: Implement actual replication via gRPC
-        }
-        
+        self.push(&key, &update, replicas).await;
         Ok(())
     }
-    
+
     pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
         let data = self.data.read().await;
         data.get(key).map(|e| e.value.clone())
     }
-    
+
+    pub async fn version(&self, key: &str) -> Option<u64> {
+        self.data.read().await.get(key).map(|e| e.version)
+    }
+
+    // Re-push every entry this node wrote that some replica hasn't confirmed
     pub async fn sync_data(&self) -> Result<()> {
         debug!("Syncing replication data");
-        
-        let data = self.data.read().await;
-        let count = data.len();
-        
+
+        let pending: Vec<(String, ReplicaUpdate, Vec<String>)> = {
+            let data = self.data.read().await;
+            data.values()
+                .filter(|entry| entry.origin == self.node_id)
+                .map(|entry| (entry.key.clone(), to_update(entry), entry.pending_replicas().cloned().collect::<Vec<_>>()))
+                .filter(|(_, _, replicas)| !replicas.is_empty())
+                .collect()
+        };
+        let count = pending.len();
+
+        for (key, update, replicas) in pending {
+            self.push(&key, &update, replicas).await;
+        }
+
         if count > 0 {
             info!("Synced {} replicated entries", count);
         }
-        
+
         Ok(())
     }
-    
+
+    pub async fn lag(&self) -> ReplicationLag {
+        let data = self.data.read().await;
+        let now = SystemTime::now();
+        let mut lag = ReplicationLag::default();
+
+        for entry in data.values().filter(|e| e.origin == self.node_id) {
+            if entry.pending_replicas().next().is_some() {
+                lag.pending_entries += 1;
+                let age = now.duration_since(entry.timestamp).unwrap_or_default().as_millis() as u64;
+                lag.max_lag_ms = lag.max_lag_ms.max(age);
+            }
+        }
+
+        lag
+    }
+
     pub async fn handle_failover(&self, failed_node: &str) -> Result<()> {
         warn!("Handling failover for node: {}", failed_node);
-        
+
         let mut data = self.data.write().await;
-        
+
         // Re-replicate data that was on the failed node
         for entry in data.values_mut() {
             if entry.replicas.contains(&failed_node.to_string()) {
                 entry.replicas.retain(|n| n != failed_node);
-                
+                entry.acked.remove(failed_node);
+
                 // Find new replica node
                 // TODO: Select new replica based on load
                 info!("Re-replicating {} to new nodes", entry.key);
             }
         }
-        
+
         Ok(())
     }
-}
\ No newline at end of file
+
+    async fn push(&self, key: &str, update: &ReplicaUpdate, replicas: Vec<String>) {
+        let calls = replicas.into_iter().map(|replica| async move {
+            let result = self.push_to(&replica, update.clone()).await;
+            (replica, result)
+        });
+
+        for (replica, result) in join_all(calls).await {
+            match result {
+                Ok(ack) => {
+                    debug!("Replicated {} v{} to {}", key, update.version, replica);
+                    if let Some(entry) = self.data.write().await.get_mut(key) {
+                        let acked = entry.acked.entry(replica).or_insert(0);
+                        *acked = (*acked).max(ack.version);
+                    }
+                }
+                Err(e) => warn!("Failed to replicate {} to {}: {}", key, replica, e),
+            }
+        }
+    }
+
+    async fn push_to(&self, replica: &str, update: ReplicaUpdate) -> Result<ReplicaAck> {
+        let addr = self.nodes.read().await
+            .get(replica)
+            .map(|node| node.grpc_addr)
+            .ok_or_else(|| anyhow!("unknown node"))?;
+
+        let mut client = {
+            let mut clients = self.clients.lock().await;
+            match clients.get(&addr) {
+                Some(client) => client.clone(),
+                None => {
+                    let client = ReplicationClient::new(lazy_channel(addr, PUSH_TIMEOUT)?);
+                    clients.insert(addr, client.clone());
+                    client
+                }
+            }
+        };
+
+        let response = tokio::time::timeout(PUSH_TIMEOUT, client.push(update)).await
+            .map_err(|_| anyhow!("timed out"))??;
+        Ok(response.into_inner())
+    }
+}
+
+fn to_update(entry: &ReplicationEntry) -> ReplicaUpdate {
+    ReplicaUpdate {
+        key: entry.key.clone(),
+        value: entry.value.clone(),
+        version: entry.version,
+        origin_node: entry.origin.clone(),
+        updated_at_ms: entry.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64,
+    }
+}
+
+// Applies pushes from the owner of a key
+pub struct ReplicationService {
+    data: Arc<RwLock<HashMap<String, ReplicationEntry>>>,
+}
+
+#[tonic::async_trait]
+impl Replication for ReplicationService {
+    async fn push(
+        &self,
+        request: Request<ReplicaUpdate>,
+    ) -> Result<Response<ReplicaAck>, Status> {
+        let update = request.into_inner();
+        let mut data = self.data.write().await;
+
+        // Last writer wins; an older or equal write is dropped and the
+        // sender learns what we hold instead
+        if let Some(existing) = data.get(&update.key) {
+            if existing.covers(update.version, &update.origin_node) {
+                return Ok(Response::new(ReplicaAck {
+                    version: existing.version,
+                    origin_node: existing.origin.clone(),
+                }));
+            }
+        }
+
+        let timestamp = UNIX_EPOCH + Duration::from_millis(update.updated_at_ms.max(0) as u64);
+        data.insert(update.key.clone(), ReplicationEntry {
+            key: update.key,
+            value: update.value,
+            version: update.version,
+            origin: update.origin_node.clone(),
+            timestamp,
+            replicas: Vec::new(),
+            acked: HashMap::new(),
+        });
+
+        Ok(Response::new(ReplicaAck {
+            version: update.version,
+            origin_node: update.origin_node,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::{ClusterManager, NodeState};
+
+    fn node_config(node_id: &str, grpc_port: u16) -> ClusterConfig {
+        ClusterConfig {
+            node_id: node_id.to_string(),
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], grpc_port - 1)),
+            grpc_port,
+            ..Default::default()
+        }
+    }
+
+    // Managers for `ids`, all serving on their grpc_port and sharing one
+    // membership view
+    async fn cluster(ids: &[(&str, u16)]) -> Vec<ReplicationManager> {
+        let configs: Vec<_> = ids.iter().map(|(id, port)| node_config(id, *port)).collect();
+        let membership: HashMap<String, NodeInfo> = configs.iter()
+            .map(|config| {
+                let mut node = ClusterManager::local_node_info(config).unwrap();
+                node.state = NodeState::Active;
+                (node.id.clone(), node)
+            })
+            .collect();
+
+        let mut managers = Vec::new();
+        for config in &configs {
+            let nodes = Arc::new(RwLock::new(membership.clone()));
+            let manager = ReplicationManager::new(config, nodes).await.unwrap();
+            let addr = SocketAddr::new(config.bind_addr.ip(), config.grpc_port);
+            tokio::spawn(tonic::transport::Server::builder().add_service(manager.service()).serve(addr));
+            managers.push(manager);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        managers
+    }
+
+    #[tokio::test]
+    async fn test_replicates_to_replicas() {
+        let managers = cluster(&[("node-a", 17971), ("node-b", 17973), ("node-c", 17975)]).await;
+        let replicas = vec!["node-a".to_string(), "node-b".to_string(), "node-c".to_string()];
+
+        managers[0].replicate("session:1".into(), b"v1".to_vec(), replicas.clone()).await.unwrap();
+        for manager in &managers {
+            assert_eq!(manager.get("session:1").await.as_deref(), Some(&b"v1"[..]));
+        }
+
+        // A later write from a replica wins everywhere
+        managers[1].replicate("session:1".into(), b"v2".to_vec(), replicas).await.unwrap();
+        assert_eq!(managers[1].version("session:1").await, Some(2));
+        assert_eq!(managers[0].get("session:1").await.as_deref(), Some(&b"v2"[..]));
+        assert_eq!(managers[2].get("session:1").await.as_deref(), Some(&b"v2"[..]));
+
+        let lag = managers[0].lag().await;
+        assert_eq!(lag.pending_entries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stale_push_is_ignored() {
+        let data = Arc::new(RwLock::new(HashMap::new()));
+        let service = ReplicationService { data: data.clone() };
+        let update = |version, origin: &str, value: &[u8]| Request::new(ReplicaUpdate {
+            key: "k".to_string(),
+            value: value.to_vec(),
+            version,
+            origin_node: origin.to_string(),
+            updated_at_ms: 0,
+        });
+
+        service.push(update(2, "node-a", b"new")).await.unwrap();
+        let ack = service.push(update(1, "node-b", b"old")).await.unwrap().into_inner();
+        assert_eq!(ack.version, 2);
+        // Same version: the higher origin wins
+        service.push(update(2, "node-c", b"tie")).await.unwrap();
+        assert_eq!(data.read().await["k"].value, b"tie");
+        service.push(update(2, "node-b", b"lost")).await.unwrap();
+        assert_eq!(data.read().await["k"].value, b"tie");
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_replica_shows_as_lag() {
+        let managers = cluster(&[("node-a", 17977)]).await;
+        // node-z is a member but nothing listens on its port
+        let mut ghost = ClusterManager::local_node_info(&node_config("node-z", 17979)).unwrap();
+        ghost.state = NodeState::Active;
+        managers[0].nodes.write().await.insert("node-z".to_string(), ghost);
+
+        managers[0].replicate("k".into(), b"v".to_vec(), vec!["node-z".to_string()]).await.unwrap();
+        managers[0].sync_data().await.unwrap();
+
+        let lag = managers[0].lag().await;
+        assert_eq!(lag.pending_entries, 1);
+    }
+}
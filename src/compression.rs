@@ -0,0 +1,351 @@
+use axum::{
+    body::HttpBody,
+    extract::Request,
+    http::{header, HeaderValue, Response},
+    middleware::Next,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tower_http::compression::{CompressionLayer, CompressionLevel, Predicate};
+
+use crate::body_limit;
+
+// [compression]
+// enabled = true
+// encodings = ["zstd", "br", "gzip"]  # preferred first
+// levels = { gzip = 6, br = 4 }       # library default when unset
+// min_size = "1KB"
+// content_types = []                  # prefixes to compress; empty = all
+// exclude_content_types = ["image/", "video/"]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // Picked by the client's q-values; on a tie the first one listed wins
+    pub encodings: Vec<Encoding>,
+    pub levels: HashMap<Encoding, i32>,
+    // Responses with a known size below this go out as they are
+    #[serde(deserialize_with = "body_limit::deserialize_size")]
+    pub min_size: u64,
+    pub content_types: Vec<String>,
+    pub exclude_content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            encodings: vec![Encoding::Zstd, Encoding::Br, Encoding::Gzip],
+            levels: HashMap::new(),
+            min_size: 1024,
+            content_types: Vec::new(),
+            // Already compressed, or streamed (gRPC, server-sent events)
+            exclude_content_types: [
+                "image/", "video/", "audio/", "font/woff",
+                "application/zip", "application/gzip", "application/x-gzip", "application/zstd",
+                "application/grpc", "text/event-stream",
+            ].iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[serde(alias = "brotli")]
+    Br,
+    Gzip,
+    Deflate,
+    Zstd,
+}
+
+impl Encoding {
+    // The Accept-Encoding / Content-Encoding token
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Br => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    fn level_range(self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            Encoding::Br => 0..=11,
+            Encoding::Gzip | Encoding::Deflate => 0..=9,
+            Encoding::Zstd => 1..=22,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (encoding, level) in &self.levels {
+            let range = encoding.level_range();
+            if !range.contains(level) {
+                anyhow::bail!(
+                    "compression level {} for {:?} is out of range {}..={}",
+                    level, encoding, range.start(), range.end()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Which responses get compressed: big enough (or of unknown size, when
+// streamed) and of a content type the config allows
+#[derive(Clone)]
+struct ShouldCompress {
+    min_size: u64,
+    content_types: Arc<[String]>,
+    exclude_content_types: Arc<[String]>,
+}
+
+impl Predicate for ShouldCompress {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        let size = response.body().size_hint().exact().or_else(|| {
+            response.headers().get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+        });
+        if size.is_some_and(|size| size < self.min_size) {
+            return false;
+        }
+
+        let content_type = response.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let matches = |prefixes: &[String]| {
+            prefixes.iter().any(|prefix| content_type.starts_with(&prefix.to_ascii_lowercase()))
+        };
+        (self.content_types.is_empty() || matches(&self.content_types))
+            && !matches(&self.exclude_content_types)
+    }
+}
+
+// The q-value `accept_encoding` gives `encoding`: 0 when it isn't listed
+// (or is refused with q=0), the `*` entry's value when only that matches
+pub fn encoding_quality(accept_encoding: &str, encoding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()))
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return quality;
+        }
+        if name == "*" {
+            wildcard = quality;
+        }
+    }
+    wildcard
+}
+
+// The encoding to use for a client: highest q-value first, then the order
+// of `encodings`
+pub fn negotiate(accept_encoding: &str, encodings: &[Encoding]) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in encodings {
+        let quality = encoding_quality(accept_encoding, encoding.token());
+        if quality > 0.0 && best.map_or(true, |(_, q)| quality > q) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// The client's Accept-Encoding while the compression layers only see the
+// negotiated encoding
+#[derive(Clone)]
+struct OriginalAcceptEncoding(Option<HeaderValue>);
+
+// Each compression layer only offers its own encoding, so left alone the
+// innermost one the client accepts at all would win regardless of q-values.
+// This narrows Accept-Encoding to the negotiated encoding on the way in.
+async fn negotiate_middleware(
+    axum::extract::State(encodings): axum::extract::State<Arc<[Encoding]>>,
+    mut req: Request,
+    next: Next,
+) -> axum::response::Response {
+    let original = req.headers_mut().remove(header::ACCEPT_ENCODING);
+    let accepted = original.as_ref().and_then(|v| v.to_str().ok()).unwrap_or("");
+    let chosen = negotiate(accepted, &encodings).map_or("identity", Encoding::token);
+    req.headers_mut().insert(header::ACCEPT_ENCODING, HeaderValue::from_static(chosen));
+    req.extensions_mut().insert(OriginalAcceptEncoding(original));
+    next.run(req).await
+}
+
+// ...and puts the client's header back for the app, so backends and
+// precompressed files see what the client sent
+async fn restore_middleware(mut req: Request, next: Next) -> axum::response::Response {
+    if let Some(OriginalAcceptEncoding(original)) = req.extensions_mut().remove::<OriginalAcceptEncoding>() {
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+        if let Some(value) = original {
+            req.headers_mut().insert(header::ACCEPT_ENCODING, value);
+        }
+    }
+    next.run(req).await
+}
+
+// One CompressionLayer per encoding so each gets its own level. The
+// negotiation middleware outside them makes sure only the chosen one acts;
+// a response the app already encoded (a precompressed file) passes through.
+pub fn apply(router: Router, config: &CompressionConfig) -> Router {
+    if !config.enabled || config.encodings.is_empty() {
+        return router;
+    }
+
+    let predicate = ShouldCompress {
+        min_size: config.min_size,
+        content_types: config.content_types.clone().into(),
+        exclude_content_types: config.exclude_content_types.clone().into(),
+    };
+    let mut router = router.layer(axum::middleware::from_fn(restore_middleware));
+    for &encoding in &config.encodings {
+        let layer = CompressionLayer::new()
+            .no_br()
+            .no_gzip()
+            .no_deflate()
+            .no_zstd();
+        let layer = match encoding {
+            Encoding::Br => layer.br(true),
+            Encoding::Gzip => layer.gzip(true),
+            Encoding::Deflate => layer.deflate(true),
+            Encoding::Zstd => layer.zstd(true),
+        };
+        let quality = config.levels.get(&encoding)
+            .map_or(CompressionLevel::Default, |&level| CompressionLevel::Precise(level));
+        router = router.layer(layer.quality(quality).compress_when(predicate.clone()));
+    }
+    let encodings: Arc<[Encoding]> = config.encodings.clone().into();
+    router.layer(axum::middleware::from_fn_with_state(encodings, negotiate_middleware))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::Request, routing::get};
+    use std::io::Read;
+    use tower::ServiceExt;
+
+    fn app(config: &CompressionConfig) -> Router {
+        let router = Router::new()
+            .route("/small", get(|| async { "tiny" }))
+            .route("/large", get(|| async { "lorem ipsum dolor sit amet ".repeat(400) }))
+            .route("/image", get(|| async {
+                ([(header::CONTENT_TYPE, "image/png")], vec![7u8; 20_000])
+            }))
+            .route("/echo", get(|headers: axum::http::HeaderMap| async move {
+                headers.get(header::ACCEPT_ENCODING).map(|v| v.to_str().unwrap().to_string()).unwrap_or_default()
+            }));
+        apply(router, config)
+    }
+
+    async fn fetch(app: Router, uri: &str, accept_encoding: &str) -> (Option<String>, Vec<u8>) {
+        let req = Request::builder()
+            .uri(uri)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let encoding = response.headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (encoding, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_compresses_large_text_only() {
+        let config = CompressionConfig { enabled: true, ..CompressionConfig::default() };
+
+        let (encoding, body) = fetch(app(&config), "/small", "gzip").await;
+        assert_eq!(encoding, None);
+        assert_eq!(body, b"tiny");
+
+        let (encoding, body) = fetch(app(&config), "/large", "gzip").await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "lorem ipsum dolor sit amet ".repeat(400));
+        assert!(body.len() < text.len() / 10);
+
+        // Images are already compressed
+        let (encoding, body) = fetch(app(&config), "/image", "gzip").await;
+        assert_eq!(encoding, None);
+        assert_eq!(body.len(), 20_000);
+
+        // Disabled leaves everything alone
+        let (encoding, _) = fetch(app(&CompressionConfig::default()), "/large", "gzip").await;
+        assert_eq!(encoding, None);
+    }
+
+    #[tokio::test]
+    async fn test_encoding_preference_and_levels() {
+        let mut config: CompressionConfig = toml::from_str(r#"
+            enabled = true
+            encodings = ["gzip", "brotli"]
+            levels = { gzip = 1, br = 11 }
+            min_size = "2KB"
+        "#).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.min_size, 2000);
+
+        // The client takes both; the config prefers gzip
+        let (encoding, _) = fetch(app(&config), "/large", "br, gzip").await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        let (encoding, _) = fetch(app(&config), "/large", "br").await;
+        assert_eq!(encoding.as_deref(), Some("br"));
+        let (encoding, _) = fetch(app(&config), "/large", "identity").await;
+        assert_eq!(encoding, None);
+
+        config.levels.insert(Encoding::Gzip, 12);
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_zstd_negotiation() {
+        let config = CompressionConfig { enabled: true, ..CompressionConfig::default() };
+
+        let (encoding, body) = fetch(app(&config), "/large", "gzip, deflate, br, zstd").await;
+        assert_eq!(encoding.as_deref(), Some("zstd"));
+        // A zstd frame, and much smaller than the text
+        assert_eq!(&body[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+        assert!(body.len() < 1_000);
+
+        // The client's q-values beat the configured order
+        let (encoding, _) = fetch(app(&config), "/large", "zstd;q=0.5, gzip").await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        let (encoding, _) = fetch(app(&config), "/large", "zstd;q=0, *").await;
+        assert_eq!(encoding.as_deref(), Some("br"));
+
+        // The app still sees what the client sent
+        let (encoding, body) = fetch(app(&config), "/echo", "zstd;q=0.5, gzip").await;
+        assert_eq!(encoding, None);
+        assert_eq!(body, b"zstd;q=0.5, gzip");
+    }
+
+    #[test]
+    fn test_encoding_quality() {
+        assert_eq!(encoding_quality("gzip, br", "br"), 1.0);
+        assert_eq!(encoding_quality("GZIP;q=0.5", "gzip"), 0.5);
+        assert_eq!(encoding_quality("gzip;q=0", "gzip"), 0.0);
+        assert_eq!(encoding_quality("deflate", "gzip"), 0.0);
+        assert_eq!(encoding_quality("*;q=0.2, br", "zstd"), 0.2);
+        assert_eq!(encoding_quality("", "br"), 0.0);
+
+        let all = [Encoding::Zstd, Encoding::Br, Encoding::Gzip];
+        assert_eq!(negotiate("gzip, zstd", &all), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip;q=0.9, zstd;q=0.8", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate", &all), None);
+    }
+}
@@ -0,0 +1,301 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+// Upstreams for a backend found at runtime instead of a fixed `target`,
+// e.g.
+//
+//   [backends."api.example.com".discovery]
+//   source = "srv"
+//   name = "_http._tcp.api.service.consul"
+//   interval_seconds = 10
+//
+// Docker's embedded DNS and Consul's DNS interface both work through the
+// "dns" and "srv" sources. Read at startup, so changes need a restart.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DiscoveryConfig {
+    pub source: DiscoverySource,
+    // Hostname for "dns", record name for "srv"
+    pub name: String,
+    // Port for "dns" addresses; SRV records carry their own
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    #[serde(default = "default_interval_seconds")]
+    pub interval_seconds: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoverySource {
+    // A/AAAA records of `name`, paired with `port`
+    Dns,
+    // SRV records of `name`, one upstream per target and port
+    Srv,
+}
+
+fn default_scheme() -> String {
+    "http".to_string()
+}
+
+fn default_interval_seconds() -> u64 {
+    30
+}
+
+impl DiscoveryConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.name.trim().is_empty() {
+            anyhow::bail!("discovery name is empty");
+        }
+        if self.source == DiscoverySource::Dns && self.port.is_none() {
+            anyhow::bail!("discovery source \"dns\" needs a port");
+        }
+        if !matches!(self.scheme.as_str(), "http" | "https") {
+            anyhow::bail!("discovery scheme must be http or https, not {:?}", self.scheme);
+        }
+        if self.interval_seconds == 0 {
+            anyhow::bail!("discovery interval_seconds must be greater than zero");
+        }
+        Ok(())
+    }
+}
+
+// Where a backend's upstreams come from
+#[async_trait::async_trait]
+pub trait Resolver: Send + Sync {
+    // The current upstreams as host:port pairs
+    async fn resolve(&self) -> anyhow::Result<Vec<String>>;
+}
+
+pub struct DnsResolver {
+    name: String,
+    port: u16,
+}
+
+#[async_trait::async_trait]
+impl Resolver for DnsResolver {
+    async fn resolve(&self) -> anyhow::Result<Vec<String>> {
+        let addrs = tokio::net::lookup_host((self.name.as_str(), self.port)).await?;
+        Ok(addrs.map(|addr| addr.to_string()).collect())
+    }
+}
+
+pub struct SrvResolver {
+    name: String,
+    resolver: hickory_resolver::TokioAsyncResolver,
+}
+
+#[async_trait::async_trait]
+impl Resolver for SrvResolver {
+    async fn resolve(&self) -> anyhow::Result<Vec<String>> {
+        let records = self.resolver.srv_lookup(self.name.as_str()).await?;
+        Ok(records.iter()
+            .map(|srv| format!("{}:{}", srv.target().to_utf8().trim_end_matches('.'), srv.port()))
+            .collect())
+    }
+}
+
+fn resolver_for(config: &DiscoveryConfig) -> Arc<dyn Resolver> {
+    match config.source {
+        DiscoverySource::Dns => Arc::new(DnsResolver {
+            name: config.name.clone(),
+            port: config.port.unwrap_or(80),
+        }),
+        DiscoverySource::Srv => {
+            use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+            let resolver = hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()
+                .unwrap_or_else(|e| {
+                    warn!("Failed to read the system DNS config ({}), using defaults", e);
+                    hickory_resolver::TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+                });
+            Arc::new(SrvResolver { name: config.name.clone(), resolver })
+        }
+    }
+}
+
+// A backend's current upstreams, picked round-robin
+pub struct UpstreamPool {
+    scheme: String,
+    upstreams: RwLock<Vec<String>>,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(scheme: &str) -> Self {
+        Self {
+            scheme: scheme.to_string(),
+            upstreams: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // Base URL of the next upstream, or None while none are known
+    pub fn select_upstream(&self) -> Option<String> {
+        let upstreams = self.upstreams.read().unwrap();
+        if upstreams.is_empty() {
+            return None;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % upstreams.len();
+        Some(format!("{}://{}", self.scheme, upstreams[index]))
+    }
+
+    pub fn upstreams(&self) -> Vec<String> {
+        self.upstreams.read().unwrap().clone()
+    }
+
+    // Replace the set; true when it changed
+    fn update(&self, mut upstreams: Vec<String>) -> bool {
+        upstreams.sort();
+        upstreams.dedup();
+        let mut current = self.upstreams.write().unwrap();
+        if *current == upstreams {
+            return false;
+        }
+        *current = upstreams;
+        true
+    }
+
+    // Resolve once. A failed lookup keeps the last known upstreams, so a
+    // DNS hiccup doesn't take the backend down.
+    pub async fn refresh(&self, name: &str, resolver: &dyn Resolver) {
+        match resolver.resolve().await {
+            Ok(upstreams) => {
+                if self.update(upstreams) {
+                    info!("Upstreams for {} are now {:?}", name, self.upstreams());
+                }
+            }
+            Err(e) => warn!("Discovery for {} failed, keeping {} upstream(s): {}", name, self.upstreams().len(), e),
+        }
+    }
+}
+
+// Upstream pools for every backend with a [discovery] section
+#[derive(Default)]
+pub struct Discovery {
+    pools: HashMap<String, Arc<UpstreamPool>>,
+}
+
+impl Discovery {
+    // Start a refresh loop per backend
+    pub fn start<'a>(backends: impl IntoIterator<Item = (&'a String, &'a DiscoveryConfig)>) -> Self {
+        let mut discovery = Self::default();
+        for (name, config) in backends {
+            discovery.watch(name, resolver_for(config), &config.scheme, Duration::from_secs(config.interval_seconds));
+        }
+        discovery
+    }
+
+    pub fn watch(&mut self, name: &str, resolver: Arc<dyn Resolver>, scheme: &str, interval: Duration) {
+        let pool = Arc::new(UpstreamPool::new(scheme));
+        self.pools.insert(name.to_string(), pool.clone());
+
+        debug!("Discovering upstreams for {} every {:?}", name, interval);
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                pool.refresh(&name, resolver.as_ref()).await;
+            }
+        });
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&Arc<UpstreamPool>> {
+        self.pools.get(name)
+    }
+
+    pub fn select_upstream(&self, name: &str) -> Option<String> {
+        self.pools.get(name).and_then(|pool| pool.select_upstream())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Hands out whatever the test put in it, or an error when set to None
+    struct StubResolver(Mutex<Option<Vec<String>>>);
+
+    impl StubResolver {
+        fn set(&self, upstreams: Option<&[&str]>) {
+            *self.0.lock().unwrap() = upstreams.map(|u| u.iter().map(|s| s.to_string()).collect());
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Resolver for StubResolver {
+        async fn resolve(&self) -> anyhow::Result<Vec<String>> {
+            self.0.lock().unwrap().clone().ok_or_else(|| anyhow::anyhow!("SERVFAIL"))
+        }
+    }
+
+    fn selected(pool: &UpstreamPool, rounds: usize) -> Vec<String> {
+        let mut seen: Vec<String> = (0..rounds).filter_map(|_| pool.select_upstream()).collect();
+        seen.sort();
+        seen.dedup();
+        seen
+    }
+
+    #[tokio::test]
+    async fn test_upstream_set_follows_resolver() {
+        let resolver = StubResolver(Mutex::new(None));
+        let pool = UpstreamPool::new("http");
+        assert_eq!(pool.select_upstream(), None);
+
+        resolver.set(Some(&["10.0.0.1:8080"]));
+        pool.refresh("api", &resolver).await;
+        assert_eq!(selected(&pool, 4), vec!["http://10.0.0.1:8080"]);
+
+        // Scaling up adds upstreams...
+        resolver.set(Some(&["10.0.0.3:8080", "10.0.0.1:8080", "10.0.0.2:8080"]));
+        pool.refresh("api", &resolver).await;
+        assert_eq!(selected(&pool, 6), vec!["http://10.0.0.1:8080", "http://10.0.0.2:8080", "http://10.0.0.3:8080"]);
+
+        // ...a failed lookup keeps them...
+        resolver.set(None);
+        pool.refresh("api", &resolver).await;
+        assert_eq!(pool.upstreams().len(), 3);
+
+        // ...and scaling down removes them
+        resolver.set(Some(&["10.0.0.2:8080"]));
+        pool.refresh("api", &resolver).await;
+        assert_eq!(selected(&pool, 4), vec!["http://10.0.0.2:8080"]);
+
+        resolver.set(Some(&[]));
+        pool.refresh("api", &resolver).await;
+        assert_eq!(pool.select_upstream(), None);
+    }
+
+    #[tokio::test]
+    async fn test_watch_refreshes_in_background() {
+        let resolver = Arc::new(StubResolver(Mutex::new(Some(vec!["127.0.0.1:3000".to_string()]))));
+        let mut discovery = Discovery::default();
+        discovery.watch("api", resolver.clone(), "https", Duration::from_millis(20));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(discovery.select_upstream("api").as_deref(), Some("https://127.0.0.1:3000"));
+
+        resolver.set(Some(&["127.0.0.1:3001"]));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(discovery.select_upstream("api").as_deref(), Some("https://127.0.0.1:3001"));
+        assert_eq!(discovery.select_upstream("other"), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: DiscoveryConfig = toml::from_str("source = \"srv\"\nname = \"_http._tcp.api\"").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.interval_seconds, 30);
+
+        let config: DiscoveryConfig = toml::from_str("source = \"dns\"\nname = \"api\"").unwrap();
+        assert!(config.validate().is_err());
+        let config: DiscoveryConfig = toml::from_str("source = \"dns\"\nname = \"api\"\nport = 80\ninterval_seconds = 0").unwrap();
+        assert!(config.validate().is_err());
+        assert!(toml::from_str::<DiscoveryConfig>("source = \"zookeeper\"\nname = \"api\"").is_err());
+    }
+}
@@ -9,14 +9,28 @@ use axum::{
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt;
-use std::path::PathBuf;
+use std::backtrace::Backtrace;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{error, warn, debug};
 use uuid::Uuid;
 
+use crate::logging::{RequestId, VirtualHost};
+
+// [errors]
+// mode = "production"
+// templates_dir = "./errors"
+// custom_pages = { 404 = "404.html" }
+//
+// Templates may use {{status}}, {{status_text}}, {{message}}, {{details}},
+// {{error_id}} and {{timestamp}}.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ErrorConfig {
     pub mode: ErrorMode,
+    // Status code to template file, relative to templates_dir
     pub custom_pages: HashMap<u16, String>,
     pub templates_dir: Option<PathBuf>,
     pub show_details: bool,
@@ -24,6 +38,26 @@ pub struct ErrorConfig {
     pub notify_errors: Option<NotificationConfig>,
     pub rate_limit_errors: bool,
     pub error_tracking: Option<ErrorTrackingConfig>,
+    // Per-host overrides of custom_pages, taken from each backend's
+    // error_pages
+    #[serde(skip)]
+    pub host_pages: HashMap<String, HashMap<u16, String>>,
+}
+
+impl Default for ErrorConfig {
+    fn default() -> Self {
+        Self {
+            mode: ErrorMode::Production,
+            custom_pages: HashMap::new(),
+            templates_dir: None,
+            show_details: false,
+            log_errors: true,
+            notify_errors: None,
+            rate_limit_errors: false,
+            error_tracking: None,
+            host_pages: HashMap::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -34,14 +68,20 @@ pub enum ErrorMode {
     Maintenance, // Show maintenance page
 }
 
+// Sent for server errors (5xx) once `threshold` of one status have piled
+// up, and at most once per `interval` for that status
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NotificationConfig {
+    // Not supported yet; use a webhook
     pub email: Option<String>,
+    // Receives a JSON POST per notification
     pub webhook: Option<String>,
     pub threshold: u32, // Number of errors before notification
     pub interval: u64,  // Seconds between notifications
 }
 
+// Every server error is reported to Sentry (sentry_dsn) and/or POSTed as
+// JSON to custom_endpoint
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorTrackingConfig {
     pub sentry_dsn: Option<String>,
@@ -49,7 +89,7 @@ pub struct ErrorTrackingConfig {
     pub custom_endpoint: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct AppError {
     pub id: String,
     pub status: StatusCode,
@@ -59,8 +99,15 @@ pub struct AppError {
     pub source: Option<Box<dyn std::error::Error + Send + Sync>>,
     pub context: HashMap<String, String>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    // Where the error was created; only captured once a Development mode
+    // ErrorHandler exists, and only ever shown in that mode
+    pub backtrace: Option<Backtrace>,
 }
 
+// Capturing is slow, so errors only record a backtrace when some handler
+// is in Development mode
+static CAPTURE_BACKTRACES: AtomicBool = AtomicBool::new(false);
+
 impl AppError {
     pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
         Self {
@@ -72,9 +119,16 @@ impl AppError {
             source: None,
             context: HashMap::new(),
             timestamp: chrono::Utc::now(),
+            backtrace: CAPTURE_BACKTRACES.load(Ordering::Relaxed).then(Backtrace::force_capture),
         }
     }
 
+    // Use the request's id, so the page, the logs and the backend agree
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = id.into();
+        self
+    }
+
     pub fn with_code(mut self, code: impl Into<String>) -> Self {
         self.code = Some(code.into());
         self
@@ -168,6 +222,7 @@ pub struct ErrorDetails {
 
 #[derive(Debug, Clone, Serialize)]
 pub struct DebugInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stack_trace: Option<Vec<String>>,
     pub context: HashMap<String, String>,
     pub source: Option<String>,
@@ -176,37 +231,119 @@ pub struct DebugInfo {
 pub struct ErrorHandler {
     config: ErrorConfig,
     templates: Arc<HashMap<u16, String>>,
+    host_templates: Arc<HashMap<String, HashMap<u16, String>>>,
     error_counts: Arc<tokio::sync::RwLock<HashMap<String, u32>>>,
+    // When each status last caused a notification
+    last_notified: Arc<tokio::sync::Mutex<HashMap<String, Instant>>>,
+    sentry: Option<SentryTarget>,
+    client: reqwest::Client,
+}
+
+// Where a Sentry DSN says to store events
+#[derive(Debug, Clone, PartialEq)]
+struct SentryTarget {
+    store_url: String,
+    public_key: String,
+}
+
+impl SentryTarget {
+    // https://<public_key>@<host>[/<path>]/<project_id>
+    fn from_dsn(dsn: &str) -> Option<Self> {
+        let url = reqwest::Url::parse(dsn).ok()?;
+        let public_key = url.username();
+        let host = url.host_str()?;
+        let (prefix, project_id) = url.path().trim_end_matches('/').rsplit_once('/')?;
+        if public_key.is_empty() || project_id.is_empty() {
+            return None;
+        }
+        let port = url.port().map(|port| format!(":{}", port)).unwrap_or_default();
+        Some(Self {
+            store_url: format!("{}://{}{}{}/api/{}/store/", url.scheme(), host, port, prefix, project_id),
+            public_key: public_key.to_string(),
+        })
+    }
+
+    fn auth_header(&self) -> String {
+        format!(
+            "Sentry sentry_version=7, sentry_client=miwidothttp/{}, sentry_key={}",
+            env!("CARGO_PKG_VERSION"),
+            self.public_key,
+        )
+    }
+}
+
+// Read each status code's template; paths are relative to `dir` when set
+async fn load_templates(dir: Option<&Path>, pages: &HashMap<u16, String>) -> Result<HashMap<u16, String>> {
+    let mut templates = HashMap::new();
+    for (status_code, template_file) in pages {
+        let path = match dir {
+            Some(dir) => dir.join(template_file),
+            None => PathBuf::from(template_file),
+        };
+        if path.exists() {
+            let content = tokio::fs::read_to_string(&path).await?;
+            templates.insert(*status_code, content);
+        } else {
+            warn!("Error page template {} not found", path.display());
+        }
+    }
+    Ok(templates)
 }
 
 impl ErrorHandler {
     pub async fn new(config: ErrorConfig) -> Result<Self> {
-        let mut templates = HashMap::new();
-        
         // Load custom error page templates
-        if let Some(dir) = &config.templates_dir {
-            for (status_code, template_file) in &config.custom_pages {
-                let path = dir.join(template_file);
-                if path.exists() {
-                    let content = tokio::fs::read_to_string(&path).await?;
-                    templates.insert(*status_code, content);
-                }
-            }
-        }
+        let mut templates = load_templates(config.templates_dir.as_deref(), &config.custom_pages).await?;
 
         // Add default templates if not provided
         templates.entry(404).or_insert_with(|| DEFAULT_404_PAGE.to_string());
         templates.entry(500).or_insert_with(|| DEFAULT_500_PAGE.to_string());
         templates.entry(503).or_insert_with(|| DEFAULT_503_PAGE.to_string());
 
+        let mut host_templates = HashMap::new();
+        for (host, pages) in &config.host_pages {
+            host_templates.insert(host.clone(), load_templates(config.templates_dir.as_deref(), pages).await?);
+        }
+
+        if config.notify_errors.as_ref().map_or(false, |notify| notify.email.is_some()) {
+            warn!("Email error notifications are not supported, configure a webhook instead");
+        }
+        let tracking = config.error_tracking.as_ref();
+        if tracking.map_or(false, |tracking| tracking.datadog_api_key.is_some()) {
+            warn!("Datadog error tracking is not supported, use sentry_dsn or custom_endpoint");
+        }
+        let sentry = match tracking.and_then(|tracking| tracking.sentry_dsn.as_deref()) {
+            Some(dsn) => match SentryTarget::from_dsn(dsn) {
+                Some(target) => Some(target),
+                None => {
+                    warn!("Ignoring invalid Sentry DSN");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+
+        if config.mode == ErrorMode::Development {
+            CAPTURE_BACKTRACES.store(true, Ordering::Relaxed);
+        }
+
         Ok(Self {
             config,
             templates: Arc::new(templates),
+            host_templates: Arc::new(host_templates),
             error_counts: Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+            last_notified: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            sentry,
+            client,
         })
     }
 
-    pub async fn handle_error(&self, error: AppError, headers: &HeaderMap) -> Response {
+    // `host` picks that host's error pages over the server-wide ones
+    pub async fn handle_error(&self, error: AppError, headers: &HeaderMap, host: Option<&str>) -> Response {
         // Log the error
         if self.config.log_errors {
             match error.status.as_u16() {
@@ -228,14 +365,14 @@ impl ErrorHandler {
         if accept.contains("application/json") {
             self.json_error_response(error)
         } else {
-            self.html_error_response(error).await
+            self.html_error_response(error, host).await
         }
     }
 
     fn json_error_response(&self, error: AppError) -> Response {
         let debug_info = if self.config.mode == ErrorMode::Development && self.config.show_details {
             Some(DebugInfo {
-                stack_trace: None, // Could capture actual stack trace
+                stack_trace: error.backtrace.as_ref().map(backtrace_frames),
                 context: error.context.clone(),
                 source: error.source.as_ref().map(|e| e.to_string()),
             })
@@ -265,11 +402,15 @@ impl ErrorHandler {
         (error.status, Json(response)).into_response()
     }
 
-    async fn html_error_response(&self, error: AppError) -> Response {
+    async fn html_error_response(&self, error: AppError, host: Option<&str>) -> Response {
         let status_code = error.status.as_u16();
         
-        // Check for custom template
-        if let Some(template) = self.templates.get(&status_code) {
+        // Check for a custom template, the host's own first
+        let template = host
+            .and_then(|host| self.host_templates.get(host))
+            .and_then(|pages| pages.get(&status_code))
+            .or_else(|| self.templates.get(&status_code));
+        if let Some(template) = template {
             let html = self.render_template(template, &error);
             return (error.status, Html(html)).into_response();
         }
@@ -323,7 +464,7 @@ impl ErrorHandler {
             if self.config.mode == ErrorMode::Production {
                 self.get_user_friendly_message(error.status)
             } else {
-                &error.message
+                error.message.clone()
             },
             if self.config.mode == ErrorMode::Development && error.details.is_some() {
                 format!(r#"<div class="debug">Debug: {}</div>"#, error.details.as_ref().unwrap())
@@ -348,35 +489,154 @@ impl ErrorHandler {
     }
 
     async fn track_error(&self, error: &AppError) {
+        let key = error.status.as_u16().to_string();
         let mut counts = self.error_counts.write().await;
-        let count = counts.entry(error.status.as_u16().to_string()).or_insert(0);
+        let count = counts.entry(key.clone()).or_insert(0);
         *count += 1;
 
+        if !error.status.is_server_error() {
+            return;
+        }
+
         // Check if we should send notifications
         if let Some(notify) = &self.config.notify_errors {
-            if *count >= notify.threshold {
-                self.send_error_notification(error, *count).await;
+            if *count >= notify.threshold && self.notification_due(&key, notify.interval).await {
+                self.send_error_notification(error, *count);
                 *count = 0; // Reset counter after notification
             }
         }
+        drop(counts);
 
         // Send to error tracking service
         if let Some(tracking) = &self.config.error_tracking {
-            self.send_to_tracking_service(error, tracking).await;
+            self.send_to_tracking_service(error, tracking);
+        }
+    }
+
+    // True (and the clock restarted) when `key` hasn't notified within
+    // `interval` seconds
+    async fn notification_due(&self, key: &str, interval: u64) -> bool {
+        let mut last_notified = self.last_notified.lock().await;
+        let now = Instant::now();
+        match last_notified.get(key) {
+            Some(last) if now.duration_since(*last) < Duration::from_secs(interval) => false,
+            _ => {
+                last_notified.insert(key.to_string(), now);
+                true
+            }
         }
     }
 
-    async fn send_error_notification(&self, error: &AppError, count: u32) {
+    // Delivery happens in the background; failures are only logged
+    fn send_error_notification(&self, error: &AppError, count: u32) {
         debug!("Sending error notification for {} errors", count);
-        // Implementation would send email/webhook
+        let Some(webhook) = self.config.notify_errors.as_ref().and_then(|notify| notify.webhook.clone()) else {
+            return;
+        };
+
+        let mut payload = error_payload(error);
+        payload["count"] = serde_json::json!(count);
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = post_json(&client, &webhook, &payload, None).await {
+                warn!("Error notification webhook failed: {}", e);
+            }
+        });
     }
 
-    async fn send_to_tracking_service(&self, error: &AppError, config: &ErrorTrackingConfig) {
+    fn send_to_tracking_service(&self, error: &AppError, config: &ErrorTrackingConfig) {
         debug!("Sending error to tracking service: {}", error.id);
-        // Implementation would send to Sentry/Datadog/etc
+
+        if let Some(sentry) = self.sentry.clone() {
+            let event = sentry_event(error);
+            let client = self.client.clone();
+            tokio::spawn(async move {
+                let auth = ("X-Sentry-Auth", sentry.auth_header());
+                if let Err(e) = post_json(&client, &sentry.store_url, &event, Some(auth)).await {
+                    warn!("Sending error to Sentry failed: {}", e);
+                }
+            });
+        }
+
+        if let Some(endpoint) = config.custom_endpoint.clone() {
+            let payload = error_payload(error);
+            let client = self.client.clone();
+            tokio::spawn(async move {
+                if let Err(e) = post_json(&client, &endpoint, &payload, None).await {
+                    warn!("Sending error to {} failed: {}", endpoint, e);
+                }
+            });
+        }
     }
 }
 
+// One entry per frame: "N: symbol at file:line"
+fn backtrace_frames(backtrace: &Backtrace) -> Vec<String> {
+    let mut frames: Vec<String> = Vec::new();
+    for line in backtrace.to_string().lines().map(str::trim) {
+        match frames.last_mut() {
+            Some(frame) if line.starts_with("at ") => {
+                frame.push(' ');
+                frame.push_str(line);
+            }
+            _ if !line.is_empty() => frames.push(line.to_string()),
+            _ => {}
+        }
+    }
+    frames
+}
+
+fn error_payload(error: &AppError) -> serde_json::Value {
+    serde_json::json!({
+        "id": error.id,
+        "status": error.status.as_u16(),
+        "code": error.code,
+        "message": error.message,
+        "details": error.details,
+        "context": error.context,
+        "timestamp": error.timestamp.to_rfc3339(),
+    })
+}
+
+// Event for Sentry's store endpoint
+fn sentry_event(error: &AppError) -> serde_json::Value {
+    let mut extra: serde_json::Map<String, serde_json::Value> = error.context.iter()
+        .map(|(key, value)| (key.clone(), serde_json::json!(value)))
+        .collect();
+    if let Some(details) = &error.details {
+        extra.insert("details".to_string(), serde_json::json!(details));
+    }
+    let mut tags = serde_json::json!({ "status": error.status.as_u16().to_string() });
+    if let Some(code) = &error.code {
+        tags["code"] = serde_json::json!(code);
+    }
+
+    serde_json::json!({
+        "event_id": error.id.replace('-', ""),
+        "timestamp": error.timestamp.to_rfc3339(),
+        "level": "error",
+        "platform": "other",
+        "logger": "miwidothttp",
+        "message": error.message,
+        "tags": tags,
+        "extra": extra,
+    })
+}
+
+async fn post_json(
+    client: &reqwest::Client,
+    url: &str,
+    body: &serde_json::Value,
+    header: Option<(&str, String)>,
+) -> Result<()> {
+    let mut request = client.post(url).json(body);
+    if let Some((name, value)) = header {
+        request = request.header(name, value);
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
 // Default error pages
 const DEFAULT_404_PAGE: &str = r#"<!DOCTYPE html>
 <html>
@@ -419,7 +679,7 @@ const DEFAULT_500_PAGE: &str = r#"<!DOCTYPE html>
 </body>
 </html>"#;
 
-const DEFAULT_503_PAGE: &str = r#"<!DOCTYPE html>
+pub(crate) const DEFAULT_503_PAGE: &str = r#"<!DOCTYPE html>
 <html>
 <head>
     <title>503 - Maintenance</title>
@@ -442,6 +702,9 @@ const DEFAULT_503_PAGE: &str = r#"<!DOCTYPE html>
 use axum::middleware::Next;
 use axum::extract::Request;
 
+// Replaces bare error responses (no Content-Type, like the server's own
+// "404 Not Found" strings) with the configured error pages. Responses that
+// already have a typed body, such as backend pages or API JSON, pass through.
 pub async fn error_recovery_middleware(
     State(handler): State<Arc<ErrorHandler>>,
     request: Request<Body>,
@@ -450,19 +713,35 @@ pub async fn error_recovery_middleware(
     let headers = request.headers().clone();
     let uri = request.uri().clone();
     let method = request.method().clone();
+    let request_id = request.extensions().get::<RequestId>().cloned();
     
     let response = next.run(request).await;
     
     // Check if response is an error
-    if response.status().is_server_error() || response.status().is_client_error() {
-        let error = AppError::new(response.status(), "Request failed")
-            .with_context("uri", uri.to_string())
-            .with_context("method", method.to_string());
-        
-        return handler.handle_error(error, &headers).await;
+    let is_error = response.status().is_server_error() || response.status().is_client_error();
+    if !is_error || response.headers().contains_key(header::CONTENT_TYPE) {
+        return response;
     }
     
-    response
+    let mut error = AppError::new(response.status(), "Request failed")
+        .with_context("uri", uri.to_string())
+        .with_context("method", method.to_string());
+    if let Some(RequestId(id)) = request_id {
+        error = error.with_id(id);
+    }
+    let host = response.extensions().get::<VirtualHost>().map(|VirtualHost(host)| host.as_str());
+    let mut page = handler.handle_error(error, &headers, host).await;
+    
+    // Keep what the original response said besides its body, e.g.
+    // WWW-Authenticate or Retry-After, and what later layers look for
+    let (parts, _) = response.into_parts();
+    for (name, value) in &parts.headers {
+        if name != header::CONTENT_LENGTH && !page.headers().contains_key(name) {
+            page.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    page.extensions_mut().extend(parts.extensions);
+    page
 }
 
 // Panic handler
@@ -525,4 +804,253 @@ impl From<anyhow::Error> for AppError {
         AppError::internal_server_error()
             .with_details(err.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::{get, post};
+    use axum::Router;
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    async fn app(config: ErrorConfig) -> Router {
+        let handler = Arc::new(ErrorHandler::new(config).await.unwrap());
+        Router::new()
+            .route("/api", get(|| async { (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "no such thing" }))) }))
+            .route("/hosted", get(|| async {
+                let mut response = StatusCode::NOT_FOUND.into_response();
+                response.extensions_mut().insert(VirtualHost("shop.example.com".to_string()));
+                response
+            }))
+            .fallback(|| async { (StatusCode::NOT_FOUND, Body::from("404 Not Found")).into_response() })
+            .layer(axum::middleware::from_fn_with_state(handler, error_recovery_middleware))
+    }
+
+    async fn get_page(app: &Router, uri: &str) -> (StatusCode, String) {
+        let request = axum::http::Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_error_page_uses_request_id() {
+        let app = app(ErrorConfig::default()).await;
+        let mut request = axum::http::Request::builder()
+            .uri("/missing")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        request.extensions_mut().insert(RequestId("req-42".to_string()));
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["request_id"], "req-42");
+    }
+
+    #[tokio::test]
+    async fn test_default_404_page() {
+        let app = app(ErrorConfig::default()).await;
+
+        let (status, body) = get_page(&app, "/missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, DEFAULT_404_PAGE);
+
+        // Typed bodies are left alone
+        let (status, body) = get_page(&app, "/api").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("no such thing"));
+    }
+
+    #[tokio::test]
+    async fn test_custom_404_templates() {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-errors-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("404.html"), "<h1>{{status}} {{status_text}}</h1>").unwrap();
+        std::fs::write(dir.join("shop-404.html"), "<h1>Not in the shop</h1>").unwrap();
+
+        let app = app(ErrorConfig {
+            templates_dir: Some(dir.clone()),
+            custom_pages: HashMap::from([(404, "404.html".to_string())]),
+            host_pages: HashMap::from([(
+                "shop.example.com".to_string(),
+                HashMap::from([(404, "shop-404.html".to_string())]),
+            )]),
+            ..Default::default()
+        }).await;
+
+        let (status, body) = get_page(&app, "/missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "<h1>404 Not Found</h1>");
+
+        let (status, body) = get_page(&app, "/hosted").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "<h1>Not in the shop</h1>");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Records every JSON body POSTed to `path`, with its X-Sentry-Auth header
+    async fn mock_receiver(path: &str) -> (String, mpsc::UnboundedReceiver<(Option<String>, serde_json::Value)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(path, post(move |headers: HeaderMap, Json(body): Json<serde_json::Value>| {
+            let tx = tx.clone();
+            async move {
+                let auth = headers.get("x-sentry-auth").and_then(|v| v.to_str().ok()).map(str::to_string);
+                let _ = tx.send((auth, body));
+                StatusCode::OK
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("127.0.0.1:{}", addr.port()), rx)
+    }
+
+    async fn next_post(rx: &mut mpsc::UnboundedReceiver<(Option<String>, serde_json::Value)>) -> Option<(Option<String>, serde_json::Value)> {
+        tokio::time::timeout(Duration::from_millis(500), rx.recv()).await.ok().flatten()
+    }
+
+    fn server_error() -> AppError {
+        AppError::internal_server_error().with_context("uri", "/boom")
+    }
+
+    #[tokio::test]
+    async fn test_webhook_fires_at_threshold() {
+        let (addr, mut posts) = mock_receiver("/hook").await;
+        let handler = ErrorHandler::new(ErrorConfig {
+            notify_errors: Some(NotificationConfig {
+                email: None,
+                webhook: Some(format!("http://{}/hook", addr)),
+                threshold: 3,
+                interval: 0,
+            }),
+            ..Default::default()
+        }).await.unwrap();
+
+        for _ in 0..2 {
+            handler.handle_error(server_error(), &HeaderMap::new(), None).await;
+        }
+        // Client errors never notify
+        for _ in 0..5 {
+            handler.handle_error(AppError::not_found("/x"), &HeaderMap::new(), None).await;
+        }
+        assert!(next_post(&mut posts).await.is_none());
+
+        handler.handle_error(server_error(), &HeaderMap::new(), None).await;
+        let (_, body) = next_post(&mut posts).await.expect("webhook after threshold");
+        assert_eq!(body["status"], 500);
+        assert_eq!(body["count"], 3);
+        assert_eq!(body["context"]["uri"], "/boom");
+    }
+
+    #[tokio::test]
+    async fn test_webhook_respects_interval() {
+        let (addr, mut posts) = mock_receiver("/hook").await;
+        let handler = ErrorHandler::new(ErrorConfig {
+            notify_errors: Some(NotificationConfig {
+                email: None,
+                webhook: Some(format!("http://{}/hook", addr)),
+                threshold: 1,
+                interval: 3600,
+            }),
+            ..Default::default()
+        }).await.unwrap();
+
+        handler.handle_error(server_error(), &HeaderMap::new(), None).await;
+        assert!(next_post(&mut posts).await.is_some());
+
+        for _ in 0..5 {
+            handler.handle_error(server_error(), &HeaderMap::new(), None).await;
+        }
+        assert!(next_post(&mut posts).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_webhook_is_harmless() {
+        let handler = ErrorHandler::new(ErrorConfig {
+            notify_errors: Some(NotificationConfig {
+                email: None,
+                webhook: Some("http://127.0.0.1:1/hook".to_string()),
+                threshold: 1,
+                interval: 0,
+            }),
+            ..Default::default()
+        }).await.unwrap();
+
+        let response = handler.handle_error(server_error(), &HeaderMap::new(), None).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_sentry_event() {
+        let (addr, mut posts) = mock_receiver("/api/42/store/").await;
+        let handler = ErrorHandler::new(ErrorConfig {
+            error_tracking: Some(ErrorTrackingConfig {
+                sentry_dsn: Some(format!("http://publickey@{}/42", addr)),
+                datadog_api_key: None,
+                custom_endpoint: None,
+            }),
+            ..Default::default()
+        }).await.unwrap();
+
+        let error = server_error();
+        let event_id = error.id.replace('-', "");
+        handler.handle_error(error, &HeaderMap::new(), None).await;
+
+        let (auth, event) = next_post(&mut posts).await.expect("sentry event");
+        assert!(auth.unwrap().contains("sentry_key=publickey"));
+        assert_eq!(event["event_id"], event_id);
+        assert_eq!(event["level"], "error");
+        assert_eq!(event["tags"]["status"], "500");
+        assert_eq!(event["extra"]["uri"], "/boom");
+    }
+
+    #[test]
+    fn test_sentry_dsn() {
+        let target = SentryTarget::from_dsn("https://abc@o1.ingest.sentry.io/123").unwrap();
+        assert_eq!(target.store_url, "https://o1.ingest.sentry.io/api/123/store/");
+        assert_eq!(target.public_key, "abc");
+
+        let target = SentryTarget::from_dsn("http://abc@localhost:9000/sentry/7").unwrap();
+        assert_eq!(target.store_url, "http://localhost:9000/sentry/api/7/store/");
+
+        assert!(SentryTarget::from_dsn("https://o1.ingest.sentry.io/123").is_none());
+        assert!(SentryTarget::from_dsn("not a dsn").is_none());
+    }
+
+    async fn json_body(handler: &ErrorHandler, error: AppError) -> serde_json::Value {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "application/json".parse().unwrap());
+        let response = handler.handle_error(error, &headers, None).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stack_trace_only_in_development() {
+        let development = ErrorHandler::new(ErrorConfig {
+            mode: ErrorMode::Development,
+            show_details: true,
+            ..Default::default()
+        }).await.unwrap();
+        let body = json_body(&development, server_error()).await;
+        let frames = body["debug"]["stack_trace"].as_array().expect("stack trace in development");
+        assert!(!frames.is_empty());
+        assert_eq!(body["debug"]["context"]["uri"], "/boom");
+
+        // Same error, production handler: no debug section at all
+        let production = ErrorHandler::new(ErrorConfig {
+            mode: ErrorMode::Production,
+            show_details: true,
+            ..Default::default()
+        }).await.unwrap();
+        let error = server_error();
+        assert!(error.backtrace.is_some());
+        let body = json_body(&production, error).await;
+        assert!(body.get("debug").is_none());
+        assert!(!body.to_string().contains("stack_trace"));
+    }
+}
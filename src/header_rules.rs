@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Result};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::warn;
+
+// Headers to strip and to set on a request or response, e.g.
+//
+//   [backends."example.com".response_headers]
+//   remove = ["Server", "X-Powered-By"]
+//   set = { "Strict-Transport-Security" = "max-age=31536000", "X-Frame-Options" = "DENY" }
+//
+// Removals run first, so a header can be replaced by listing it in both.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HeaderRules {
+    pub set: HashMap<String, String>,
+    pub remove: Vec<String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.remove.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for name in self.remove.iter().chain(self.set.keys()) {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| anyhow!("invalid header name {:?}", name))?;
+        }
+        for (name, value) in &self.set {
+            HeaderValue::from_str(value)
+                .map_err(|_| anyhow!("invalid value for header {}", name))?;
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name.as_str());
+        }
+        for (name, value) in &self.set {
+            match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => warn!("Skipping invalid header rule {}: {}", name, value),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_sets_and_removes() {
+        let rules: HeaderRules = toml::from_str(r#"
+            remove = ["server", "X-Powered-By"]
+            set = { "X-Frame-Options" = "DENY", "Cache-Control" = "no-store" }
+        "#).unwrap();
+        assert!(rules.validate().is_ok());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("nginx"));
+        headers.insert("x-powered-by", HeaderValue::from_static("PHP"));
+        headers.insert("cache-control", HeaderValue::from_static("public"));
+        headers.insert("content-type", HeaderValue::from_static("text/html"));
+
+        rules.apply(&mut headers);
+
+        assert!(headers.get("server").is_none());
+        assert!(headers.get("x-powered-by").is_none());
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(headers["cache-control"], "no-store");
+        assert_eq!(headers["content-type"], "text/html");
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_names() {
+        let rules = HeaderRules {
+            set: HashMap::from([("Bad Header".to_string(), "x".to_string())]),
+            remove: vec![],
+        };
+        assert!(rules.validate().is_err());
+        assert!(HeaderRules::default().is_empty());
+    }
+}
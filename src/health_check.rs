@@ -0,0 +1,467 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use crate::cluster::ClusterManager;
+use crate::process_manager::ProcessManager;
+use crate::session::SessionManager;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    pub interval_seconds: u64,
+    pub timeout_seconds: u64,
+    // Consecutive failed probes before a backend is taken out of rotation
+    pub unhealthy_threshold: u32,
+    // How long an unhealthy backend rests before a half-open probe
+    pub cooldown_seconds: u64,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval_seconds: 10,
+            timeout_seconds: 5,
+            unhealthy_threshold: 3,
+            cooldown_seconds: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BackendHealth {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub last_check: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    pub unhealthy_since: Option<Instant>,
+}
+
+impl Default for BackendHealth {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            consecutive_failures: 0,
+            last_check: None,
+            last_success: None,
+            last_error: None,
+            unhealthy_since: None,
+        }
+    }
+}
+
+// A backend to probe: its name, base URL and health check path
+#[derive(Clone, Debug)]
+pub struct HealthTarget {
+    pub name: String,
+    pub base_url: String,
+    pub path: String,
+    // Managed process behind the backend. Probes go to the port it is
+    // currently running on, which moves with each graceful restart.
+    pub process: Option<String>,
+}
+
+pub struct HealthChecker {
+    config: HealthCheckConfig,
+    states: Arc<RwLock<HashMap<String, BackendHealth>>>,
+    // Backends being probed, which readiness waits on; replaced on reload
+    targets: std::sync::RwLock<Vec<HealthTarget>>,
+    client: reqwest::Client,
+    process_manager: Option<Arc<ProcessManager>>,
+}
+
+impl HealthChecker {
+    pub fn new(config: HealthCheckConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .build()
+            .expect("Failed to create health check client");
+
+        Self {
+            config,
+            states: Arc::new(RwLock::new(HashMap::new())),
+            targets: std::sync::RwLock::new(Vec::new()),
+            client,
+            process_manager: None,
+        }
+    }
+
+    // Look up the ports of targets' managed processes here
+    pub fn with_process_manager(mut self, process_manager: Arc<ProcessManager>) -> Self {
+        self.process_manager = Some(process_manager);
+        self
+    }
+
+    // Backends that were never probed are considered healthy
+    pub async fn is_healthy(&self, name: &str) -> bool {
+        self.states.read().await
+            .get(name)
+            .map_or(true, |state| state.healthy)
+    }
+
+    // Watched backends that aren't ready: not yet passed a probe, or
+    // taken out of rotation since
+    pub async fn not_ready(&self) -> Vec<String> {
+        let watched: Vec<String> = self.targets.read().unwrap().iter().map(|target| target.name.clone()).collect();
+        let states = self.states.read().await;
+        watched.into_iter()
+            .filter(|name| !states.get(name).map_or(false, |state| state.healthy && state.last_success.is_some()))
+            .collect()
+    }
+
+    pub async fn snapshot(&self) -> HashMap<String, BackendHealth> {
+        self.states.read().await.clone()
+    }
+
+    pub async fn get(&self, name: &str) -> Option<BackendHealth> {
+        self.states.read().await.get(name).cloned()
+    }
+
+    // An unhealthy backend is only probed again once its cooldown has passed;
+    // that probe is the half-open attempt which can restore it
+    async fn probe_due(&self, name: &str) -> bool {
+        let states = self.states.read().await;
+        match states.get(name).and_then(|state| state.unhealthy_since) {
+            Some(since) => since.elapsed() >= Duration::from_secs(self.config.cooldown_seconds),
+            None => true,
+        }
+    }
+
+    pub async fn record_result(&self, name: &str, result: Result<(), String>) {
+        let mut states = self.states.write().await;
+        let state = states.entry(name.to_string()).or_default();
+        state.last_check = Some(Utc::now());
+
+        match result {
+            Ok(()) => {
+                if !state.healthy {
+                    info!("Backend {} recovered", name);
+                }
+                state.healthy = true;
+                state.consecutive_failures = 0;
+                state.last_success = state.last_check;
+                state.last_error = None;
+                state.unhealthy_since = None;
+            }
+            Err(e) => {
+                state.consecutive_failures += 1;
+                state.last_error = Some(e.clone());
+
+                if !state.healthy {
+                    // Failed half-open probe, start a new cooldown
+                    state.unhealthy_since = Some(Instant::now());
+                    debug!("Backend {} still unhealthy: {}", name, e);
+                } else if state.consecutive_failures >= self.config.unhealthy_threshold {
+                    warn!("Backend {} marked unhealthy after {} failures: {}", name, state.consecutive_failures, e);
+                    state.healthy = false;
+                    state.unhealthy_since = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    async fn probe(&self, target: &HealthTarget) -> Result<(), String> {
+        let active_port = match (&target.process, &self.process_manager) {
+            (Some(process), Some(process_manager)) => process_manager.active_port(process).await,
+            _ => None,
+        };
+        let base_url = match active_port {
+            Some(port) => format!("http://localhost:{}", port),
+            None => target.base_url.clone(),
+        };
+        let url = format!("{}{}", base_url.trim_end_matches('/'), target.path);
+        match self.client.get(&url).send().await {
+            Ok(resp) if resp.status().is_success() => Ok(()),
+            Ok(resp) => Err(format!("{} returned {}", url, resp.status())),
+            Err(e) => Err(format!("{} failed: {}", url, e)),
+        }
+    }
+
+    pub async fn check_all(&self, targets: &[HealthTarget]) {
+        for target in targets {
+            if !self.probe_due(&target.name).await {
+                continue;
+            }
+            let result = self.probe(target).await;
+            self.record_result(&target.name, result).await;
+        }
+    }
+
+    pub fn start(self: Arc<Self>, targets: Vec<HealthTarget>) {
+        if !targets.is_empty() {
+            info!("Health checking {} backend(s) every {}s", targets.len(), self.config.interval_seconds);
+        }
+        *self.targets.write().unwrap() = targets;
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_seconds));
+            loop {
+                interval.tick().await;
+                let targets = self.targets.read().unwrap().clone();
+                self.check_all(&targets).await;
+            }
+        });
+    }
+
+    // Probe `targets` from the next round on. Backends that are no longer
+    // checked lose their state, so they don't stay out of rotation.
+    pub async fn set_targets(&self, targets: Vec<HealthTarget>) {
+        let mut states = self.states.write().await;
+        states.retain(|name, _| targets.iter().any(|target| &target.name == name));
+        *self.targets.write().unwrap() = targets;
+    }
+}
+
+// What /readyz waits on. /livez only needs the process to answer.
+pub struct Readiness {
+    pub health_checker: Arc<HealthChecker>,
+    pub session_manager: Option<Arc<SessionManager>>,
+    // Required when [cluster] is enabled; None there means it failed to start
+    pub cluster_enabled: bool,
+    pub cluster: Option<Arc<ClusterManager>>,
+    // Set when shutdown starts, so load balancers stop sending new traffic
+    // while in-flight requests finish
+    pub draining: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    // Reasons the node isn't ready; empty once it is
+    pub async fn check(&self) -> Vec<String> {
+        if self.draining.load(Ordering::SeqCst) {
+            return vec!["draining for shutdown".to_string()];
+        }
+        let mut failing: Vec<String> = self.health_checker.not_ready().await
+            .into_iter()
+            .map(|name| format!("backend {} is not healthy", name))
+            .collect();
+        if let Some(sessions) = &self.session_manager {
+            if let Err(e) = sessions.ping().await {
+                failing.push(format!("session store unreachable: {}", e));
+            }
+        }
+        if self.cluster_enabled {
+            let joined = match &self.cluster {
+                Some(cluster) => cluster.is_joined().await,
+                None => false,
+            };
+            if !joined {
+                failing.push("node has not joined the cluster".to_string());
+            }
+        }
+        failing
+    }
+}
+
+pub async fn readyz(State(readiness): State<Arc<Readiness>>) -> Response {
+    let failing = readiness.check().await;
+    if failing.is_empty() {
+        return (StatusCode::OK, Json(serde_json::json!({ "status": "ready" }))).into_response();
+    }
+    let body = Json(serde_json::json!({ "status": "not ready", "failing": failing }));
+    (StatusCode::SERVICE_UNAVAILABLE, body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::Request, routing::get, Router};
+
+    fn get_request(uri: &str) -> Request {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_after_threshold_and_recovers() {
+        let checker = HealthChecker::new(HealthCheckConfig {
+            unhealthy_threshold: 2,
+            cooldown_seconds: 0,
+            ..HealthCheckConfig::default()
+        });
+
+        checker.record_result("api", Err("down".to_string())).await;
+        assert!(checker.is_healthy("api").await);
+
+        checker.record_result("api", Err("down".to_string())).await;
+        assert!(!checker.is_healthy("api").await);
+
+        // Cooldown of zero means the half-open probe is due straight away
+        assert!(checker.probe_due("api").await);
+        checker.record_result("api", Ok(())).await;
+        assert!(checker.is_healthy("api").await);
+        assert_eq!(checker.get("api").await.unwrap().consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cooldown_delays_probe() {
+        let checker = HealthChecker::new(HealthCheckConfig {
+            unhealthy_threshold: 1,
+            cooldown_seconds: 60,
+            ..HealthCheckConfig::default()
+        });
+
+        assert!(checker.probe_due("api").await);
+        checker.record_result("api", Err("down".to_string())).await;
+        assert!(!checker.probe_due("api").await);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_waits_for_backend_health() {
+        use tower::ServiceExt;
+
+        // A backend that fails its health check until switched on
+        let up = Arc::new(AtomicBool::new(false));
+        let backend = Router::new().route("/health", get({
+            let up = up.clone();
+            move || async move {
+                if up.load(Ordering::SeqCst) { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE }
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, backend).await.unwrap() });
+
+        let checker = Arc::new(HealthChecker::new(HealthCheckConfig {
+            interval_seconds: 1,
+            cooldown_seconds: 0,
+            ..HealthCheckConfig::default()
+        }));
+        checker.clone().start(vec![HealthTarget {
+            name: "api".to_string(),
+            base_url: format!("http://{}", addr),
+            path: "/health".to_string(),
+            process: None,
+        }]);
+        let app = Router::new().route("/readyz", get(readyz)).with_state(Arc::new(Readiness {
+            health_checker: checker,
+            session_manager: None,
+            cluster_enabled: false,
+            cluster: None,
+            draining: Arc::new(AtomicBool::new(false)),
+        }));
+        let status = |app: Router| async move {
+            app.oneshot(get_request("/readyz")).await.unwrap().status()
+        };
+
+        // Not ready before the first probe, nor while the backend fails it
+        assert_eq!(status(app.clone()).await, StatusCode::SERVICE_UNAVAILABLE);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(status(app.clone()).await, StatusCode::SERVICE_UNAVAILABLE);
+
+        up.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while status(app.clone()).await != StatusCode::OK {
+            assert!(Instant::now() < deadline, "readyz never became ready");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_targets_replaces_watched_backends() {
+        let checker = HealthChecker::new(HealthCheckConfig {
+            unhealthy_threshold: 1,
+            ..HealthCheckConfig::default()
+        });
+        let target = |name: &str| HealthTarget {
+            name: name.to_string(),
+            base_url: "http://127.0.0.1:1".to_string(),
+            path: "/health".to_string(),
+            process: None,
+        };
+        checker.set_targets(vec![target("old")]).await;
+        checker.record_result("old", Err("down".to_string())).await;
+        assert_eq!(checker.not_ready().await, vec!["old".to_string()]);
+
+        checker.set_targets(vec![target("new")]).await;
+        assert_eq!(checker.not_ready().await, vec!["new".to_string()]);
+        assert!(checker.is_healthy("old").await);
+    }
+
+    #[tokio::test]
+    async fn test_probe_follows_managed_process_port() {
+        use crate::process_manager::{AppType, ProcessConfig};
+
+        let backend = Router::new().route("/health", get(|| async { "OK" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, backend).await.unwrap() });
+
+        // Registered as running on the live port, as after a graceful restart
+        let process_manager = Arc::new(ProcessManager::new());
+        process_manager.start_process("app".to_string(), ProcessConfig {
+            app_type: AppType::Static,
+            command: String::new(),
+            args: vec![],
+            working_dir: None,
+            env: HashMap::new(),
+            clear_env: false,
+            port,
+            health_check: Some("/health".to_string()),
+            auto_restart: false,
+        }).await.unwrap();
+
+        let checker = HealthChecker::new(HealthCheckConfig {
+            unhealthy_threshold: 1,
+            ..HealthCheckConfig::default()
+        }).with_process_manager(process_manager);
+        checker.check_all(&[HealthTarget {
+            name: "app".to_string(),
+            base_url: "http://127.0.0.1:1".to_string(),
+            path: "/health".to_string(),
+            process: Some("app".to_string()),
+        }]).await;
+
+        let health = checker.get("app").await.unwrap();
+        assert!(health.healthy && health.last_success.is_some(), "{:?}", health);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_requires_cluster_when_enabled() {
+        let readiness = Readiness {
+            health_checker: Arc::new(HealthChecker::new(HealthCheckConfig::default())),
+            session_manager: None,
+            cluster_enabled: true,
+            cluster: None,
+            draining: Arc::new(AtomicBool::new(false)),
+        };
+        assert_eq!(readiness.check().await, vec!["node has not joined the cluster".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_draining_fails_readyz_but_not_livez() {
+        use tower::ServiceExt;
+
+        let draining = Arc::new(AtomicBool::new(false));
+        let app = Router::new()
+            .route("/livez", get(|| async { "OK" }))
+            .route("/readyz", get(readyz))
+            .with_state(Arc::new(Readiness {
+                health_checker: Arc::new(HealthChecker::new(HealthCheckConfig::default())),
+                session_manager: None,
+                cluster_enabled: false,
+                cluster: None,
+                draining: draining.clone(),
+            }));
+        let status = |uri: &'static str| {
+            let app = app.clone();
+            async move { app.oneshot(get_request(uri)).await.unwrap().status() }
+        };
+
+        assert_eq!(status("/readyz").await, StatusCode::OK);
+        draining.store(true, Ordering::SeqCst);
+        assert_eq!(status("/readyz").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("/livez").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,492 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+// Per-backend request authentication, e.g.
+//
+//   [backends."app.example.com".auth]
+//   auth_type = "basic"
+//   realm = "Staff only"
+//   users = { alice = "$2b$12$..." }   # bcrypt hashes (htpasswd -B)
+//
+// or auth_type = "bearer" with `tokens = ["..."]`, or auth_type = "jwt"
+// with a [backends."app.example.com".auth.jwt] table (see JwtConfig).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthConfig {
+    pub auth_type: AuthType,
+    pub realm: String,
+    // Username to bcrypt hash
+    pub users: Option<HashMap<String, String>>,
+    // Accepted bearer tokens
+    #[serde(default)]
+    pub tokens: Option<Vec<String>>,
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+    // Shared by every clone of this config
+    #[serde(skip)]
+    jwks: Arc<JwksCache>,
+}
+
+// Bearer JWTs checked against the issuer's published keys, e.g.
+//
+//   [backends."app.example.com".auth.jwt]
+//   jwks_url = "https://auth.example.com/.well-known/jwks.json"
+//   issuer = "https://auth.example.com/"
+//   audience = ["api"]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JwtConfig {
+    pub jwks_url: String,
+    pub issuer: Option<String>,
+    pub audience: Option<Vec<String>>,
+    #[serde(default = "default_jwt_algorithms")]
+    pub algorithms: Vec<Algorithm>,
+    #[serde(default = "default_jwks_cache_seconds")]
+    pub jwks_cache_seconds: u64,
+    // Clock skew allowed on exp/nbf
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway_seconds: u64,
+}
+
+fn default_jwt_algorithms() -> Vec<Algorithm> {
+    vec![Algorithm::RS256]
+}
+
+fn default_jwks_cache_seconds() -> u64 {
+    300
+}
+
+fn default_jwt_leeway() -> u64 {
+    60
+}
+
+// Claims of a validated JWT, added to the request extensions
+#[derive(Debug, Clone)]
+pub struct JwtClaims(pub serde_json::Value);
+
+impl JwtClaims {
+    pub fn get(&self, name: &str) -> Option<&serde_json::Value> {
+        self.0.get(name)
+    }
+
+    // Top-level string, number and bool claims as rewrite variables, so a
+    // rule can use `${jwt_sub}` or `${jwt_tenant}`
+    pub fn variables(&self) -> HashMap<String, String> {
+        self.0.as_object().into_iter().flatten()
+            .filter_map(|(name, value)| {
+                let value = match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    serde_json::Value::Number(n) => n.to_string(),
+                    serde_json::Value::Bool(b) => b.to_string(),
+                    _ => return None,
+                };
+                Some((format!("jwt_{}", name), value))
+            })
+            .collect()
+    }
+}
+
+// Don't refetch the JWKS for unknown key ids more often than this, so
+// tokens with made-up `kid`s can't hammer the issuer
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(10);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct JwksCache {
+    keys: tokio::sync::RwLock<Option<CachedJwks>>,
+    client: reqwest::Client,
+}
+
+#[derive(Debug)]
+struct CachedJwks {
+    fetched: Instant,
+    set: JwkSet,
+}
+
+impl CachedJwks {
+    fn find(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        let jwk = match kid {
+            Some(kid) => self.set.find(kid),
+            // Without a kid only an unambiguous single key will do
+            None if self.set.keys.len() == 1 => self.set.keys.first(),
+            None => None,
+        };
+        jwk.and_then(|jwk| DecodingKey::from_jwk(jwk).ok())
+    }
+}
+
+impl JwksCache {
+    async fn key(&self, config: &JwtConfig, kid: Option<&str>) -> Result<DecodingKey> {
+        let ttl = Duration::from_secs(config.jwks_cache_seconds);
+        {
+            let cached = self.keys.read().await;
+            if let Some(cached) = cached.as_ref().filter(|c| c.fetched.elapsed() < ttl) {
+                if let Some(key) = cached.find(kid) {
+                    return Ok(key);
+                }
+            }
+        }
+
+        let mut cached = self.keys.write().await;
+        // Another request may have refreshed while we waited for the lock
+        if let Some(key) = cached.as_ref().filter(|c| c.fetched.elapsed() < ttl).and_then(|c| c.find(kid)) {
+            return Ok(key);
+        }
+        let stale = cached.as_ref().map_or(true, |c| c.fetched.elapsed() >= ttl);
+        let may_refresh = cached.as_ref().map_or(true, |c| c.fetched.elapsed() >= JWKS_MIN_REFRESH);
+        if stale || may_refresh {
+            match self.fetch(&config.jwks_url).await {
+                Ok(set) => *cached = Some(CachedJwks { fetched: Instant::now(), set }),
+                // Keep using the old keys rather than locking everyone out
+                Err(e) if cached.is_some() => warn!("Failed to refresh JWKS from {}: {}", config.jwks_url, e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        cached.as_ref()
+            .and_then(|c| c.find(kid))
+            .ok_or_else(|| anyhow!("no JWKS key for kid {:?}", kid))
+    }
+
+    async fn fetch(&self, url: &str) -> Result<JwkSet> {
+        debug!("Fetching JWKS from {}", url);
+        let set = self.client.get(url)
+            .timeout(JWKS_TIMEOUT)
+            .send().await?
+            .error_for_status()?
+            .json::<JwkSet>().await?;
+        Ok(set)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthType {
+    Basic,
+    Bearer,
+    Jwt,
+    // Not supported for backends yet; rejected by validate()
+    Digest,
+}
+
+impl AuthConfig {
+    pub fn validate(&self) -> Result<()> {
+        match self.auth_type {
+            AuthType::Basic => {
+                for (user, hash) in self.users.iter().flatten() {
+                    // Catches plaintext passwords left in the config
+                    if !hash.starts_with("$2") {
+                        return Err(anyhow!("password for user {:?} is not a bcrypt hash", user));
+                    }
+                }
+                Ok(())
+            }
+            AuthType::Bearer => Ok(()),
+            AuthType::Jwt => match &self.jwt {
+                Some(jwt) if jwt.algorithms.is_empty() => Err(anyhow!("jwt.algorithms must not be empty")),
+                Some(_) => Ok(()),
+                None => Err(anyhow!("auth_type \"jwt\" requires a jwt section")),
+            },
+            AuthType::Digest => Err(anyhow!("digest authentication is not supported for backends")),
+        }
+    }
+
+    // Ok when the request carries valid credentials (with the token's claims
+    // for JWT), otherwise the 401 to send back
+    pub async fn authorize(&self, headers: &HeaderMap) -> Result<Option<JwtClaims>, Response> {
+        let authorization = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+
+        match self.auth_type {
+            AuthType::Basic => {
+                let credentials = authorization
+                    .and_then(|v| v.strip_prefix("Basic "))
+                    .and_then(|encoded| general_purpose::STANDARD.decode(encoded.trim()).ok())
+                    .and_then(|decoded| String::from_utf8(decoded).ok());
+                let (user, password) = match credentials.as_deref().and_then(|c| c.split_once(':')) {
+                    Some((user, password)) => (user.to_string(), password.to_string()),
+                    None => return Err(self.challenge(None)),
+                };
+
+                let hash = self.users.as_ref().and_then(|users| users.get(&user)).cloned();
+                // bcrypt is deliberately slow, keep it off the async workers
+                let valid = match hash {
+                    Some(hash) => tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+                        .await
+                        .unwrap_or(false),
+                    None => false,
+                };
+                if valid {
+                    Ok(None)
+                } else {
+                    debug!("Basic authentication failed for user {:?}", user);
+                    Err(self.challenge(None))
+                }
+            }
+            AuthType::Bearer => {
+                let token = match authorization.and_then(|v| v.strip_prefix("Bearer ")) {
+                    Some(token) => token.trim(),
+                    None => return Err(self.challenge(None)),
+                };
+                let valid = self.tokens.iter().flatten()
+                    .any(|expected| constant_time_eq(expected.as_bytes(), token.as_bytes()));
+                if valid {
+                    Ok(None)
+                } else {
+                    Err(self.challenge(Some("invalid_token")))
+                }
+            }
+            AuthType::Jwt => {
+                let token = match authorization.and_then(|v| v.strip_prefix("Bearer ")) {
+                    Some(token) => token.trim(),
+                    None => return Err(self.challenge(None)),
+                };
+                match self.validate_jwt(token).await {
+                    Ok(claims) => Ok(Some(claims)),
+                    Err(e) => {
+                        debug!("Rejected JWT: {}", e);
+                        Err(self.challenge(Some("invalid_token")))
+                    }
+                }
+            }
+            AuthType::Digest => {
+                warn!("Digest authentication is not supported for backends");
+                Err(self.challenge(None))
+            }
+        }
+    }
+
+    async fn validate_jwt(&self, token: &str) -> Result<JwtClaims> {
+        let config = self.jwt.as_ref().ok_or_else(|| anyhow!("jwt auth without a jwt section"))?;
+        let header = jsonwebtoken::decode_header(token)?;
+        // Only the configured algorithms, never whatever the token asks for
+        if !config.algorithms.contains(&header.alg) {
+            return Err(anyhow!("algorithm {:?} not allowed", header.alg));
+        }
+
+        let key = self.jwks.key(config, header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = config.leeway_seconds;
+        match &config.audience {
+            Some(audience) => validation.set_audience(audience.as_slice()),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let data = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)?;
+        Ok(JwtClaims(data.claims))
+    }
+
+    fn challenge(&self, error: Option<&str>) -> Response {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let challenge = match (&self.auth_type, error) {
+            (AuthType::Bearer | AuthType::Jwt, Some(error)) => format!("Bearer realm=\"{}\", error=\"{}\"", realm, error),
+            (AuthType::Bearer | AuthType::Jwt, None) => format!("Bearer realm=\"{}\"", realm),
+            _ => format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+        };
+
+        let mut response = Response::new(Body::from("Unauthorized"));
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        if let Ok(value) = HeaderValue::from_str(&challenge) {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+        }
+        response
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn basic() -> AuthConfig {
+        AuthConfig {
+            auth_type: AuthType::Basic,
+            realm: "Staff".to_string(),
+            // Lowest cost keeps the test fast
+            users: Some(HashMap::from([("alice".to_string(), bcrypt::hash("s3cret", 4).unwrap())])),
+            tokens: None,
+            jwt: None,
+            jwks: Arc::default(),
+        }
+    }
+
+    fn with_authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn basic_header(credentials: &str) -> HeaderMap {
+        with_authorization(&format!("Basic {}", general_purpose::STANDARD.encode(credentials)))
+    }
+
+    #[tokio::test]
+    async fn test_basic_success_and_failure() {
+        let auth = basic();
+        assert!(auth.validate().is_ok());
+        assert!(auth.authorize(&basic_header("alice:s3cret")).await.unwrap().is_none());
+
+        for credentials in ["alice:wrong", "bob:s3cret", "alice"] {
+            let response = auth.authorize(&basic_header(credentials)).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_credentials_challenge() {
+        let response = basic().authorize(&HeaderMap::new()).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Basic realm=\"Staff\", charset=\"UTF-8\"");
+    }
+
+    #[tokio::test]
+    async fn test_bearer_tokens() {
+        let auth = AuthConfig {
+            auth_type: AuthType::Bearer,
+            realm: "api".to_string(),
+            users: None,
+            tokens: Some(vec!["token-1".to_string()]),
+            jwt: None,
+            jwks: Arc::default(),
+        };
+        assert!(auth.authorize(&with_authorization("Bearer token-1")).await.is_ok());
+
+        let response = auth.authorize(&with_authorization("Bearer nope")).await.unwrap_err();
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"api\", error=\"invalid_token\"");
+    }
+
+    #[test]
+    fn test_validate_rejects_plaintext_passwords() {
+        let mut auth = basic();
+        auth.users = Some(HashMap::from([("alice".to_string(), "s3cret".to_string())]));
+        assert!(auth.validate().is_err());
+    }
+
+    // Public half of src/testdata/jwt_rs256.pem
+    const TEST_JWK_N: &str = "0lL4ulS0UzUigtXvu1EfTb0ABRG5RRyzPoSDkOzn8bzAhhaBclkEPRwEJzI7IAoA9SZHFUrQkL69H0YpPIw8TqTiNAKOO9ks2MEh2cq3gcUwWmTQocX_ZTOMw8BOSsStGhhrAAbKShz9Th3Fty7Jc3nZEFN6MZvWdcrw-Xjv-Yy66ZD5pdkY8pZADXpsziXfxtxZDj9TD2N-zmohoZW5CpksTeKWOeh-oxZHem6WELywqBdTldkJ1anlZNKoI7rOKbwn-wXyLWbvgDDIj0O2L75FqomsDdu7c7OJmSxgIxSke-a1ksUy78GAfGvxqeSc-nCPZ9rsCNqhaoQcH1V4nQ";
+
+    // JWKS endpoint publishing the test key as `kid = "test"`; counts fetches
+    pub(crate) async fn spawn_jwks_server() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let app = axum::Router::new().route("/jwks.json", axum::routing::get(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {
+                axum::Json(serde_json::json!({
+                    "keys": [{ "kty": "RSA", "kid": "test", "alg": "RS256", "use": "sig", "n": TEST_JWK_N, "e": "AQAB" }]
+                }))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}/jwks.json", addr), hits)
+    }
+
+    fn jwt_auth(jwks_url: String) -> AuthConfig {
+        AuthConfig {
+            auth_type: AuthType::Jwt,
+            realm: "api".to_string(),
+            users: None,
+            tokens: None,
+            jwt: Some(JwtConfig {
+                jwks_url,
+                issuer: Some("https://issuer.test/".to_string()),
+                audience: Some(vec!["api".to_string()]),
+                algorithms: default_jwt_algorithms(),
+                jwks_cache_seconds: 300,
+                leeway_seconds: 0,
+            }),
+            jwks: Arc::default(),
+        }
+    }
+
+    // RS256 token signed with src/testdata/jwt_rs256.pem
+    pub(crate) fn token(kid: &str, claims: serde_json::Value) -> String {
+        let header = jsonwebtoken::Header {
+            kid: Some(kid.to_string()),
+            ..jsonwebtoken::Header::new(Algorithm::RS256)
+        };
+        let key = jsonwebtoken::EncodingKey::from_rsa_pem(include_bytes!("testdata/jwt_rs256.pem")).unwrap();
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    fn sign(kid: &str, claims: serde_json::Value) -> HeaderMap {
+        with_authorization(&format!("Bearer {}", token(kid, claims)))
+    }
+
+    pub(crate) fn claims(exp_offset: i64, aud: &str) -> serde_json::Value {
+        serde_json::json!({
+            "sub": "user-42",
+            "tenant": "acme",
+            "iss": "https://issuer.test/",
+            "aud": aud,
+            "exp": chrono::Utc::now().timestamp() + exp_offset,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_jwt_valid_token_yields_claims() {
+        let (url, _) = spawn_jwks_server().await;
+        let auth = jwt_auth(url);
+        assert!(auth.validate().is_ok());
+
+        let claims = auth.authorize(&sign("test", claims(300, "api"))).await.unwrap().unwrap();
+        assert_eq!(claims.get("sub"), Some(&serde_json::json!("user-42")));
+        let variables = claims.variables();
+        assert_eq!(variables["jwt_tenant"], "acme");
+        assert_eq!(variables["jwt_sub"], "user-42");
+    }
+
+    #[tokio::test]
+    async fn test_jwt_rejects_expired_and_wrong_audience() {
+        let (url, _) = spawn_jwks_server().await;
+        let auth = jwt_auth(url);
+
+        for headers in [sign("test", claims(-300, "api")), sign("test", claims(300, "other")), with_authorization("Bearer not.a.jwt")] {
+            let response = auth.authorize(&headers).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"api\", error=\"invalid_token\"");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jwks_cached_and_refreshed_on_unknown_kid() {
+        use std::sync::atomic::Ordering;
+
+        let (url, hits) = spawn_jwks_server().await;
+        let auth = jwt_auth(url);
+
+        assert!(auth.authorize(&sign("test", claims(300, "api"))).await.is_ok());
+        assert!(auth.authorize(&sign("test", claims(300, "api"))).await.is_ok());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // Right after a fetch an unknown kid doesn't trigger another one
+        assert!(auth.authorize(&sign("rotated", claims(300, "api"))).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // Later it does, once
+        auth.jwks.keys.write().await.as_mut().unwrap().fetched -= JWKS_MIN_REFRESH;
+        assert!(auth.authorize(&sign("rotated", claims(300, "api"))).await.is_err());
+        assert!(auth.authorize(&sign("rotated", claims(300, "api"))).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+}
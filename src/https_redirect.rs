@@ -0,0 +1,98 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+// Left on plain HTTP so ACME HTTP-01 validation keeps working
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+// Answers every request on the HTTP listener with a 301 to its https://
+// equivalent when `ssl.redirect_http` is set. `https_port` only shows up in
+// the Location when it isn't 443.
+pub async fn https_redirect_middleware(
+    State(https_port): State<u16>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if req.uri().path().starts_with(ACME_CHALLENGE_PREFIX) {
+        return next.run(req).await;
+    }
+
+    let host = req.headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host());
+    let location = match host.and_then(|host| https_location(host, req.uri(), https_port)) {
+        Some(location) => location,
+        // Nothing to redirect to; let the app answer
+        None => return next.run(req).await,
+    };
+
+    (StatusCode::MOVED_PERMANENTLY, [(header::LOCATION, location)]).into_response()
+}
+
+fn https_location(host: &str, uri: &axum::http::Uri, https_port: u16) -> Option<HeaderValue> {
+    let host = strip_port(host);
+    if host.is_empty() {
+        return None;
+    }
+    let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path_and_query)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path_and_query)
+    };
+    HeaderValue::from_str(&location).ok()
+}
+
+// "example.com:8080" -> "example.com", "[::1]:8080" -> "[::1]"
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.find(']').map_or(host, |end| &host[..=end]);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app(https_port: u16) -> Router {
+        Router::new()
+            .route("/.well-known/acme-challenge/:token", get(|| async { "challenge" }))
+            .fallback(|| async { "plain http" })
+            .layer(axum::middleware::from_fn_with_state(https_port, https_redirect_middleware))
+    }
+
+    async fn get_with_host(app: Router, uri: &str, host: &str) -> Response {
+        let req = Request::builder().uri(uri).header(header::HOST, host).body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_redirects_to_https() {
+        let response = get_with_host(app(443), "/shop/cart?item=1&qty=2", "example.com:8080").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "https://example.com/shop/cart?item=1&qty=2");
+
+        // Non-standard HTTPS ports are kept
+        let response = get_with_host(app(8443), "/", "example.com").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "https://example.com:8443/");
+
+        let response = get_with_host(app(8443), "/a", "[::1]:8080").await;
+        assert_eq!(response.headers()[header::LOCATION], "https://[::1]:8443/a");
+    }
+
+    #[tokio::test]
+    async fn test_acme_challenge_stays_on_http() {
+        let response = get_with_host(app(443), "/.well-known/acme-challenge/abc123", "example.com").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 64).await.unwrap();
+        assert_eq!(&body[..], b"challenge");
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::{bail, Context, Result};
+use axum::Router;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use axum_server::Handle;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+use crate::proxy_protocol::ProxyProtocolAcceptor;
+
+// [[server.listeners]]
+// address = "0.0.0.0:80"
+//
+// [[server.listeners]]
+// address = "[::]:443"
+// tls = true
+//
+// [[server.listeners]]
+// address = "unix:/run/miwidothttp.sock"   # e.g. behind nginx
+// socket_mode = 0o660
+//
+// Without any, the server listens on bind_address:http_port, plus
+// bind_address:https_port with TLS when [ssl] is enabled.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ListenerConfig {
+    pub address: String,
+    #[serde(default)]
+    pub tls: bool,
+    // Permissions for unix socket files; the umask decides when unset
+    #[serde(default)]
+    pub socket_mode: Option<u32>,
+}
+
+impl ListenerConfig {
+    pub fn tcp(address: impl Into<String>, tls: bool) -> Self {
+        Self { address: address.into(), tls, socket_mode: None }
+    }
+}
+
+const UNIX_PREFIX: &str = "unix:";
+
+// A socket bound at startup, so a taken port fails before anything serves
+pub struct BoundListener {
+    pub tls: bool,
+    socket: Socket,
+}
+
+enum Socket {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener, PathBuf),
+}
+
+impl BoundListener {
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match &self.socket {
+            Socket::Tcp(listener) => listener.local_addr().ok(),
+            Socket::Unix(..) => None,
+        }
+    }
+}
+
+impl fmt::Display for BoundListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.socket {
+            Socket::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => f.write_str("?"),
+            },
+            Socket::Unix(_, path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+pub fn bind(config: &ListenerConfig) -> Result<BoundListener> {
+    if let Some(path) = config.address.strip_prefix(UNIX_PREFIX) {
+        if config.tls {
+            bail!("listener {}: TLS is not supported on unix sockets", config.address);
+        }
+        return bind_unix(PathBuf::from(path), config.socket_mode);
+    }
+
+    let addr: SocketAddr = config.address.parse()
+        .with_context(|| format!("invalid listener address {:?}", config.address))?;
+    let listener = std::net::TcpListener::bind(addr)
+        .with_context(|| format!("failed to bind {}", addr))?;
+    listener.set_nonblocking(true)?;
+    Ok(BoundListener { tls: config.tls, socket: Socket::Tcp(listener) })
+}
+
+// A socket file left behind by a previous run is removed first; one that a
+// running server still answers on, or a file that isn't a socket, is an error
+fn bind_unix(path: PathBuf, mode: Option<u32>) -> Result<BoundListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            bail!("{} is in use by another server", path.display());
+        }
+        std::fs::remove_file(&path)
+            .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+    }
+
+    let listener = std::os::unix::net::UnixListener::bind(&path)
+        .with_context(|| format!("failed to bind {}", path.display()))?;
+    listener.set_nonblocking(true)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set permissions on {}", path.display()))?;
+    }
+    Ok(BoundListener { tls: false, socket: Socket::Unix(listener, path) })
+}
+
+// Shuts every listener down: TCP ones through axum_server's handle, unix
+// sockets (served by hyper directly) through a watch channel
+#[derive(Clone)]
+pub struct ServerHandle {
+    tcp: Handle,
+    // Some(timeout) once shutdown has started
+    unix: Arc<watch::Sender<Option<Option<Duration>>>>,
+}
+
+impl ServerHandle {
+    pub fn new() -> Self {
+        Self {
+            tcp: Handle::new(),
+            unix: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    pub fn graceful_shutdown(&self, timeout: Option<Duration>) {
+        self.tcp.graceful_shutdown(timeout);
+        self.unix.send_replace(Some(timeout));
+    }
+}
+
+// Serve `app` on a bound listener until `handle` shuts it down. TLS
+// listeners need `tls`; the PROXY header, when enabled, precedes the TLS
+// handshake. Unix sockets never carry a PROXY header.
+pub fn serve(
+    listener: BoundListener,
+    app: Router,
+    tls: Option<RustlsConfig>,
+    proxy_protocol: bool,
+    handle: ServerHandle,
+) -> JoinHandle<()> {
+    let name = listener.to_string();
+    match listener.socket {
+        Socket::Tcp(listener) => {
+            let acceptor = ProxyProtocolAcceptor::new(proxy_protocol);
+            tokio::spawn(async move {
+                let server = axum_server::from_tcp(listener).handle(handle.tcp);
+                let result = match tls {
+                    Some(tls) => {
+                        server
+                            .acceptor(RustlsAcceptor::new(tls).acceptor(acceptor))
+                            .serve(app.into_make_service())
+                            .await
+                    }
+                    None => server.acceptor(acceptor).serve(app.into_make_service()).await,
+                };
+                if let Err(e) = result {
+                    error!("Listener {} failed: {}", name, e);
+                }
+            })
+        }
+        Socket::Unix(listener, path) => tokio::spawn(async move {
+            if let Err(e) = serve_unix(listener, &path, app, handle.unix.subscribe()).await {
+                error!("Listener {} failed: {}", name, e);
+            }
+            let _ = std::fs::remove_file(&path);
+        }),
+    }
+}
+
+async fn serve_unix(
+    listener: std::os::unix::net::UnixListener,
+    path: &std::path::Path,
+    app: Router,
+    mut shutdown: watch::Receiver<Option<Option<Duration>>>,
+) -> std::io::Result<()> {
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    let graceful = GracefulShutdown::new();
+
+    let timeout = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Accept failed on {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let service = TowerToHyperService::new(app.clone());
+                let connection = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                    .into_owned();
+                let connection = graceful.watch(connection);
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        debug!("Unix socket connection ended: {}", e);
+                    }
+                });
+            }
+            changed = shutdown.changed() => {
+                // A dropped handle can never ask for a graceful shutdown
+                match changed {
+                    Ok(()) => if let Some(timeout) = *shutdown.borrow() {
+                        break timeout;
+                    },
+                    Err(_) => break None,
+                }
+            }
+        }
+    };
+
+    // Stop accepting, then let open connections finish
+    drop(listener);
+    match timeout {
+        Some(timeout) => {
+            if tokio::time::timeout(timeout, graceful.shutdown()).await.is_err() {
+                warn!("Connections on {} still open after {:?}", path.display(), timeout);
+            }
+        }
+        None => graceful.shutdown().await,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    const HEALTH_REQUEST: &[u8] = b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+    fn health_app() -> Router {
+        Router::new().route("/health", get(|| async { "OK" }))
+    }
+
+    async fn get_health<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> String {
+        stream.write_all(HEALTH_REQUEST).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!("miwidothttp-listener-{}.sock", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_serves_on_every_listener() {
+        let handle = ServerHandle::new();
+
+        let mut addrs = Vec::new();
+        for address in ["127.0.0.1:0", "127.0.0.1:0"] {
+            let listener = bind(&ListenerConfig::tcp(address, false)).unwrap();
+            addrs.push(listener.tcp_addr().unwrap());
+            serve(listener, health_app(), None, false, handle.clone());
+        }
+
+        assert_ne!(addrs[0], addrs[1]);
+        for addr in addrs {
+            let response = get_health(tokio::net::TcpStream::connect(addr).await.unwrap()).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(response.ends_with("OK"));
+        }
+        handle.graceful_shutdown(None);
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_listener() {
+        let path = socket_path();
+        // Left behind by a server that didn't clean up
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let config = ListenerConfig {
+            address: format!("unix:{}", path.display()),
+            tls: false,
+            socket_mode: Some(0o660),
+        };
+        let listener = bind(&config).unwrap();
+        assert_eq!(listener.to_string(), config.address);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+
+        let handle = ServerHandle::new();
+        let server = serve(listener, health_app(), None, false, handle.clone());
+
+        let response = get_health(tokio::net::UnixStream::connect(&path).await.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        // A live socket is not taken over
+        let error = bind(&config).err().unwrap();
+        assert!(error.to_string().contains("in use"), "{}", error);
+
+        handle.graceful_shutdown(Some(Duration::from_secs(1)));
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_bind_errors() {
+        let taken = bind(&ListenerConfig::tcp("127.0.0.1:0", false)).unwrap();
+        let error = bind(&ListenerConfig::tcp(taken.tcp_addr().unwrap().to_string(), false)).err().unwrap();
+        assert!(error.to_string().contains("failed to bind"));
+
+        assert!(bind(&ListenerConfig::tcp("localhost", false)).is_err());
+        assert!(bind(&ListenerConfig::tcp("unix:/tmp/never.sock", true)).is_err());
+
+        // Regular files are never removed to make room for a socket
+        let path = socket_path();
+        std::fs::write(&path, "data").unwrap();
+        let error = bind(&ListenerConfig::tcp(format!("unix:{}", path.display()), false)).err().unwrap();
+        assert!(error.to_string().contains("not a socket"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
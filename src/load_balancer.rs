@@ -0,0 +1,299 @@
+use axum::http::{header, HeaderMap};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tracing::debug;
+
+// How a backend with several upstreams spreads its requests, e.g.
+//
+//   [backends."app.example.com"]
+//   upstreams = ["http://10.0.0.1:3000", "http://10.0.0.2:3000"]
+//   strategy = "weighted"
+//   weights = [3, 1]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalanceStrategy {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    IpHash,
+    Random,
+    Weighted,
+    // Round robin for a client's first request, then the upstream named in
+    // its affinity cookie for as long as that upstream is healthy
+    Sticky,
+}
+
+// Affinity cookie for the sticky strategy
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StickyConfig {
+    pub cookie_name: String,
+    // Session cookie when unset
+    pub max_age_seconds: Option<u64>,
+    pub secure: bool,
+}
+
+impl Default for StickyConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: "mwd_upstream".to_string(),
+            max_age_seconds: None,
+            secure: false,
+        }
+    }
+}
+
+impl StickyConfig {
+    // Set-Cookie value pinning the client to `upstream`
+    pub fn cookie(&self, upstream: &str) -> String {
+        let mut cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax", self.cookie_name, upstream_token(upstream));
+        if let Some(max_age) = self.max_age_seconds {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    // The candidate the request's affinity cookie points at, if any
+    pub fn pinned<'a>(&self, headers: &HeaderMap, candidates: &[(&'a str, u32)]) -> Option<&'a str> {
+        let token = headers.get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie_name)
+            .map(|(_, value)| value)?;
+        candidates.iter()
+            .map(|(url, _)| *url)
+            .find(|url| upstream_token(url) == token)
+    }
+}
+
+// Affinity cookie value for an upstream; a digest, so the cookie doesn't
+// expose internal addresses
+pub fn upstream_token(upstream: &str) -> String {
+    Sha256::digest(upstream.as_bytes())[..8].iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Requests an upstream is handling and has handled
+#[derive(Debug, Default)]
+pub struct UpstreamState {
+    pub active_connections: AtomicUsize,
+    pub total_requests: AtomicU64,
+}
+
+// Counts a request against its upstream until dropped
+pub struct ConnectionGuard(Arc<UpstreamState>);
+
+impl ConnectionGuard {
+    fn new(state: Arc<UpstreamState>) -> Self {
+        state.active_connections.fetch_add(1, Ordering::Relaxed);
+        state.total_requests.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(state)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Round-robin positions per backend and connection counts per upstream
+#[derive(Default)]
+pub struct LoadBalancer {
+    upstreams: RwLock<HashMap<String, Arc<UpstreamState>>>,
+    counters: RwLock<HashMap<String, Arc<AtomicUsize>>>,
+}
+
+impl LoadBalancer {
+    // Pick one of `candidates`, (upstream, weight) pairs the caller has
+    // already narrowed to healthy upstreams. Without a client IP (unix
+    // socket listeners) ip_hash falls back to round robin, as do weights
+    // that are all zero.
+    pub fn select<'a>(
+        &self,
+        backend: &str,
+        strategy: LoadBalanceStrategy,
+        candidates: &[(&'a str, u32)],
+        client_ip: Option<IpAddr>,
+    ) -> Option<&'a str> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match (strategy, client_ip) {
+            (LoadBalanceStrategy::RoundRobin | LoadBalanceStrategy::Sticky, _) | (LoadBalanceStrategy::IpHash, None) => {
+                self.next_counter(backend) % candidates.len()
+            }
+            (LoadBalanceStrategy::LeastConn, _) => {
+                candidates.iter()
+                    .enumerate()
+                    .min_by_key(|(_, (url, _))| self.connections(url))
+                    .map_or(0, |(i, _)| i)
+            }
+            (LoadBalanceStrategy::Random, _) => rand::thread_rng().gen_range(0..candidates.len()),
+            (LoadBalanceStrategy::IpHash, Some(ip)) => {
+                let mut hasher = DefaultHasher::new();
+                ip.hash(&mut hasher);
+                (hasher.finish() % candidates.len() as u64) as usize
+            }
+            (LoadBalanceStrategy::Weighted, _) => {
+                let total: u64 = candidates.iter().map(|(_, weight)| *weight as u64).sum();
+                let mut point = self.next_counter(backend) as u64;
+                if total == 0 {
+                    point as usize % candidates.len()
+                } else {
+                    point %= total;
+                    candidates.iter()
+                        .position(|(_, weight)| {
+                            let weight = *weight as u64;
+                            if point < weight {
+                                return true;
+                            }
+                            point -= weight;
+                            false
+                        })
+                        .unwrap_or(0)
+                }
+            }
+        };
+
+        let selected = candidates[index].0;
+        debug!("Selected upstream {} for {} ({:?})", selected, backend, strategy);
+        Some(selected)
+    }
+
+    // Hold the guard for as long as the request is in flight, so least_conn
+    // sees it
+    pub fn track(&self, upstream: &str) -> ConnectionGuard {
+        ConnectionGuard::new(self.upstream_state(upstream))
+    }
+
+    pub fn connections(&self, upstream: &str) -> usize {
+        self.upstreams.read().unwrap()
+            .get(upstream)
+            .map_or(0, |state| state.active_connections.load(Ordering::Relaxed))
+    }
+
+    fn upstream_state(&self, upstream: &str) -> Arc<UpstreamState> {
+        if let Some(state) = self.upstreams.read().unwrap().get(upstream) {
+            return state.clone();
+        }
+        self.upstreams.write().unwrap()
+            .entry(upstream.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn next_counter(&self, backend: &str) -> usize {
+        if let Some(counter) = self.counters.read().unwrap().get(backend) {
+            return counter.fetch_add(1, Ordering::Relaxed);
+        }
+        let counter = self.counters.write().unwrap()
+            .entry(backend.to_string())
+            .or_default()
+            .clone();
+        counter.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    fn picks(balancer: &LoadBalancer, strategy: LoadBalanceStrategy, candidates: &[(&str, u32)], rounds: usize) -> Vec<String> {
+        (0..rounds)
+            .map(|_| balancer.select("app", strategy, candidates, ip("10.0.0.1")).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin_cycles() {
+        let balancer = LoadBalancer::default();
+        let candidates = [("http://a", 1), ("http://b", 1), ("http://c", 1)];
+        assert_eq!(
+            picks(&balancer, LoadBalanceStrategy::RoundRobin, &candidates, 6),
+            vec!["http://a", "http://b", "http://c", "http://a", "http://b", "http://c"]
+        );
+        assert_eq!(balancer.select("app", LoadBalanceStrategy::RoundRobin, &[], None), None);
+    }
+
+    #[test]
+    fn test_weighted_distribution() {
+        let balancer = LoadBalancer::default();
+        let mut counts = HashMap::new();
+        for pick in picks(&balancer, LoadBalanceStrategy::Weighted, &[("http://a", 3), ("http://b", 1)], 400) {
+            *counts.entry(pick).or_insert(0) += 1;
+        }
+        assert_eq!(counts["http://a"], 300);
+        assert_eq!(counts["http://b"], 100);
+    }
+
+    #[test]
+    fn test_zero_weights_fall_back_to_round_robin() {
+        let balancer = LoadBalancer::default();
+        assert_eq!(
+            picks(&balancer, LoadBalanceStrategy::Weighted, &[("http://a", 0), ("http://b", 0)], 2),
+            vec!["http://a", "http://b"]
+        );
+    }
+
+    #[test]
+    fn test_ip_hash_keeps_a_client_on_one_upstream() {
+        let balancer = LoadBalancer::default();
+        let candidates = [("http://a", 1), ("http://b", 1), ("http://c", 1)];
+        let first = balancer.select("app", LoadBalanceStrategy::IpHash, &candidates, ip("10.0.0.1")).unwrap();
+        for _ in 0..10 {
+            assert_eq!(balancer.select("app", LoadBalanceStrategy::IpHash, &candidates, ip("10.0.0.1")), Some(first));
+        }
+    }
+
+    #[test]
+    fn test_sticky_cookie() {
+        let sticky = StickyConfig { cookie_name: "lb".to_string(), max_age_seconds: Some(600), secure: true };
+        let token = upstream_token("http://10.0.0.2:3000");
+        assert_eq!(token.len(), 16);
+        assert_eq!(
+            sticky.cookie("http://10.0.0.2:3000"),
+            format!("lb={}; Path=/; HttpOnly; SameSite=Lax; Max-Age=600; Secure", token)
+        );
+
+        let candidates = [("http://10.0.0.1:3000", 1), ("http://10.0.0.2:3000", 1)];
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, format!("theme=dark; lb={}", token).parse().unwrap());
+        assert_eq!(sticky.pinned(&headers, &candidates), Some("http://10.0.0.2:3000"));
+
+        // A pinned upstream that left the candidates (unhealthy, removed)
+        // pins nothing
+        assert_eq!(sticky.pinned(&headers, &candidates[..1]), None);
+        assert_eq!(sticky.pinned(&HeaderMap::new(), &candidates), None);
+    }
+
+    #[test]
+    fn test_least_conn_prefers_idle_upstream() {
+        let balancer = LoadBalancer::default();
+        let candidates = [("http://a", 1), ("http://b", 1)];
+        let busy = balancer.track("http://a");
+        assert_eq!(picks(&balancer, LoadBalanceStrategy::LeastConn, &candidates, 3), vec!["http://b"; 3]);
+        assert_eq!(balancer.connections("http://a"), 1);
+
+        drop(busy);
+        assert_eq!(balancer.connections("http://a"), 0);
+    }
+}
@@ -1,22 +1,59 @@
 use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
 use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
 use tokio::time::{interval, Duration};
 use tracing::{info, warn, error};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+use crate::metrics::{content_length, MetricsCollector};
+use crate::proxy_protocol::ClientAddr;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Rotated logs are "<stem>.<timestamp>.log", or "<stem>.<timestamp>.gz" once
+// compressed, e.g. access.20250101_120000.log
+const ROTATION_TIMESTAMP: &str = "%Y%m%d_%H%M%S";
+
+fn is_rotated_log(file_name: &str, stem: &str) -> bool {
+    let rest = match file_name.strip_prefix(stem).and_then(|rest| rest.strip_prefix('.')) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    match rest.split_once('.') {
+        Some((timestamp, "log" | "gz")) => {
+            chrono::NaiveDateTime::parse_from_str(timestamp, ROTATION_TIMESTAMP).is_ok()
+        }
+        _ => false,
+    }
+}
+
+// The [logging] section; each part falls back to its defaults
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct LogConfig {
     pub access_log: AccessLogConfig,
     pub error_log: ErrorLogConfig,
     pub rotation: LogRotationConfig,
+    // Requests taking longer than this get a WARN entry in the error log;
+    // 0 turns it off
+    pub slow_request_threshold_ms: u64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct AccessLogConfig {
     pub enabled: bool,
     pub path: String,
@@ -25,6 +62,7 @@ pub struct AccessLogConfig {
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ErrorLogConfig {
     pub enabled: bool,
     pub path: String,
@@ -32,6 +70,7 @@ pub struct ErrorLogConfig {
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct LogRotationConfig {
     pub enabled: bool,
     pub max_size_mb: u64,
@@ -61,6 +100,55 @@ pub struct AccessLogEntry {
     pub user_agent: Option<String>,
     pub referer: Option<String>,
     pub request_id: String,
+    // Set for proxied requests
+    pub upstream_addr: Option<String>,
+    pub upstream_response_time_ms: Option<u64>,
+    // X-Cache of the response: HIT, MISS or STALE
+    pub cache_status: Option<String>,
+    pub vhost: Option<String>,
+}
+
+// Fills in {placeholder}s in one pass, so values that happen to contain
+// braces aren't expanded again. Unknown placeholders are left as they are.
+fn render_custom(format: &str, entry: &AccessLogEntry) -> String {
+    fn or_dash(value: Option<&str>) -> String {
+        value.unwrap_or("-").to_string()
+    }
+
+    let mut line = String::with_capacity(format.len() + 64);
+    let mut rest = format;
+    while let Some(open) = rest.find('{') {
+        line.push_str(&rest[..open]);
+        let after = &rest[open..];
+        let close = match after.find('}') {
+            Some(close) => close,
+            None => {
+                rest = after;
+                break;
+            }
+        };
+        let value = match &after[1..close] {
+            "remote_addr" => entry.remote_addr.clone(),
+            "timestamp" => entry.timestamp.to_rfc3339(),
+            "method" => entry.method.clone(),
+            "path" => entry.path.clone(),
+            "status" => entry.status.to_string(),
+            "response_time" => entry.response_time_ms.to_string(),
+            "bytes" => entry.bytes_sent.to_string(),
+            "request_id" => entry.request_id.clone(),
+            "user_agent" => or_dash(entry.user_agent.as_deref()),
+            "referer" => or_dash(entry.referer.as_deref()),
+            "upstream_addr" => or_dash(entry.upstream_addr.as_deref()),
+            "upstream_response_time" => or_dash(entry.upstream_response_time_ms.map(|ms| ms.to_string()).as_deref()),
+            "cache_status" => or_dash(entry.cache_status.as_deref()),
+            "vhost" => or_dash(entry.vhost.as_deref()),
+            _ => after[..=close].to_string(),
+        };
+        line.push_str(&value);
+        rest = &after[close + 1..];
+    }
+    line.push_str(rest);
+    line
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -78,6 +166,8 @@ pub struct LogManager {
     error_writer: Arc<RwLock<Option<File>>>,
     access_buffer: Arc<RwLock<Vec<AccessLogEntry>>>,
     error_buffer: Arc<RwLock<Vec<ErrorLogEntry>>>,
+    // Counts slow requests into http_slow_requests_total
+    metrics: Option<Arc<MetricsCollector>>,
 }
 
 impl LogManager {
@@ -100,6 +190,7 @@ impl LogManager {
             error_writer: Arc::new(RwLock::new(error_writer)),
             access_buffer: Arc::new(RwLock::new(Vec::new())),
             error_buffer: Arc::new(RwLock::new(Vec::new())),
+            metrics: None,
         };
 
         // Start background tasks
@@ -111,6 +202,11 @@ impl LogManager {
         Ok(manager)
     }
 
+    pub fn with_metrics(mut self, metrics: Arc<MetricsCollector>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
     fn open_log_file(path: &str) -> Result<File> {
         let path = Path::new(path);
         if let Some(parent) = path.parent() {
@@ -145,16 +241,73 @@ impl LogManager {
             return;
         }
 
+        // Errors are flushed immediately for critical issues
+        let critical = entry.level == "ERROR" || entry.level == "FATAL";
+
         let mut buffer = self.error_buffer.write().await;
         buffer.push(entry);
 
-        // Errors are flushed immediately for critical issues
-        if entry.level == "ERROR" || entry.level == "FATAL" {
+        if critical {
             drop(buffer);
             self.flush_error_logs().await;
         }
     }
 
+    // The access log line, plus error log entries for server errors and
+    // slow requests under the same request id
+    async fn finish_request(&self, entry: AccessLogEntry, first_byte_ms: u64) {
+        if entry.status >= 500 {
+            self.log_error(ErrorLogEntry {
+                timestamp: Utc::now(),
+                level: "ERROR".to_string(),
+                message: format!(
+                    "{} {} -> {} upstream={}",
+                    entry.method,
+                    entry.path,
+                    entry.status,
+                    entry.upstream_addr.as_deref().unwrap_or("-"),
+                ),
+                request_id: Some(entry.request_id.clone()),
+                stack_trace: None,
+            }).await;
+        }
+        let threshold = self.config.slow_request_threshold_ms;
+        if threshold > 0 && entry.response_time_ms > threshold {
+            self.log_slow_request(&entry, first_byte_ms).await;
+        }
+        self.log_access(entry).await;
+    }
+
+    async fn log_slow_request(&self, entry: &AccessLogEntry, first_byte_ms: u64) {
+        let message = format!(
+            "Slow request: {} {} -> {} upstream={} upstream_time={} first_byte={}ms total={}ms",
+            entry.method,
+            entry.path,
+            entry.status,
+            entry.upstream_addr.as_deref().unwrap_or("-"),
+            entry.upstream_response_time_ms.map_or_else(|| "-".to_string(), |ms| format!("{}ms", ms)),
+            first_byte_ms,
+            entry.response_time_ms,
+        );
+        warn!("{}", message);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_slow_request();
+        }
+        self.log_error(ErrorLogEntry {
+            timestamp: Utc::now(),
+            level: "WARN".to_string(),
+            message,
+            request_id: Some(entry.request_id.clone()),
+            stack_trace: None,
+        }).await;
+    }
+
+    // Write out anything still buffered, e.g. before shutting down
+    pub async fn flush(&self) {
+        self.flush_access_logs().await;
+        self.flush_error_logs().await;
+    }
+
     async fn flush_access_logs(&self) {
         let mut buffer = self.access_buffer.write().await;
         if buffer.is_empty() {
@@ -228,18 +381,7 @@ impl LogManager {
             LogFormat::Json => {
                 serde_json::to_string(entry).unwrap_or_default()
             }
-            LogFormat::Custom(format) => {
-                // Simple template replacement
-                format
-                    .replace("{remote_addr}", &entry.remote_addr)
-                    .replace("{timestamp}", &entry.timestamp.to_rfc3339())
-                    .replace("{method}", &entry.method)
-                    .replace("{path}", &entry.path)
-                    .replace("{status}", &entry.status.to_string())
-                    .replace("{response_time}", &entry.response_time_ms.to_string())
-                    .replace("{bytes}", &entry.bytes_sent.to_string())
-                    .replace("{request_id}", &entry.request_id)
-            }
+            LogFormat::Custom(format) => render_custom(format, entry),
         }
     }
 
@@ -313,57 +455,68 @@ impl LogManager {
         let metadata = fs::metadata(path)?;
         
         // Check if rotation is needed
-        let size_mb = metadata.len() / (1024 * 1024);
-        if size_mb < config.max_size_mb {
+        if metadata.len() < config.max_size_mb * 1024 * 1024 {
             return Ok(());
         }
 
         info!("Rotating log file: {:?}", path);
 
-        // Generate rotation filename
-        let timestamp = Utc::now().format("%Y%m%d_%H%M%S");
+        // Generate rotation filename (see is_rotated_log)
+        let timestamp = Utc::now().format(ROTATION_TIMESTAMP);
         let rotation_path = path.with_extension(format!("{}.log", timestamp));
 
-        // Rename current file
-        fs::rename(path, &rotation_path)?;
+        // Rename current file and switch writers to a fresh one, so nothing
+        // is written to the rotated copy while it's being compressed
+        {
+            let mut writer_guard = writer.write().await;
+            fs::rename(path, &rotation_path)?;
+            *writer_guard = Some(Self::open_log_file(path.to_str().unwrap())?);
+        }
 
-        // Compress if enabled
+        // Compress if enabled; gzip of a large log would stall the runtime
         if config.compress {
-            Self::compress_log_file(&rotation_path)?;
+            let rotated = rotation_path.clone();
+            tokio::task::spawn_blocking(move || Self::compress_log_file(&rotated)).await??;
         }
 
-        // Create new log file
-        let new_file = Self::open_log_file(path.to_str().unwrap())?;
-        let mut writer_guard = writer.write().await;
-        *writer_guard = Some(new_file);
-
         // Clean up old backups
         Self::cleanup_old_logs(path, config)?;
 
         Ok(())
     }
 
-    fn compress_log_file(path: &Path) -> Result<()> {
+    // Blocking; the original is only removed once the .gz is complete and
+    // on disk, and a partial .gz is removed on failure
+    fn compress_log_file(path: &Path) -> Result<PathBuf> {
         use flate2::write::GzEncoder;
         use flate2::Compression;
         
-        let input = File::open(path)?;
         let output_path = path.with_extension("gz");
-        let output = File::create(&output_path)?;
-        
-        let mut encoder = GzEncoder::new(output, Compression::default());
-        std::io::copy(&mut &input, &mut encoder)?;
-        encoder.finish()?;
+        let compress = || -> Result<()> {
+            let mut input = std::io::BufReader::new(File::open(path)?);
+            let output = File::create(&output_path)?;
+            let mut encoder = GzEncoder::new(std::io::BufWriter::new(output), Compression::default());
+            std::io::copy(&mut input, &mut encoder)?;
+            let output = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+            output.sync_all()?;
+            Ok(())
+        };
+        if let Err(e) = compress() {
+            let _ = fs::remove_file(&output_path);
+            return Err(e);
+        }
         
         fs::remove_file(path)?;
         info!("Compressed log file to: {:?}", output_path);
         
-        Ok(())
+        Ok(output_path)
     }
 
+    // Drops rotated copies older than max_age_days (0 keeps them regardless
+    // of age), then all but the newest max_backups
     fn cleanup_old_logs(base_path: &Path, config: &LogRotationConfig) -> Result<()> {
         let parent = base_path.parent().unwrap_or(Path::new("."));
-        let base_name = base_path.file_stem().unwrap_or_default();
+        let base_name = base_path.file_stem().unwrap_or_default().to_string_lossy();
         
         let mut rotated_files: Vec<(PathBuf, std::time::SystemTime)> = Vec::new();
         
@@ -372,8 +525,7 @@ impl LogManager {
             let path = entry.path();
             let file_name = path.file_name().unwrap_or_default().to_string_lossy();
             
-            if file_name.starts_with(&base_name.to_string_lossy()) && 
-               (file_name.contains(".2025") || file_name.contains(".2024")) {
+            if is_rotated_log(&file_name, &base_name) {
                 if let Ok(metadata) = entry.metadata() {
                     if let Ok(modified) = metadata.modified() {
                         rotated_files.push((path, modified));
@@ -382,15 +534,17 @@ impl LogManager {
             }
         }
         
-        // Sort by modification time (oldest first)
-        rotated_files.sort_by_key(|k| k.1);
+        // Sort by modification time (newest first)
+        rotated_files.sort_by_key(|k| std::cmp::Reverse(k.1));
         
-        // Remove old files exceeding max_backups
-        while rotated_files.len() > config.max_backups as usize {
-            if let Some((path, _)) = rotated_files.first() {
+        let max_age = std::time::Duration::from_secs(u64::from(config.max_age_days) * 24 * 3600);
+        let now = std::time::SystemTime::now();
+        for (index, (path, modified)) in rotated_files.iter().enumerate() {
+            let expired = config.max_age_days > 0
+                && now.duration_since(*modified).map_or(false, |age| age > max_age);
+            if expired || index >= config.max_backups as usize {
                 fs::remove_file(path)?;
                 info!("Removed old log file: {:?}", path);
-                rotated_files.remove(0);
             }
         }
         
@@ -406,31 +560,533 @@ impl Clone for LogManager {
             error_writer: self.error_writer.clone(),
             access_buffer: self.access_buffer.clone(),
             error_buffer: self.error_buffer.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "logs/access.log".to_string(),
+            format: LogFormat::Combined,
+            buffer_size: 100,
         }
     }
 }
 
-impl Default for LogConfig {
+impl Default for ErrorLogConfig {
     fn default() -> Self {
         Self {
+            enabled: true,
+            path: "logs/error.log".to_string(),
+            level: "ERROR".to_string(),
+        }
+    }
+}
+
+impl Default for LogRotationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_size_mb: 100,
+            max_age_days: 30,
+            max_backups: 10,
+            compress: true,
+        }
+    }
+}
+
+// Request id for the current request, also sent back as X-Request-Id
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// Response extensions the proxy path sets for the access log
+#[derive(Debug, Clone)]
+pub struct UpstreamInfo {
+    pub addr: String,
+    pub response_time_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtualHost(pub String);
+
+// Gives every request an id (keeping a sane one from an upstream load
+// balancer) and, when access logging is configured, writes one line per
+// request once its body has been sent
+pub async fn access_log_middleware(
+    State(logs): State<Option<Arc<LogManager>>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+
+    let request_id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let header_value = HeaderValue::from_str(&request_id).expect("request id is visible ASCII");
+    // Backends see the same id
+    req.headers_mut().insert(REQUEST_ID_HEADER, header_value.clone());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    // Tags every log line of the request when inside trace_middleware's span
+    tracing::Span::current().record("request_id", request_id.as_str());
+
+    let entry = logs.as_ref().map(|_| AccessLogEntry {
+        timestamp: Utc::now(),
+        remote_addr: req.extensions().get::<ClientAddr>()
+            .map_or_else(|| "-".to_string(), |ClientAddr(addr)| addr.ip().to_string()),
+        method: req.method().to_string(),
+        path: req.uri().path_and_query().map_or("/", |pq| pq.as_str()).to_string(),
+        status: 0,
+        response_time_ms: 0,
+        bytes_sent: 0,
+        user_agent: header_string(&req, header::USER_AGENT),
+        referer: header_string(&req, header::REFERER),
+        request_id,
+        upstream_addr: None,
+        upstream_response_time_ms: None,
+        cache_status: None,
+        vhost: None,
+    });
+
+    let mut response = next.run(req).await;
+    let first_byte_ms = start.elapsed().as_millis() as u64;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header_value);
+
+    let (logs, mut entry) = match (logs, entry) {
+        (Some(logs), Some(entry)) => (logs, entry),
+        _ => return response,
+    };
+    entry.status = response.status().as_u16();
+    if let Some(upstream) = response.extensions().get::<UpstreamInfo>() {
+        entry.upstream_addr = Some(upstream.addr.clone());
+        entry.upstream_response_time_ms = Some(upstream.response_time_ms);
+    }
+    entry.cache_status = response.headers().get("x-cache")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    entry.vhost = response.extensions().get::<VirtualHost>().map(|VirtualHost(host)| host.clone());
+
+    match content_length(response.headers()) {
+        Some(length) => {
+            entry.bytes_sent = length;
+            entry.response_time_ms = start.elapsed().as_millis() as u64;
+            logs.finish_request(entry, first_byte_ms).await;
+            response
+        }
+        // Logged once the body has been sent (or the client went away)
+        None => {
+            let mut pending = PendingAccess { logs, entry: Some(entry), start, first_byte_ms };
+            response.map(|body| Body::from_stream(body.into_data_stream().map(move |chunk| {
+                if let (Ok(chunk), Some(entry)) = (&chunk, pending.entry.as_mut()) {
+                    entry.bytes_sent += chunk.len() as u64;
+                }
+                chunk
+            })))
+        }
+    }
+}
+
+fn header_string(req: &Request, name: header::HeaderName) -> Option<String> {
+    req.headers().get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+// An access log line written when the response body is dropped
+struct PendingAccess {
+    logs: Arc<LogManager>,
+    entry: Option<AccessLogEntry>,
+    start: Instant,
+    first_byte_ms: u64,
+}
+
+impl Drop for PendingAccess {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.response_time_ms = self.start.elapsed().as_millis() as u64;
+            let logs = self.logs.clone();
+            let first_byte_ms = self.first_byte_ms;
+            tokio::spawn(async move {
+                logs.finish_request(entry, first_byte_ms).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+    use tower::ServiceExt;
+
+    fn log_config(dir: &Path, format: LogFormat) -> LogConfig {
+        LogConfig {
             access_log: AccessLogConfig {
                 enabled: true,
-                path: "logs/access.log".to_string(),
-                format: LogFormat::Combined,
+                path: dir.join("access.log").to_string_lossy().to_string(),
+                format,
                 buffer_size: 100,
             },
-            error_log: ErrorLogConfig {
-                enabled: true,
-                path: "logs/error.log".to_string(),
-                level: "ERROR".to_string(),
-            },
-            rotation: LogRotationConfig {
-                enabled: true,
-                max_size_mb: 100,
-                max_age_days: 30,
-                max_backups: 10,
-                compress: true,
-            },
+            error_log: ErrorLogConfig { enabled: false, ..Default::default() },
+            rotation: LogRotationConfig { enabled: false, ..Default::default() },
+            slow_request_threshold_ms: 0,
         }
     }
+
+    // Sends one request and returns the response's request id and the
+    // access log line it produced
+    async fn logged_request(format: LogFormat) -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-logs-{}", uuid::Uuid::new_v4()));
+        let logs = Arc::new(LogManager::new(log_config(&dir, format)).unwrap());
+        let app = Router::new()
+            .route("/hello", get(|| async { "hello world" }))
+            .layer(axum::middleware::from_fn_with_state(Some(logs.clone()), access_log_middleware));
+
+        let response = app
+            .oneshot(
+                axum::http::Request::builder()
+                    .uri("/hello?x=1")
+                    .header(header::USER_AGENT, "test-agent")
+                    .header(header::REFERER, "http://example.com/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"hello world");
+
+        // The entry is queued once the body is dropped
+        let path = dir.join("access.log");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let line = loop {
+            logs.flush().await;
+            let contents = fs::read_to_string(&path).unwrap();
+            if let Some(line) = contents.lines().next() {
+                break line.to_string();
+            }
+            assert!(std::time::Instant::now() < deadline, "no access log line written");
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        };
+        fs::remove_dir_all(&dir).unwrap();
+        (request_id, line)
+    }
+
+    #[tokio::test]
+    async fn test_access_log_json() {
+        let (request_id, line) = logged_request(LogFormat::Json).await;
+
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["path"], "/hello?x=1");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["bytes_sent"], 11);
+        assert_eq!(entry["user_agent"], "test-agent");
+        assert_eq!(entry["referer"], "http://example.com/");
+        assert_eq!(entry["request_id"], request_id.as_str());
+        assert!(uuid::Uuid::parse_str(&request_id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_access_log_combined() {
+        let (_, line) = logged_request(LogFormat::Combined).await;
+
+        assert!(line.starts_with("- - - ["), "{}", line);
+        assert!(line.ends_with("\"GET /hello?x=1\" 200 11 \"http://example.com/\" \"test-agent\""), "{}", line);
+    }
+
+    fn proxied_entry() -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp: Utc::now(),
+            remote_addr: "203.0.113.7".to_string(),
+            method: "GET".to_string(),
+            path: "/api/users".to_string(),
+            status: 200,
+            response_time_ms: 12,
+            bytes_sent: 512,
+            user_agent: Some("curl/8.0 {status}".to_string()),
+            referer: None,
+            request_id: "req-1".to_string(),
+            upstream_addr: Some("localhost:3000".to_string()),
+            upstream_response_time_ms: Some(9),
+            cache_status: Some("MISS".to_string()),
+            vhost: Some("api.example.com".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_format_upstream_fields() {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-logs-{}", uuid::Uuid::new_v4()));
+        let format = LogFormat::Custom(
+            "{vhost} {remote_addr} \"{method} {path}\" {status} upstream={upstream_addr} \
+             upstream_time={upstream_response_time} cache={cache_status} ref={referer} ua=\"{user_agent}\" {unknown}".to_string(),
+        );
+        let mut config = log_config(&dir, format);
+        config.access_log.enabled = false;
+        let logs = LogManager::new(config).unwrap();
+
+        let line = logs.format_access_log(&proxied_entry());
+        assert_eq!(
+            line,
+            "api.example.com 203.0.113.7 \"GET /api/users\" 200 upstream=localhost:3000 \
+             upstream_time=9 cache=MISS ref=- ua=\"curl/8.0 {status}\" {unknown}"
+        );
+
+        // Static responses have no upstream
+        let mut entry = proxied_entry();
+        entry.upstream_addr = None;
+        entry.upstream_response_time_ms = None;
+        entry.cache_status = None;
+        let format = LogFormat::Custom("{upstream_addr} {upstream_response_time} {cache_status} {unterminated".to_string());
+        let mut config = log_config(&dir, format);
+        config.access_log.enabled = false;
+        let logs = LogManager::new(config).unwrap();
+        assert_eq!(logs.format_access_log(&entry), "- - - {unterminated");
+
+        let json: serde_json::Value = serde_json::from_str(&LogManager::new(log_config(&dir, LogFormat::Json)).unwrap()
+            .format_access_log(&proxied_entry())).unwrap();
+        assert_eq!(json["upstream_addr"], "localhost:3000");
+        assert_eq!(json["upstream_response_time_ms"], 9);
+        assert_eq!(json["cache_status"], "MISS");
+        assert_eq!(json["vhost"], "api.example.com");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_is_rotated_log() {
+        assert!(is_rotated_log("access.20260301_120000.log", "access"));
+        assert!(is_rotated_log("access.20991231_235959.gz", "access"));
+        assert!(!is_rotated_log("access.log", "access"));
+        assert!(!is_rotated_log("error.20260301_120000.log", "access"));
+        assert!(!is_rotated_log("access.backup.log", "access"));
+        assert!(!is_rotated_log("access.20260301_120000.txt", "access"));
+        assert!(!is_rotated_log("access-old.20260301_120000.log", "access"));
+    }
+
+    fn rotated_file(dir: &Path, name: &str, age_days: u64) -> PathBuf {
+        let path = dir.join(name);
+        let file = File::create(&path).unwrap();
+        let modified = std::time::SystemTime::now() - std::time::Duration::from_secs(age_days * 24 * 3600);
+        file.set_modified(modified).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_cleanup_by_count_and_age() {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-logs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let current = dir.join("access.log");
+        File::create(&current).unwrap();
+
+        let newest = rotated_file(&dir, "access.20260305_000000.log", 1);
+        let second = rotated_file(&dir, "access.20260304_000000.gz", 2);
+        let third = rotated_file(&dir, "access.20260303_000000.gz", 3);
+        let too_old = rotated_file(&dir, "access.20260101_000000.gz", 40);
+        let other = rotated_file(&dir, "error.20260101_000000.gz", 40);
+
+        // Age: only the 40 day old copy goes
+        let config = LogRotationConfig { max_age_days: 30, max_backups: 10, ..Default::default() };
+        LogManager::cleanup_old_logs(&current, &config).unwrap();
+        assert!(newest.exists() && second.exists() && third.exists());
+        assert!(!too_old.exists());
+
+        // Count: the oldest beyond two backups goes
+        let config = LogRotationConfig { max_age_days: 0, max_backups: 2, ..Default::default() };
+        LogManager::cleanup_old_logs(&current, &config).unwrap();
+        assert!(newest.exists() && second.exists());
+        assert!(!third.exists());
+
+        // Neither the live log nor other logs' backups are touched
+        assert!(current.exists());
+        assert!(other.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compress_log_file() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = std::env::temp_dir().join(format!("miwidothttp-logs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let rotated = dir.join("access.20260301_120000.log");
+        let original: Vec<u8> = (0..200_000u32).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        fs::write(&rotated, &original).unwrap();
+
+        let compressed = LogManager::compress_log_file(&rotated).unwrap();
+        assert_eq!(compressed, dir.join("access.20260301_120000.gz"));
+        assert!(!rotated.exists());
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(File::open(&compressed).unwrap()).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, original);
+
+        // A failed compression leaves the original alone and no .gz behind
+        let missing = dir.join("access.20260302_120000.log");
+        assert!(LogManager::compress_log_file(&missing).is_err());
+        assert!(!dir.join("access.20260302_120000.gz").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_logged_and_counted() {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-logs-{}", uuid::Uuid::new_v4()));
+        let mut config = log_config(&dir, LogFormat::Json);
+        config.error_log = ErrorLogConfig {
+            enabled: true,
+            path: dir.join("error.log").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        config.slow_request_threshold_ms = 100;
+        let metrics = Arc::new(MetricsCollector::new());
+        let logs = Arc::new(LogManager::new(config).unwrap().with_metrics(metrics.clone()));
+        let app = Router::new()
+            .route("/fast", get(|| async { "fast" }))
+            .route("/slow", get(|| async {
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                "slow"
+            }))
+            .layer(axum::middleware::from_fn_with_state(Some(logs.clone()), access_log_middleware));
+
+        for path in ["/fast", "/slow"] {
+            let response = app.clone()
+                .oneshot(axum::http::Request::builder().uri(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        }
+
+        let path = dir.join("error.log");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let contents = loop {
+            logs.flush().await;
+            let contents = fs::read_to_string(&path).unwrap();
+            if !contents.is_empty() {
+                break contents;
+            }
+            assert!(std::time::Instant::now() < deadline, "no slow request logged");
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        };
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", contents);
+        let entry: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(entry["level"], "WARN");
+        let message = entry["message"].as_str().unwrap();
+        assert!(message.starts_with("Slow request: GET /slow -> 200 upstream=- upstream_time=- first_byte="), "{}", message);
+        assert!(message.contains(" total="), "{}", message);
+        assert!(metrics.get_prometheus_metrics().await.contains("http_slow_requests_total 1\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_id_and_traceparent_reach_backend() {
+        use crate::proxy_client::{build_client, send, ConnectionPoolConfig, TimeoutConfig};
+        use crate::telemetry::{trace_middleware, TRACEPARENT_HEADER};
+        use axum::http::StatusCode;
+
+        // Backend that answers with the ids it got
+        let backend = Router::new().fallback(|req: Request| async move {
+            let header = |name: &str| req.headers().get(name).map_or("", |v| v.to_str().unwrap()).to_string();
+            format!("{} {}", header(REQUEST_ID_HEADER), header(TRACEPARENT_HEADER))
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, backend).await.unwrap() });
+
+        let dir = std::env::temp_dir().join(format!("miwidothttp-logs-{}", uuid::Uuid::new_v4()));
+        let mut config = log_config(&dir, LogFormat::Json);
+        config.error_log = ErrorLogConfig {
+            enabled: true,
+            path: dir.join("error.log").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let logs = Arc::new(LogManager::new(config).unwrap());
+        // Forwards the headers like send_to_backend, failing with a 502 on /fail
+        let app = Router::new()
+            .fallback(move |req: Request| async move {
+                let mut proxy_req = axum::http::Request::get(format!("http://{}/", addr)).body(Body::empty()).unwrap();
+                *proxy_req.headers_mut() = req.headers().clone();
+                let timeouts = TimeoutConfig::default();
+                let client = build_client(&ConnectionPoolConfig::default(), &timeouts);
+                let response = send(&client, proxy_req, 1024, 1024, &timeouts).await.unwrap();
+                let status = if req.uri().path() == "/fail" { StatusCode::BAD_GATEWAY } else { StatusCode::OK };
+                (status, Body::new(response.into_body()))
+            })
+            .layer(axum::middleware::from_fn_with_state(Some(logs.clone()), access_log_middleware))
+            .layer(axum::middleware::from_fn(trace_middleware));
+
+        async fn forwarded(app: &Router, request: axum::http::Request<Body>) -> (String, String, String) {
+            let response = app.clone().oneshot(request).await.unwrap();
+            let echoed = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+            let body = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let (request_id, traceparent) = body.split_once(' ').unwrap();
+            (echoed, request_id.to_string(), traceparent.to_string())
+        }
+
+        // An incoming id and trace are kept end to end
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let request = axum::http::Request::get("/")
+            .header(REQUEST_ID_HEADER, "lb-1234")
+            .header(TRACEPARENT_HEADER, traceparent)
+            .body(Body::empty())
+            .unwrap();
+        let (echoed, seen, seen_traceparent) = forwarded(&app, request).await;
+        assert_eq!((echoed.as_str(), seen.as_str()), ("lb-1234", "lb-1234"));
+        assert!(seen_traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"), "{}", seen_traceparent);
+
+        // Missing ones are minted, and the backend gets the same id the
+        // client and the error log see
+        let (echoed, seen, seen_traceparent) = forwarded(&app, axum::http::Request::get("/fail").body(Body::empty()).unwrap()).await;
+        assert!(uuid::Uuid::parse_str(&echoed).is_ok(), "{}", echoed);
+        assert_eq!(seen, echoed);
+        assert_eq!(seen_traceparent.split('-').count(), 4, "{}", seen_traceparent);
+
+        let path = dir.join("error.log");
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let line = loop {
+            logs.flush().await;
+            if let Some(line) = fs::read_to_string(&path).unwrap().lines().next() {
+                break line.to_string();
+            }
+            assert!(std::time::Instant::now() < deadline, "no error logged");
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        };
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["request_id"], echoed.as_str());
+        assert_eq!(entry["message"], "GET /fail -> 502 upstream=-");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_id_kept_from_upstream() {
+        let app = Router::new()
+            .route("/", get(|req: Request| async move {
+                req.extensions().get::<RequestId>().unwrap().0.clone()
+            }))
+            .layer(axum::middleware::from_fn_with_state(None, access_log_middleware));
+
+        let response = app
+            .oneshot(
+                axum::http::Request::builder()
+                    .uri("/")
+                    .header(REQUEST_ID_HEADER, "lb-1234")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "lb-1234");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"lb-1234");
+    }
 }
\ No newline at end of file
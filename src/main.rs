@@ -1,29 +1,32 @@
 use axum::{
     body::Body,
-    extract::{Host, Request, State},
-    http::{StatusCode, Uri, HeaderValue, Method},
+    extract::{Extension, Host, Request, State},
+    http::{header, HeaderMap, StatusCode, Uri, HeaderValue, Method},
     response::{Html, IntoResponse, Response},
     routing::{get, post, any},
     Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
 use std::{net::SocketAddr, sync::Arc, path::PathBuf, time::Duration};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tower::ServiceBuilder;
 use tower_http::{
-    compression::CompressionLayer,
     cors::CorsLayer,
     services::ServeDir,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
 };
-use tracing::{info, warn, error, Level};
+use tracing::{info, warn, error, Instrument, Level};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 use std::collections::HashMap;
+use arc_swap::ArcSwap;
 
 mod process_manager;
+mod error;
 mod security;
-mod session_manager;
+mod session;
+mod middleware;
 mod rewrite_engine;
 mod metrics;
 mod websocket;
@@ -35,13 +38,63 @@ mod circuit_breaker;
 // mod cache;  // API changes in cacache
 mod static_cache;
 mod linux_io;
+mod body_limit;
+mod health_check;
+mod header_rules;
+mod response_cache;
+mod http_auth;
+mod proxy;
+mod proxy_client;
+mod proxy_protocol;
+mod try_files;
+mod autoindex;
+mod logging;
+mod cluster;
+mod maintenance;
+mod https_redirect;
+mod listeners;
+mod redact;
+mod cli;
+mod compression;
+mod discovery;
+mod mirror;
+mod sub_filter;
+mod request_decompression;
+mod telemetry;
+mod admin_allowlist;
+mod load_balancer;
+mod sni;
 
 use process_manager::{ProcessManager, ProcessConfig, AppType};
-use security::{SecurityConfig, RateLimiter, security_headers_middleware};
-use session_manager::{SessionManager, SessionConfig};
+use security::{SecurityConfig, SecurityHeaderOverrides, SecurityHeadersState, RateLimiter, rate_limit_middleware, security_headers_middleware};
+use session::{SessionManager, SessionConfig};
+use middleware::{csrf_middleware, session_middleware, SessionState};
 use rewrite_engine::{RewriteEngine, RewriteConfig, RewriteResult};
-use metrics::{MetricsCollector, RequestMetrics};
-use static_cache::StaticCache;
+use metrics::{MetricsCollector, MetricsConfig, metrics_middleware};
+use static_cache::{resolve_static_path, StaticCache, StaticPath};
+use health_check::{readyz, HealthChecker, HealthCheckConfig, HealthTarget, Readiness};
+use header_rules::HeaderRules;
+use response_cache::{ResponseCache, ResponseCacheConfig};
+use proxy::{FastCGIConfig, FastCGIProxy, ProxyManager};
+use proxy_client::{build_client, ConnectionPoolConfig, PooledClient, RetryConfig, SendError, TimeoutConfig};
+use proxy_protocol::ClientAddr;
+use try_files::{TryFiles, TryFilesResult};
+use cluster::{ClusterConfig, ClusterManager};
+use logging::{access_log_middleware, LogConfig, LogManager, UpstreamInfo, VirtualHost};
+use maintenance::{maintenance_middleware, MaintenanceConfig, MaintenanceMode};
+use error::{error_recovery_middleware, ErrorConfig, ErrorHandler};
+use https_redirect::https_redirect_middleware;
+use listeners::{ListenerConfig, ServerHandle};
+use cli::Cli;
+use compression::CompressionConfig;
+use discovery::{Discovery, DiscoveryConfig};
+use circuit_breaker::CircuitBreakers;
+use mirror::MirrorConfig;
+use telemetry::TelemetryConfig;
+use admin_allowlist::{admin_allowlist_middleware, AdminAllowlist, AdminAllowlistConfig, IpNet};
+use load_balancer::{LoadBalanceStrategy, LoadBalancer, StickyConfig};
+use websocket::{WebSocketConfig, WebSocketManager};
+use clap::Parser;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct Config {
@@ -49,10 +102,49 @@ struct Config {
     server: ServerConfig,
     #[serde(default)]
     ssl: SslConfig,
-    #[serde(skip)]
+    #[serde(default)]
     security: SecurityConfig,
     #[serde(default)]
+    proxy: ProxySettings,
+    #[serde(default)]
+    session: Option<SessionConfig>,
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+    // Access and error log files; request ids are issued either way
+    #[serde(default)]
+    logging: Option<LogConfig>,
+    // Sizes for the proxy response cache; backends opt in with `cache = true`
+    #[serde(default)]
+    cache: ResponseCacheConfig,
+    // Response compression, off unless `enabled = true`; read at startup
+    #[serde(default)]
+    compression: CompressionConfig,
+    // Multi-node mode; off unless `enabled = true`
+    #[serde(default)]
+    cluster: ClusterConfig,
+    // Answer with a 503 page; toggled at runtime via /api/maintenance
+    #[serde(default)]
+    maintenance: MaintenanceConfig,
+    // Error page templates for the server's own error responses
+    #[serde(default)]
+    errors: ErrorConfig,
+    // Clients that get past maintenance mode and rate limiting
+    #[serde(default)]
+    admin_allowlist: AdminAllowlistConfig,
+    // Per-vhost/route series for /metrics; read at startup
+    #[serde(default)]
+    metrics: MetricsConfig,
+    // OpenTelemetry span export; read at startup
+    #[serde(default)]
+    telemetry: TelemetryConfig,
+    // Built-in WebSocket hub at /ws, off unless `enabled = true`; read at startup
+    #[serde(default)]
+    websocket: WebSocketConfig,
+    #[serde(default)]
     backends: HashMap<String, BackendConfig>,
+    // Forward and SOCKS5 proxies, each on its own bind_addr; read at startup
+    #[serde(default)]
+    proxy_servers: Vec<proxy::ProxyConfig>,
     #[serde(skip)]
     processes: HashMap<String, ProcessConfig>,
 }
@@ -67,6 +159,29 @@ struct ServerConfig {
     bind_address: String,
     #[serde(default = "default_static_dir")]
     static_dir: String,
+    // How long in-flight requests get to finish after SIGTERM/Ctrl-C
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout_seconds: u64,
+    // After SIGTERM/Ctrl-C, /readyz fails for this long before the listeners
+    // stop accepting, so load balancers take the node out first
+    #[serde(default)]
+    drain_delay_seconds: u64,
+    // Expect a PROXY protocol (v1 or v2) header on every connection, as sent
+    // by HAProxy or an AWS NLB in front of the server
+    #[serde(default)]
+    proxy_protocol: bool,
+    // nginx-style try_files for requests served from static_dir
+    #[serde(default)]
+    try_files: Option<TryFiles>,
+    // List directories that have no index.html, like nginx `autoindex on`
+    #[serde(default)]
+    autoindex: bool,
+    // Header changes for responses served from static_dir
+    #[serde(default)]
+    response_headers: HeaderRules,
+    // Sockets to serve on; empty means bind_address with http_port/https_port
+    #[serde(default)]
+    listeners: Vec<ListenerConfig>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -75,6 +190,29 @@ struct SslConfig {
     enabled: bool,
     cert_path: Option<String>,
     key_path: Option<String>,
+    // Extra SANs for the generated self-signed certificate
+    #[serde(default)]
+    domains: Vec<String>,
+    #[serde(default = "default_self_signed_days")]
+    self_signed_days: u32,
+    // Answer plain HTTP with a 301 to HTTPS (ACME challenges excepted)
+    #[serde(default)]
+    redirect_http: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ProxySettings {
+    // Bytes, or a size string like "10MB"
+    #[serde(default = "default_max_request_size", deserialize_with = "body_limit::deserialize_size")]
+    max_request_size: u64,
+    #[serde(default = "default_max_response_size", deserialize_with = "body_limit::deserialize_size")]
+    max_response_size: u64,
+    // Backend connection pool; read once at startup
+    #[serde(default)]
+    connection_pool: ConnectionPoolConfig,
+    // Backend timeouts; connect_timeout_seconds is read once at startup
+    #[serde(default)]
+    timeout: TimeoutConfig,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -82,8 +220,96 @@ struct BackendConfig {
     #[serde(flatten)]
     process: Option<ProcessConfig>,
     target: Option<String>,
+    // Several upstreams to spread requests over, instead of one target
+    #[serde(default)]
+    upstreams: Vec<String>,
+    #[serde(default)]
+    strategy: LoadBalanceStrategy,
+    // Parallel to upstreams for the weighted strategy; missing entries are 1
+    #[serde(default)]
+    weights: Vec<u32>,
+    // Affinity cookie for the sticky strategy
+    #[serde(default)]
+    sticky: StickyConfig,
+    // Try failed requests again, on another upstream when there are several
+    #[serde(default)]
+    retry: Option<RetryConfig>,
     #[serde(default)]
     health_check: Option<String>,
+    // Header changes on the way to the backend and on the way back
+    #[serde(default)]
+    request_headers: HeaderRules,
+    #[serde(default)]
+    response_headers: HeaderRules,
+    // Keep cacheable GET responses in the shared response cache
+    #[serde(default)]
+    cache: bool,
+    // Status code to template file for this host, over [errors] custom_pages
+    #[serde(default)]
+    error_pages: HashMap<u16, String>,
+    // Changes to [security.headers] for this host, read at startup
+    #[serde(default)]
+    security_headers: SecurityHeaderOverrides,
+    // Request body limit for this host, over [proxy] max_request_size
+    #[serde(default, deserialize_with = "body_limit::deserialize_optional_size")]
+    max_request_size: Option<u64>,
+    // "grpc" talks HTTP/2 (h2c) to the backend and keeps response trailers
+    #[serde(default)]
+    protocol: BackendProtocol,
+    // Upstreams looked up in DNS instead of a fixed target
+    #[serde(default)]
+    discovery: Option<DiscoveryConfig>,
+    // Fail fast with a 503 while an upstream keeps failing
+    #[serde(default)]
+    circuit_breaker: Option<circuit_breaker::Config>,
+    // Copy a share of requests to a shadow upstream
+    #[serde(default)]
+    mirror: Option<MirrorConfig>,
+    // String replacements in text responses, like nginx's sub_filter
+    #[serde(default)]
+    response_substitutions: Vec<sub_filter::Substitution>,
+    // Decode gzip/deflate/br request bodies for backends that can't
+    #[serde(default)]
+    decompress_requests: bool,
+    // Certificate for this host on the TLS listeners, picked by SNI; read
+    // at startup
+    #[serde(default)]
+    tls: Option<sni::HostCertificate>,
+    // PHP-FPM over FastCGI instead of an HTTP target
+    #[serde(default)]
+    fastcgi: Option<FastCGIConfig>,
+    // Client addresses or CIDR blocks let in or kept out of this host
+    #[serde(default)]
+    access: Option<BackendAccess>,
+    // Credentials every request to this host must carry
+    #[serde(default)]
+    auth: Option<http_auth::AuthConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BackendProtocol {
+    #[default]
+    Http,
+    Grpc,
+}
+
+// Deny matches are refused first; with an allow list, only its matches get
+// through
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct BackendAccess {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl BackendAccess {
+    fn permits(&self, ip: std::net::IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
 }
 
 impl Default for ServerConfig {
@@ -93,6 +319,24 @@ impl Default for ServerConfig {
             https_port: default_https_port(),
             bind_address: default_bind_address(),
             static_dir: default_static_dir(),
+            shutdown_timeout_seconds: default_shutdown_timeout(),
+            drain_delay_seconds: 0,
+            proxy_protocol: false,
+            try_files: None,
+            autoindex: false,
+            response_headers: HeaderRules::default(),
+            listeners: Vec::new(),
+        }
+    }
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        Self {
+            max_request_size: default_max_request_size(),
+            max_response_size: default_max_response_size(),
+            connection_pool: ConnectionPoolConfig::default(),
+            timeout: TimeoutConfig::default(),
         }
     }
 }
@@ -103,6 +347,9 @@ impl Default for SslConfig {
             enabled: false,
             cert_path: None,
             key_path: None,
+            domains: Vec::new(),
+            self_signed_days: default_self_signed_days(),
+            redirect_http: false,
         }
     }
 }
@@ -112,16 +359,40 @@ fn default_https_port() -> u16 { 8443 }
 fn default_bind_address() -> String { "0.0.0.0".to_string() }
 fn default_static_dir() -> String { "./static".to_string() }
 
+fn default_shutdown_timeout() -> u64 { 30 }
+fn default_self_signed_days() -> u32 { 365 }
+fn default_max_request_size() -> u64 { 100 * 1024 * 1024 } // 100MB
+fn default_max_response_size() -> u64 { 100 * 1024 * 1024 } // 100MB
+
 #[derive(Clone)]
 struct AppState {
-    config: Arc<Config>,
+    // Swapped wholesale on reload; handlers take a snapshot per request
+    config: Arc<ArcSwap<Config>>,
+    config_path: Option<PathBuf>,
+    // Command-line overrides, applied again on every reload
+    cli: Cli,
     static_dir: PathBuf,
-    http_client: reqwest::Client,
+    http_client: PooledClient,
+    // HTTP/2 prior-knowledge client for `protocol = "grpc"` backends
+    grpc_client: PooledClient,
     process_manager: Arc<ProcessManager>,
     rate_limiter: Arc<RateLimiter>,
     session_manager: Option<Arc<SessionManager>>,
     metrics: Arc<MetricsCollector>,
     static_cache: Arc<StaticCache>,
+    response_cache: Arc<ResponseCache>,
+    health_checker: Arc<HealthChecker>,
+    discovery: Arc<Discovery>,
+    load_balancer: Arc<LoadBalancer>,
+    circuit_breakers: Arc<CircuitBreakers>,
+    cluster: Option<Arc<ClusterManager>>,
+    log_manager: Option<Arc<LogManager>>,
+    maintenance: Arc<MaintenanceMode>,
+    admin_allowlist: Arc<AdminAllowlist>,
+    error_handler: Arc<ErrorHandler>,
+    websocket: Arc<WebSocketManager>,
+    // Set on shutdown; /readyz fails from then on
+    draining: Arc<AtomicBool>,
 }
 
 #[tokio::main]
@@ -131,17 +402,43 @@ async fn main() {
         .install_default()
         .expect("Failed to install rustls crypto provider");
     
-    // Initialize tracing
+    let cli = Cli::parse();
+    
+    // Initialize tracing; --log-level wins over RUST_LOG
+    let env_filter = || match cli.log_filter() {
+        Some(filter) => tracing_subscriber::EnvFilter::new(filter),
+        None => tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| "miwidothttp=info,tower_http=info".into()),
+    };
+    // Logs while the config loads; the global subscriber below also exports
+    // spans when [telemetry] says so
+    let startup_logging = tracing_subscriber::registry()
+        .with(env_filter())
+        .with(tracing_subscriber::fmt::layer())
+        .set_default();
+
+    // Load configuration; a broken config file stops the server instead of
+    // falling back to defaults. `--check-config` only validates it.
+    let (config, config_path) = match load_config(&cli).await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("Configuration error: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.check_config {
+        match &config_path {
+            Some(path) => println!("Configuration {} is valid", path.display()),
+            None => println!("No config file found, the defaults are valid"),
+        }
+        return;
+    }
+    drop(startup_logging);
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "miwidothttp=info,tower_http=info".into()),
-        )
+        .with(env_filter())
         .with(tracing_subscriber::fmt::layer())
+        .with(telemetry::layer(&config.telemetry))
         .init();
-
-    // Load configuration
-    let config = load_config().await;
     
     // Create static directory
     let static_dir = PathBuf::from(&config.server.static_dir);
@@ -170,7 +467,7 @@ async fn main() {
         <div class="feature enabled">✅ HTTP/1.1 Support</div>
         <div class="feature enabled">✅ HTTP/2 Support</div>
         <div class="feature enabled">✅ Static File Serving</div>
-        <div class="feature enabled">✅ Compression (gzip/brotli)</div>
+        <div class="feature enabled">✅ Compression (zstd/brotli/gzip)</div>
         <div class="feature enabled">✅ CORS Support</div>
         <div class="feature enabled">✅ Request Logging</div>
         <div class="feature enabled">✅ Proxy Support</div>
@@ -178,16 +475,17 @@ async fn main() {
     </div>
     <p>Configuration: <code>/etc/miwidothttp/config.toml</code> or <code>./config.toml</code></p>
     <p>API Status: <a href="/api/status">/api/status</a></p>
-    <p>Metrics: <a href="/metrics">/metrics</a></p>
+    <p>Metrics: <a href="/metrics">/metrics</a> (<a href="/metrics.json">JSON</a>)</p>
     <p>Health: <a href="/health">/health</a></p>
 </body>
 </html>"#).unwrap();
     }
 
-    let http_client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .expect("Failed to create HTTP client");
+    let http_client = build_client(&config.proxy.connection_pool, &config.proxy.timeout);
+    let grpc_client = build_client(&ConnectionPoolConfig {
+        http2: true,
+        ..config.proxy.connection_pool.clone()
+    }, &config.proxy.timeout);
 
     // Initialize process manager
     let process_manager = Arc::new(ProcessManager::new());
@@ -206,107 +504,345 @@ async fn main() {
     // Initialize rate limiter
     let rate_limiter = Arc::new(RateLimiter::new(config.security.clone()));
     
-    // Initialize session manager (optional)
-    let session_manager = None; // TODO: Add session config to Config struct
+    // Initialize session manager (only when a [session] section is configured)
+    let session_manager = match &config.session {
+        Some(session_config) => match SessionManager::from_config(session_config.clone()) {
+            Ok(manager) => {
+                info!("Sessions enabled with {:?} store", session_config.store);
+                manager.start_cleanup_task();
+                Some(Arc::new(manager))
+            }
+            Err(e) => {
+                error!("Failed to initialize session store: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
     
     // Initialize metrics collector
-    let metrics = Arc::new(MetricsCollector::new());
+    let metrics = Arc::new(MetricsCollector::new().with_config(config.metrics.clone()));
+    
+    // Initialize access/error logs (only when a [logging] section is configured)
+    let log_manager = match &config.logging {
+        Some(log_config) => match LogManager::new(log_config.clone()) {
+            Ok(manager) => {
+                info!("Access log enabled at {}", log_config.access_log.path);
+                Some(Arc::new(manager.with_metrics(metrics.clone())))
+            }
+            Err(e) => {
+                error!("Failed to open log files: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
     
     // Initialize static file cache
     let static_cache = Arc::new(StaticCache::new(true));
     
+    // Proxy response cache; sized at startup, so changes need a restart
+    let response_cache = Arc::new(ResponseCache::new(config.cache.clone()));
+    
+    // Start active health checks for backends with a health_check path
+    let health_checker = Arc::new(HealthChecker::new(config.health_check.clone())
+        .with_process_manager(process_manager.clone()));
+    health_checker.clone().start(health_targets(&config));
+    
+    // Keep upstream sets fresh for backends with [discovery]
+    let discovery = Arc::new(Discovery::start(config.backends.iter()
+        .filter_map(|(name, backend)| Some((name, backend.discovery.as_ref()?)))));
+    
+    // Join the cluster (only when [cluster] enabled = true); a node that
+    // can't start its cluster services keeps serving on its own
+    let cluster = if config.cluster.enabled {
+        let started = async {
+            let mut manager = ClusterManager::new(config.cluster.clone()).await?;
+            manager.set_metrics(metrics.clone());
+            manager.start().await?;
+            anyhow::Ok(Arc::new(manager))
+        };
+        match started.await {
+            Ok(manager) => {
+                info!("Cluster mode enabled as node {}", manager.node_id());
+                Some(manager)
+            }
+            Err(e) => {
+                error!("Failed to start cluster manager: {:#}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    
+    // Error pages; templates are read once, so edits need a restart
+    let mut error_config = config.errors.clone();
+    error_config.host_pages = config.backends.iter()
+        .filter(|(_, backend)| !backend.error_pages.is_empty())
+        .map(|(host, backend)| (host.clone(), backend.error_pages.clone()))
+        .collect();
+    let error_handler = match ErrorHandler::new(error_config).await {
+        Ok(handler) => Arc::new(handler),
+        Err(e) => {
+            error!("Failed to load error page templates, using defaults: {:#}", e);
+            Arc::new(ErrorHandler::new(ErrorConfig::default()).await.expect("default error pages"))
+        }
+    };
+    
     let app_state = Arc::new(AppState {
-        config: Arc::new(config.clone()),
+        config: Arc::new(ArcSwap::from_pointee(config.clone())),
+        config_path: config_path.clone(),
+        cli: cli.clone(),
         static_dir: static_dir.clone(),
         http_client,
+        grpc_client,
         process_manager,
         rate_limiter,
         session_manager,
         metrics,
         static_cache,
+        response_cache,
+        health_checker,
+        discovery,
+        load_balancer: Arc::new(LoadBalancer::default()),
+        circuit_breakers: Arc::new(CircuitBreakers::default()),
+        cluster,
+        log_manager,
+        maintenance: Arc::new(MaintenanceMode::new(config.maintenance.clone())),
+        admin_allowlist: Arc::new(AdminAllowlist::new(config.admin_allowlist.clone())),
+        error_handler,
+        websocket: Arc::new(WebSocketManager::with_config(config.websocket.clone())),
+        draining: Arc::new(AtomicBool::new(false)),
     });
 
-    // Build our application with routes
-    let app = create_app(app_state.clone());
+    // Pick up config.toml edits without a restart
+    if let Some(path) = config_path {
+        if let Err(e) = watch_config(app_state.clone(), path) {
+            warn!("Config hot-reload disabled: {}", e);
+        }
+    }
+
+    // Forward and SOCKS5 proxies from [[proxy_servers]]
+    for proxy_config in &config.proxy_servers {
+        match ProxyManager::new(proxy_config.clone()) {
+            Ok(manager) => {
+                tokio::spawn(async move {
+                    if let Err(e) = Arc::new(manager).run().await {
+                        error!("Proxy server stopped: {:#}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Proxy server disabled: {:#}", e),
+        }
+    }
 
-    // Start HTTP server
-    let http_addr = SocketAddr::new(
-        config.server.bind_address.parse().unwrap(),
-        config.server.http_port
-    );
-    
     info!("🚀 miwidothttp server starting");
     info!("📁 Serving static files from {}", config.server.static_dir);
-    info!("🌐 HTTP server on http://{}", http_addr);
     
-    let http_server = tokio::spawn(async move {
-        let listener = tokio::net::TcpListener::bind(http_addr)
-            .await
-            .expect("Failed to bind HTTP address");
-        
-        axum::serve(listener, app)
-            .await
-            .expect("HTTP server failed");
-    });
+    let listener_configs = listener_configs(&config);
+    let tls_config = if listener_configs.iter().any(|listener| listener.tls) {
+        load_tls_config(&config).await
+    } else {
+        None
+    };
+    
+    // One handle shared by all listeners so a single signal drains everything
+    let handle = ServerHandle::new();
+    tokio::spawn(shutdown_signal(
+        handle.clone(),
+        app_state.draining.clone(),
+        Duration::from_secs(config.server.drain_delay_seconds),
+        Duration::from_secs(config.server.shutdown_timeout_seconds),
+    ));
+    
+    let proxy_protocol = config.server.proxy_protocol;
+    if proxy_protocol {
+        info!("PROXY protocol enabled, connections without a PROXY header are rejected");
+    }
+    
+    // Bind everything before serving, so a taken port shows up right away
+    let mut bound = Vec::new();
+    for listener_config in &listener_configs {
+        if listener_config.tls && tls_config.is_none() {
+            warn!("HTTPS listener {} disabled, no usable certificate", listener_config.address);
+            continue;
+        }
+        match listeners::bind(listener_config) {
+            Ok(listener) => bound.push(listener),
+            Err(e) => error!("Listener disabled: {:#}", e),
+        }
+    }
+    if bound.is_empty() {
+        error!("No listener could be started");
+    }
+    
+    // With redirect_http the plain listeners only send clients over to the
+    // first HTTPS one
+    let https_port = bound.iter()
+        .filter(|listener| listener.tls)
+        .find_map(|listener| listener.tcp_addr())
+        .map_or(config.server.https_port, |addr| addr.port());
+    let redirect_http = config.ssl.enabled && config.ssl.redirect_http;
+    
+    let servers: Vec<_> = bound.into_iter().map(|listener| {
+        let app = create_app(app_state.clone(), listener.tls);
+        let app = if listener.tls {
+            info!("🔒 HTTPS server on https://{}", listener);
+            app
+        } else if redirect_http {
+            info!("🌐 HTTP server on http://{}, redirecting to HTTPS port {}", listener, https_port);
+            app.layer(axum::middleware::from_fn_with_state(https_port, https_redirect_middleware))
+        } else {
+            info!("🌐 HTTP server on http://{}", listener);
+            app
+        };
+        let tls = if listener.tls { tls_config.clone() } else { None };
+        listeners::serve(listener, app, tls, proxy_protocol, handle.clone())
+    }).collect();
+    
+    // Wait for every listener to drain
+    futures::future::join_all(servers).await;
+    
+    // Connections are drained (or timed out); take managed apps down with us
+    info!("Stopping managed processes");
+    app_state.process_manager.stop_all().await;
+    if let Some(logs) = &app_state.log_manager {
+        logs.flush().await;
+    }
+    telemetry::shutdown();
+    if let Some(cluster) = &app_state.cluster {
+        if let Err(e) = cluster.shutdown().await {
+            warn!("Cluster shutdown failed: {}", e);
+        }
+    }
+    info!("Shutdown complete");
+}
 
-    // Start HTTPS server if SSL is enabled
+// The [[server.listeners]] list, or the HTTP (and with [ssl] enabled, HTTPS)
+// listener that bind_address, http_port and https_port describe
+fn listener_configs(config: &Config) -> Vec<ListenerConfig> {
+    if !config.server.listeners.is_empty() {
+        return config.server.listeners.clone();
+    }
+    
+    let address = |port: u16| match config.server.bind_address.parse::<std::net::IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => format!("{}:{}", config.server.bind_address, port),
+    };
+    let mut listeners = vec![ListenerConfig::tcp(address(config.server.http_port), false)];
     if config.ssl.enabled {
-        if let (Some(cert_path), Some(key_path)) = (&config.ssl.cert_path, &config.ssl.key_path) {
-            let https_addr = SocketAddr::new(
-                config.server.bind_address.parse().unwrap(),
-                config.server.https_port
-            );
-            
-            // Check if certificate files exist, if not create self-signed
-            if !PathBuf::from(cert_path).exists() || !PathBuf::from(key_path).exists() {
-                info!("Certificate files not found, generating self-signed certificate...");
-                generate_self_signed_cert(cert_path, key_path).await;
-            }
-            
-            match RustlsConfig::from_pem_file(cert_path, key_path).await {
-                Ok(tls_config) => {
-                    info!("🔒 HTTPS server on https://{}", https_addr);
-                    
-                    let app = create_app(app_state);
-                    let https_server = tokio::spawn(async move {
-                        axum_server::bind_rustls(https_addr, tls_config)
-                            .serve(app.into_make_service())
-                            .await
-                            .expect("HTTPS server failed");
-                    });
-                    
-                    // Wait for both servers
-                    tokio::select! {
-                        _ = http_server => {},
-                        _ = https_server => {},
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to load TLS configuration: {}", e);
-                    warn!("HTTPS server disabled, running HTTP only");
-                    http_server.await.unwrap();
-                }
+        listeners.push(ListenerConfig::tcp(address(config.server.https_port), true));
+    }
+    listeners
+}
+
+// Certificate for the TLS listeners; a self-signed one is generated when
+// the configured files don't exist
+async fn load_tls_config(config: &Config) -> Option<RustlsConfig> {
+    let (Some(cert_path), Some(key_path)) = (&config.ssl.cert_path, &config.ssl.key_path) else {
+        warn!("TLS listeners need ssl.cert_path and ssl.key_path");
+        return None;
+    };
+    
+    if !PathBuf::from(cert_path).exists() || !PathBuf::from(key_path).exists() {
+        info!("Certificate files not found, generating self-signed certificate...");
+        let subject_alt_names = self_signed_names(config);
+        if let Err(e) = generate_self_signed_cert(cert_path, key_path, subject_alt_names, config.ssl.self_signed_days).await {
+            error!("Failed to generate self-signed certificate: {:#}", e);
+        }
+    }
+    
+    // The [ssl] certificate for any other name, or clients without SNI
+    let default = match sni::load_certified_key(cert_path, key_path) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Failed to load TLS configuration: {:#}", e);
+            return None;
+        }
+    };
+    let mut resolver = sni::SniCertResolver::new(Some(default));
+    for (host, backend) in &config.backends {
+        let Some(tls) = &backend.tls else { continue };
+        match sni::load_certified_key(&tls.cert_path, &tls.key_path) {
+            Ok(key) => {
+                info!("Serving {} with its own certificate {}", host, tls.cert_path);
+                resolver.add(host, key);
             }
-        } else {
-            warn!("SSL enabled but cert_path or key_path not configured");
-            http_server.await.unwrap();
+            Err(e) => error!("Certificate for {} not loaded, using the default: {:#}", host, e),
         }
-    } else {
-        http_server.await.unwrap();
     }
+    
+    match resolver.into_rustls_config() {
+        Ok(tls_config) => Some(tls_config),
+        Err(e) => {
+            error!("Failed to load TLS configuration: {:#}", e);
+            None
+        }
+    }
+}
+
+// Wait for Ctrl-C or SIGTERM, then fail readiness for `drain_delay` while
+// still serving, stop accepting new connections and give in-flight requests
+// up to `timeout` to finish
+async fn shutdown_signal(handle: ServerHandle, draining: Arc<AtomicBool>, drain_delay: Duration, timeout: Duration) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl-C handler");
+    };
+    
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    
+    draining.store(true, Ordering::SeqCst);
+    if !drain_delay.is_zero() {
+        info!("Shutdown signal received, failing /readyz for {}s before draining", drain_delay.as_secs());
+        tokio::time::sleep(drain_delay).await;
+    }
+    
+    info!("Shutdown signal received, draining connections for up to {}s", timeout.as_secs());
+    handle.graceful_shutdown(Some(timeout));
 }
 
-fn create_app(state: Arc<AppState>) -> Router {
-    Router::new()
-        // Health check endpoint
+// `https` tells the security headers which listener the app serves
+fn create_app(state: Arc<AppState>, https: bool) -> Router {
+    let router = Router::new()
+        // Liveness (/health is the original name) and readiness
         .route("/health", get(|| async { "OK" }))
+        .route("/livez", get(|| async { "OK" }))
+        .route("/readyz", get(readyz).with_state(Arc::new(Readiness {
+            health_checker: state.health_checker.clone(),
+            session_manager: state.session_manager.clone(),
+            cluster_enabled: state.config.load().cluster.enabled,
+            cluster: state.cluster.clone(),
+            draining: state.draining.clone(),
+        })))
         // API endpoints
         .route("/api/status", get(api_status))
+        .route("/api/config", get(api_config))
         .route("/api/backends", get(list_backends))
         .route("/api/processes", get(list_processes))
         .route("/api/processes/:name/restart", post(restart_process))
+        .route("/api/processes/:name/stop", post(stop_process))
+        .route("/api/processes/:name/start", post(start_process))
+        .route("/api/reload", post(reload_handler))
         // Metrics endpoint
         .route("/metrics", get(metrics))
+        .route("/metrics.json", get(metrics_json))
         // Static files
         .nest_service("/static", ServeDir::new(&state.static_dir))
         .fallback_service(ServeDir::new(&state.static_dir))
@@ -320,15 +856,131 @@ fn create_app(state: Arc<AppState>) -> Router {
                 //         .level(Level::INFO))
                 //     .on_response(DefaultOnResponse::new()
                 //         .level(Level::INFO))) // Disabled for max performance
-                // .layer(CompressionLayer::new()) // Disabled for max performance
                 .layer(CorsLayer::permissive())
-        )
-        // Security middlewares (added separately for now)
-        // .layer(axum::middleware::from_fn(security_headers_middleware)) // Disabled for max performance
-        .with_state(state)
+        );
+    
+    // Cluster status, only when this node is part of a cluster
+    let router = match &state.cluster {
+        Some(manager) => router.nest_service("/api/cluster", cluster::api::router(manager.clone())),
+        None => router,
+    };
+    
+    let router = router.nest_service("/api/maintenance", maintenance::router(state.maintenance.clone()));
+    
+    // WebSocket hub; when off, /ws paths go to the backends like any other
+    let websocket_config = state.config.load().websocket.clone();
+    let router = if websocket_config.enabled {
+        router.merge(websocket::websocket_routes(state.websocket.clone(), websocket_config.publish_auth))
+    } else {
+        router
+    };
+    
+    // Issue session cookies and load sessions per request when enabled
+    let router = match &state.session_manager {
+        Some(manager) => {
+            let session_state = SessionState { manager: manager.clone() };
+            // Added first so it runs inside session_middleware
+            let router = if manager.config().csrf_protection {
+                router.layer(axum::middleware::from_fn_with_state(session_state.clone(), csrf_middleware))
+            } else {
+                router
+            };
+            router.layer(axum::middleware::from_fn_with_state(session_state, session_middleware))
+        }
+        None => router,
+    };
+    
+    // Per-host authentication, inside the error layer so a 401 gets its page
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.clone(),
+        backend_auth_middleware,
+    ));
+    
+    // Request size limits, inside the error layer so a 413 gets its page
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.clone(),
+        request_size_middleware,
+    ));
+    
+    // Styled pages for bare error responses from anything inside
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.error_handler.clone(),
+        error_recovery_middleware,
+    ));
+    
+    // Ahead of sessions and routing, but still counted and logged
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.maintenance.clone(),
+        maintenance_middleware,
+    ));
+    
+    // Per-client request limits ([security], read at startup); inside the
+    // allowlist, which waives them
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.rate_limiter.clone(),
+        rate_limit_middleware,
+    ));
+    
+    // Outside maintenance and rate limiting so on-call engineers can always
+    // get in
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.admin_allowlist.clone(),
+        admin_allowlist_middleware,
+    ));
+    
+    // Security headers on everything, maintenance and error pages included;
+    // [security] needs a restart, so the per-host policies are built once
+    let config = state.config.load();
+    let security_headers = SecurityHeadersState {
+        headers: Arc::new(config.security.headers.clone()),
+        host_headers: Arc::new(config.backends.iter()
+            .filter(|(_, backend)| !backend.security_headers.is_empty())
+            .map(|(host, backend)| (host.clone(), config.security.headers.with_overrides(&backend.security_headers)))
+            .collect()),
+        https,
+    };
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        security_headers,
+        security_headers_middleware,
+    ));
+    
+    // Compress whatever the stack produced; metrics outside count the
+    // bytes actually sent. Read once, like [security].
+    let router = compression::apply(router, &config.compression);
+    
+    // Outermost so timings and byte counts cover the whole stack
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.metrics.clone(),
+        metrics_middleware,
+    ));
+    
+    // Request ids and access log lines; outside metrics so the logged
+    // response time covers the same work
+    let router = router.layer(axum::middleware::from_fn_with_state(
+        state.log_manager.clone(),
+        access_log_middleware,
+    ));
+    
+    // A span per request, continuing the client's traceparent
+    let router = router.layer(axum::middleware::from_fn(telemetry::trace_middleware));
+    
+    router.with_state(state)
 }
 
-async fn load_config() -> Config {
+// Load --config, or the first config file found; a file that can't be read,
+// parsed or validated is an error rather than a silent fall back to defaults
+async fn load_config(cli: &Cli) -> anyhow::Result<(Config, Option<PathBuf>)> {
+    use anyhow::Context;
+    
+    if let Some(path) = &cli.config {
+        let content = fs::read_to_string(path).await
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let config = parse_config(&content, cli)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        info!("Loaded configuration from {}", path.display());
+        return Ok((config, Some(path.clone())));
+    }
+    
     // Try to load from various locations
     let paths = vec![
         "/etc/miwidothttp/config.toml",
@@ -338,118 +990,471 @@ async fn load_config() -> Config {
     
     for path in paths {
         if PathBuf::from(path).exists() {
-            match fs::read_to_string(path).await {
-                Ok(content) => {
-                    match toml::from_str(&content) {
-                        Ok(config) => {
-                            info!("Loaded configuration from {}", path);
-                            return config;
-                        }
-                        Err(e) => {
-                            error!("Failed to parse config file {}: {}", path, e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!("Failed to read config file {}: {}", path, e);
-                }
-            }
+            let content = fs::read_to_string(path).await
+                .with_context(|| format!("failed to read {}", path))?;
+            let config = parse_config(&content, cli)
+                .with_context(|| format!("invalid config file {}", path))?;
+            info!("Loaded configuration from {}", path);
+            return Ok((config, Some(PathBuf::from(path))));
         }
     }
     
     info!("Using default configuration");
-    Config {
+    let mut config = Config {
         server: ServerConfig::default(),
         ssl: SslConfig::default(),
         security: SecurityConfig::default(),
+        proxy: ProxySettings::default(),
+        session: None,
+        health_check: HealthCheckConfig::default(),
+        logging: None,
+        cache: ResponseCacheConfig::default(),
+        compression: CompressionConfig::default(),
+        cluster: ClusterConfig::default(),
+        maintenance: MaintenanceConfig::default(),
+        errors: ErrorConfig::default(),
+        admin_allowlist: AdminAllowlistConfig::default(),
+        metrics: MetricsConfig::default(),
+        telemetry: TelemetryConfig::default(),
+        websocket: WebSocketConfig::default(),
         backends: HashMap::new(),
+        proxy_servers: Vec::new(),
         processes: HashMap::new(),
+    };
+    apply_cli_overrides(&mut config, cli);
+    validate_config(&config)?;
+    Ok((config, None))
+}
+
+fn parse_config(content: &str, cli: &Cli) -> anyhow::Result<Config> {
+    let mut config: Config = toml::from_str(content)?;
+    apply_cli_overrides(&mut config, cli);
+    validate_config(&config)?;
+    Ok(config)
+}
+
+// --bind takes over the HTTP listener; the HTTPS one (with [ssl] enabled)
+// follows it onto the same IP
+fn apply_cli_overrides(config: &mut Config, cli: &Cli) {
+    if let Some(bind) = cli.bind {
+        config.server.bind_address = bind.ip().to_string();
+        config.server.http_port = bind.port();
+        config.server.listeners.clear();
+    }
+    if let Some(static_dir) = &cli.static_dir {
+        config.server.static_dir = static_dir.clone();
     }
 }
 
-async fn generate_self_signed_cert(cert_path: &str, key_path: &str) {
-    use std::process::Command;
+fn validate_config(config: &Config) -> anyhow::Result<()> {
+    use anyhow::bail;
     
-    // Create directories if they don't exist
-    if let Some(parent) = PathBuf::from(cert_path).parent() {
-        std::fs::create_dir_all(parent).ok();
-    }
-    if let Some(parent) = PathBuf::from(key_path).parent() {
-        std::fs::create_dir_all(parent).ok();
-    }
-    
-    // Generate self-signed certificate using openssl
-    let output = Command::new("openssl")
-        .args(&[
-            "req", "-x509", "-newkey", "rsa:4096",
-            "-keyout", key_path,
-            "-out", cert_path,
-            "-days", "365",
-            "-nodes",
-            "-subj", "/C=US/ST=State/L=City/O=Organization/CN=localhost"
-        ])
-        .output();
-    
-    match output {
-        Ok(output) => {
-            if output.status.success() {
-                info!("Generated self-signed certificate successfully");
-            } else {
-                error!("Failed to generate certificate: {}", String::from_utf8_lossy(&output.stderr));
+    if config.server.bind_address.parse::<std::net::IpAddr>().is_err() {
+        bail!("invalid bind_address {:?}", config.server.bind_address);
+    }
+    for (name, backend) in &config.backends {
+        if let Some(target) = &backend.target {
+            match reqwest::Url::parse(target) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => bail!("backend {} has invalid target {:?}", name, target),
             }
         }
-        Err(e) => {
-            error!("Failed to run openssl: {}", e);
+        backend.request_headers.validate()
+            .and_then(|_| backend.response_headers.validate())
+            .map_err(|e| anyhow::anyhow!("backend {}: {}", name, e))?;
+        if backend.max_request_size == Some(0) {
+            bail!("backend {}: max_request_size must be greater than zero", name);
+        }
+        for upstream in &backend.upstreams {
+            match reqwest::Url::parse(upstream) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => bail!("backend {} has invalid upstream {:?}", name, upstream),
+            }
+        }
+        if !backend.upstreams.is_empty() && backend.target.is_some() {
+            bail!("backend {} sets both target and upstreams", name);
+        }
+        if backend.weights.len() > backend.upstreams.len() {
+            bail!("backend {} has more weights than upstreams", name);
+        }
+        if let Some(discovery) = &backend.discovery {
+            if backend.target.is_some() || !backend.upstreams.is_empty() {
+                bail!("backend {} sets discovery along with target or upstreams", name);
+            }
+            discovery.validate().map_err(|e| anyhow::anyhow!("backend {}: {}", name, e))?;
+        }
+        if backend.fastcgi.is_some() && (backend.target.is_some() || !backend.upstreams.is_empty() || backend.discovery.is_some()) {
+            bail!("backend {} sets fastcgi along with target, upstreams or discovery", name);
+        }
+        if let Some(breaker) = &backend.circuit_breaker {
+            breaker.validate().map_err(|e| anyhow::anyhow!("backend {}: {}", name, e))?;
+        }
+        if let Some(mirror) = &backend.mirror {
+            mirror.validate().map_err(|e| anyhow::anyhow!("backend {}: {}", name, e))?;
+        }
+        if let Some(auth) = &backend.auth {
+            auth.validate().map_err(|e| anyhow::anyhow!("backend {}: {}", name, e))?;
         }
+        sub_filter::validate(&backend.response_substitutions)
+            .map_err(|e| anyhow::anyhow!("backend {}: {}", name, e))?;
     }
+    config.server.response_headers.validate()
+        .map_err(|e| anyhow::anyhow!("server.response_headers: {}", e))?;
+    config.telemetry.validate()?;
+    config.admin_allowlist.validate()?;
+    if let Some(auth) = &config.websocket.publish_auth {
+        auth.validate().map_err(|e| anyhow::anyhow!("websocket.publish_auth: {}", e))?;
+    }
+    for proxy_server in &config.proxy_servers {
+        proxy_server.validate().map_err(|e| anyhow::anyhow!("proxy_servers: {}", e))?;
+    }
+    if config.metrics.per_route && config.metrics.max_series == 0 {
+        bail!("metrics max_series must be greater than zero");
+    }
+    if config.proxy.max_request_size == 0 || config.proxy.max_response_size == 0 {
+        bail!("proxy size limits must be greater than zero");
+    }
+    config.compression.validate()?;
+    let listeners = listener_configs(config);
+    validate_listeners(&listeners)?;
+    
+    if listeners.iter().any(|listener| listener.tls) {
+        let (Some(cert_path), Some(key_path)) = (&config.ssl.cert_path, &config.ssl.key_path) else {
+            bail!("TLS is enabled but ssl.cert_path and ssl.key_path are not both set");
+        };
+        // Both missing is fine, a self-signed pair is generated at startup
+        match (PathBuf::from(cert_path).exists(), PathBuf::from(key_path).exists()) {
+            (true, false) => bail!("ssl.key_path {} does not exist (ssl.cert_path does)", key_path),
+            (false, true) => bail!("ssl.cert_path {} does not exist (ssl.key_path does)", cert_path),
+            _ => {}
+        }
+    }
+    Ok(())
 }
 
-async fn api_status(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    axum::Json(serde_json::json!({
-        "status": "running",
-        "version": "1.0.0",
-        "server": "miwidothttp",
-        "config": {
-            "http_port": state.config.server.http_port,
-            "https_port": state.config.server.https_port,
-            "ssl_enabled": state.config.ssl.enabled,
-            "backends_configured": state.config.backends.len(),
+// Every listener address parses, and no two of them claim the same socket.
+// An unspecified address (0.0.0.0, [::]) takes the port on every interface.
+fn validate_listeners(listeners: &[ListenerConfig]) -> anyhow::Result<()> {
+    use anyhow::bail;
+    
+    let mut tcp: Vec<SocketAddr> = Vec::new();
+    let mut unix: Vec<&str> = Vec::new();
+    for listener in listeners {
+        if let Some(path) = listener.address.strip_prefix("unix:") {
+            if listener.tls {
+                bail!("listener {}: TLS is not supported on unix sockets", listener.address);
+            }
+            if unix.contains(&path) {
+                bail!("listener {} is configured twice", listener.address);
+            }
+            unix.push(path);
+            continue;
         }
-    }))
+        
+        let addr: SocketAddr = match listener.address.parse() {
+            Ok(addr) => addr,
+            Err(_) => bail!("invalid listener address {:?}, expected ip:port or unix:/path", listener.address),
+        };
+        let clash = tcp.iter().find(|other| {
+            other.port() == addr.port()
+                && (other.ip() == addr.ip() || other.ip().is_unspecified() || addr.ip().is_unspecified())
+        });
+        if let Some(other) = clash {
+            bail!("listeners {} and {} use the same port", other, addr);
+        }
+        tcp.push(addr);
+    }
+    Ok(())
 }
 
-async fn list_backends(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let backends: Vec<_> = state.config.backends.iter()
-        .map(|(name, config)| {
-            serde_json::json!({
-                "name": name,
-                "target": config.target,
-                "health_check": config.health_check,
-            })
-        })
-        .collect();
+// Re-read the config file and swap it in. Settings that are wired up once at
+// startup (listeners, TLS, sessions, health checks) keep their current values.
+async fn reload_config(state: &AppState) -> anyhow::Result<()> {
+    use anyhow::{anyhow, Context};
     
-    axum::Json(serde_json::json!({
-        "backends": backends
-    }))
+    let path = state.config_path.as_ref()
+        .ok_or_else(|| anyhow!("no config file loaded, running with defaults"))?;
+    let content = fs::read_to_string(path).await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let mut config = parse_config(&content, &state.cli)
+        .with_context(|| format!("invalid config file {}", path.display()))?;
+    
+    let current = state.config.load_full();
+    if changed(&config.server, &current.server) {
+        warn!("Changes to [server] require a restart and were ignored");
+    }
+    if changed(&config.ssl, &current.ssl) {
+        warn!("Changes to [ssl] require a restart and were ignored");
+    }
+    if changed(&config.session, &current.session) {
+        warn!("Changes to [session] require a restart and were ignored");
+    }
+    if changed(&config.health_check, &current.health_check) {
+        warn!("Changes to [health_check] require a restart and were ignored");
+    }
+    if changed(&config.errors, &current.errors) {
+        warn!("Changes to [errors] require a restart and were ignored");
+    }
+    if changed(&config.compression, &current.compression) {
+        warn!("Changes to [compression] require a restart and were ignored");
+    }
+    if changed(&config.websocket, &current.websocket) {
+        warn!("Changes to [websocket] require a restart and were ignored");
+    }
+    
+    config.server = current.server.clone();
+    config.ssl = current.ssl.clone();
+    config.session = current.session.clone();
+    config.health_check = current.health_check.clone();
+    config.errors = current.errors.clone();
+    config.security = current.security.clone();
+    config.compression = current.compression.clone();
+    config.websocket = current.websocket.clone();
+    config.processes = current.processes.clone();
+    
+    if changed(&config.maintenance, &current.maintenance) {
+        state.maintenance.configure(config.maintenance.clone());
+    }
+    if changed(&config.admin_allowlist, &current.admin_allowlist) {
+        state.admin_allowlist.configure(config.admin_allowlist.clone());
+    }
+    state.health_checker.set_targets(health_targets(&config)).await;
+    state.config.store(Arc::new(config));
+    info!("Reloaded configuration from {}", path.display());
+    Ok(())
 }
 
-async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let metrics = state.metrics.get_prometheus_metrics().await;
-    (StatusCode::OK, metrics)
+fn changed<T: Serialize>(new: &T, old: &T) -> bool {
+    serde_json::to_value(new).ok() != serde_json::to_value(old).ok()
 }
 
-async fn list_processes(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let statuses = state.process_manager.get_status().await;
+fn watch_config(state: Arc<AppState>, path: PathBuf) -> anyhow::Result<()> {
+    use notify::{RecursiveMode, Watcher};
+    
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            let relevant = (event.kind.is_modify() || event.kind.is_create())
+                && event.paths.iter().any(|p| p.file_name() == file_name.as_deref());
+            if relevant {
+                let _ = tx.blocking_send(());
+            }
+        }
+    })?;
+    
+    // Watch the directory so editors that save by renaming are still seen
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    info!("Watching {} for changes", path.display());
+    
+    tokio::spawn(async move {
+        // Owned by the task so it lives as long as the server
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            // Editors often write in several steps; let them settle
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            while rx.try_recv().is_ok() {}
+            
+            if let Err(e) = reload_config(&state).await {
+                error!("Config reload failed, keeping previous config: {:#}", e);
+            }
+        }
+    });
+    
+    Ok(())
+}
+
+async fn reload_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match reload_config(&state).await {
+        Ok(()) => (StatusCode::OK, axum::Json(serde_json::json!({
+            "status": "reloaded"
+        }))),
+        Err(e) => (StatusCode::BAD_REQUEST, axum::Json(serde_json::json!({
+            "status": "error",
+            "error": format!("{:#}", e)
+        }))),
+    }
+}
+
+// localhost, the bind address (unless it's a wildcard) and configured domains
+fn self_signed_names(config: &Config) -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    
+    if let Ok(ip) = config.server.bind_address.parse::<std::net::IpAddr>() {
+        if !ip.is_unspecified() {
+            names.push(ip.to_string());
+        }
+    }
+    
+    names.extend(config.ssl.domains.iter().cloned());
+    // Drop repeats wherever they are, keeping the order (the first name is
+    // the certificate's CN)
+    let mut seen = std::collections::HashSet::new();
+    names.retain(|name| seen.insert(name.clone()));
+    names
+}
+
+async fn generate_self_signed_cert(
+    cert_path: &str,
+    key_path: &str,
+    subject_alt_names: Vec<String>,
+    validity_days: u32,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use rcgen::{CertificateParams, DnType, KeyPair};
+    use tokio::io::AsyncWriteExt;
+    
+    let mut params = CertificateParams::new(subject_alt_names.clone())
+        .context("invalid subject alternative name")?;
+    params.distinguished_name.push(DnType::CommonName, subject_alt_names[0].clone());
+    params.distinguished_name.push(DnType::OrganizationName, "miwidothttp");
+    
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now + time::Duration::days(validity_days as i64);
+    
+    let key_pair = KeyPair::generate().context("failed to generate key pair")?;
+    let cert = params.self_signed(&key_pair).context("failed to sign certificate")?;
+    
+    // Create directories if they don't exist
+    for path in [cert_path, key_path] {
+        if let Some(parent) = PathBuf::from(path).parent() {
+            fs::create_dir_all(parent).await
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+    }
+    
+    fs::write(cert_path, cert.pem()).await
+        .with_context(|| format!("failed to write {}", cert_path))?;
+    // The key is private from the moment the file exists
+    let mut key_options = fs::OpenOptions::new();
+    key_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    key_options.mode(0o600);
+    let mut key_file = key_options.open(key_path).await
+        .with_context(|| format!("failed to create {}", key_path))?;
+    key_file.write_all(key_pair.serialize_pem().as_bytes()).await
+        .with_context(|| format!("failed to write {}", key_path))?;
+    
+    info!("Generated self-signed certificate for {} (valid {} days)", subject_alt_names.join(", "), validity_days);
+    Ok(())
+}
+
+async fn api_status(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let config = state.config.load();
+    axum::Json(serde_json::json!({
+        "status": "running",
+        "version": "1.0.0",
+        "server": "miwidothttp",
+        "config": {
+            "http_port": config.server.http_port,
+            "https_port": config.server.https_port,
+            "ssl_enabled": config.ssl.enabled,
+            "backends_configured": config.backends.len(),
+        }
+    }))
+}
+
+// The configuration the server is running with, defaults filled in and
+// secrets masked
+async fn api_config(State(state): State<Arc<AppState>>) -> Response {
+    let config = state.config.load();
+    let mut value = match serde_json::to_value(&**config) {
+        Ok(value) => value,
+        Err(e) => {
+            error!("Failed to serialize config: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // Not read from the config file, so it would otherwise be missing
+    value["processes"] = serde_json::to_value(&config.processes).unwrap_or_default();
+    redact::redact_secrets(&mut value);
+    
     axum::Json(serde_json::json!({
-        "processes": statuses
+        "path": state.config_path,
+        "config": value,
+    })).into_response()
+}
+
+async fn list_backends(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let health = state.health_checker.snapshot().await;
+    let config = state.config.load_full();
+    let backends: Vec<_> = config.backends.iter()
+        .map(|(name, config)| {
+            let status = health.get(name);
+            serde_json::json!({
+                "name": name,
+                "target": config.target,
+                "upstreams": state.discovery.pool(name)
+                    .map(|pool| pool.upstreams())
+                    .or_else(|| (!config.upstreams.is_empty()).then(|| config.upstreams.clone())),
+                "circuit_breaker": state.circuit_breakers.stats(name),
+                "health_check": config.health_check,
+                "healthy": status.map_or(true, |h| h.healthy),
+                "health": status,
+            })
+        })
+        .collect();
+    
+    axum::Json(serde_json::json!({
+        "backends": backends
+    }))
+}
+
+async fn metrics(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    // Prometheus text stays the default; JSON only when explicitly asked for
+    let wants_json = headers.get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |accept| accept.contains("application/json"));
+
+    if wants_json {
+        return metrics_json(State(state)).await.into_response();
+    }
+
+    let metrics = state.metrics.get_prometheus_metrics().await;
+    (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics).into_response()
+}
+
+async fn metrics_json(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    axum::Json(state.metrics.get_json_metrics().await)
+}
+
+async fn list_processes(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let processes = state.process_manager.get_details().await;
+    axum::Json(serde_json::json!({
+        "processes": processes
     }))
 }
 
+#[derive(Deserialize)]
+struct RestartParams {
+    #[serde(default)]
+    graceful: bool,
+}
+
 async fn restart_process(
     State(state): State<Arc<AppState>>,
     axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::Query(params): axum::extract::Query<RestartParams>,
 ) -> impl IntoResponse {
+    if params.graceful {
+        return match state.process_manager.restart_process_graceful(&name).await {
+            Ok(port) => (StatusCode::OK, axum::Json(serde_json::json!({
+                "status": "success",
+                "message": format!("Process {} restarted gracefully", name),
+                "port": port
+            }))),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({
+                "status": "error",
+                "message": format!("Graceful restart failed, previous instance kept: {}", e)
+            })))
+        };
+    }
+    
     match state.process_manager.restart_process(&name).await {
         Ok(_) => (StatusCode::OK, axum::Json(serde_json::json!({
             "status": "success",
@@ -462,106 +1467,383 @@ async fn restart_process(
     }
 }
 
+async fn stop_process(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    match state.process_manager.stop_process(&name).await {
+        Ok(_) => (StatusCode::OK, axum::Json(serde_json::json!({
+            "status": "success",
+            "message": format!("Process {} stopped", name)
+        }))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({
+            "status": "error",
+            "message": format!("Failed to stop process: {}", e)
+        })))
+    }
+}
+
+async fn start_process(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    match state.process_manager.start_stopped_process(&name).await {
+        Ok(_) => (StatusCode::OK, axum::Json(serde_json::json!({
+            "status": "success",
+            "message": format!("Process {} started", name)
+        }))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, axum::Json(serde_json::json!({
+            "status": "error",
+            "message": format!("Failed to start process: {}", e)
+        })))
+    }
+}
+
+// Base URL requests for a backend are sent to
+fn backend_target(backend: &BackendConfig) -> Option<String> {
+    if let Some(ref target) = backend.target {
+        Some(target.clone())
+    } else {
+        backend.process.as_ref()
+            .map(|process| format!("http://localhost:{}", process.port))
+    }
+}
+
+// Backends with a health_check path; each of a backend's `upstreams` is
+// checked on its own
+fn health_targets(config: &Config) -> Vec<HealthTarget> {
+    config.backends.iter()
+        .filter(|(_, backend)| backend.health_check.is_some())
+        .flat_map(|(name, backend)| {
+            let path = backend.health_check.clone().unwrap_or_default();
+            let targets: Vec<HealthTarget> = if backend.upstreams.is_empty() {
+                backend_target(backend)
+                    .map(|base_url| HealthTarget {
+                        name: name.clone(),
+                        base_url,
+                        path,
+                        process: backend.target.is_none().then(|| name.clone()),
+                    })
+                    .into_iter()
+                    .collect()
+            } else {
+                backend.upstreams.iter()
+                    .map(|url| HealthTarget { name: upstream_health_name(name, url), base_url: url.clone(), path: path.clone(), process: None })
+                    .collect()
+            };
+            targets
+        })
+        .collect()
+}
+
+// Health state key for one of a backend's `upstreams`
+fn upstream_health_name(backend: &str, upstream: &str) -> String {
+    format!("{} ({})", backend, upstream)
+}
+
+// One of the backend's `upstreams` by its strategy, leaving out those that
+// fail their health check; None when every one of them does. With the
+// sticky strategy a client stays on the upstream its cookie names.
+async fn select_upstream(
+    state: &AppState,
+    name: &str,
+    backend: &BackendConfig,
+    client_ip: Option<std::net::IpAddr>,
+    headers: &HeaderMap,
+) -> Option<String> {
+    let mut candidates = Vec::with_capacity(backend.upstreams.len());
+    for (i, upstream) in backend.upstreams.iter().enumerate() {
+        if state.health_checker.is_healthy(&upstream_health_name(name, upstream)).await {
+            candidates.push((upstream.as_str(), backend.weights.get(i).copied().unwrap_or(1)));
+        }
+    }
+    if backend.strategy == LoadBalanceStrategy::Sticky {
+        if let Some(pinned) = backend.sticky.pinned(headers, &candidates) {
+            return Some(pinned.to_string());
+        }
+    }
+    state.load_balancer.select(name, backend.strategy, &candidates, client_ip).map(str::to_string)
+}
+
+// Headers for a single hop that must not be forwarded, least of all onto an
+// HTTP/2 backend connection. Trailer is end-to-end and passes through; upgrade
+// requests get Connection and Upgrade back in send_to_backend.
+fn is_hop_by_hop(name: &header::HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection" | "keep-alive" | "proxy-connection" | "te" | "transfer-encoding" | "upgrade"
+    )
+}
+
+// Append the client to any X-Forwarded-For chain it sent
+fn forwarded_for(headers: &HeaderMap, client_ip: Option<std::net::IpAddr>) -> Option<String> {
+    let existing = headers.get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    match (existing.is_empty(), client_ip) {
+        (true, None) => None,
+        (true, Some(ip)) => Some(ip.to_string()),
+        (false, None) => Some(existing),
+        (false, Some(ip)) => Some(format!("{}, {}", existing, ip)),
+    }
+}
+
 async fn proxy_handler(
     Host(host): Host,
     State(state): State<Arc<AppState>>,
+    client_addr: Option<Extension<ClientAddr>>,
     req: Request<Body>,
-) -> impl IntoResponse {
+) -> Response {
+    let config = state.config.load_full();
+    let is_static = !config.backends.contains_key(&host);
+    
+    let mut response = route_request(host.clone(), state, client_addr, req).await;
+    // Backend responses get their backend's rules inside route_request
+    if is_static {
+        config.server.response_headers.apply(response.headers_mut());
+    }
+    response.extensions_mut().insert(VirtualHost(host));
+    response
+}
+
+// Apply the host's request size limit (or the global one) to every request,
+// so no handler can be made to buffer an unbounded body
+async fn request_size_middleware(
+    State(state): State<Arc<AppState>>,
+    host: Option<Host>,
+    req: Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let config = state.config.load();
+    let host = host.map(|Host(host)| host).unwrap_or_default();
+    let limit = config.backends.get(&host)
+        .and_then(|backend| backend.max_request_size)
+        .unwrap_or(config.proxy.max_request_size);
+    match body_limit::limit_request(req, limit) {
+        Ok(req) => next.run(req).await,
+        Err(response) => {
+            warn!("Request body for {} declared over the {} byte limit", host, limit);
+            response
+        }
+    }
+}
+
+// Challenge requests to hosts with `auth` set unless they carry valid
+// credentials; a JWT's claims go on to the handlers as JwtClaims
+async fn backend_auth_middleware(
+    State(state): State<Arc<AppState>>,
+    host: Option<Host>,
+    mut req: Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let host = host.map(|Host(host)| host).unwrap_or_default();
+    // A clone shares the JWKS cache, and keeps the config unpinned while the
+    // keys are fetched
+    let auth = state.config.load().backends.get(&host).and_then(|backend| backend.auth.clone());
+    let Some(auth) = auth else {
+        return next.run(req).await;
+    };
+    match auth.authorize(req.headers()).await {
+        Ok(Some(claims)) => {
+            req.extensions_mut().insert(claims);
+            next.run(req).await
+        }
+        Ok(None) => next.run(req).await,
+        Err(mut challenge) => {
+            challenge.extensions_mut().insert(VirtualHost(host));
+            challenge
+        }
+    }
+}
+
+async fn route_request(
+    host: String,
+    state: Arc<AppState>,
+    client_addr: Option<Extension<ClientAddr>>,
+    req: Request<Body>,
+) -> Response {
+    let config = state.config.load_full();
+    
+    // Hosts without a backend are served from static_dir, through try_files
+    // when configured; a named try_files fallback hands off to that backend
+    let mut backend_name = host.clone();
+    if !config.backends.contains_key(&host) {
+        if let Some(try_files) = &config.server.try_files {
+            match try_files.resolve(&state.static_dir, req.uri().path()) {
+                TryFilesResult::File(path) => return state.static_cache.serve_precompressed(&state.static_dir, &path, req.headers()).await,
+                TryFilesResult::Rewrite(uri) => {
+                    let path = uri.split('?').next().unwrap_or_default();
+                    return match resolve_static_path(&state.static_dir, path) {
+                        StaticPath::Found(file_path) if file_path.is_file() => state.static_cache.serve_precompressed(&state.static_dir, &file_path, req.headers()).await,
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("404 Not Found"))
+                            .unwrap(),
+                    };
+                }
+                TryFilesResult::Named(name) if config.backends.contains_key(&name) => backend_name = name,
+                TryFilesResult::Named(name) => {
+                    warn!("try_files fallback names unknown backend @{}", name);
+                    return Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(Body::from("No backend target configured"))
+                        .unwrap();
+                }
+                TryFilesResult::Status(status) => {
+                    return Response::builder()
+                        .status(status)
+                        .body(Body::empty())
+                        .unwrap();
+                }
+            }
+        }
+    }
+    
     // Check if this host has a configured backend
-    let backend = state.config.backends.get(&host);
+    let backend = config.backends.get(&backend_name);
     
     if let Some(backend_config) = backend {
-        // Get the target URL - either from direct target or from process config
-        let target = if let Some(ref target_str) = backend_config.target {
-            target_str.clone()
-        } else if let Some(ref process_config) = backend_config.process {
-            format!("http://localhost:{}", process_config.port)
-        } else {
-            return Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(Body::from("No backend target configured"))
-                .unwrap();
-        };
-        
-        // Proxy the request to the backend
-        let target_url = format!("{}{}", target, req.uri().path());
-        
-        info!("Proxying request from {} to {}", host, target_url);
-        
-        // Create proxy request
-        let method = req.method().clone();
-        let headers = req.headers().clone();
-        let body_bytes = match axum::body::to_bytes(req.into_body(), usize::MAX).await {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                error!("Failed to read request body: {}", e);
+        let client_ip = client_addr.map(|Extension(ClientAddr(addr))| addr.ip());
+        if let Some(access) = &backend_config.access {
+            // Clients of unknown address can't be matched, so they're refused too
+            if !client_ip.map_or(false, |ip| access.permits(ip)) {
+                warn!("Refused {:?} access to {}", client_ip, host);
                 return Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Body::from("Failed to read request body"))
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("403 Forbidden"))
                     .unwrap();
             }
+        }
+        
+        if let Some(fastcgi) = &backend_config.fastcgi {
+            return match FastCGIProxy::new(fastcgi.clone()).handle_request(req).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("FastCGI request for {} failed: {}", host, e);
+                    Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(Body::from("Bad Gateway"))
+                        .unwrap()
+                }
+            };
+        }
+        
+        let target = match backend_upstream(&state, &backend_name, backend_config, client_ip, req.headers()).await {
+            Ok(target) => target,
+            Err(response) => return response,
         };
         
-        // Build the proxy request
-        let mut proxy_req = state.http_client
-            .request(method, &target_url)
-            .body(body_bytes.to_vec());
+        // Proxy the request to the backend
+        let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        let target_url = format!("{}{}", target.trim_end_matches('/'), path_and_query);
+        match client_ip {
+            Some(ip) => info!("Proxying request from {} ({}) to {}", host, ip, target_url),
+            None => info!("Proxying request from {} to {}", host, target_url),
+        }
+        // The affinity cookie is checked against whichever upstream answers
+        let sticky_headers = (backend_config.strategy == LoadBalanceStrategy::Sticky).then(|| req.headers().clone());
         
-        // Copy headers (except Host)
-        for (name, value) in headers.iter() {
-            if name != "host" {
-                proxy_req = proxy_req.header(name, value);
+        let limits = &config.proxy;
+        let (mut parts, body) = req.into_parts();
+        let body = if backend_config.decompress_requests {
+            let limit = backend_config.max_request_size.unwrap_or(limits.max_request_size);
+            match request_decompression::decompress_body(&mut parts.headers, body, limit).await {
+                Ok(body) => body,
+                Err(response) => return response,
             }
-        }
+        } else {
+            body
+        };
+        let body = match &backend_config.mirror {
+            Some(mirror) => match mirror::tee(&state.http_client, mirror, &parts, body).await {
+                Ok(body) => body,
+                Err(e) if body_limit::is_limit_exceeded(&e) => return body_limit::payload_too_large(),
+                Err(e) => {
+                    warn!("Failed to read request body for {}: {}", host, e);
+                    return Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from("Bad request body"))
+                        .unwrap();
+                }
+            },
+            None => body,
+        };
+        let cacheable = backend_config.cache
+            && ResponseCache::is_cacheable_request(&parts.method, &parts.headers)
+            && !proxy_client::is_upgrade_request(&parts.headers);
+        let (mut response, target) = if cacheable {
+            let key = ResponseCache::key(&host, &parts.uri);
+            let request_headers = parts.headers.clone();
+            // The fetch may run after this request has been answered, when a
+            // stale entry is revalidated in the background
+            let fetch_state = Arc::clone(&state);
+            let fetch_config = Arc::clone(&config);
+            let fetch_backend = backend_name.clone();
+            let fetch_target = target.clone();
+            let response = state.response_cache.get_or_fetch(&key, &request_headers, move |headers| async move {
+                let mut parts = parts;
+                parts.headers = headers;
+                let backend_config = &fetch_config.backends[&fetch_backend];
+                forward_with_retries(&fetch_state, &fetch_backend, backend_config, &fetch_config.proxy, fetch_target, client_ip, parts, body).await.0
+            }).await;
+            (response, target)
+        } else {
+            forward_with_retries(&state, &backend_name, backend_config, limits, target, client_ip, parts, body).await
+        };
         
-        // Send the request
-        match proxy_req.send().await {
-            Ok(resp) => {
-                let status = StatusCode::from_u16(resp.status().as_u16()).unwrap();
-                let headers = resp.headers().clone();
-                let body = match resp.bytes().await {
-                    Ok(bytes) => Body::from(bytes),
-                    Err(e) => {
-                        error!("Failed to read response body: {}", e);
-                        Body::from("Failed to read response from backend")
-                    }
-                };
-                
-                let mut response = Response::builder().status(status);
-                
-                // Copy response headers
-                for (name, value) in headers.iter() {
-                    response = response.header(name, value);
+        // Pin new sticky clients, and re-pin those whose upstream went away
+        if let Some(headers) = sticky_headers {
+            if backend_config.sticky.pinned(&headers, &[(target.as_str(), 1)]).is_none() {
+                if let Ok(cookie) = HeaderValue::from_str(&backend_config.sticky.cookie(&target)) {
+                    response.headers_mut().append(header::SET_COOKIE, cookie);
                 }
-                
-                response.body(body).unwrap()
-            }
-            Err(e) => {
-                error!("Failed to proxy request: {}", e);
-                Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
-                    .body(Body::from(format!("Backend unavailable: {}", e)))
-                    .unwrap()
             }
         }
+        response
     } else {
-        // No backend configured for this host, serve from static with cache
+        // No backend configured for this host, serve from static with cache.
+        // The path is decoded and canonicalized so nothing outside
+        // static_dir can be reached, whether by ../ or by symlink.
         let path = req.uri().path();
-        let file_path = state.static_dir.join(path.trim_start_matches('/'));
+        let file_path = match resolve_static_path(&state.static_dir, path) {
+            StaticPath::Found(file_path) => Some(file_path),
+            StaticPath::Missing => None,
+            StaticPath::Forbidden => {
+                warn!("Blocked static path outside {}: {}", state.static_dir.display(), path);
+                return Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("403 Forbidden"))
+                    .unwrap();
+            }
+        };
         
-        if file_path.exists() && file_path.is_file() {
+        if let Some(file_path) = file_path.as_ref().filter(|p| p.is_file()) {
             // Use cached file serving
-            state.static_cache.serve_file(&file_path).await
+            state.static_cache.serve_precompressed(&state.static_dir, file_path, req.headers()).await
         } else {
-            // Try index.html for directories
-            let index_path = if file_path.is_dir() {
-                file_path.join("index.html")
+            // Try index.html for directories, then the root index.html
+            let directory = file_path.filter(|p| p.is_dir());
+            let index_path = if directory.is_some() {
+                format!("{}/index.html", path.trim_end_matches('/'))
             } else {
-                state.static_dir.join("index.html")
+                "/index.html".to_string()
             };
             
-            if index_path.exists() {
-                state.static_cache.serve_file(&index_path).await
+            if let StaticPath::Found(index_path) = resolve_static_path(&state.static_dir, &index_path) {
+                state.static_cache.serve_precompressed(&state.static_dir, &index_path, req.headers()).await
+            } else if let Some(directory) = directory.filter(|_| config.server.autoindex) {
+                match autoindex::render_listing(&directory, path).await {
+                    Ok(listing) => Html(listing).into_response(),
+                    Err(e) => {
+                        warn!("Failed to list {}: {}", directory.display(), e);
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("404 Not Found"))
+                            .unwrap()
+                    }
+                }
             } else {
                 Response::builder()
                     .status(StatusCode::NOT_FOUND)
@@ -570,4 +1852,840 @@ async fn proxy_handler(
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// Where to send a request for this backend: a discovered or load-balanced
+// upstream, the direct target or the managed process, whose port moves
+// after a graceful restart. Err is the response to answer with instead.
+async fn backend_upstream(
+    state: &AppState,
+    backend_name: &str,
+    backend_config: &BackendConfig,
+    client_ip: Option<std::net::IpAddr>,
+    headers: &HeaderMap,
+) -> Result<String, Response> {
+    let health_interval = state.config.load().health_check.interval_seconds;
+    let target = if backend_config.discovery.is_some() {
+        state.discovery.select_upstream(backend_name)
+    } else if !backend_config.upstreams.is_empty() {
+        select_upstream(state, backend_name, backend_config, client_ip, headers).await
+    } else {
+        let managed_port = match backend_config.target {
+            Some(_) => None,
+            None => state.process_manager.active_port(backend_name).await,
+        };
+        managed_port
+            .map(|port| format!("http://localhost:{}", port))
+            .or_else(|| backend_target(backend_config))
+    };
+    let target = match target {
+        Some(target) => target,
+        None if backend_config.discovery.is_some() => {
+            warn!("No upstreams discovered yet for {}", backend_name);
+            let retry_after = backend_config.discovery.as_ref().map_or(0, |d| d.interval_seconds);
+            return Err(backend_unavailable(retry_after));
+        }
+        None if !backend_config.upstreams.is_empty() => {
+            warn!("No healthy upstream for {}", backend_name);
+            return Err(backend_unavailable(health_interval));
+        }
+        None => {
+            return Err(Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::from("No backend target configured"))
+                .unwrap());
+        }
+    };
+    
+    if !state.health_checker.is_healthy(backend_name).await {
+        warn!("Backend for {} is unhealthy, rejecting request", backend_name);
+        return Err(backend_unavailable(health_interval));
+    }
+    Ok(target)
+}
+
+// forward_to_backend, tried again per the backend's [retry] after a failure
+// or a 502/503/504, each time on a freshly picked upstream. A retried
+// request can't stream its body: it is buffered (up to max_request_size)
+// so it can be replayed. Requests that aren't retried still stream
+// straight through. Also returns the upstream that gave the final answer.
+async fn forward_with_retries(
+    state: &AppState,
+    backend_name: &str,
+    backend_config: &BackendConfig,
+    limits: &ProxySettings,
+    mut target: String,
+    client_ip: Option<std::net::IpAddr>,
+    parts: axum::http::request::Parts,
+    body: Body,
+) -> (Response, String) {
+    let path_and_query = parts.uri.path_and_query().map_or("/", |pq| pq.as_str()).to_string();
+    let target_url = |target: &str| format!("{}{}", target.trim_end_matches('/'), path_and_query);
+    // Counted until the response headers are in, for least_conn
+    let track = |target: &str| (!backend_config.upstreams.is_empty()).then(|| state.load_balancer.track(target));
+    
+    // An upgrade can't be replayed once the client connection is handed over
+    let retry = backend_config.retry.as_ref()
+        .filter(|retry| retry.attempts > 0 && retry.allows(&parts.method))
+        .filter(|_| !proxy_client::is_upgrade_request(&parts.headers));
+    let Some(retry) = retry else {
+        let _in_flight = track(&target);
+        let response = forward_to_backend(state, backend_name, backend_config, limits, &target_url(&target), client_ip, parts, body).await;
+        return (response, target);
+    };
+    
+    let limit = backend_config.max_request_size.unwrap_or(limits.max_request_size);
+    let body = match axum::body::to_bytes(body, limit as usize).await {
+        Ok(body) => body,
+        Err(e) if body_limit::is_limit_exceeded(&e) => return (body_limit::payload_too_large(), target),
+        Err(e) => {
+            warn!("Failed to read request body for {}: {}", backend_name, e);
+            let response = Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Bad request body"))
+                .unwrap();
+            return (response, target);
+        }
+    };
+    
+    let mut delay = Duration::from_millis(retry.delay_ms);
+    let mut attempt = 0;
+    loop {
+        let response = {
+            let _in_flight = track(&target);
+            let parts = parts.clone();
+            forward_to_backend(state, backend_name, backend_config, limits, &target_url(&target), client_ip, parts, Body::from(body.clone())).await
+        };
+        if attempt >= retry.attempts || !proxy_client::is_retryable_status(response.status()) {
+            return (response, target);
+        }
+        attempt += 1;
+        warn!("{} answered {} for {}, retrying ({}/{})", target, response.status(), backend_name, attempt, retry.attempts);
+        
+        tokio::time::sleep(delay).await;
+        if retry.backoff {
+            delay *= 2;
+        }
+        target = match backend_upstream(state, backend_name, backend_config, client_ip, &parts.headers).await {
+            Ok(next) => next,
+            Err(unavailable) => return (unavailable, target),
+        };
+    }
+}
+
+// send_to_backend, noting the upstream and how long it took to answer for
+// the access log. With a circuit breaker, an upstream whose circuit is open
+// isn't tried at all, and 5xx answers and failures count against it.
+async fn forward_to_backend(
+    state: &AppState,
+    backend_name: &str,
+    backend_config: &BackendConfig,
+    limits: &ProxySettings,
+    target_url: &str,
+    client_ip: Option<std::net::IpAddr>,
+    parts: axum::http::request::Parts,
+    body: Body,
+) -> Response {
+    let addr = target_url.parse::<axum::http::Uri>().ok()
+        .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+        .unwrap_or_else(|| target_url.to_string());
+    let permit = match &backend_config.circuit_breaker {
+        Some(config) => {
+            let breaker = state.circuit_breakers.get(backend_name, &addr, config);
+            match breaker.try_acquire() {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!("Circuit open for {} upstream {}, failing fast", backend_name, addr);
+                    return backend_unavailable(breaker.retry_after().as_secs_f64().ceil() as u64);
+                }
+            }
+        }
+        None => None,
+    };
+    
+    let started = std::time::Instant::now();
+    let mut response = send_to_backend(state, backend_config, limits, target_url, client_ip, parts, body).await;
+    if let Some(permit) = permit {
+        if response.status().is_server_error() {
+            permit.failure();
+        } else {
+            permit.success();
+        }
+    }
+    response.extensions_mut().insert(UpstreamInfo {
+        addr,
+        response_time_ms: started.elapsed().as_millis() as u64,
+    });
+    response
+}
+
+async fn send_to_backend(
+    state: &AppState,
+    backend_config: &BackendConfig,
+    limits: &ProxySettings,
+    target_url: &str,
+    client_ip: Option<std::net::IpAddr>,
+    mut parts: axum::http::request::Parts,
+    body: Body,
+) -> Response {
+    // WebSocket and other upgrades go through to HTTP/1 backends, which
+    // decide whether to switch; h2c has no Upgrade
+    let client_upgrade = match backend_config.protocol {
+        BackendProtocol::Http if proxy_client::is_upgrade_request(&parts.headers) => {
+            parts.extensions.remove::<hyper::upgrade::OnUpgrade>()
+        }
+        _ => None,
+    };
+    
+    // Build the proxy request; the body streams through instead of being buffered
+    let mut proxy_req = match Request::builder().method(parts.method).uri(target_url).body(body) {
+        Ok(proxy_req) => proxy_req,
+        Err(e) => {
+            error!("Invalid backend URL {}: {}", target_url, e);
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::from("Invalid backend target"))
+                .unwrap();
+        }
+    };
+    
+    // Copy headers (except Host and X-Forwarded-For, which is extended below);
+    // the client fills in Host from the target URL
+    let proxy_headers = proxy_req.headers_mut();
+    for (name, value) in parts.headers.iter() {
+        if name != header::HOST && name != "x-forwarded-for" && !is_hop_by_hop(name) {
+            proxy_headers.append(name, value.clone());
+        }
+    }
+    if let Some(forwarded_for) = forwarded_for(&parts.headers, client_ip) {
+        if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+            proxy_headers.insert("x-forwarded-for", value);
+        }
+    }
+    if client_upgrade.is_some() {
+        if let Some(upgrade) = parts.headers.get(header::UPGRADE) {
+            proxy_headers.insert(header::UPGRADE, upgrade.clone());
+            proxy_headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        }
+    }
+    backend_config.request_headers.apply(proxy_headers);
+    // Substitutions need a plain body; the compression layer re-encodes the
+    // result for the client
+    if !backend_config.response_substitutions.is_empty() {
+        proxy_headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    }
+    
+    // Send the request over a pooled backend connection. Bodies without a
+    // Content-Length are cut off once they pass the limits.
+    let client = match backend_config.protocol {
+        BackendProtocol::Http => &state.http_client,
+        BackendProtocol::Grpc => &state.grpc_client,
+    };
+    let max_request_size = backend_config.max_request_size.unwrap_or(limits.max_request_size);
+    let span = telemetry::proxy_span(target_url);
+    telemetry::inject(&span, proxy_req.headers_mut());
+    let sent = proxy_client::send(client, proxy_req, max_request_size, limits.max_response_size, &limits.timeout)
+        .instrument(span.clone())
+        .await;
+    if let Ok(resp) = &sent {
+        span.record("http.response.status_code", resp.status().as_u16());
+    }
+    match sent {
+        Ok(mut resp) if resp.status() == StatusCode::SWITCHING_PROTOCOLS => {
+            let Some(client_upgrade) = client_upgrade else {
+                error!("Backend {} switched protocols unasked", target_url);
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::from("Unexpected protocol switch"))
+                    .unwrap();
+            };
+            // The handshake headers (Sec-WebSocket-Accept, -Protocol, ...)
+            // go back as the backend sent them; the connections are spliced
+            // once the 101 is out
+            let backend_upgrade = hyper::upgrade::on(&mut resp);
+            proxy_client::tunnel(client_upgrade, backend_upgrade, target_url.to_string());
+            let (mut parts, _) = resp.into_parts();
+            backend_config.response_headers.apply(&mut parts.headers);
+            Response::from_parts(parts, Body::empty())
+        }
+        Ok(resp) => {
+            let content_length = resp.headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            if content_length.map_or(false, |len| len > limits.max_response_size) {
+                error!("Backend response from {} exceeds max_response_size", target_url);
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::from("Backend response too large"))
+                    .unwrap();
+            }
+            
+            // Stream the backend body to the client
+            let (mut parts, body) = resp.into_parts();
+            let hop_by_hop: Vec<_> = parts.headers.keys()
+                .filter(|name| is_hop_by_hop(name))
+                .cloned()
+                .collect();
+            for name in hop_by_hop {
+                parts.headers.remove(name);
+            }
+            backend_config.response_headers.apply(&mut parts.headers);
+            let body = sub_filter::apply(&backend_config.response_substitutions, &mut parts.headers, body);
+            
+            Response::from_parts(parts, body)
+        }
+        Err(e) if body_limit::is_limit_exceeded(&e) => {
+            warn!("Request body too large for {}", target_url);
+            body_limit::payload_too_large()
+        }
+        Err(e) => {
+            error!("Failed to proxy request to {}: {}", target_url, e);
+            backend_error(&e)
+        }
+    }
+}
+
+// No healthy upstream right now; Retry-After says when the next health check
+// or discovery round may have found one. The body is bare, like
+// backend_error's, so error_recovery_middleware renders the error page.
+fn backend_unavailable(retry_after_seconds: u64) -> Response {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(header::RETRY_AFTER, retry_after_seconds.max(1))
+        .body(Body::from("Backend unavailable"))
+        .unwrap()
+}
+
+// 504 when the backend timed out, 502 when it couldn't be reached at all
+fn backend_error(error: &SendError) -> Response {
+    let status = error.status();
+    let message = if status == StatusCode::GATEWAY_TIMEOUT { "Backend timed out" } else { "Bad gateway" };
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upstream_failures_get_error_pages() {
+        use tower::ServiceExt;
+
+        let handler = Arc::new(ErrorHandler::new(ErrorConfig::default()).await.unwrap());
+        let refused = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let app = Router::new()
+            .route("/down", get(|| async { backend_unavailable(10) }))
+            .route("/refused", get(move || async move {
+                let timeouts = TimeoutConfig::default();
+                let client = build_client(&ConnectionPoolConfig::default(), &timeouts);
+                let req = Request::get(format!("http://{}/", refused)).body(Body::empty()).unwrap();
+                backend_error(&proxy_client::send(&client, req, 1024, 1024, &timeouts).await.unwrap_err())
+            }))
+            .layer(axum::middleware::from_fn_with_state(handler, error_recovery_middleware));
+
+        let response = app.clone().oneshot(Request::get("/down").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "10");
+        assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+
+        let request = Request::get("/refused").header(header::ACCEPT, "application/json").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    }
+
+    // AppState for `config` with nothing started in the background: no
+    // processes, health check loop, discovery or cluster
+    async fn test_state(config: Config) -> Arc<AppState> {
+        let http_client = build_client(&config.proxy.connection_pool, &config.proxy.timeout);
+        Arc::new(AppState {
+            config: Arc::new(ArcSwap::from_pointee(config.clone())),
+            config_path: None,
+            cli: Cli::default(),
+            static_dir: PathBuf::from(&config.server.static_dir),
+            http_client: http_client.clone(),
+            grpc_client: http_client,
+            process_manager: Arc::new(ProcessManager::new()),
+            rate_limiter: Arc::new(RateLimiter::new(config.security.clone())),
+            session_manager: None,
+            metrics: Arc::new(MetricsCollector::new()),
+            static_cache: Arc::new(StaticCache::new(true)),
+            response_cache: Arc::new(ResponseCache::new(config.cache.clone())),
+            health_checker: Arc::new(HealthChecker::new(config.health_check.clone())),
+            discovery: Arc::new(Discovery::default()),
+            load_balancer: Arc::new(LoadBalancer::default()),
+            circuit_breakers: Arc::new(CircuitBreakers::default()),
+            cluster: None,
+            log_manager: None,
+            maintenance: Arc::new(MaintenanceMode::new(config.maintenance.clone())),
+            admin_allowlist: Arc::new(AdminAllowlist::new(config.admin_allowlist.clone())),
+            error_handler: Arc::new(ErrorHandler::new(ErrorConfig::default()).await.unwrap()),
+            websocket: Arc::new(WebSocketManager::with_config(config.websocket.clone())),
+            draining: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // An upstream that answers every request with its name
+    async fn named_upstream(name: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().fallback(move || async move { name });
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    // (upstream that answered, affinity cookie set on the response)
+    async fn fetch_app(state: &Arc<AppState>, cookie: Option<&str>) -> (String, Option<String>) {
+        let mut req = Request::get("/");
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, format!("theme=dark; {}", cookie));
+        }
+        let response = route_request("app.example.com".to_string(), state.clone(), None, req.body(Body::empty()).unwrap()).await;
+        let set_cookie = response.headers().get(header::SET_COOKIE)
+            .map(|v| v.to_str().unwrap().split(';').next().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+        (String::from_utf8(body.to_vec()).unwrap(), set_cookie)
+    }
+
+    // GET `uri` through the full create_app stack from `ip`
+    async fn get_app(app: &Router, uri: &str, ip: &str) -> Response {
+        use tower::ServiceExt;
+
+        let mut req = Request::get(uri).body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ClientAddr(format!("{}:40000", ip).parse().unwrap()));
+        app.clone().oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_in_app_stack() {
+        let mut config = parse_config("", &Cli::default()).unwrap();
+        config.security.rate_limit_requests = 2;
+        let app = create_app(test_state(config).await, false);
+
+        for remaining in ["1", "0"] {
+            let response = get_app(&app, "/health", "192.0.2.1").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["x-ratelimit-remaining"], remaining);
+        }
+        let response = get_app(&app, "/health", "192.0.2.1").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+
+        // Counted per client
+        assert_eq!(get_app(&app, "/health", "192.0.2.2").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_admin_allowlist_in_app_stack() {
+        let content = "[admin_allowlist]\nips = [\"192.0.2.0/28\"]\n[maintenance]\nenabled = true";
+        let mut config = parse_config(content, &Cli::default()).unwrap();
+        config.security.rate_limit_requests = 1;
+        let app = create_app(test_state(config).await, false);
+
+        // Past both maintenance mode and the rate limit
+        for _ in 0..3 {
+            assert_eq!(get_app(&app, "/api/status", "192.0.2.9").await.status(), StatusCode::OK);
+        }
+        assert_eq!(get_app(&app, "/api/status", "198.51.100.1").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(get_app(&app, "/api/status", "198.51.100.1").await.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_publish_in_app_stack() {
+        use tower::ServiceExt;
+
+        let content = "[websocket]\nenabled = true\n[websocket.publish_auth]\nauth_type = \"bearer\"\nrealm = \"publish\"\ntokens = [\"s3cret\"]";
+        let app = create_app(test_state(parse_config(content, &Cli::default()).unwrap()).await, false);
+        let publish = |token: Option<&str>| {
+            let mut req = Request::post("/ws/publish").header(header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let mut req = req.body(Body::from(r#"{"room_id":"news","data":"hi"}"#)).unwrap();
+            req.extensions_mut().insert(ClientAddr("192.0.2.1:40000".parse().unwrap()));
+            app.clone().oneshot(req)
+        };
+
+        assert_eq!(publish(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(publish(Some("wrong")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(publish(Some("s3cret")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get_app(&app, "/ws/stats", "192.0.2.1").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_backend_auth_in_app_stack() {
+        use tower::ServiceExt;
+
+        let upstream = named_upstream("private").await;
+        let content = format!(
+            "[backends.\"app.example.com\"]\ntarget = {:?}\n[backends.\"app.example.com\".auth]\nauth_type = \"bearer\"\nrealm = \"API\"\ntokens = [\"s3cret\"]",
+            upstream,
+        );
+        let app = create_app(test_state(parse_config(&content, &Cli::default()).unwrap()).await, false);
+        let request = |host: &str, token: Option<&str>| {
+            let mut req = Request::get("/account").header(header::HOST, host);
+            if let Some(token) = token {
+                req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let mut req = req.body(Body::empty()).unwrap();
+            req.extensions_mut().insert(ClientAddr("192.0.2.1:40000".parse().unwrap()));
+            app.clone().oneshot(req)
+        };
+
+        let response = request("app.example.com", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"API\"");
+        let response = request("app.example.com", Some("wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"API\", error=\"invalid_token\"");
+
+        let response = request("app.example.com", Some("s3cret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"private");
+
+        // Other hosts are left alone
+        assert_ne!(request("other.example.com", None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_backend_jwt_in_app_stack() {
+        use http_auth::tests::{claims, spawn_jwks_server, token};
+        use http_auth::JwtClaims;
+        use tower::ServiceExt;
+
+        let (jwks_url, _) = spawn_jwks_server().await;
+        let upstream = named_upstream("private").await;
+        let content = format!(
+            "[backends.\"app.example.com\"]\ntarget = {:?}\n[backends.\"app.example.com\".auth]\nauth_type = \"jwt\"\nrealm = \"API\"\n[backends.\"app.example.com\".auth.jwt]\njwks_url = {:?}\nissuer = \"https://issuer.test/\"\naudience = [\"api\"]",
+            upstream, jwks_url,
+        );
+        let state = test_state(parse_config(&content, &Cli::default()).unwrap()).await;
+        let request = |app: &Router, token: &str| {
+            let mut req = Request::get("/account")
+                .header(header::HOST, "app.example.com")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            req.extensions_mut().insert(ClientAddr("192.0.2.1:40000".parse().unwrap()));
+            app.clone().oneshot(req)
+        };
+        let app = create_app(state.clone(), false);
+
+        let valid = token("test", claims(300, "api"));
+        let response = request(&app, &valid).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"private");
+
+        // Expired, and the payload of another token under this one's signature
+        let expired = token("test", claims(-300, "api"));
+        let other = token("test", claims(600, "api"));
+        let mut forged: Vec<&str> = valid.split('.').collect();
+        forged[1] = other.split('.').nth(1).unwrap();
+        for rejected in [expired, forged.join(".")] {
+            let response = request(&app, &rejected).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"API\", error=\"invalid_token\"");
+        }
+
+        // Handlers behind the middleware get the token's claims
+        let echo = Router::new()
+            .route("/account", get(|Extension(jwt): Extension<JwtClaims>| async move {
+                jwt.variables()["jwt_tenant"].clone()
+            }))
+            .layer(axum::middleware::from_fn_with_state(state, backend_auth_middleware));
+        let response = request(&echo, &valid).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"acme");
+    }
+
+    #[tokio::test]
+    async fn test_backend_access_cidr() {
+        let upstream = named_upstream("internal").await;
+        let content = format!(
+            "[backends.\"app.example.com\"]\ntarget = {:?}\n[backends.\"app.example.com\".access]\nallow = [\"192.168.0.0/16\", \"2001:db8::/32\"]\ndeny = [\"192.168.66.0/24\"]",
+            upstream,
+        );
+        let state = test_state(parse_config(&content, &Cli::default()).unwrap()).await;
+        let status = |addr: Option<&str>| {
+            let state = state.clone();
+            let client_addr = addr.map(|addr| Extension(ClientAddr(addr.parse().unwrap())));
+            async move {
+                let req = Request::get("/").body(Body::empty()).unwrap();
+                route_request("app.example.com".to_string(), state, client_addr, req).await.status()
+            }
+        };
+
+        assert_eq!(status(Some("192.168.5.9:40000")).await, StatusCode::OK);
+        assert_eq!(status(Some("[2001:db8::1]:40000")).await, StatusCode::OK);
+        assert_eq!(status(Some("192.168.66.10:40000")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(Some("192.169.0.1:40000")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(None).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_sticky_cookie_pins_upstream() {
+        let a = named_upstream("a").await;
+        let b = named_upstream("b").await;
+        let content = format!("[backends.\"app.example.com\"]\nupstreams = [{:?}, {:?}]\nstrategy = \"sticky\"", a, b);
+        let state = test_state(parse_config(&content, &Cli::default()).unwrap()).await;
+
+        let (first, cookie) = fetch_app(&state, None).await;
+        let cookie = cookie.expect("first response sets the affinity cookie");
+        assert!(cookie.starts_with("mwd_upstream="));
+        assert!(!cookie.contains("127.0.0.1"));
+
+        // The same upstream every time the cookie comes back
+        for _ in 0..10 {
+            assert_eq!(fetch_app(&state, Some(&cookie)).await, (first.clone(), None));
+        }
+
+        // A cookieless client is spread to the other upstream
+        let (other, _) = fetch_app(&state, None).await;
+        assert_ne!(other, first);
+
+        // When the pinned upstream fails its health check the client moves
+        // and is re-pinned
+        let pinned = if first == "a" { &a } else { &b };
+        for _ in 0..state.config.load().health_check.unhealthy_threshold {
+            state.health_checker.record_result(&upstream_health_name("app.example.com", pinned), Err("down".to_string())).await;
+        }
+        let (moved, new_cookie) = fetch_app(&state, Some(&cookie)).await;
+        assert_eq!(moved, other);
+        let new_cookie = new_cookie.expect("re-pinned");
+        assert_ne!(new_cookie, cookie);
+        assert_eq!(fetch_app(&state, Some(&new_cookie)).await, (other, None));
+    }
+
+    #[tokio::test]
+    async fn test_retry_moves_to_another_upstream() {
+        // Nothing listens here once the listener is dropped
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let live = named_upstream("live").await;
+
+        let content = format!(
+            "[backends.\"app.example.com\"]\nupstreams = [{:?}, {:?}]\n[backends.\"app.example.com\".retry]\ndelay_ms = 0",
+            dead, live
+        );
+        let state = test_state(parse_config(&content, &Cli::default()).unwrap()).await;
+        // Round robin lands on the dead upstream every other request
+        for _ in 0..4 {
+            assert_eq!(fetch_app(&state, None).await.0, "live");
+        }
+
+        // Without retries the failure reaches the client
+        let content = format!("[backends.\"app.example.com\"]\nupstreams = [{:?}, {:?}]", dead, live);
+        let state = test_state(parse_config(&content, &Cli::default()).unwrap()).await;
+        let request = || Request::get("/").body(Body::empty()).unwrap();
+        let first = route_request("app.example.com".to_string(), state.clone(), None, request()).await;
+        assert_eq!(first.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_is_proxied() {
+        use axum::extract::ws::{Message, WebSocketUpgrade};
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite;
+
+        // Echo server that insists on a subprotocol
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        let echo = Router::new().route("/ws", get(|ws: WebSocketUpgrade| async move {
+            ws.protocols(["chat"]).on_upgrade(|mut socket| async move {
+                while let Some(Ok(message)) = socket.recv().await {
+                    if let Message::Text(_) = message {
+                        if socket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            })
+        }));
+        tokio::spawn(async move { axum::serve(listener, echo).await.unwrap() });
+
+        let content = format!("[backends.\"app.example.com\"]\ntarget = {:?}", upstream);
+        let state = test_state(parse_config(&content, &Cli::default()).unwrap()).await;
+        let proxy = Router::new().fallback(move |req: Request<Body>| route_request("app.example.com".to_string(), state, None, req));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, proxy).await.unwrap() });
+
+        let mut request = tungstenite::client::IntoClientRequest::into_client_request(format!("ws://{}/ws", addr)).unwrap();
+        request.headers_mut().insert("sec-websocket-protocol", HeaderValue::from_static("chat"));
+        let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(response.headers()["sec-websocket-protocol"], "chat");
+
+        socket.send(tungstenite::Message::Text("hello".into())).await.unwrap();
+        assert_eq!(socket.next().await.unwrap().unwrap(), tungstenite::Message::Text("hello".into()));
+        socket.close(None).await.unwrap();
+    }
+
+    fn config_error(content: &str) -> String {
+        format!("{:#}", parse_config(content, &Cli::default()).err().expect("config should be rejected"))
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let config = parse_config(r#"
+            [server]
+            http_port = 8080
+
+            [[server.listeners]]
+            address = "127.0.0.1:8080"
+            [[server.listeners]]
+            address = "[::1]:8080"
+            [[server.listeners]]
+            address = "unix:/run/miwidothttp.sock"
+
+            [backends."api.example.com"]
+            target = "http://localhost:3000"
+            max_request_size = "10MB"
+        "#, &Cli::default()).unwrap();
+        assert_eq!(config.server.listeners.len(), 3);
+        assert_eq!(config.backends["api.example.com"].max_request_size, Some(10_000_000));
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected() {
+        assert!(config_error("[server\nhttp_port = 80").contains("expected"));
+        assert!(config_error("[server]\nhttp_port = \"eighty\"").contains("http_port"));
+
+        let error = config_error("[backends.api]\ntarget = \"localhost:3000\"");
+        assert!(error.contains("localhost:3000"), "{}", error);
+
+        let error = config_error("[backends.api]\nupstreams = [\"http://10.0.0.1:3000\", \"10.0.0.2:3000\"]");
+        assert!(error.contains("10.0.0.2:3000"), "{}", error);
+
+        let error = config_error("[backends.api]\nupstreams = [\"http://10.0.0.1:3000\"]\nweights = [1, 2]");
+        assert!(error.contains("weights"), "{}", error);
+
+        let error = config_error("[backends.api]\ntarget = \"http://localhost:3000\"\nmax_request_size = \"10 parsecs\"");
+        assert!(error.contains("unknown unit"), "{}", error);
+
+        let error = config_error("[backends.php]\ntarget = \"http://localhost:3000\"\n[backends.php.fastcgi]\ntcp_addr = \"127.0.0.1:9000\"");
+        assert!(error.contains("fastcgi"), "{}", error);
+
+        let error = config_error("[server]\nhttp_port = 8443\nhttps_port = 8443\n[ssl]\nenabled = true\ncert_path = \"a.crt\"\nkey_path = \"a.key\"");
+        assert!(error.contains("same port"), "{}", error);
+
+        let error = config_error(r#"
+            [[server.listeners]]
+            address = "0.0.0.0:80"
+            [[server.listeners]]
+            address = "127.0.0.1:80"
+        "#);
+        assert!(error.contains("same port"), "{}", error);
+
+        let error = config_error("[[server.listeners]]\naddress = \"localhost:80\"");
+        assert!(error.contains("invalid listener address"), "{}", error);
+
+        let error = config_error("[ssl]\nenabled = true");
+        assert!(error.contains("cert_path"), "{}", error);
+    }
+
+    #[test]
+    fn test_proxy_servers_config() {
+        let config = parse_config(r#"
+            [[proxy_servers]]
+            mode = "socks5"
+            bind_addr = "127.0.0.1:1080"
+
+            [proxy_servers.authentication]
+            auth_type = "basic"
+            username = "socksuser"
+            password = "sockspass"
+        "#, &Cli::default()).unwrap();
+        assert_eq!(config.proxy_servers[0].mode, proxy::ProxyMode::Socks5);
+        assert_eq!(config.proxy_servers[0].bind_addr, Some("127.0.0.1:1080".parse().unwrap()));
+
+        let error = config_error("[[proxy_servers]]\nmode = \"socks5\"");
+        assert!(error.contains("bind_addr"), "{}", error);
+
+        let error = config_error(r#"
+            [[proxy_servers]]
+            mode = "socks5"
+            bind_addr = "127.0.0.1:1080"
+
+            [proxy_servers.authentication]
+            auth_type = "digest"
+            username = "socksuser"
+            password = "sockspass"
+        "#);
+        assert!(error.contains("SOCKS5"), "{}", error);
+    }
+
+    #[test]
+    fn test_command_line_overrides_config_file() {
+        let content = r#"
+            [server]
+            bind_address = "0.0.0.0"
+            http_port = 8080
+            static_dir = "./static"
+
+            [[server.listeners]]
+            address = "0.0.0.0:80"
+        "#;
+        let cli = Cli::try_parse_from(["miwidothttp", "--bind", "127.0.0.1:9000", "--static-dir", "/srv/www"]).unwrap();
+        let config = parse_config(content, &cli).unwrap();
+        assert_eq!(config.server.bind_address, "127.0.0.1");
+        assert_eq!(config.server.http_port, 9000);
+        assert_eq!(config.server.static_dir, "/srv/www");
+        assert_eq!(listener_configs(&config), vec![ListenerConfig::tcp("127.0.0.1:9000", false)]);
+
+        // Without arguments the file decides
+        let config = parse_config(content, &Cli::default()).unwrap();
+        assert_eq!(config.server.http_port, 8080);
+        assert_eq!(config.server.static_dir, "./static");
+        assert_eq!(listener_configs(&config), vec![ListenerConfig::tcp("0.0.0.0:80", false)]);
+    }
+
+    #[test]
+    fn test_half_missing_certificate_is_rejected() {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("server.crt");
+        let key = dir.join("server.key");
+        let content = format!(
+            "[ssl]\nenabled = true\ncert_path = {:?}\nkey_path = {:?}",
+            cert.display().to_string(),
+            key.display().to_string(),
+        );
+
+        // Neither file yet: a self-signed pair gets generated
+        assert!(parse_config(&content, &Cli::default()).is_ok());
+
+        std::fs::write(&cert, "cert").unwrap();
+        let error = config_error(&content);
+        assert!(error.contains("key_path"), "{}", error);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_cert() {
+        let content = "[ssl]\ndomains = [\"localhost\", \"example.com\", \"localhost\"]";
+        let config = parse_config(content, &Cli::default()).unwrap();
+        let names = self_signed_names(&config);
+        assert_eq!(names, vec!["localhost", "127.0.0.1", "example.com"]);
+
+        let dir = std::env::temp_dir().join(format!("miwidothttp-certs-{}", uuid::Uuid::new_v4()));
+        let cert = dir.join("server.crt").display().to_string();
+        let key = dir.join("server.key").display().to_string();
+        generate_self_signed_cert(&cert, &key, names, 1).await.unwrap();
+        assert!(std::fs::read_to_string(&cert).unwrap().contains("BEGIN CERTIFICATE"));
+        assert!(std::fs::read_to_string(&key).unwrap().contains("PRIVATE KEY"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
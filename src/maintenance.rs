@@ -0,0 +1,305 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+use crate::admin_allowlist::AdminBypass;
+use crate::error::{AppError, ErrorMode, DEFAULT_503_PAGE};
+use crate::proxy_protocol::ClientAddr;
+
+// Paths served even in maintenance mode, so load balancers keep the node
+const ALWAYS_ALLOWED: &[&str] = &["/health", "/livez", "/readyz"];
+
+// Reading the state stays open; switching it needs allow_ips or
+// [admin_allowlist]
+const STATUS_PATH: &str = "/api/maintenance";
+
+// [maintenance]
+// enabled = false             # start in maintenance mode
+// retry_after_seconds = 300
+// allow_paths = ["/status"]   # prefixes that stay reachable
+// allow_ips = ["10.0.0.5"]    # admins who see the live site
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    pub enabled: bool,
+    pub retry_after_seconds: u64,
+    pub allow_paths: Vec<String>,
+    pub allow_ips: Vec<IpAddr>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retry_after_seconds: 300,
+            allow_paths: Vec::new(),
+            allow_ips: Vec::new(),
+        }
+    }
+}
+
+// Server-wide maintenance switch; starts from the config and can be flipped
+// at runtime through /api/maintenance
+#[derive(Debug)]
+pub struct MaintenanceMode {
+    enabled: AtomicBool,
+    config: RwLock<MaintenanceConfig>,
+}
+
+impl MaintenanceMode {
+    pub fn new(config: MaintenanceConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(config.enabled),
+            config: RwLock::new(config),
+        }
+    }
+
+    pub fn mode(&self) -> ErrorMode {
+        if self.enabled.load(Ordering::Relaxed) {
+            ErrorMode::Maintenance
+        } else {
+            ErrorMode::Production
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            info!("Maintenance mode {}", if enabled { "enabled" } else { "disabled" });
+        }
+    }
+
+    // Apply a reloaded [maintenance] section, including its `enabled` flag
+    pub fn configure(&self, config: MaintenanceConfig) {
+        self.set_enabled(config.enabled);
+        *self.config.write().unwrap() = config;
+    }
+
+    fn allows(&self, method: &Method, path: &str, client_ip: Option<IpAddr>) -> bool {
+        let config = self.config.read().unwrap();
+        ALWAYS_ALLOWED.iter().any(|allowed| is_under(path, allowed))
+            || (method == Method::GET && is_under(path, STATUS_PATH))
+            || config.allow_paths.iter().any(|allowed| is_under(path, allowed))
+            || self.is_admin(client_ip)
+    }
+
+    fn is_admin(&self, client_ip: Option<IpAddr>) -> bool {
+        client_ip.map_or(false, |ip| self.config.read().unwrap().allow_ips.contains(&ip))
+    }
+
+    fn unavailable(&self, wants_json: bool) -> Response {
+        let mut response = if wants_json {
+            AppError::new(StatusCode::SERVICE_UNAVAILABLE, "Down for maintenance")
+                .with_code("MAINTENANCE")
+                .into_response()
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, Html(DEFAULT_503_PAGE)).into_response()
+        };
+        let retry_after = self.config.read().unwrap().retry_after_seconds;
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    }
+}
+
+// `path` is `prefix` itself or below it
+fn is_under(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+// Answers everything outside the allowlists (and [admin_allowlist]) with
+// the 503 maintenance page while maintenance mode is on
+pub async fn maintenance_middleware(
+    State(maintenance): State<Arc<MaintenanceMode>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if maintenance.mode() != ErrorMode::Maintenance {
+        return next.run(req).await;
+    }
+
+    let client_ip = req.extensions().get::<ClientAddr>().map(|ClientAddr(addr)| addr.ip());
+    if maintenance.allows(req.method(), req.uri().path(), client_ip) {
+        return next.run(req).await;
+    }
+    if let Some(AdminBypass(reason)) = req.extensions().get::<AdminBypass>() {
+        info!("Maintenance bypassed for {} {} from {} ({})",
+            req.method(), req.uri().path(), client_ip.map_or_else(|| "-".to_string(), |ip| ip.to_string()), reason);
+        return next.run(req).await;
+    }
+
+    let wants_json = req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |accept| accept.contains("application/json"));
+    maintenance.unavailable(wants_json)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MaintenanceStatus {
+    enabled: bool,
+}
+
+// Mounted under /api/maintenance: GET for the state, POST {"enabled": bool}
+// to switch it, for allow_ips and [admin_allowlist] clients only
+pub fn router(maintenance: Arc<MaintenanceMode>) -> Router {
+    Router::new()
+        .route("/", get(maintenance_status).post(set_maintenance))
+        .with_state(maintenance)
+}
+
+async fn maintenance_status(State(maintenance): State<Arc<MaintenanceMode>>) -> Json<MaintenanceStatus> {
+    Json(MaintenanceStatus { enabled: maintenance.mode() == ErrorMode::Maintenance })
+}
+
+async fn set_maintenance(
+    State(maintenance): State<Arc<MaintenanceMode>>,
+    client_addr: Option<Extension<ClientAddr>>,
+    bypass: Option<Extension<AdminBypass>>,
+    Json(status): Json<MaintenanceStatus>,
+) -> Response {
+    let client_ip = client_addr.map(|Extension(ClientAddr(addr))| addr.ip());
+    if bypass.is_none() && !maintenance.is_admin(client_ip) {
+        return AppError::new(StatusCode::FORBIDDEN, "Not allowed to switch maintenance mode").into_response();
+    }
+    maintenance.set_enabled(status.enabled);
+    maintenance_status(State(maintenance)).await.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    fn app(maintenance: Arc<MaintenanceMode>) -> Router {
+        Router::new()
+            .route("/health", get(|| async { "OK" }))
+            .route("/page", get(|| async { "page" }))
+            .route("/status/db", get(|| async { "db ok" }))
+            .nest_service("/api/maintenance", router(maintenance.clone()))
+            .layer(axum::middleware::from_fn_with_state(maintenance, maintenance_middleware))
+    }
+
+    fn get_request(uri: &str) -> Request {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    async fn status_of(app: &Router, req: Request) -> StatusCode {
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    fn toggle_request(enabled: bool, client: &str) -> Request {
+        let mut req = Request::post("/api/maintenance")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{"enabled":{}}}"#, enabled)))
+            .unwrap();
+        req.extensions_mut().insert(ClientAddr(client.parse().unwrap()));
+        req
+    }
+
+    #[tokio::test]
+    async fn test_toggle_maintenance() {
+        let maintenance = Arc::new(MaintenanceMode::new(MaintenanceConfig {
+            allow_ips: vec!["10.0.0.5".parse().unwrap()],
+            ..MaintenanceConfig::default()
+        }));
+        let app = app(maintenance.clone());
+        assert_eq!(status_of(&app, get_request("/page")).await, StatusCode::OK);
+
+        let toggle = |enabled: bool| toggle_request(enabled, "10.0.0.5:40000");
+        assert_eq!(status_of(&app, toggle(true)).await, StatusCode::OK);
+
+        let response = app.clone().oneshot(get_request("/page")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "300");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Under Maintenance"));
+        assert_eq!(status_of(&app, get_request("/health")).await, StatusCode::OK);
+
+        // JSON clients get a JSON error
+        let req = Request::builder()
+            .uri("/page")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "MAINTENANCE");
+
+        // The state stays readable to everyone
+        assert_eq!(status_of(&app, get_request("/api/maintenance")).await, StatusCode::OK);
+
+        assert_eq!(status_of(&app, toggle(false)).await, StatusCode::OK);
+        assert_eq!(status_of(&app, get_request("/page")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_toggle_needs_admin() {
+        let maintenance = Arc::new(MaintenanceMode::new(MaintenanceConfig {
+            allow_ips: vec!["10.0.0.5".parse().unwrap()],
+            ..MaintenanceConfig::default()
+        }));
+        let app = app(maintenance.clone());
+
+        assert_eq!(status_of(&app, toggle_request(true, "203.0.113.9:40000")).await, StatusCode::FORBIDDEN);
+        assert_eq!(maintenance.mode(), ErrorMode::Production);
+
+        // Nor can it be switched off from outside once it's on
+        maintenance.set_enabled(true);
+        assert_eq!(status_of(&app, toggle_request(false, "203.0.113.9:40000")).await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(maintenance.mode(), ErrorMode::Maintenance);
+
+        // [admin_allowlist] clients may switch it too
+        let mut req = toggle_request(false, "203.0.113.9:40000");
+        req.extensions_mut().insert(AdminBypass("ip"));
+        assert_eq!(status_of(&app, req).await, StatusCode::OK);
+        assert_eq!(maintenance.mode(), ErrorMode::Production);
+    }
+
+    #[tokio::test]
+    async fn test_allowlists_bypass_maintenance() {
+        let admin: IpAddr = "10.0.0.5".parse().unwrap();
+        let maintenance = Arc::new(MaintenanceMode::new(MaintenanceConfig {
+            enabled: true,
+            retry_after_seconds: 60,
+            allow_paths: vec!["/status".to_string()],
+            allow_ips: vec![admin],
+        }));
+        let app = app(maintenance);
+
+        assert_eq!(status_of(&app, get_request("/status/db")).await, StatusCode::OK);
+        assert_eq!(status_of(&app, get_request("/page")).await, StatusCode::SERVICE_UNAVAILABLE);
+
+        let mut req = get_request("/page");
+        req.extensions_mut().insert(ClientAddr(SocketAddr::new(admin, 40000)));
+        assert_eq!(status_of(&app, req).await, StatusCode::OK);
+
+        let mut req = get_request("/page");
+        req.extensions_mut().insert(ClientAddr("10.0.0.6:40000".parse().unwrap()));
+        assert_eq!(status_of(&app, req).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_is_under() {
+        assert!(is_under("/status", "/status"));
+        assert!(is_under("/status/db", "/status/"));
+        assert!(!is_under("/statusx", "/status"));
+        assert!(is_under("/anything", "/"));
+    }
+}
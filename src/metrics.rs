@@ -4,18 +4,117 @@ use std::time::{Duration, Instant};
 use std::collections::HashMap;
 use tokio::sync::RwLock;
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, warn};
+
+use crate::logging::VirtualHost;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header::CONTENT_LENGTH, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use futures::StreamExt;
+use hdrhistogram::Histogram;
+
+// Latencies above this (one hour) are clamped into the top bucket
+const MAX_TRACKED_MICROS: u64 = 3_600_000_000;
+
+// Label for vhost/route pairs past the series cap
+const OTHER_LABEL: &str = "other";
+
+// The [metrics] section. With `per_route` on, requests are also counted per
+// vhost and route template, e.g.
+//
+//   [metrics]
+//   per_route = true
+//   routes = ["/users/{id}/orders", "/assets/*"]
+//
+// Paths no template matches have numeric, UUID and long hex segments
+// collapsed to {id}, so /users/42 and /users/43 share a series.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub per_route: bool,
+    // "{name}" matches one segment, a trailing "*" the rest of the path
+    pub routes: Vec<String>,
+    // Distinct vhost/route pairs tracked; later ones are counted as "other"
+    pub max_series: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            per_route: false,
+            routes: Vec::new(),
+            max_series: 500,
+        }
+    }
+}
+
+impl MetricsConfig {
+    // The route label for a request path
+    pub fn route(&self, path: &str) -> String {
+        self.routes.iter()
+            .find(|template| template_matches(template, path))
+            .cloned()
+            .unwrap_or_else(|| collapse_ids(path))
+    }
+}
+
+fn template_matches(template: &str, path: &str) -> bool {
+    let mut path_segments = path.trim_start_matches('/').split('/');
+    for segment in template.trim_start_matches('/').split('/') {
+        if segment == "*" {
+            return true;
+        }
+        match path_segments.next() {
+            Some(part) if segment == part => {}
+            Some(part) if segment.starts_with('{') && segment.ends_with('}') && !part.is_empty() => {}
+            _ => return false,
+        }
+    }
+    path_segments.next().is_none()
+}
+
+fn collapse_ids(path: &str) -> String {
+    let is_id = |segment: &str| {
+        !segment.is_empty()
+            && (segment.bytes().all(|b| b.is_ascii_digit())
+                || uuid::Uuid::parse_str(segment).is_ok()
+                || (segment.len() >= 16 && segment.bytes().all(|b| b.is_ascii_hexdigit())))
+    };
+    path.split('/')
+        .map(|segment| if is_id(segment) { "{id}" } else { segment })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Counts and total seconds for one vhost/route pair
+#[derive(Default)]
+struct RouteSeries {
+    by_method_status: HashMap<(String, u16), u64>,
+    count: u64,
+    duration_seconds: f64,
+}
 
 #[derive(Clone)]
 pub struct MetricsCollector {
     requests_total: Arc<AtomicU64>,
     requests_by_method: Arc<RwLock<HashMap<String, u64>>>,
     requests_by_status: Arc<RwLock<HashMap<u16, u64>>>,
-    response_times: Arc<RwLock<Vec<Duration>>>,
+    requests_by_method_status: Arc<RwLock<HashMap<(String, u16), u64>>>,
+    // Response times in microseconds
+    response_times: Arc<RwLock<Histogram<u64>>>,
     active_connections: Arc<AtomicUsize>,
     bytes_sent: Arc<AtomicU64>,
     bytes_received: Arc<AtomicU64>,
     errors: Arc<AtomicU64>,
+    // Requests over [logging] slow_request_threshold_ms
+    slow_requests: Arc<AtomicU64>,
+    routes: Arc<MetricsConfig>,
+    // Keyed by (vhost, route)
+    route_series: Arc<RwLock<HashMap<(String, String), RouteSeries>>>,
     start_time: Instant,
 }
 
@@ -25,15 +124,51 @@ impl MetricsCollector {
             requests_total: Arc::new(AtomicU64::new(0)),
             requests_by_method: Arc::new(RwLock::new(HashMap::new())),
             requests_by_status: Arc::new(RwLock::new(HashMap::new())),
-            response_times: Arc::new(RwLock::new(Vec::new())),
+            requests_by_method_status: Arc::new(RwLock::new(HashMap::new())),
+            response_times: Arc::new(RwLock::new(
+                Histogram::new_with_bounds(1, MAX_TRACKED_MICROS, 3).expect("valid histogram bounds"),
+            )),
             active_connections: Arc::new(AtomicUsize::new(0)),
             bytes_sent: Arc::new(AtomicU64::new(0)),
             bytes_received: Arc::new(AtomicU64::new(0)),
             errors: Arc::new(AtomicU64::new(0)),
+            slow_requests: Arc::new(AtomicU64::new(0)),
+            routes: Arc::new(MetricsConfig::default()),
+            route_series: Arc::new(RwLock::new(HashMap::new())),
             start_time: Instant::now(),
         }
     }
 
+    pub fn with_config(mut self, config: MetricsConfig) -> Self {
+        self.routes = Arc::new(config);
+        self
+    }
+
+    pub fn per_route(&self) -> bool {
+        self.routes.per_route
+    }
+
+    // Count a request towards its vhost/route series. Once max_series pairs
+    // exist, new ones go to a shared "other" series.
+    pub async fn record_route(&self, vhost: &str, path: &str, method: &str, status: u16, duration: Duration) {
+        let route = self.routes.route(path);
+        let mut series = self.route_series.write().await;
+        let key = (vhost.to_string(), route);
+        let key = if series.contains_key(&key) || series.len() < self.routes.max_series {
+            key
+        } else {
+            let other = (OTHER_LABEL.to_string(), OTHER_LABEL.to_string());
+            if !series.contains_key(&other) {
+                warn!("Over {} vhost/route metric series, counting new ones as \"other\"", self.routes.max_series);
+            }
+            other
+        };
+        let entry = series.entry(key).or_default();
+        *entry.by_method_status.entry((method.to_string(), status)).or_insert(0) += 1;
+        entry.count += 1;
+        entry.duration_seconds += duration.as_secs_f64();
+    }
+
     pub async fn record_request(&self, method: &str, status: u16, duration: Duration, bytes_in: u64, bytes_out: u64) {
         self.requests_total.fetch_add(1, Ordering::Relaxed);
         
@@ -47,13 +182,15 @@ impl MetricsCollector {
         *statuses.entry(status).or_insert(0) += 1;
         drop(statuses);
         
+        // Record by method and status together, for http_requests_total
+        let mut pairs = self.requests_by_method_status.write().await;
+        *pairs.entry((method.to_string(), status)).or_insert(0) += 1;
+        drop(pairs);
+        
         // Record response time
         let mut times = self.response_times.write().await;
-        times.push(duration);
-        // Keep only last 10000 samples to prevent unbounded growth
-        if times.len() > 10000 {
-            times.drain(0..5000);
-        }
+        let micros = (duration.as_micros() as u64).clamp(1, MAX_TRACKED_MICROS);
+        times.saturating_record(micros);
         drop(times);
         
         // Record bytes
@@ -68,6 +205,10 @@ impl MetricsCollector {
         debug!("Recorded request: {} {} {}ms", method, status, duration.as_millis());
     }
 
+    pub fn record_slow_request(&self) {
+        self.slow_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn increment_connections(&self) {
         self.active_connections.fetch_add(1, Ordering::Relaxed);
     }
@@ -76,16 +217,26 @@ impl MetricsCollector {
         self.active_connections.fetch_sub(1, Ordering::Relaxed);
     }
 
+    // Current figures for the cluster heartbeat; the caller turns
+    // requests_total into a rate between samples
+    pub async fn load_sample(&self) -> LoadSample {
+        let times = self.response_times.read().await;
+        LoadSample {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            requests_total: self.requests_total.load(Ordering::Relaxed),
+            p95_ms: if times.is_empty() { 0.0 } else { percentile_ms(&times, 0.95) },
+        }
+    }
+
     pub async fn get_prometheus_metrics(&self) -> String {
-        let total = self.requests_total.load(Ordering::Relaxed);
         let active = self.active_connections.load(Ordering::Relaxed);
         let bytes_in = self.bytes_received.load(Ordering::Relaxed);
         let bytes_out = self.bytes_sent.load(Ordering::Relaxed);
         let errors = self.errors.load(Ordering::Relaxed);
+        let slow = self.slow_requests.load(Ordering::Relaxed);
         let uptime = self.start_time.elapsed().as_secs();
         
-        let methods = self.requests_by_method.read().await;
-        let statuses = self.requests_by_status.read().await;
+        let pairs = self.requests_by_method_status.read().await;
         let times = self.response_times.read().await;
         
         let mut output = String::new();
@@ -94,28 +245,24 @@ impl MetricsCollector {
         output.push_str("# HELP http_requests_total Total number of HTTP requests\n");
         output.push_str("# TYPE http_requests_total counter\n");
         
-        for (method, count) in methods.iter() {
-            for (status, status_count) in statuses.iter() {
-                if *status_count > 0 {
-                    output.push_str(&format!(
-                        "http_requests_total{{method=\"{}\",status=\"{}\"}} {}\n",
-                        method, status, count * status_count / total.max(1)
-                    ));
-                }
-            }
+        // Sorted so the series come out in a stable order between scrapes
+        let mut series: Vec<_> = pairs.iter().collect();
+        series.sort();
+        for ((method, status), count) in series {
+            output.push_str(&format!(
+                "http_requests_total{{method=\"{}\",status=\"{}\"}} {}\n",
+                method, status, count
+            ));
         }
         
         // Response time histogram
         if !times.is_empty() {
-            let mut sorted_times: Vec<_> = times.iter().map(|d| d.as_secs_f64()).collect();
-            sorted_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
-            
             output.push_str("\n# HELP http_request_duration_seconds HTTP request latency\n");
             output.push_str("# TYPE http_request_duration_seconds histogram\n");
             
             let buckets = vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
             for bucket in &buckets {
-                let count = sorted_times.iter().filter(|&&t| t <= *bucket).count();
+                let count = times.count_between(0, (bucket * 1_000_000.0) as u64);
                 output.push_str(&format!(
                     "http_request_duration_seconds_bucket{{le=\"{}\"}} {}\n",
                     bucket, count
@@ -123,23 +270,23 @@ impl MetricsCollector {
             }
             output.push_str(&format!(
                 "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}\n",
-                sorted_times.len()
+                times.len()
             ));
             
-            let sum: f64 = sorted_times.iter().sum();
+            let sum = times.mean() * times.len() as f64 / 1_000_000.0;
             output.push_str(&format!(
                 "http_request_duration_seconds_sum {:.3}\n",
                 sum
             ));
             output.push_str(&format!(
                 "http_request_duration_seconds_count {}\n",
-                sorted_times.len()
+                times.len()
             ));
             
             // Percentiles
-            let p50 = percentile(&sorted_times, 0.5);
-            let p95 = percentile(&sorted_times, 0.95);
-            let p99 = percentile(&sorted_times, 0.99);
+            let p50 = times.value_at_quantile(0.5) as f64 / 1_000_000.0;
+            let p95 = times.value_at_quantile(0.95) as f64 / 1_000_000.0;
+            let p99 = times.value_at_quantile(0.99) as f64 / 1_000_000.0;
             
             output.push_str(&format!(
                 "\n# HELP http_request_duration_quantile Response time quantiles\n"
@@ -180,6 +327,38 @@ impl MetricsCollector {
         output.push_str("# TYPE http_errors_total counter\n");
         output.push_str(&format!("http_errors_total {}\n", errors));
         
+        output.push_str("\n# HELP http_slow_requests_total Requests over the slow request threshold\n");
+        output.push_str("# TYPE http_slow_requests_total counter\n");
+        output.push_str(&format!("http_slow_requests_total {}\n", slow));
+        
+        let route_series = self.route_series.read().await;
+        if !route_series.is_empty() {
+            let mut routes: Vec<_> = route_series.iter().collect();
+            routes.sort_by(|a, b| a.0.cmp(b.0));
+            
+            output.push_str("\n# HELP http_route_requests_total Requests by vhost and route\n");
+            output.push_str("# TYPE http_route_requests_total counter\n");
+            for ((vhost, route), entry) in &routes {
+                let mut pairs: Vec<_> = entry.by_method_status.iter().collect();
+                pairs.sort();
+                for ((method, status), count) in pairs {
+                    output.push_str(&format!(
+                        "http_route_requests_total{{vhost=\"{}\",route=\"{}\",method=\"{}\",status=\"{}\"}} {}\n",
+                        label_value(vhost), label_value(route), method, status, count
+                    ));
+                }
+            }
+            
+            output.push_str("\n# HELP http_route_request_duration_seconds Request latency by vhost and route\n");
+            output.push_str("# TYPE http_route_request_duration_seconds summary\n");
+            for ((vhost, route), entry) in &routes {
+                let labels = format!("vhost=\"{}\",route=\"{}\"", label_value(vhost), label_value(route));
+                output.push_str(&format!("http_route_request_duration_seconds_sum{{{}}} {:.3}\n", labels, entry.duration_seconds));
+                output.push_str(&format!("http_route_request_duration_seconds_count{{{}}} {}\n", labels, entry.count));
+            }
+        }
+        drop(route_series);
+        
         // Process metrics
         output.push_str("\n# HELP process_uptime_seconds Time since server start\n");
         output.push_str("# TYPE process_uptime_seconds gauge\n");
@@ -211,18 +390,22 @@ impl MetricsCollector {
         let bytes_in = self.bytes_received.load(Ordering::Relaxed);
         let bytes_out = self.bytes_sent.load(Ordering::Relaxed);
         let errors = self.errors.load(Ordering::Relaxed);
+        let slow = self.slow_requests.load(Ordering::Relaxed);
         let uptime = self.start_time.elapsed();
         
-        let times = self.response_times.read().await;
-        let mut sorted_times: Vec<_> = times.iter().map(|d| d.as_millis() as f64).collect();
-        sorted_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let by_method: HashMap<String, u64> = self.requests_by_method.read().await.clone();
+        let by_status: HashMap<String, u64> = self.requests_by_status.read().await
+            .iter()
+            .map(|(status, count)| (status.to_string(), *count))
+            .collect();
         
-        let (p50, p95, p99, avg) = if !sorted_times.is_empty() {
+        let times = self.response_times.read().await;
+        let (p50, p95, p99, avg) = if !times.is_empty() {
             (
-                percentile(&sorted_times, 0.5),
-                percentile(&sorted_times, 0.95),
-                percentile(&sorted_times, 0.99),
-                sorted_times.iter().sum::<f64>() / sorted_times.len() as f64,
+                percentile_ms(&times, 0.5),
+                percentile_ms(&times, 0.95),
+                percentile_ms(&times, 0.99),
+                times.mean() / 1000.0,
             )
         } else {
             (0.0, 0.0, 0.0, 0.0)
@@ -240,6 +423,9 @@ impl MetricsCollector {
                 "per_second": rps,
                 "errors": errors,
                 "error_rate": if total > 0 { errors as f64 / total as f64 } else { 0.0 },
+                "slow": slow,
+                "by_method": by_method,
+                "by_status": by_status,
             },
             "latency": {
                 "p50": p50,
@@ -264,13 +450,20 @@ impl MetricsCollector {
     }
 }
 
-fn percentile(sorted_data: &[f64], p: f64) -> f64 {
-    if sorted_data.is_empty() {
-        return 0.0;
-    }
-    
-    let index = ((sorted_data.len() - 1) as f64 * p) as usize;
-    sorted_data[index]
+#[derive(Debug, Clone, Copy)]
+pub struct LoadSample {
+    pub active_connections: usize,
+    pub requests_total: u64,
+    pub p95_ms: f64,
+}
+
+// Escape a Prometheus label value
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn percentile_ms(times: &Histogram<u64>, quantile: f64) -> f64 {
+    times.value_at_quantile(quantile) as f64 / 1000.0
 }
 
 fn format_duration(duration: Duration) -> String {
@@ -310,4 +503,255 @@ impl RequestMetrics {
     pub fn duration(&self) -> Duration {
         self.start.elapsed()
     }
-}
\ No newline at end of file
+}
+
+// Records every request passing through. Byte counts come from Content-Length
+// when present; otherwise the body is wrapped so chunks are counted as they
+// stream by, never buffered.
+pub async fn metrics_middleware(
+    State(metrics): State<Arc<MetricsCollector>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    metrics.increment_connections();
+
+    let request_length = content_length(req.headers());
+    let bytes_in = Arc::new(AtomicU64::new(request_length.unwrap_or(0)));
+    let request = RequestMetrics::new(req.method().to_string(), request_length.unwrap_or(0));
+    let path = metrics.per_route().then(|| req.uri().path().to_string());
+
+    let req = match request_length {
+        Some(_) => req,
+        None => {
+            let counter = bytes_in.clone();
+            req.map(|body| Body::from_stream(body.into_data_stream().map(move |chunk| {
+                if let Ok(chunk) = &chunk {
+                    counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+                chunk
+            })))
+        }
+    };
+
+    let response = next.run(req).await;
+
+    // Responses name their vhost once routed; anything else is "-"
+    let route = path.map(|path| {
+        let vhost = response.extensions().get::<VirtualHost>()
+            .map_or_else(|| "-".to_string(), |VirtualHost(host)| host.clone());
+        (vhost, path)
+    });
+    let mut in_flight = InFlight {
+        metrics,
+        method: request.method.clone(),
+        status: response.status().as_u16(),
+        duration: request.duration(),
+        bytes_in,
+        bytes_out: 0,
+        route,
+    };
+
+    match content_length(response.headers()) {
+        Some(length) => {
+            in_flight.bytes_out = length;
+            response
+        }
+        // Recorded once the body has been sent (or the client went away)
+        None => response.map(|body| Body::from_stream(body.into_data_stream().map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                in_flight.bytes_out += chunk.len() as u64;
+            }
+            chunk
+        }))),
+    }
+}
+
+pub(crate) fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+// A request whose metrics are written when it is dropped
+struct InFlight {
+    metrics: Arc<MetricsCollector>,
+    method: String,
+    status: u16,
+    duration: Duration,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: u64,
+    // (vhost, path) when per-route metrics are on
+    route: Option<(String, String)>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.metrics.decrement_connections();
+
+        let metrics = self.metrics.clone();
+        let method = std::mem::take(&mut self.method);
+        let (status, duration, bytes_out) = (self.status, self.duration, self.bytes_out);
+        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        let route = self.route.take();
+        tokio::spawn(async move {
+            metrics.record_request(&method, status, duration, bytes_in, bytes_out).await;
+            if let Some((vhost, path)) = route {
+                metrics.record_route(&vhost, &path, &method, status, duration).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_json_metrics_shape() {
+        let metrics = MetricsCollector::new();
+        metrics.record_request("GET", 200, Duration::from_millis(5), 10, 100).await;
+        metrics.record_request("GET", 404, Duration::from_millis(2), 10, 20).await;
+        metrics.record_request("POST", 500, Duration::from_millis(8), 50, 0).await;
+
+        let json = metrics.get_json_metrics().await;
+
+        assert_eq!(json["requests"]["total"], 3);
+        assert_eq!(json["requests"]["errors"], 1);
+        assert_eq!(json["requests"]["by_method"]["GET"], 2);
+        assert_eq!(json["requests"]["by_method"]["POST"], 1);
+        assert_eq!(json["requests"]["by_status"]["200"], 1);
+        assert_eq!(json["requests"]["by_status"]["404"], 1);
+        assert_eq!(json["requests"]["by_status"]["500"], 1);
+        assert_eq!(json["throughput"]["bytes_in"], 70);
+        assert!(json["latency"]["p50"].is_number());
+        assert!(json["connections"]["active"].is_number());
+        assert!(json["uptime"]["seconds"].is_number());
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_requests_total_exact_counts() {
+        let metrics = MetricsCollector::new();
+        for _ in 0..3 {
+            metrics.record_request("GET", 200, Duration::from_millis(1), 0, 0).await;
+        }
+        metrics.record_request("POST", 500, Duration::from_millis(1), 0, 0).await;
+
+        let output = metrics.get_prometheus_metrics().await;
+
+        assert!(output.contains("http_requests_total{method=\"GET\",status=\"200\"} 3\n"));
+        assert!(output.contains("http_requests_total{method=\"POST\",status=\"500\"} 1\n"));
+        assert!(!output.contains("method=\"GET\",status=\"500\""));
+        assert!(!output.contains("method=\"POST\",status=\"200\""));
+    }
+
+    #[test]
+    fn test_route_labels() {
+        let config = MetricsConfig {
+            per_route: true,
+            routes: vec!["/users/{id}/orders".to_string(), "/assets/*".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(config.route("/users/alice/orders"), "/users/{id}/orders");
+        assert_eq!(config.route("/assets/css/site.css"), "/assets/*");
+        assert_eq!(config.route("/users/42"), "/users/{id}");
+        assert_eq!(config.route("/orders/3f2b8c1e-9d4a-4b6f-a1c2-0e5d7f9a8b3c/items/7"), "/orders/{id}/items/{id}");
+        assert_eq!(config.route("/commits/0123456789abcdef0123"), "/commits/{id}");
+        assert_eq!(config.route("/users/alice/orders/1"), "/users/alice/orders/{id}");
+        assert_eq!(config.route("/about"), "/about");
+    }
+
+    #[tokio::test]
+    async fn test_vhosts_get_separate_series() {
+        use axum::{routing::get, Router};
+        use tower::ServiceExt;
+
+        let metrics = Arc::new(MetricsCollector::new().with_config(MetricsConfig { per_route: true, ..Default::default() }));
+        let app = Router::new()
+            .route("/users/:id", get(|req: Request| async move {
+                let host = req.headers()["host"].to_str().unwrap().to_string();
+                let mut response = Response::new(Body::from("ok"));
+                response.extensions_mut().insert(VirtualHost(host));
+                response
+            }))
+            .layer(axum::middleware::from_fn_with_state(metrics.clone(), metrics_middleware));
+
+        for (host, path) in [("a.example.com", "/users/1"), ("a.example.com", "/users/2"), ("b.example.com", "/users/3")] {
+            let response = app.clone()
+                .oneshot(axum::http::Request::get(path).header("host", host).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        }
+
+        // Recorded from a spawned task once each response is dropped
+        let a_series = "http_route_requests_total{vhost=\"a.example.com\",route=\"/users/{id}\",method=\"GET\",status=\"200\"} 2\n";
+        let b_series = "http_route_requests_total{vhost=\"b.example.com\",route=\"/users/{id}\",method=\"GET\",status=\"200\"} 1\n";
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let output = loop {
+            let output = metrics.get_prometheus_metrics().await;
+            if output.contains(a_series) && output.contains(b_series) {
+                break output;
+            }
+            assert!(Instant::now() < deadline, "{}", output);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert!(output.contains("http_route_request_duration_seconds_count{vhost=\"a.example.com\",route=\"/users/{id}\"} 2\n"));
+        assert!(output.contains("http_requests_total{method=\"GET\",status=\"200\"} 3\n"));
+    }
+
+    #[tokio::test]
+    async fn test_route_series_capped() {
+        let metrics = MetricsCollector::new().with_config(MetricsConfig { per_route: true, max_series: 2, ..Default::default() });
+        for path in ["/a", "/b", "/c", "/d", "/a"] {
+            metrics.record_route("example.com", path, "GET", 200, Duration::from_millis(1)).await;
+        }
+        let output = metrics.get_prometheus_metrics().await;
+        assert!(output.contains("vhost=\"example.com\",route=\"/a\",method=\"GET\",status=\"200\"} 2\n"));
+        assert!(output.contains("vhost=\"example.com\",route=\"/b\",method=\"GET\",status=\"200\"} 1\n"));
+        assert!(output.contains("vhost=\"other\",route=\"other\",method=\"GET\",status=\"200\"} 2\n"));
+        assert!(!output.contains("route=\"/c\""));
+    }
+
+    #[tokio::test]
+    async fn test_load_sample() {
+        let metrics = MetricsCollector::new();
+        let sample = metrics.load_sample().await;
+        assert_eq!((sample.active_connections, sample.requests_total), (0, 0));
+        assert_eq!(sample.p95_ms, 0.0);
+
+        metrics.increment_connections();
+        metrics.increment_connections();
+        for ms in 1..=20 {
+            metrics.record_request("GET", 200, Duration::from_millis(ms), 0, 0).await;
+        }
+        let sample = metrics.load_sample().await;
+        assert_eq!(sample.active_connections, 2);
+        assert_eq!(sample.requests_total, 20);
+        assert!((sample.p95_ms - 19.0).abs() < 0.1, "p95 was {}", sample.p95_ms);
+    }
+
+    #[tokio::test]
+    async fn test_latency_percentiles_from_histogram() {
+        let metrics = MetricsCollector::new();
+        // 1ms..=100ms, one request each
+        for ms in 1..=100 {
+            metrics.record_request("GET", 200, Duration::from_millis(ms), 0, 0).await;
+        }
+
+        let json = metrics.get_json_metrics().await;
+        let p50 = json["latency"]["p50"].as_f64().unwrap();
+        let p95 = json["latency"]["p95"].as_f64().unwrap();
+        let p99 = json["latency"]["p99"].as_f64().unwrap();
+        let avg = json["latency"]["avg"].as_f64().unwrap();
+
+        // Three significant digits keeps these within 0.1%
+        assert!((p50 - 50.0).abs() < 0.1, "p50 was {}", p50);
+        assert!((p95 - 95.0).abs() < 0.1, "p95 was {}", p95);
+        assert!((p99 - 99.0).abs() < 0.1, "p99 was {}", p99);
+        assert!((avg - 50.5).abs() < 0.1, "avg was {}", avg);
+
+        let output = metrics.get_prometheus_metrics().await;
+        assert!(output.contains("http_request_duration_seconds_bucket{le=\"0.05\"} 50\n"));
+        assert!(output.contains("http_request_duration_seconds_count 100\n"));
+    }
+}
@@ -1,12 +1,13 @@
 use axum::{
     body::Body,
     extract::{Request, State},
-    http::{header::SET_COOKIE, HeaderValue, StatusCode},
+    http::{header::{CONTENT_TYPE, SET_COOKIE}, HeaderValue, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
+use percent_encoding::percent_decode_str;
 use std::sync::Arc;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::session::{Session, SessionManager, extract_csrf_token, validate_csrf_token};
 
@@ -77,48 +78,100 @@ pub async fn session_middleware(
 
     // Set session cookie if new or updated
     if let Some(session) = session {
-        if is_new {
-            let cookie = state.manager.create_cookie(&session.id);
-            response.headers_mut().insert(
-                SET_COOKIE,
-                HeaderValue::from_str(&cookie).unwrap_or_else(|_| HeaderValue::from_static("")),
-            );
+        // Stateless sessions live in the cookie, so it's reissued every time
+        if is_new || state.manager.is_stateless() {
+            match state.manager.session_cookie(&session) {
+                Ok(cookie) => {
+                    response.headers_mut().insert(
+                        SET_COOKIE,
+                        HeaderValue::from_str(&cookie).unwrap_or_else(|_| HeaderValue::from_static("")),
+                    );
+                }
+                Err(e) => error!("Failed to set session cookie: {}", e),
+            }
         }
     }
 
     Ok(response)
 }
 
-// CSRF protection middleware
+// Largest form body buffered when looking for a csrf_token field
+const CSRF_FORM_LIMIT: usize = 1024 * 1024;
+
+// CSRF protection middleware. Must run inside session_middleware, which
+// provides the session whose token is checked.
+//
+// POST/PUT/PATCH/DELETE need the session's token in an X-CSRF-Token header
+// or, for urlencoded forms, a csrf_token field; anything else gets a 403.
+// Paths in csrf_exempt_paths (e.g. webhook receivers) are not checked.
 pub async fn csrf_middleware(
+    State(state): State<SessionState>,
     request: Request<Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
     let method = request.method();
-    
+
     // Only check CSRF for state-changing methods
-    if method == "POST" || method == "PUT" || method == "DELETE" || method == "PATCH" {
-        let session_data = request.extensions().get::<SessionData>();
-        
-        if let Some(session_data) = session_data {
-            if let Some(ref session) = session_data.session {
-                // Extract CSRF token from request
-                let provided_token = extract_csrf_token(request.headers());
-                
-                if let Some(token) = provided_token {
-                    if !validate_csrf_token(session, &token) {
-                        error!("CSRF token validation failed");
-                        return Err(StatusCode::FORBIDDEN);
-                    }
-                } else {
-                    error!("Missing CSRF token");
-                    return Err(StatusCode::FORBIDDEN);
-                }
-            }
+    let unsafe_method = method == "POST" || method == "PUT" || method == "DELETE" || method == "PATCH";
+    if !unsafe_method || is_csrf_exempt(&state.manager.config().csrf_exempt_paths, request.uri().path()) {
+        return Ok(next.run(request).await);
+    }
+
+    let session = match request.extensions().get::<SessionData>().and_then(|d| d.session.clone()) {
+        Some(session) => session,
+        None => {
+            warn!("Rejecting {} {} without a session", method, request.uri().path());
+            return Err(StatusCode::FORBIDDEN);
+        }
+    };
+
+    let (request, provided_token) = match extract_csrf_token(request.headers()) {
+        Some(token) => (request, Some(token)),
+        None if is_urlencoded_form(&request) => {
+            let (parts, body) = request.into_parts();
+            let bytes = axum::body::to_bytes(body, CSRF_FORM_LIMIT).await
+                .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+            let token = csrf_form_field(&bytes);
+            (Request::from_parts(parts, Body::from(bytes)), token)
+        }
+        None => (request, None),
+    };
+
+    match provided_token {
+        Some(token) if validate_csrf_token(&session, &token) => Ok(next.run(request).await),
+        Some(_) => {
+            warn!("CSRF token validation failed for {}", request.uri().path());
+            Err(StatusCode::FORBIDDEN)
+        }
+        None => {
+            warn!("Missing CSRF token for {}", request.uri().path());
+            Err(StatusCode::FORBIDDEN)
         }
     }
-    
-    Ok(next.run(request).await)
+}
+
+// Exact paths, or prefixes when the entry ends in `*` ("/webhooks/*")
+fn is_csrf_exempt(exempt_paths: &[String], path: &str) -> bool {
+    exempt_paths.iter().any(|exempt| match exempt.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == exempt,
+    })
+}
+
+fn is_urlencoded_form(request: &Request<Body>) -> bool {
+    request.headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("application/x-www-form-urlencoded"))
+}
+
+fn csrf_form_field(body: &[u8]) -> Option<String> {
+    body.split(|b| *b == b'&')
+        .filter_map(|pair| {
+            let pair = String::from_utf8_lossy(pair).replace('+', " ");
+            let (name, value) = pair.split_once('=')?;
+            (name == "csrf_token").then(|| percent_decode_str(value).decode_utf8_lossy().into_owned())
+        })
+        .next()
 }
 
 // Helper extractors for handlers
@@ -195,4 +248,73 @@ where
         
         Err(StatusCode::UNAUTHORIZED)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{MemoryStore, SessionConfig};
+    use axum::{http::{HeaderMap, Request as HttpRequest}, routing::post, Router};
+    use tower::ServiceExt;
+
+    async fn app() -> (Router, Session) {
+        let config = SessionConfig {
+            csrf_protection: true,
+            csrf_exempt_paths: vec!["/webhooks/*".to_string()],
+            ..Default::default()
+        };
+        let manager = Arc::new(SessionManager::new(config, Arc::new(MemoryStore::new())));
+        let session = manager.create_session(&HeaderMap::new()).await.unwrap();
+
+        let state = SessionState { manager };
+        let router = Router::new()
+            .route("/form", post(|| async { "ok" }).get(|| async { "ok" }))
+            .route("/webhooks/github", post(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), csrf_middleware))
+            .layer(axum::middleware::from_fn_with_state(state, session_middleware));
+        (router, session)
+    }
+
+    async fn status(router: &Router, request: axum::http::request::Builder, body: &str) -> StatusCode {
+        router.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_valid_token_passes() {
+        let (router, session) = app().await;
+        let cookie = format!("session_id={}", session.id);
+
+        let header = HttpRequest::post("/form").header("cookie", &cookie).header("x-csrf-token", &session.csrf_token);
+        assert_eq!(status(&router, header, "").await, StatusCode::OK);
+
+        let form = HttpRequest::post("/form")
+            .header("cookie", &cookie)
+            .header("content-type", "application/x-www-form-urlencoded");
+        let body = format!("name=a+b&csrf_token={}", session.csrf_token);
+        assert_eq!(status(&router, form, &body).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_missing_or_wrong_token_rejected() {
+        let (router, session) = app().await;
+        let cookie = format!("session_id={}", session.id);
+
+        let missing = HttpRequest::post("/form").header("cookie", &cookie);
+        assert_eq!(status(&router, missing, "").await, StatusCode::FORBIDDEN);
+
+        let wrong = HttpRequest::post("/form").header("cookie", &cookie).header("x-csrf-token", "forged");
+        assert_eq!(status(&router, wrong, "").await, StatusCode::FORBIDDEN);
+
+        // A fresh session's token can't be known to the client yet
+        let no_session = HttpRequest::post("/form").header("x-csrf-token", &session.csrf_token);
+        assert_eq!(status(&router, no_session, "").await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_safe_methods_and_exempt_paths_pass() {
+        let (router, _) = app().await;
+
+        assert_eq!(status(&router, HttpRequest::get("/form"), "").await, StatusCode::OK);
+        assert_eq!(status(&router, HttpRequest::post("/webhooks/github"), "{}").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,210 @@
+use axum::body::Body;
+use axum::http::{header, request::Parts, Request};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::body_limit::deserialize_size;
+use crate::proxy_client::PooledClient;
+
+// Shadow traffic for a backend: a share of its requests is copied to
+// another upstream, e.g. a new version under test. The copy is sent in the
+// background and its response only logged, so it never changes what the
+// client gets or how long it waits.
+//
+//   [backends."api.example.com".mirror]
+//   target = "http://10.0.0.9:3000"
+//   percent = 10
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MirrorConfig {
+    pub target: String,
+    // Share of requests to copy, 0-100
+    #[serde(default = "default_percent")]
+    pub percent: f64,
+    // Request bodies are buffered to be sent twice; larger ones (and
+    // streamed bodies of unknown length) aren't mirrored
+    #[serde(default = "default_max_body_size", deserialize_with = "deserialize_size")]
+    pub max_body_size: u64,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_percent() -> f64 {
+    100.0
+}
+
+fn default_max_body_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_timeout_seconds() -> u64 {
+    10
+}
+
+impl MirrorConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match reqwest::Url::parse(&self.target) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => anyhow::bail!("mirror has invalid target {:?}", self.target),
+        }
+        if !(0.0..=100.0).contains(&self.percent) {
+            anyhow::bail!("mirror percent must be between 0 and 100, not {}", self.percent);
+        }
+        Ok(())
+    }
+
+    fn sampled(&self) -> bool {
+        self.percent >= 100.0 || rand::random::<f64>() * 100.0 < self.percent
+    }
+}
+
+// Copy the request to the mirror when it's sampled, returning the body to
+// send on to the primary. Mirrored bodies are read into memory first; a body
+// that fails to read is returned as the error.
+pub async fn tee(client: &PooledClient, config: &MirrorConfig, parts: &Parts, body: Body) -> Result<Body, axum::Error> {
+    if !config.sampled() {
+        return Ok(body);
+    }
+
+    let declared_length = parts.headers.get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let body_length = match declared_length {
+        Some(length) => length,
+        None if parts.headers.contains_key(header::TRANSFER_ENCODING) => u64::MAX,
+        None => 0,
+    };
+    if body_length > config.max_body_size {
+        debug!("Not mirroring {} {}: body over {} bytes", parts.method, parts.uri, config.max_body_size);
+        return Ok(body);
+    }
+
+    let bytes = axum::body::to_bytes(body, config.max_body_size as usize).await?;
+    spawn_mirror(client.clone(), config, parts, bytes.clone());
+    Ok(Body::from(bytes))
+}
+
+fn spawn_mirror(client: PooledClient, config: &MirrorConfig, parts: &Parts, body: Bytes) {
+    let path_and_query = parts.uri.path_and_query().map_or("/", |pq| pq.as_str());
+    let target = format!("{}{}", config.target.trim_end_matches('/'), path_and_query);
+    let mut builder = Request::builder().method(parts.method.clone()).uri(&target);
+    for (name, value) in &parts.headers {
+        // The client sets Host from the target URL
+        if name != header::HOST && name != header::CONNECTION && name != header::TRANSFER_ENCODING {
+            builder = builder.header(name, value);
+        }
+    }
+    let req = match builder.body(Body::from(body)) {
+        Ok(req) => req,
+        Err(e) => {
+            warn!("Invalid mirror request to {}: {}", target, e);
+            return;
+        }
+    };
+
+    let timeout = Duration::from_secs(config.timeout_seconds);
+    let method = parts.method.clone();
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let response = tokio::time::timeout(timeout, async {
+            let response = client.request(req).await?;
+            let status = response.status();
+            // Read the body so the connection goes back to the pool
+            let length = axum::body::to_bytes(Body::new(response.into_body()), usize::MAX).await
+                .map_or(0, |body| body.len());
+            anyhow::Ok((status, length))
+        }).await;
+        let elapsed = started.elapsed().as_millis();
+        match response {
+            Ok(Ok((status, length))) => info!("Mirror {} {} -> {} ({} bytes) in {}ms", method, target, status.as_u16(), length, elapsed),
+            Ok(Err(e)) => warn!("Mirror {} {} failed after {}ms: {}", method, target, elapsed, e),
+            Err(_) => warn!("Mirror {} {} timed out after {}ms", method, target, elapsed),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_client::{build_client, send, ConnectionPoolConfig, TimeoutConfig};
+    use axum::http::StatusCode;
+    use axum::Router;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    // Upstream that reports each request's method, path and body, answering
+    // with `status` and `reply` after `delay`
+    async fn spawn_upstream(status: StatusCode, reply: &'static str, delay: Duration) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new().fallback(move |req: Request<Body>| {
+            let tx = tx.clone();
+            async move {
+                let (parts, body) = req.into_parts();
+                let body = axum::body::to_bytes(body, 1024).await.unwrap();
+                let _ = tx.send(format!("{} {} {}", parts.method, parts.uri, String::from_utf8_lossy(&body)));
+                tokio::time::sleep(delay).await;
+                (status, reply)
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn test_mirrored_request_reaches_both_upstreams() {
+        let (primary, mut primary_hits) = spawn_upstream(StatusCode::OK, "primary", Duration::ZERO).await;
+        let (shadow, mut shadow_hits) = spawn_upstream(StatusCode::INTERNAL_SERVER_ERROR, "shadow", Duration::from_secs(2)).await;
+        let client = build_client(&ConnectionPoolConfig::default(), &TimeoutConfig::default());
+        let config: MirrorConfig = toml::from_str(&format!("target = \"http://{}\"", shadow)).unwrap();
+        config.validate().unwrap();
+
+        let req = Request::post("/orders?id=7")
+            .header(header::CONTENT_LENGTH, "5")
+            .body(Body::from("hello"))
+            .unwrap();
+        let (mut parts, body) = req.into_parts();
+        let started = Instant::now();
+        let body = tee(&client, &config, &parts, body).await.unwrap();
+        parts.uri = format!("http://{}/orders?id=7", primary).parse().unwrap();
+        let response = send(&client, Request::from_parts(parts, body), 1024, 1024, &TimeoutConfig::default()).await.unwrap();
+
+        // The client gets the primary's answer without waiting on the shadow
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(&body[..], b"primary");
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(primary_hits.recv().await.unwrap(), "POST /orders?id=7 hello");
+        let shadowed = tokio::time::timeout(Duration::from_secs(1), shadow_hits.recv()).await.unwrap().unwrap();
+        assert_eq!(shadowed, "POST /orders?id=7 hello");
+    }
+
+    #[tokio::test]
+    async fn test_sampling_and_large_bodies() {
+        let client = build_client(&ConnectionPoolConfig::default(), &TimeoutConfig::default());
+        let (shadow, mut shadow_hits) = spawn_upstream(StatusCode::OK, "shadow", Duration::ZERO).await;
+
+        // 0% mirrors nothing
+        let config: MirrorConfig = toml::from_str(&format!("target = \"http://{}\"\npercent = 0", shadow)).unwrap();
+        let (parts, body) = Request::get("/").body(Body::empty()).unwrap().into_parts();
+        tee(&client, &config, &parts, body).await.unwrap();
+
+        // Bodies over max_body_size go to the primary untouched
+        let config: MirrorConfig = toml::from_str(&format!("target = \"http://{}\"\nmax_body_size = \"4B\"", shadow)).unwrap();
+        let (parts, body) = Request::post("/").header(header::CONTENT_LENGTH, "5").body(Body::from("hello")).unwrap().into_parts();
+        let body = tee(&client, &config, &parts, body).await.unwrap();
+        assert_eq!(&axum::body::to_bytes(body, 1024).await.unwrap()[..], b"hello");
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(shadow_hits.try_recv().is_err());
+
+        let config: MirrorConfig = toml::from_str("target = \"ftp://shadow\"").unwrap();
+        assert!(config.validate().is_err());
+        let config: MirrorConfig = toml::from_str("target = \"http://shadow\"\npercent = 150").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
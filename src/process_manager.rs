@@ -12,8 +12,15 @@ pub struct ProcessConfig {
     pub app_type: AppType,
     pub command: String,
     pub args: Vec<String>,
-    pub working_dir: String,
+    // Defaults to the server's working directory
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    #[serde(default)]
     pub env: HashMap<String, String>,
+    // Start from an empty environment instead of inheriting the server's;
+    // PATH and friends then have to be set in `env`
+    #[serde(default)]
+    pub clear_env: bool,
     pub port: u16,
     pub health_check: Option<String>,
     pub auto_restart: bool,
@@ -35,8 +42,46 @@ pub struct ProcessInfo {
     pub status: ProcessStatus,
     pub restarts: u32,
     pub last_health_check: std::time::Instant,
+    pub started_at: Option<std::time::Instant>,
+    // Port the running instance listens on; differs from config.port after
+    // a graceful restart moved it to a fresh port
+    pub port: u16,
+    pub last_exit_code: Option<i32>,
+    // Automatic restarts inside the crash loop window
+    pub restart_history: Vec<std::time::Instant>,
+    pub next_restart_at: Option<std::time::Instant>,
+}
+
+// What /api/processes reports for each process
+#[derive(Clone, Debug, Serialize)]
+pub struct ProcessDetails {
+    pub status: ProcessStatus,
+    pub pid: Option<u32>,
+    pub uptime_seconds: Option<u64>,
+    pub restarts: u32,
+    pub last_exit_code: Option<i32>,
+    // Configured env, with values that look like secrets redacted
+    pub env: HashMap<String, String>,
 }
 
+// How long a process gets to exit after SIGTERM before it is killed
+const STOP_GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(5);
+
+// How long a replacement instance has to pass its health check
+const STARTUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// How long the old instance keeps serving in-flight requests after a switch
+const DRAIN_PERIOD: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Automatic restarts back off exponentially from RESTART_BASE_DELAY up to
+// RESTART_MAX_DELAY. A process that needs more than MAX_RESTARTS_IN_WINDOW
+// restarts within RESTART_WINDOW is crash looping and is left Failed.
+const RESTART_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const RESTART_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+const RESTART_WINDOW: std::time::Duration = std::time::Duration::from_secs(300);
+const MAX_RESTARTS_IN_WINDOW: usize = 5;
+const MONITOR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ProcessStatus {
@@ -62,10 +107,7 @@ impl ProcessManager {
         info!("Starting {} process: {}", config.app_type.to_string(), name);
         
         let mut child = match config.app_type {
-            AppType::NodeJs => self.start_nodejs(&config)?,
-            AppType::Python => self.start_python(&config)?,
-            AppType::Tomcat => self.start_tomcat(&config)?,
-            AppType::PhpFpm => self.start_phpfpm(&config)?,
+            AppType::NodeJs | AppType::Python | AppType::Tomcat | AppType::PhpFpm => self.spawn(&config)?,
             AppType::Static => {
                 // Static doesn't need a process
                 let mut processes = self.processes.write().await;
@@ -75,24 +117,45 @@ impl ProcessManager {
                     status: ProcessStatus::Running,
                     restarts: 0,
                     last_health_check: std::time::Instant::now(),
+                    started_at: Some(std::time::Instant::now()),
+                    port: config.port,
+                    last_exit_code: None,
+                    restart_history: Vec::new(),
+                    next_restart_at: None,
                 });
                 return Ok(());
             }
         };
 
         let mut processes = self.processes.write().await;
+        let port = config.port;
         processes.insert(name.clone(), ProcessInfo {
             config,
             child: Some(child),
             status: ProcessStatus::Running,
             restarts: 0,
             last_health_check: std::time::Instant::now(),
+            started_at: Some(std::time::Instant::now()),
+            port,
+            last_exit_code: None,
+            restart_history: Vec::new(),
+            next_restart_at: None,
         });
 
         info!("Process {} started successfully", name);
         Ok(())
     }
 
+    fn spawn(&self, config: &ProcessConfig) -> Result<Child> {
+        match config.app_type {
+            AppType::NodeJs => self.start_nodejs(config),
+            AppType::Python => self.start_python(config),
+            AppType::Tomcat => self.start_tomcat(config),
+            AppType::PhpFpm => self.start_phpfpm(config),
+            AppType::Static => Err(anyhow!("Static apps have no process to spawn")),
+        }
+    }
+
     fn start_nodejs(&self, config: &ProcessConfig) -> Result<Child> {
         let mut cmd = Command::new("node");
         
@@ -101,13 +164,7 @@ impl ProcessManager {
             cmd.arg(arg);
         }
         
-        // Set working directory
-        cmd.current_dir(&config.working_dir);
-        
-        // Set environment variables
-        for (key, value) in &config.env {
-            cmd.env(key, value);
-        }
+        apply_environment(&mut cmd, config);
         
         // Set PORT environment variable
         cmd.env("PORT", config.port.to_string());
@@ -130,13 +187,7 @@ impl ProcessManager {
             cmd.arg(arg);
         }
         
-        // Set working directory
-        cmd.current_dir(&config.working_dir);
-        
-        // Set environment variables
-        for (key, value) in &config.env {
-            cmd.env(key, value);
-        }
+        apply_environment(&mut cmd, config);
         
         // Set PORT environment variable
         cmd.env("PORT", config.port.to_string());
@@ -164,13 +215,7 @@ impl ProcessManager {
         let mut cmd = Command::new(catalina_path);
         cmd.arg("run"); // Run in foreground
         
-        // Set working directory
-        cmd.current_dir(&config.working_dir);
-        
-        // Set environment variables
-        for (key, value) in &config.env {
-            cmd.env(key, value);
-        }
+        apply_environment(&mut cmd, config);
         
         // Set Tomcat port (modifies server.xml typically, but we'll use env var)
         cmd.env("CATALINA_OPTS", format!("-Dserver.port={}", config.port));
@@ -196,13 +241,7 @@ impl ProcessManager {
             cmd.arg("-y").arg(config_file);
         }
         
-        // Set working directory
-        cmd.current_dir(&config.working_dir);
-        
-        // Set environment variables
-        for (key, value) in &config.env {
-            cmd.env(key, value);
-        }
+        apply_environment(&mut cmd, config);
         
         // Redirect stdout/stderr for logging
         cmd.stdout(Stdio::piped())
@@ -215,35 +254,101 @@ impl ProcessManager {
     }
 
     pub async fn stop_process(&self, name: &str) -> Result<()> {
-        let mut processes = self.processes.write().await;
+        // Take the child out so the lock isn't held while it shuts down; the
+        // entry stays so the process can be started again later
+        let child = {
+            let mut processes = self.processes.write().await;
+            let process_info = processes.get_mut(name)
+                .ok_or_else(|| anyhow!("Process {} not found", name))?;
+            process_info.status = ProcessStatus::Stopped;
+            process_info.started_at = None;
+            process_info.child.take()
+        };
         
-        if let Some(mut process_info) = processes.remove(name) {
-            if let Some(mut child) = process_info.child {
-                info!("Stopping process: {}", name);
-                
-                // Try graceful shutdown first
-                #[cfg(unix)]
-                {
-                    use nix::sys::signal::{self, Signal};
-                    use nix::unistd::Pid;
-                    
-                    if let Ok(pid) = child.id().try_into() {
-                        let _ = signal::kill(Pid::from_raw(pid), Signal::SIGTERM);
-                    }
-                }
-                
-                // Wait a bit for graceful shutdown
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                
-                // Force kill if still running
-                let _ = child.kill();
-                let _ = child.wait();
-                
-                info!("Process {} stopped", name);
+        if let Some(child) = child {
+            info!("Stopping process: {}", name);
+            terminate_child(name, child).await;
+            info!("Process {} stopped", name);
+        }
+        Ok(())
+    }
+
+    // Restart without a gap in service: bring a new instance up on a spare
+    // port, switch traffic to it once healthy, then drain and stop the old
+    // one. If the new instance never becomes healthy the old one keeps running.
+    pub async fn restart_process_graceful(&self, name: &str) -> Result<u16> {
+        let config = {
+            let processes = self.processes.read().await;
+            let process_info = processes.get(name)
+                .ok_or_else(|| anyhow!("Process {} not found", name))?;
+            if process_info.child.is_none() {
+                return Err(anyhow!("Process {} is not running", name));
+            }
+            process_info.config.clone()
+        };
+        
+        let mut new_config = config.clone();
+        new_config.port = free_port()?;
+        info!("Starting replacement for {} on port {}", name, new_config.port);
+        
+        let mut new_child = self.spawn(&new_config)?;
+        if let Err(e) = wait_until_healthy(&new_config).await {
+            warn!("Replacement for {} failed to become healthy, keeping the old instance: {}", name, e);
+            let _ = new_child.kill();
+            let _ = new_child.wait();
+            return Err(e);
+        }
+        
+        // Switch traffic over; the old child is returned so it can drain
+        let old_child = {
+            let mut processes = self.processes.write().await;
+            let process_info = processes.get_mut(name)
+                .ok_or_else(|| anyhow!("Process {} disappeared during restart", name))?;
+            process_info.port = new_config.port;
+            process_info.status = ProcessStatus::Running;
+            process_info.started_at = Some(std::time::Instant::now());
+            process_info.restarts += 1;
+            process_info.child.replace(new_child)
+        };
+        info!("Switched {} to port {}", name, new_config.port);
+        
+        if let Some(old_child) = old_child {
+            tokio::time::sleep(DRAIN_PERIOD).await;
+            terminate_child(name, old_child).await;
+        }
+        
+        Ok(new_config.port)
+    }
+
+    // Port the process is currently reachable on
+    pub async fn active_port(&self, name: &str) -> Option<u16> {
+        let processes = self.processes.read().await;
+        processes.get(name)
+            .filter(|info| info.status == ProcessStatus::Running)
+            .map(|info| info.port)
+    }
+
+    // Start a previously stopped process again with its original config
+    pub async fn start_stopped_process(&self, name: &str) -> Result<()> {
+        let config = {
+            let processes = self.processes.read().await;
+            let process_info = processes.get(name)
+                .ok_or_else(|| anyhow!("Process {} not found", name))?;
+            if process_info.status == ProcessStatus::Running {
+                return Err(anyhow!("Process {} is already running", name));
+            }
+            process_info.config.clone()
+        };
+        
+        self.start_process(name.to_string(), config).await
+    }
+
+    pub async fn stop_all(&self) {
+        let names: Vec<String> = self.processes.read().await.keys().cloned().collect();
+        for name in names {
+            if let Err(e) = self.stop_process(&name).await {
+                warn!("Failed to stop process {}: {}", name, e);
             }
-            Ok(())
-        } else {
-            Err(anyhow!("Process {} not found", name))
         }
     }
 
@@ -286,41 +391,198 @@ impl ProcessManager {
         Ok(false)
     }
 
-    pub async fn get_status(&self) -> HashMap<String, ProcessStatus> {
+    pub async fn get_details(&self) -> HashMap<String, ProcessDetails> {
         let processes = self.processes.read().await;
-        let mut result = HashMap::new();
-        for (name, info) in processes.iter() {
-            result.insert(name.clone(), info.status.clone());
-        }
-        result
+        processes.iter()
+            .map(|(name, info)| {
+                let details = ProcessDetails {
+                    status: info.status.clone(),
+                    pid: info.child.as_ref().map(|child| child.id()),
+                    uptime_seconds: info.started_at.map(|started| started.elapsed().as_secs()),
+                    restarts: info.restarts,
+                    last_exit_code: info.last_exit_code,
+                    env: redacted_env(&info.config.env),
+                };
+                (name.clone(), details)
+            })
+            .collect()
     }
 
     pub async fn monitor_processes(&self) {
         let manager = self.clone();
         tokio::spawn(async move {
             loop {
-                tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
-                
-                let names_to_restart = {
-                    let processes = manager.processes.read().await;
-                    let mut to_restart = Vec::new();
-                    for (name, info) in processes.iter() {
-                        if info.config.auto_restart && info.status == ProcessStatus::Failed {
-                            to_restart.push(name.clone());
-                        }
-                    }
-                    to_restart
-                };
-                
-                for name in names_to_restart {
-                    warn!("Process {} failed, attempting restart", name);
-                    if let Err(e) = manager.restart_process(&name).await {
-                        error!("Failed to restart process {}: {}", name, e);
-                    }
-                }
+                tokio::time::sleep(MONITOR_INTERVAL).await;
+                manager.reap_exited().await;
+                manager.restart_due().await;
             }
         });
     }
+
+    // Notice children that have exited and schedule their restart
+    async fn reap_exited(&self) {
+        let mut processes = self.processes.write().await;
+        for (name, info) in processes.iter_mut() {
+            let exit = match info.child.as_mut().map(|child| child.try_wait()) {
+                Some(Ok(Some(exit))) => exit,
+                _ => continue,
+            };
+            
+            warn!("Process {} exited with {}", name, exit);
+            info.child = None;
+            info.started_at = None;
+            info.last_exit_code = exit.code();
+            
+            if info.config.auto_restart {
+                schedule_restart(name, info, std::time::Instant::now());
+            } else {
+                info.status = ProcessStatus::Failed;
+            }
+        }
+    }
+
+    async fn restart_due(&self) {
+        let mut processes = self.processes.write().await;
+        let now = std::time::Instant::now();
+        
+        for (name, info) in processes.iter_mut() {
+            let due = info.status == ProcessStatus::Restarting
+                && info.next_restart_at.map_or(false, |at| at <= now);
+            if !due {
+                continue;
+            }
+            
+            info.next_restart_at = None;
+            info.restart_history.push(now);
+            info.restarts += 1;
+            
+            match self.spawn(&info.config) {
+                Ok(child) => {
+                    info!("Process {} restarted (restart #{})", name, info.restarts);
+                    info.child = Some(child);
+                    info.status = ProcessStatus::Running;
+                    info.started_at = Some(now);
+                    info.port = info.config.port;
+                }
+                Err(e) => {
+                    error!("Failed to restart process {}: {}", name, e);
+                    schedule_restart(name, info, now);
+                }
+            }
+        }
+    }
+}
+
+// Put a dead process into Restarting with a backoff delay, or into Failed if
+// it has been restarted too often recently
+fn schedule_restart(name: &str, info: &mut ProcessInfo, now: std::time::Instant) {
+    info.restart_history.retain(|t| now.duration_since(*t) < RESTART_WINDOW);
+    
+    if info.restart_history.len() >= MAX_RESTARTS_IN_WINDOW {
+        error!(
+            "Process {} restarted {} times within {}s, giving up until it is started manually",
+            name, info.restart_history.len(), RESTART_WINDOW.as_secs()
+        );
+        info.status = ProcessStatus::Failed;
+        info.next_restart_at = None;
+        return;
+    }
+    
+    let delay = restart_delay(info.restart_history.len());
+    info!("Restarting process {} in {}s", name, delay.as_secs());
+    info.status = ProcessStatus::Restarting;
+    info.next_restart_at = Some(now + delay);
+}
+
+fn restart_delay(recent_restarts: usize) -> std::time::Duration {
+    let factor = 2u32.saturating_pow(recent_restarts as u32);
+    RESTART_BASE_DELAY.saturating_mul(factor).min(RESTART_MAX_DELAY)
+}
+
+// SIGTERM, then SIGKILL if the process is still around after the grace period
+async fn terminate_child(name: &str, mut child: Child) {
+    // Try graceful shutdown first
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{self, Signal};
+        use nix::unistd::Pid;
+        
+        if let Ok(pid) = child.id().try_into() {
+            let _ = signal::kill(Pid::from_raw(pid), Signal::SIGTERM);
+        }
+    }
+    
+    // Wait for it to exit, up to the grace period
+    let deadline = std::time::Instant::now() + STOP_GRACE_PERIOD;
+    while std::time::Instant::now() < deadline {
+        if let Ok(Some(_)) = child.try_wait() {
+            break;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+    
+    // Force kill if still running
+    if let Ok(None) = child.try_wait() {
+        warn!("Process {} did not exit after SIGTERM, killing it", name);
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+}
+
+fn apply_environment(cmd: &mut Command, config: &ProcessConfig) {
+    if config.clear_env {
+        cmd.env_clear();
+    }
+    if let Some(working_dir) = &config.working_dir {
+        cmd.current_dir(working_dir);
+    }
+    cmd.envs(&config.env);
+}
+
+const SECRET_MARKERS: &[&str] = &["SECRET", "PASSWORD", "PASSWD", "TOKEN", "KEY", "CREDENTIAL", "DATABASE_URL", "DSN"];
+
+fn redacted_env(env: &HashMap<String, String>) -> HashMap<String, String> {
+    env.iter()
+        .map(|(key, value)| {
+            let upper = key.to_uppercase();
+            let value = if SECRET_MARKERS.iter().any(|marker| upper.contains(marker)) {
+                "[REDACTED]".to_string()
+            } else {
+                value.clone()
+            };
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+// Ask the OS for an unused local port
+fn free_port() -> Result<u16> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+// Poll the health check path (or just the port, without one) until it answers
+async fn wait_until_healthy(config: &ProcessConfig) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(2))
+        .build()?;
+    let deadline = std::time::Instant::now() + STARTUP_TIMEOUT;
+    
+    while std::time::Instant::now() < deadline {
+        let healthy = match &config.health_check {
+            Some(path) => {
+                let url = format!("http://127.0.0.1:{}{}", config.port, path);
+                matches!(client.get(&url).send().await, Ok(resp) if resp.status().is_success())
+            }
+            None => tokio::net::TcpStream::connect(("127.0.0.1", config.port)).await.is_ok(),
+        };
+        if healthy {
+            return Ok(());
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    }
+    
+    Err(anyhow!("not healthy on port {} after {}s", config.port, STARTUP_TIMEOUT.as_secs()))
 }
 
 impl Clone for ProcessManager {
@@ -341,4 +603,107 @@ impl AppType {
             AppType::Static => "Static",
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn crashed_process() -> ProcessInfo {
+        ProcessInfo {
+            config: ProcessConfig {
+                app_type: AppType::NodeJs,
+                command: "server.js".to_string(),
+                args: vec![],
+                working_dir: None,
+                env: HashMap::new(),
+                clear_env: false,
+                port: 3000,
+                health_check: None,
+                auto_restart: true,
+            },
+            child: None,
+            status: ProcessStatus::Running,
+            restarts: 0,
+            last_health_check: Instant::now(),
+            started_at: None,
+            port: 3000,
+            last_exit_code: Some(1),
+            restart_history: Vec::new(),
+            next_restart_at: None,
+        }
+    }
+
+    #[test]
+    fn test_restart_delay_backs_off_to_cap() {
+        assert_eq!(restart_delay(0), Duration::from_secs(1));
+        assert_eq!(restart_delay(1), Duration::from_secs(2));
+        assert_eq!(restart_delay(3), Duration::from_secs(8));
+        assert_eq!(restart_delay(10), RESTART_MAX_DELAY);
+        assert_eq!(restart_delay(100), RESTART_MAX_DELAY);
+    }
+
+    #[test]
+    fn test_crash_loop_gives_up() {
+        let mut info = crashed_process();
+        let now = Instant::now();
+
+        for _ in 0..MAX_RESTARTS_IN_WINDOW {
+            schedule_restart("app", &mut info, now);
+            assert_eq!(info.status, ProcessStatus::Restarting);
+            info.restart_history.push(now);
+        }
+
+        schedule_restart("app", &mut info, now);
+        assert_eq!(info.status, ProcessStatus::Failed);
+        assert!(info.next_restart_at.is_none());
+    }
+
+    #[test]
+    fn test_old_restarts_fall_out_of_window() {
+        let mut info = crashed_process();
+        let long_ago = Instant::now();
+        info.restart_history = vec![long_ago; MAX_RESTARTS_IN_WINDOW];
+        let now = long_ago + RESTART_WINDOW + Duration::from_secs(1);
+
+        schedule_restart("app", &mut info, now);
+        assert_eq!(info.status, ProcessStatus::Restarting);
+        assert_eq!(info.next_restart_at, Some(now + RESTART_BASE_DELAY));
+    }
+    fn shell(script: &str, config: &ProcessConfig) -> String {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(script);
+        apply_environment(&mut cmd, config);
+        let output = cmd.output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_env_and_working_dir() {
+        let mut config = crashed_process().config;
+        config.env.insert("FOO".to_string(), "bar-from-config".to_string());
+        assert!(shell("echo $FOO", &config).contains("bar-from-config"));
+
+        std::env::set_var("MIWIDOTHTTP_INHERITED", "leaked");
+        config.clear_env = true;
+        config.working_dir = Some(std::env::temp_dir());
+        let output = shell("echo \"[$MIWIDOTHTTP_INHERITED]\"; pwd", &config);
+        assert!(output.contains("[]"), "{}", output);
+        let temp_dir = std::env::temp_dir().canonicalize().unwrap();
+        assert!(output.contains(temp_dir.to_str().unwrap()), "{}", output);
+    }
+
+    #[test]
+    fn test_redacted_env() {
+        let mut env = HashMap::new();
+        env.insert("NODE_ENV".to_string(), "production".to_string());
+        env.insert("DATABASE_URL".to_string(), "postgres://user:pw@db/app".to_string());
+        env.insert("api_token".to_string(), "abc123".to_string());
+
+        let env = redacted_env(&env);
+        assert_eq!(env["NODE_ENV"], "production");
+        assert_eq!(env["DATABASE_URL"], "[REDACTED]");
+        assert_eq!(env["api_token"], "[REDACTED]");
+    }
+}
@@ -0,0 +1,219 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long an issued nonce is accepted before the client is asked to retry
+// with a fresh one (stale=true)
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthOutcome {
+    Authorized,
+    Rejected,
+    // Right credentials, but the nonce expired or is unknown
+    Stale,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+struct NonceState {
+    issued: Instant,
+    // Highest nonce count seen; a request must use a larger one
+    last_nc: u32,
+}
+
+// RFC 7616 Digest authentication (qop=auth) with MD5 or SHA-256. Nonces are
+// tracked server-side so replayed responses are refused.
+pub struct DigestAuth {
+    realm: String,
+    nonces: Mutex<HashMap<String, NonceState>>,
+}
+
+impl DigestAuth {
+    pub fn new(realm: String) -> Self {
+        Self {
+            realm,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Proxy-Authenticate values for a 407, strongest algorithm first
+    pub fn challenges(&self, stale: bool) -> Vec<String> {
+        let nonce = uuid::Uuid::new_v4().simple().to_string();
+        self.register_nonce(&nonce);
+
+        let stale = if stale { ", stale=true" } else { "" };
+        ["SHA-256", "MD5"].iter()
+            .map(|algorithm| format!(
+                "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}",
+                self.realm, algorithm, nonce, stale,
+            ))
+            .collect()
+    }
+
+    fn register_nonce(&self, nonce: &str) {
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, state| state.issued.elapsed() < NONCE_LIFETIME);
+        nonces.insert(nonce.to_string(), NonceState { issued: Instant::now(), last_nc: 0 });
+    }
+
+    pub fn verify(&self, header: &str, method: &str, uri: &str, username: &str, password: &str) -> AuthOutcome {
+        let params = match header.strip_prefix("Digest ") {
+            Some(params) => parse_params(params),
+            None => return AuthOutcome::Rejected,
+        };
+        let get = |key: &str| params.get(key).map(String::as_str);
+
+        let (Some(user), Some(realm), Some(nonce), Some(digest_uri), Some(response), Some(nc), Some(cnonce)) = (
+            get("username"), get("realm"), get("nonce"), get("uri"), get("response"), get("nc"), get("cnonce"),
+        ) else {
+            return AuthOutcome::Rejected;
+        };
+        if user != username || realm != self.realm || digest_uri != uri || get("qop") != Some("auth") {
+            return AuthOutcome::Rejected;
+        }
+
+        let algorithm = match get("algorithm").unwrap_or("MD5") {
+            a if a.eq_ignore_ascii_case("MD5") => Algorithm::Md5,
+            a if a.eq_ignore_ascii_case("SHA-256") => Algorithm::Sha256,
+            _ => return AuthOutcome::Rejected,
+        };
+        let nc_value = match u32::from_str_radix(nc, 16) {
+            Ok(nc) => nc,
+            Err(_) => return AuthOutcome::Rejected,
+        };
+
+        let ha1 = hash(algorithm, &format!("{}:{}:{}", username, self.realm, password));
+        let ha2 = hash(algorithm, &format!("{}:{}", method, digest_uri));
+        let expected = hash(algorithm, &format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+        if !constant_time_eq(expected.as_bytes(), response.to_ascii_lowercase().as_bytes()) {
+            return AuthOutcome::Rejected;
+        }
+
+        // Only checked once the credentials are known to be right, since
+        // stale=true tells the client to retry without asking the user
+        let mut nonces = self.nonces.lock().unwrap();
+        match nonces.get_mut(nonce) {
+            None => AuthOutcome::Stale,
+            Some(state) if state.issued.elapsed() >= NONCE_LIFETIME => {
+                nonces.remove(nonce);
+                AuthOutcome::Stale
+            }
+            Some(state) if nc_value <= state.last_nc => AuthOutcome::Rejected,
+            Some(state) => {
+                state.last_nc = nc_value;
+                AuthOutcome::Authorized
+            }
+        }
+    }
+}
+
+fn hash(algorithm: Algorithm, data: &str) -> String {
+    match algorithm {
+        Algorithm::Md5 => format!("{:x}", Md5::digest(data.as_bytes())),
+        Algorithm::Sha256 => format!("{:x}", Sha256::digest(data.as_bytes())),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Parse `key=value, key="quoted, value"` pairs from a Digest header
+fn parse_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ',')).collect();
+        if key.is_empty() {
+            break;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            continue;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value.extend(std::iter::from_fn(|| chars.next_if(|c| *c != ',')));
+        }
+        params.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example from RFC 7616 section 3.9.1
+    const REALM: &str = "http-auth@example.org";
+    const NONCE: &str = "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v";
+    const CNONCE: &str = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+    fn authorization(algorithm: &str, nc: &str, response: &str) -> String {
+        format!(
+            "Digest username=\"Mufasa\", realm=\"{}\", uri=\"/dir/index.html\", algorithm={}, \
+             nonce=\"{}\", nc={}, cnonce=\"{}\", qop=auth, response=\"{}\", opaque=\"x\"",
+            REALM, algorithm, NONCE, nc, CNONCE, response,
+        )
+    }
+
+    fn auth() -> DigestAuth {
+        let auth = DigestAuth::new(REALM.to_string());
+        auth.register_nonce(NONCE);
+        auth
+    }
+
+    #[test]
+    fn test_known_md5_response() {
+        let header = authorization("MD5", "00000001", "8ca523f5e9506fed4657c9700eebdbec");
+        assert_eq!(auth().verify(&header, "GET", "/dir/index.html", "Mufasa", "Circle of Life"), AuthOutcome::Authorized);
+    }
+
+    #[test]
+    fn test_known_sha256_response() {
+        let header = authorization("SHA-256", "00000001", "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1");
+        let auth = auth();
+        assert_eq!(auth.verify(&header, "GET", "/dir/index.html", "Mufasa", "Circle of Life"), AuthOutcome::Authorized);
+
+        // The same nonce count again is a replay
+        assert_eq!(auth.verify(&header, "GET", "/dir/index.html", "Mufasa", "Circle of Life"), AuthOutcome::Rejected);
+    }
+
+    #[test]
+    fn test_rejects_wrong_password_and_unknown_nonce() {
+        let header = authorization("MD5", "00000001", "8ca523f5e9506fed4657c9700eebdbec");
+        assert_eq!(auth().verify(&header, "GET", "/dir/index.html", "Mufasa", "wrong"), AuthOutcome::Rejected);
+        assert_eq!(auth().verify(&header, "GET", "/other", "Mufasa", "Circle of Life"), AuthOutcome::Rejected);
+
+        // A nonce this server never issued (or has forgotten) is stale
+        let fresh = DigestAuth::new(REALM.to_string());
+        assert_eq!(fresh.verify(&header, "GET", "/dir/index.html", "Mufasa", "Circle of Life"), AuthOutcome::Stale);
+    }
+
+    #[test]
+    fn test_challenges() {
+        let challenges = DigestAuth::new("Proxy".to_string()).challenges(true);
+        assert_eq!(challenges.len(), 2);
+        assert!(challenges[0].starts_with("Digest realm=\"Proxy\", qop=\"auth\", algorithm=SHA-256, nonce=\""));
+        assert!(challenges[1].contains("algorithm=MD5"));
+        assert!(challenges.iter().all(|c| c.ends_with(", stale=true")));
+    }
+}
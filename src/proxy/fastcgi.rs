@@ -1,49 +1,51 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use axum::{
-    body::{Body, Bytes},
+    body::Body,
     extract::Request,
     http::{HeaderMap, Method, StatusCode, Uri},
     response::Response,
 };
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::Cursor;
-use std::path::{Path, PathBuf};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, info, warn};
 
 const FCGI_VERSION: u8 = 1;
 const FCGI_BEGIN_REQUEST: u8 = 1;
-const FCGI_ABORT_REQUEST: u8 = 2;
 const FCGI_END_REQUEST: u8 = 3;
 const FCGI_PARAMS: u8 = 4;
 const FCGI_STDIN: u8 = 5;
 const FCGI_STDOUT: u8 = 6;
 const FCGI_STDERR: u8 = 7;
-const FCGI_DATA: u8 = 8;
-const FCGI_GET_VALUES: u8 = 9;
-const FCGI_GET_VALUES_RESULT: u8 = 10;
 
 const FCGI_RESPONDER: u16 = 1;
-const FCGI_AUTHORIZER: u16 = 2;
-const FCGI_FILTER: u16 = 3;
 
-const FCGI_REQUEST_COMPLETE: u8 = 0;
-const FCGI_CANT_MPX_CONN: u8 = 1;
-const FCGI_OVERLOADED: u8 = 2;
-const FCGI_UNKNOWN_ROLE: u8 = 3;
-
-#[derive(Debug, Clone)]
+// A PHP-FPM backend, e.g.
+//
+//   [backends."php.example.com".fastcgi]
+//   tcp_addr = "127.0.0.1:9000"
+//   document_root = "/var/www/html"
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct FastCGIConfig {
+    // Unix socket of the pool; tcp_addr takes precedence when both are set
     pub socket_path: Option<String>,
     pub tcp_addr: Option<String>,
     pub document_root: PathBuf,
     pub index_files: Vec<String>,
+    // Front controller run for every request, relative to document_root
     pub script_filename: Option<String>,
     pub params: HashMap<String, String>,
+    // Seconds; 0 turns a timeout off
     pub connect_timeout: u64,
     pub read_timeout: u64,
     pub write_timeout: u64,
+    // Request bodies are buffered for STDIN, so they are capped
+    pub max_request_size: usize,
 }
 
 impl Default for FastCGIConfig {
@@ -58,6 +60,7 @@ impl Default for FastCGIConfig {
             connect_timeout: 10,
             read_timeout: 30,
             write_timeout: 30,
+            max_request_size: 100 * 1024 * 1024, // 100MB
         }
     }
 }
@@ -66,6 +69,14 @@ pub struct FastCGIProxy {
     config: FastCGIConfig,
 }
 
+// Script resolved for a request URI, with any trailing path split off
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptTarget {
+    pub script_filename: PathBuf,
+    pub script_name: String,
+    pub path_info: Option<String>,
+}
+
 impl FastCGIProxy {
     pub fn new(config: FastCGIConfig) -> Self {
         FastCGIProxy { config }
@@ -77,33 +88,39 @@ impl FastCGIProxy {
         let headers = req.headers().clone();
         
         // Determine script to execute
-        let script_path = self.resolve_script_path(&uri)?;
-        
-        // Connect to PHP-FPM
-        let mut stream = self.connect_to_phpfpm().await?;
-        
-        // Prepare FastCGI request
-        let request_id = 1u16;
-        
-        // Send BEGIN_REQUEST
-        self.send_begin_request(&mut stream, request_id).await?;
-        
-        // Send PARAMS
-        let params = self.build_params(&method, &uri, &headers, &script_path);
-        self.send_params(&mut stream, request_id, params).await?;
+        let script = match self.resolve_script_path(&uri) {
+            Ok(script) => script,
+            Err(e) => {
+                debug!("No FastCGI script for {}: {}", uri, e);
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("404 Not Found"))?);
+            }
+        };
+        
+        // Read the body first, so oversized uploads never reach PHP-FPM
+        let body_bytes = match axum::body::to_bytes(req.into_body(), self.config.max_request_size).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Rejected request body for {}: {}", uri, e);
+                return Ok(Response::builder()
+                    .status(StatusCode::PAYLOAD_TOO_LARGE)
+                    .body(Body::from("Request body too large"))?);
+            }
+        };
         
-        // Send empty PARAMS to indicate end
-        self.send_empty_params(&mut stream, request_id).await?;
+        let params = self.build_params(&method, &uri, &headers, &script);
         
-        // Send STDIN (request body)
-        let body_bytes = axum::body::to_bytes(req.into_body(), usize::MAX).await?;
-        if !body_bytes.is_empty() {
-            self.send_stdin(&mut stream, request_id, &body_bytes).await?;
-        }
-        self.send_empty_stdin(&mut stream, request_id).await?;
-        
-        // Read response
-        let (status, response_headers, response_body) = self.read_response(&mut stream, request_id).await?;
+        // Connect to PHP-FPM
+        let (status, response_headers, response_body) = if let Some(tcp_addr) = &self.config.tcp_addr {
+            info!("Connecting to PHP-FPM at {}", tcp_addr);
+            let mut stream = with_timeout(self.config.connect_timeout, "connect", TcpStream::connect(tcp_addr)).await??;
+            self.exchange(&mut stream, params, &body_bytes).await?
+        } else if let Some(socket_path) = &self.config.socket_path {
+            self.exchange_unix(socket_path, params, &body_bytes).await?
+        } else {
+            return Err(anyhow!("No PHP-FPM connection configured"));
+        };
         
         // Build HTTP response
         let mut response = Response::builder().status(status);
@@ -115,21 +132,45 @@ impl FastCGIProxy {
         Ok(response.body(Body::from(response_body))?)
     }
 
-    async fn connect_to_phpfpm(&self) -> Result<TcpStream> {
-        if let Some(tcp_addr) = &self.config.tcp_addr {
-            info!("Connecting to PHP-FPM at {}", tcp_addr);
-            Ok(TcpStream::connect(tcp_addr).await?)
-        } else if let Some(socket_path) = &self.config.socket_path {
-            // Unix domain socket support would go here
-            // For now, fallback to TCP
-            info!("Connecting to PHP-FPM at localhost:9000");
-            Ok(TcpStream::connect("127.0.0.1:9000").await?)
-        } else {
-            Err(anyhow::anyhow!("No PHP-FPM connection configured"))
-        }
+    #[cfg(unix)]
+    async fn exchange_unix(&self, socket_path: &str, params: HashMap<String, String>, body: &[u8]) -> Result<(StatusCode, HashMap<String, String>, Vec<u8>)> {
+        info!("Connecting to PHP-FPM at {}", socket_path);
+        let mut stream = with_timeout(self.config.connect_timeout, "connect", tokio::net::UnixStream::connect(socket_path)).await??;
+        self.exchange(&mut stream, params, body).await
     }
 
-    async fn send_begin_request(&self, stream: &mut TcpStream, request_id: u16) -> Result<()> {
+    #[cfg(not(unix))]
+    async fn exchange_unix(&self, socket_path: &str, _params: HashMap<String, String>, _body: &[u8]) -> Result<(StatusCode, HashMap<String, String>, Vec<u8>)> {
+        Err(anyhow!("Unix sockets are not supported here: {}", socket_path))
+    }
+
+    // One request/response round trip on a fresh connection
+    async fn exchange<S>(&self, stream: &mut S, params: HashMap<String, String>, body: &[u8]) -> Result<(StatusCode, HashMap<String, String>, Vec<u8>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let request_id = 1u16;
+        
+        with_timeout(self.config.write_timeout, "write", async {
+            // Send BEGIN_REQUEST
+            self.send_begin_request(stream, request_id).await?;
+            
+            // Send PARAMS, then an empty PARAMS to indicate the end
+            self.send_params(stream, request_id, params).await?;
+            self.send_empty_params(stream, request_id).await?;
+            
+            // Send STDIN (request body)
+            if !body.is_empty() {
+                self.send_stdin(stream, request_id, body).await?;
+            }
+            self.send_empty_stdin(stream, request_id).await
+        }).await??;
+        
+        // Read response
+        with_timeout(self.config.read_timeout, "read", self.read_response(stream, request_id)).await?
+    }
+
+    async fn send_begin_request<S: AsyncWrite + Unpin>(&self, stream: &mut S, request_id: u16) -> Result<()> {
         let mut packet = vec![
             FCGI_VERSION,
             FCGI_BEGIN_REQUEST,
@@ -152,13 +193,13 @@ impl FastCGIProxy {
         Ok(())
     }
 
-    fn build_params(&self, method: &Method, uri: &Uri, headers: &HeaderMap, script_path: &Path) -> HashMap<String, String> {
+    fn build_params(&self, method: &Method, uri: &Uri, headers: &HeaderMap, script: &ScriptTarget) -> HashMap<String, String> {
         let mut params = HashMap::new();
         
         // Required CGI/FastCGI parameters
         params.insert("REQUEST_METHOD".to_string(), method.to_string());
-        params.insert("SCRIPT_FILENAME".to_string(), script_path.to_string_lossy().to_string());
-        params.insert("SCRIPT_NAME".to_string(), uri.path().to_string());
+        params.insert("SCRIPT_FILENAME".to_string(), script.script_filename.to_string_lossy().to_string());
+        params.insert("SCRIPT_NAME".to_string(), script.script_name.clone());
         params.insert("REQUEST_URI".to_string(), uri.to_string());
         params.insert("DOCUMENT_URI".to_string(), uri.path().to_string());
         params.insert("DOCUMENT_ROOT".to_string(), self.config.document_root.to_string_lossy().to_string());
@@ -166,6 +207,13 @@ impl FastCGIProxy {
         params.insert("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string());
         params.insert("SERVER_SOFTWARE".to_string(), "miwidothttp/1.0".to_string());
         
+        // Trailing path after the script, e.g. /index.php/foo/bar -> /foo/bar
+        if let Some(path_info) = &script.path_info {
+            params.insert("PATH_INFO".to_string(), path_info.clone());
+            params.insert("PATH_TRANSLATED".to_string(),
+                self.config.document_root.join(path_info.trim_start_matches('/')).to_string_lossy().to_string());
+        }
+        
         // Query string
         if let Some(query) = uri.query() {
             params.insert("QUERY_STRING".to_string(), query.to_string());
@@ -218,7 +266,7 @@ impl FastCGIProxy {
         params
     }
 
-    async fn send_params(&self, stream: &mut TcpStream, request_id: u16, params: HashMap<String, String>) -> Result<()> {
+    async fn send_params<S: AsyncWrite + Unpin>(&self, stream: &mut S, request_id: u16, params: HashMap<String, String>) -> Result<()> {
         let mut param_bytes = Vec::new();
         
         for (key, value) in params {
@@ -256,13 +304,13 @@ impl FastCGIProxy {
         Ok(())
     }
 
-    async fn send_empty_params(&self, stream: &mut TcpStream, request_id: u16) -> Result<()> {
+    async fn send_empty_params<S: AsyncWrite + Unpin>(&self, stream: &mut S, request_id: u16) -> Result<()> {
         let packet = self.build_packet(FCGI_PARAMS, request_id, &[]);
         stream.write_all(&packet).await?;
         Ok(())
     }
 
-    async fn send_stdin(&self, stream: &mut TcpStream, request_id: u16, data: &[u8]) -> Result<()> {
+    async fn send_stdin<S: AsyncWrite + Unpin>(&self, stream: &mut S, request_id: u16, data: &[u8]) -> Result<()> {
         for chunk in data.chunks(65535) {
             let packet = self.build_packet(FCGI_STDIN, request_id, chunk);
             stream.write_all(&packet).await?;
@@ -270,7 +318,7 @@ impl FastCGIProxy {
         Ok(())
     }
 
-    async fn send_empty_stdin(&self, stream: &mut TcpStream, request_id: u16) -> Result<()> {
+    async fn send_empty_stdin<S: AsyncWrite + Unpin>(&self, stream: &mut S, request_id: u16) -> Result<()> {
         let packet = self.build_packet(FCGI_STDIN, request_id, &[]);
         stream.write_all(&packet).await?;
         Ok(())
@@ -297,7 +345,7 @@ impl FastCGIProxy {
         packet
     }
 
-    async fn read_response(&self, stream: &mut TcpStream, request_id: u16) -> Result<(StatusCode, HashMap<String, String>, Vec<u8>)> {
+    async fn read_response<S: AsyncRead + Unpin>(&self, stream: &mut S, request_id: u16) -> Result<(StatusCode, HashMap<String, String>, Vec<u8>)> {
         let mut stdout_data = Vec::new();
         let mut stderr_data = Vec::new();
         
@@ -385,9 +433,19 @@ impl FastCGIProxy {
         Ok((status, headers, body))
     }
 
-    fn resolve_script_path(&self, uri: &Uri) -> Result<PathBuf> {
+    fn resolve_script_path(&self, uri: &Uri) -> Result<ScriptTarget> {
+        if let Some(script) = &self.config.script_filename {
+            let script_filename = self.config.document_root.join(script.trim_start_matches('/'));
+            return Ok(self.script_target(script_filename, None));
+        }
+        
+        // Scripts addressed with a trailing path, e.g. /app.php/users/5
+        if let Some(script) = self.split_path_info(uri.path()) {
+            return Ok(script);
+        }
+        
         let path = uri.path().trim_start_matches('/');
-        let mut script_path = self.config.document_root.join(path);
+        let script_path = self.config.document_root.join(path);
         
         // Check if path is a directory
         if script_path.is_dir() {
@@ -395,7 +453,7 @@ impl FastCGIProxy {
             for index in &self.config.index_files {
                 let index_path = script_path.join(index);
                 if index_path.exists() && index_path.extension() == Some(std::ffi::OsStr::new("php")) {
-                    return Ok(index_path);
+                    return Ok(self.script_target(index_path, None));
                 }
             }
         }
@@ -405,7 +463,7 @@ impl FastCGIProxy {
             // Try adding .php extension
             let php_path = PathBuf::from(format!("{}.php", script_path.display()));
             if php_path.exists() {
-                return Ok(php_path);
+                return Ok(self.script_target(php_path, None));
             }
             
             // Not a PHP file, return 404
@@ -416,14 +474,218 @@ impl FastCGIProxy {
             return Err(anyhow::anyhow!("Script not found"));
         }
         
-        Ok(script_path)
+        Ok(ScriptTarget {
+            script_filename: script_path,
+            script_name: uri.path().to_string(),
+            path_info: None,
+        })
+    }
+
+    // Walk the path left-to-right and keep the longest prefix ending in a
+    // `.php` segment that exists on disk; whatever follows becomes PATH_INFO
+    fn split_path_info(&self, path: &str) -> Option<ScriptTarget> {
+        let mut found = None;
+        
+        for (idx, _) in path.match_indices(".php") {
+            let end = idx + ".php".len();
+            if end < path.len() && !path[end..].starts_with('/') {
+                continue;
+            }
+            
+            let candidate = self.config.document_root.join(path[..end].trim_start_matches('/'));
+            if candidate.is_file() {
+                found = Some((end, candidate));
+            }
+        }
+        
+        found.map(|(end, script_filename)| ScriptTarget {
+            script_filename,
+            script_name: path[..end].to_string(),
+            path_info: Some(&path[end..])
+                .filter(|rest| !rest.is_empty())
+                .map(|rest| rest.to_string()),
+        })
+    }
+
+    fn script_target(&self, script_filename: PathBuf, path_info: Option<String>) -> ScriptTarget {
+        let script_name = script_filename.strip_prefix(&self.config.document_root)
+            .map(|rel| format!("/{}", rel.to_string_lossy()))
+            .unwrap_or_else(|_| script_filename.to_string_lossy().to_string());
+        
+        ScriptTarget {
+            script_filename,
+            script_name,
+            path_info,
+        }
     }
 }
 
+// Run `future` with a limit of `seconds`, unless that is 0
+async fn with_timeout<F: Future>(seconds: u64, what: &str, future: F) -> Result<F::Output> {
+    if seconds == 0 {
+        return Ok(future.await);
+    }
+    tokio::time::timeout(Duration::from_secs(seconds), future)
+        .await
+        .map_err(|_| anyhow!("PHP-FPM {} timed out after {}s", what, seconds))
+}
+
 impl Clone for FastCGIProxy {
     fn clone(&self) -> Self {
         FastCGIProxy {
             config: self.config.clone(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy_with_scripts(scripts: &[&str]) -> (FastCGIProxy, PathBuf) {
+        let root = std::env::temp_dir().join(format!("miwidothttp-fcgi-{}", uuid::Uuid::new_v4()));
+        for script in scripts {
+            let path = root.join(script);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "<?php echo 'ok';").unwrap();
+        }
+        
+        let config = FastCGIConfig {
+            document_root: root.clone(),
+            ..FastCGIConfig::default()
+        };
+        (FastCGIProxy::new(config), root)
+    }
+
+    #[test]
+    fn test_path_info_split() {
+        let (proxy, root) = proxy_with_scripts(&["app.php"]);
+        
+        let script = proxy.resolve_script_path(&"/app.php/users/5".parse().unwrap()).unwrap();
+        assert_eq!(script.script_filename, root.join("app.php"));
+        assert_eq!(script.script_name, "/app.php");
+        assert_eq!(script.path_info.as_deref(), Some("/users/5"));
+        
+        let params = proxy.build_params(&Method::GET, &"/app.php/users/5".parse().unwrap(), &HeaderMap::new(), &script);
+        assert_eq!(params["PATH_INFO"], "/users/5");
+        assert_eq!(params["PATH_TRANSLATED"], root.join("users/5").to_string_lossy());
+        assert_eq!(params["SCRIPT_NAME"], "/app.php");
+        
+        std::fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn test_whole_path_is_script() {
+        let (proxy, root) = proxy_with_scripts(&["admin/index.php"]);
+        
+        let script = proxy.resolve_script_path(&"/admin/index.php".parse().unwrap()).unwrap();
+        assert_eq!(script.script_filename, root.join("admin/index.php"));
+        assert_eq!(script.script_name, "/admin/index.php");
+        assert_eq!(script.path_info, None);
+        
+        let params = proxy.build_params(&Method::GET, &"/admin/index.php".parse().unwrap(), &HeaderMap::new(), &script);
+        assert!(!params.contains_key("PATH_INFO"));
+        
+        std::fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn test_longest_existing_script_wins() {
+        let (proxy, root) = proxy_with_scripts(&["legacy.php/index.php"]);
+        
+        // `/legacy.php` is a directory, so the longer prefix is the script
+        let script = proxy.resolve_script_path(&"/legacy.php/index.php/extra".parse().unwrap()).unwrap();
+        assert_eq!(script.script_name, "/legacy.php/index.php");
+        assert_eq!(script.path_info.as_deref(), Some("/extra"));
+        
+        std::fs::remove_dir_all(root).ok();
+    }
+
+    // A name-value pair length: one byte, or four with the high bit set
+    fn take_len(rest: &mut &[u8]) -> usize {
+        let bytes = *rest;
+        let (len, used) = if bytes[0] < 128 {
+            (bytes[0] as usize, 1)
+        } else {
+            (u32::from_be_bytes([bytes[0] & 0x7f, bytes[1], bytes[2], bytes[3]]) as usize, 4)
+        };
+        *rest = &bytes[used..];
+        len
+    }
+
+    // Minimal PHP-FPM: answers one request with SCRIPT_NAME, PATH_INFO and
+    // the request body
+    async fn mock_fpm() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut params = Vec::new();
+            let mut stdin = Vec::new();
+            loop {
+                let mut header = [0u8; 8];
+                stream.read_exact(&mut header).await.unwrap();
+                let length = ((header[4] as usize) << 8) | header[5] as usize;
+                let mut content = vec![0u8; length + header[6] as usize];
+                stream.read_exact(&mut content).await.unwrap();
+                content.truncate(length);
+                match header[1] {
+                    FCGI_PARAMS => params.extend_from_slice(&content),
+                    FCGI_STDIN if content.is_empty() => break,
+                    FCGI_STDIN => stdin.extend_from_slice(&content),
+                    _ => {}
+                }
+            }
+
+            let mut decoded = HashMap::new();
+            let mut rest = &params[..];
+            while !rest.is_empty() {
+                let key_len = take_len(&mut rest);
+                let value_len = take_len(&mut rest);
+                let key = String::from_utf8_lossy(&rest[..key_len]).to_string();
+                let value = String::from_utf8_lossy(&rest[key_len..key_len + value_len]).to_string();
+                decoded.insert(key, value);
+                rest = &rest[key_len + value_len..];
+            }
+
+            let output = format!(
+                "Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n{} {} {}",
+                decoded.get("SCRIPT_NAME").map_or("", String::as_str),
+                decoded.get("PATH_INFO").map_or("", String::as_str),
+                String::from_utf8_lossy(&stdin),
+            );
+            let proxy = FastCGIProxy::new(FastCGIConfig::default());
+            let mut reply = proxy.build_packet(FCGI_STDOUT, 1, output.as_bytes());
+            reply.extend(proxy.build_packet(FCGI_STDOUT, 1, &[]));
+            reply.extend(proxy.build_packet(FCGI_END_REQUEST, 1, &[0; 8]));
+            stream.write_all(&reply).await.unwrap();
+        });
+        addr.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_handle_request_round_trip() {
+        let (proxy, root) = proxy_with_scripts(&["app.php"]);
+        let proxy = FastCGIProxy::new(FastCGIConfig {
+            tcp_addr: Some(mock_fpm().await),
+            ..proxy.config
+        });
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/app.php/users?id=5")
+            .body(Body::from("name=miwi"))
+            .unwrap();
+        let response = proxy.handle_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"/app.php /users name=miwi");
+
+        // Missing scripts never reach PHP-FPM
+        let request = Request::builder().uri("/missing.php").body(Body::empty()).unwrap();
+        assert_eq!(proxy.handle_request(request).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(root).ok();
+    }
+}
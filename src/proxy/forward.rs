@@ -1,36 +1,63 @@
 use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 use axum::{
     body::Body,
     extract::Request,
     http::{HeaderMap, Method, StatusCode, Uri},
     response::Response,
 };
+use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
 use hyper_util::client::legacy::Client;
-use std::convert::Infallible;
-use std::net::SocketAddr;
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
 use tracing::{debug, error, info, warn};
 
-use super::{ProxyAuth, ProxyConfig, UpstreamProxy};
+use super::digest::{AuthOutcome, DigestAuth};
+use super::{AuthType, ProxyAuth, ProxyConfig, UpstreamProxy};
+use crate::proxy_client::{self, build_client, build_client_with, http_connector, PooledClient};
 
 pub struct ForwardProxy {
     config: ProxyConfig,
-    client: Client<hyper_util::client::legacy::connect::HttpConnector, Body>,
+    client: PooledClient,
+    // Sends every request to the upstream proxy, when one is configured
+    upstream_client: Option<Client<UpstreamConnector, Body>>,
+    // Nonce state for Digest authentication, shared by all clones
+    digest: Option<Arc<DigestAuth>>,
 }
 
 impl ForwardProxy {
     pub fn new(config: ProxyConfig) -> Result<Self> {
-        let connector = hyper_util::client::legacy::connect::HttpConnector::new();
-        let client = Client::builder(hyper_util::rt::TokioExecutor::new()).build(connector);
+        // Both clients take the pool settings and connect timeout from the
+        // config; send() adds the write, read and idle timeouts
+        let client = build_client(&config.connection_pool, &config.timeout);
+
+        let upstream_client = match &config.upstream_proxy {
+            Some(upstream) => {
+                let proxy: Uri = format!("http://{}", upstream_addr(upstream)?).parse()?;
+                let http = http_connector(&config.connection_pool, &config.timeout);
+                Some(build_client_with(&config.connection_pool, UpstreamConnector { http, proxy }))
+            }
+            None => None,
+        };
 
-        Ok(ForwardProxy { config, client })
+        let digest = config.authentication.as_ref()
+            .filter(|auth| matches!(auth.auth_type, AuthType::Digest))
+            .map(|auth| Arc::new(DigestAuth::new(
+                auth.realm.clone().unwrap_or_else(|| "Proxy".to_string()),
+            )));
+
+        Ok(ForwardProxy { config, client, upstream_client, digest })
     }
 
     // Handle HTTP CONNECT method for HTTPS tunneling
     pub async fn handle_connect(&self, req: Request<Body>) -> Result<Response> {
-        let uri = req.uri();
-        let authority = uri.authority().ok_or_else(|| {
+        let authority = req.uri().authority().cloned().ok_or_else(|| {
             anyhow::anyhow!("Missing authority in CONNECT request")
         })?;
 
@@ -43,43 +70,48 @@ impl ForwardProxy {
         );
 
         // Authenticate if required
-        if self.config.authentication.is_some() {
-            if !self.authenticate_request(req.headers()).await? {
-                return Ok(Response::builder()
-                    .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
-                    .header("Proxy-Authenticate", "Basic realm=\"Proxy\"")
-                    .body(Body::empty())?);
-            }
+        match self.authenticate_request(req.method(), req.uri(), req.headers()).await? {
+            AuthOutcome::Authorized => {}
+            AuthOutcome::Rejected => return self.auth_required(false),
+            AuthOutcome::Stale => return self.auth_required(true),
         }
 
         // Check if we should use upstream proxy
         if let Some(upstream) = &self.config.upstream_proxy {
             if upstream.use_for_https {
-                return self.connect_through_upstream(&target, upstream).await;
+                return self.connect_through_upstream(req, &target, upstream).await;
             }
         }
 
         // Direct connection to target
-        match TcpStream::connect(&target).await {
-            Ok(mut target_stream) => {
+        match self.dial(&target).await {
+            Ok(target_stream) => {
                 info!("Connected to target: {}", target);
-                
-                // Send 200 Connection Established
-                let response = Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::empty())?;
 
-                // Note: In a real implementation, we would need to:
-                // 1. Upgrade the connection to raw TCP
-                // 2. Tunnel data bidirectionally
-                // This requires more complex integration with Axum/Hyper
+                // The client connection is only handed over once hyper has
+                // written the 200 below, so the tunnel runs in its own task
+                let proxy = self.clone();
+                tokio::spawn(async move {
+                    match hyper::upgrade::on(req).await {
+                        Ok(upgraded) => {
+                            let mut client_stream = TokioIo::new(upgraded);
+                            if let Err(e) = proxy.tunnel_streams(&mut client_stream, target_stream).await {
+                                warn!("Tunnel to {} closed with error: {}", target, e);
+                            }
+                        }
+                        Err(e) => error!("Failed to upgrade CONNECT to {}: {}", target, e),
+                    }
+                });
 
-                Ok(response)
+                // Send 200 Connection Established
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .body(Body::empty())?)
             }
             Err(e) => {
                 error!("Failed to connect to {}: {}", target, e);
                 Ok(Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
+                    .status(dial_status(&e))
                     .body(Body::from(format!("Failed to connect to {}", target)))?)
             }
         }
@@ -90,20 +122,17 @@ impl ForwardProxy {
         let uri = req.uri().clone();
         
         // Ensure absolute URI for forward proxy
-        if !uri.scheme_str().is_some() {
+        if uri.scheme_str().is_none() {
             return Err(anyhow::anyhow!("Forward proxy requires absolute URI"));
         }
 
         info!("Forward proxy request to: {}", uri);
 
         // Authenticate if required
-        if self.config.authentication.is_some() {
-            if !self.authenticate_request(req.headers()).await? {
-                return Ok(Response::builder()
-                    .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
-                    .header("Proxy-Authenticate", "Basic realm=\"Proxy\"")
-                    .body(Body::empty())?);
-            }
+        match self.authenticate_request(req.method(), req.uri(), req.headers()).await? {
+            AuthOutcome::Authorized => {}
+            AuthOutcome::Rejected => return self.auth_required(false),
+            AuthOutcome::Stale => return self.auth_required(true),
         }
 
         // Remove proxy-specific headers
@@ -111,13 +140,17 @@ impl ForwardProxy {
         headers.remove("proxy-authorization");
         headers.remove("proxy-connection");
 
-        // Check if we should use upstream proxy
+        // Check if we should use upstream proxy; https:// URIs only go
+        // through it when it's meant to carry HTTPS traffic as well
         if let Some(upstream) = &self.config.upstream_proxy {
-            return self.request_through_upstream(req, upstream).await;
+            if uri.scheme_str() != Some("https") || upstream.use_for_https {
+                return self.request_through_upstream(req, upstream).await;
+            }
         }
 
         // Direct request to target
-        match self.client.request(req).await {
+        let limits = &self.config.limits;
+        match proxy_client::send(&self.client, req, limits.max_request_size, limits.max_response_size, &self.config.timeout).await {
             Ok(response) => {
                 debug!("Forward proxy response: {}", response.status());
                 Ok(response)
@@ -125,16 +158,16 @@ impl ForwardProxy {
             Err(e) => {
                 error!("Forward proxy request failed: {}", e);
                 Ok(Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
+                    .status(e.status())
                     .body(Body::from("Proxy request failed"))?)
             }
         }
     }
 
-    async fn authenticate_request(&self, headers: &HeaderMap) -> Result<bool> {
+    async fn authenticate_request(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> Result<AuthOutcome> {
         let auth = match &self.config.authentication {
             Some(auth) => auth,
-            None => return Ok(true),
+            None => return Ok(AuthOutcome::Authorized),
         };
 
         let proxy_auth = headers.get("proxy-authorization")
@@ -142,44 +175,66 @@ impl ForwardProxy {
 
         match proxy_auth {
             Some(auth_header) => {
-                self.validate_proxy_auth(auth_header, auth).await
+                self.validate_proxy_auth(auth_header, auth, method, uri).await
             }
-            None => Ok(false),
+            None => Ok(AuthOutcome::Rejected),
         }
     }
 
-    async fn validate_proxy_auth(&self, auth_header: &str, config: &ProxyAuth) -> Result<bool> {
+    async fn validate_proxy_auth(&self, auth_header: &str, config: &ProxyAuth, method: &Method, uri: &Uri) -> Result<AuthOutcome> {
         match config.auth_type {
-            super::AuthType::Basic => {
+            AuthType::Basic => {
                 if let Some(encoded) = auth_header.strip_prefix("Basic ") {
-                    match base64::decode(encoded) {
+                    match STANDARD.decode(encoded) {
                         Ok(decoded) => {
                             let credentials = String::from_utf8_lossy(&decoded);
                             let expected = format!("{}:{}", config.username, config.password);
-                            Ok(credentials == expected)
+                            Ok(if credentials == expected { AuthOutcome::Authorized } else { AuthOutcome::Rejected })
                         }
-                        Err(_) => Ok(false),
+                        Err(_) => Ok(AuthOutcome::Rejected),
                     }
                 } else {
-                    Ok(false)
+                    Ok(AuthOutcome::Rejected)
                 }
             }
+            AuthType::Digest => {
+                let digest = self.digest.as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("Digest authentication not initialised"))?;
+                Ok(digest.verify(auth_header, method.as_str(), &uri.to_string(), &config.username, &config.password))
+            }
             _ => {
                 warn!("Unsupported proxy authentication type: {:?}", config.auth_type);
-                Ok(false)
+                Ok(AuthOutcome::Rejected)
+            }
+        }
+    }
+
+    // 407 carrying the challenge for the configured scheme. `stale` tells a
+    // Digest client its credentials were fine but the nonce has to be renewed.
+    fn auth_required(&self, stale: bool) -> Result<Response> {
+        let mut response = Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        match &self.digest {
+            Some(digest) => {
+                for challenge in digest.challenges(stale) {
+                    response = response.header("Proxy-Authenticate", challenge);
+                }
+            }
+            None => {
+                let realm = self.config.authentication.as_ref()
+                    .and_then(|auth| auth.realm.as_deref())
+                    .unwrap_or("Proxy");
+                response = response.header("Proxy-Authenticate", format!("Basic realm=\"{}\"", realm));
             }
         }
+        Ok(response.body(Body::empty())?)
     }
 
-    async fn connect_through_upstream(&self, target: &str, upstream: &UpstreamProxy) -> Result<Response> {
-        // Parse upstream proxy URL
-        let upstream_uri: Uri = upstream.url.parse()?;
-        let upstream_host = upstream_uri.host().unwrap_or("localhost");
-        let upstream_port = upstream_uri.port_u16().unwrap_or(8080);
-        let upstream_addr = format!("{}:{}", upstream_host, upstream_port);
+    async fn connect_through_upstream(&self, req: Request<Body>, target: &str, upstream: &UpstreamProxy) -> Result<Response> {
+        let upstream_addr = upstream_addr(upstream)?;
 
         // Connect to upstream proxy
-        match TcpStream::connect(&upstream_addr).await {
+        match self.dial(&upstream_addr).await {
             Ok(mut upstream_stream) => {
                 // Send CONNECT request to upstream
                 let connect_req = format!(
@@ -189,7 +244,7 @@ impl ForwardProxy {
 
                 // Add upstream authentication if configured
                 let connect_req = if let Some(auth) = &upstream.auth {
-                    let encoded = base64::encode(format!("{}:{}", auth.username, auth.password));
+                    let encoded = STANDARD.encode(format!("{}:{}", auth.username, auth.password));
                     format!("{}Proxy-Authorization: Basic {}\r\n", connect_req, encoded)
                 } else {
                     connect_req
@@ -200,195 +255,293 @@ impl ForwardProxy {
                 upstream_stream.write_all(connect_req.as_bytes()).await?;
 
                 // Read response from upstream
-                let mut response_buf = Vec::new();
-                let mut temp_buf = [0u8; 1024];
-                
-                loop {
-                    let n = upstream_stream.read(&mut temp_buf).await?;
-                    if n == 0 { break; }
-                    response_buf.extend_from_slice(&temp_buf[..n]);
-                    
-                    // Check if we have complete HTTP response headers
-                    if let Ok(response_str) = String::from_utf8_lossy(&response_buf).find("\r\n\r\n") {
-                        break;
+                let read_timeout = self.config.timeout.read();
+                let (status, early_data) = match read_connect_response(&mut upstream_stream, read_timeout).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        error!("Upstream proxy connection failed: {}", e);
+                        return Ok(Response::builder()
+                            .status(StatusCode::BAD_GATEWAY)
+                            .body(Body::from("Upstream proxy connection failed"))?);
                     }
-                }
+                };
 
-                let response_str = String::from_utf8_lossy(&response_buf);
-                if response_str.contains("200") {
-                    info!("Successfully connected through upstream proxy to: {}", target);
-                    
-                    Ok(Response::builder()
-                        .status(StatusCode::OK)
-                        .body(Body::empty())?)
-                } else {
-                    error!("Upstream proxy connection failed: {}", response_str);
-                    Ok(Response::builder()
+                if status != StatusCode::OK {
+                    error!("Upstream proxy refused CONNECT to {}: {}", target, status);
+                    return Ok(Response::builder()
                         .status(StatusCode::BAD_GATEWAY)
-                        .body(Body::from("Upstream proxy connection failed"))?)
+                        .body(Body::from("Upstream proxy connection failed"))?);
                 }
+
+                info!("Successfully connected through upstream proxy to: {}", target);
+
+                // Same hand-over as a direct CONNECT; anything the upstream
+                // sent after its headers already belongs to the tunnel
+                let proxy = self.clone();
+                let target = target.to_string();
+                tokio::spawn(async move {
+                    match hyper::upgrade::on(req).await {
+                        Ok(upgraded) => {
+                            let mut client_stream = TokioIo::new(upgraded);
+                            let tunnel = async {
+                                client_stream.write_all(&early_data).await?;
+                                proxy.tunnel_streams(&mut client_stream, upstream_stream).await
+                            };
+                            if let Err(e) = tunnel.await {
+                                warn!("Tunnel to {} via upstream closed with error: {}", target, e);
+                            }
+                        }
+                        Err(e) => error!("Failed to upgrade CONNECT to {}: {}", target, e),
+                    }
+                });
+
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .body(Body::empty())?)
             }
             Err(e) => {
                 error!("Failed to connect to upstream proxy {}: {}", upstream_addr, e);
                 Ok(Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
+                    .status(dial_status(&e))
                     .body(Body::from("Failed to connect to upstream proxy"))?)
             }
         }
     }
 
     async fn request_through_upstream(&self, mut req: Request<Body>, upstream: &UpstreamProxy) -> Result<Response> {
+        let client = self.upstream_client.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No client for upstream proxy {}", upstream.url))?;
+
         // Add upstream proxy authentication
         if let Some(auth) = &upstream.auth {
-            let encoded = base64::encode(format!("{}:{}", auth.username, auth.password));
+            let encoded = STANDARD.encode(format!("{}:{}", auth.username, auth.password));
             req.headers_mut().insert(
                 "proxy-authorization",
                 format!("Basic {}", encoded).parse()?
             );
         }
 
-        // Forward request through upstream proxy
-        // Note: This would require configuring the HTTP client to use the upstream proxy
-        // For now, we'll use direct connection
-        match self.client.request(req).await {
+        // The connector dials the upstream and the request keeps its
+        // absolute-form URI, as a proxy expects
+        let limits = &self.config.limits;
+        match proxy_client::send(client, req, limits.max_request_size, limits.max_response_size, &self.config.timeout).await {
             Ok(response) => Ok(response),
             Err(e) => {
                 error!("Upstream proxy request failed: {}", e);
                 Ok(Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
+                    .status(e.status())
                     .body(Body::from("Upstream proxy request failed"))?)
             }
         }
     }
 
-    // Start forward proxy server
-    pub async fn start_server(&self, bind_addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(bind_addr).await?;
-        info!("Forward proxy server listening on {}", bind_addr);
-
-        loop {
-            match listener.accept().await {
-                Ok((stream, peer_addr)) => {
-                    info!("New forward proxy connection from: {}", peer_addr);
-                    
-                    let proxy = self.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = proxy.handle_connection(stream, peer_addr).await {
-                            error!("Forward proxy connection error: {}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Failed to accept forward proxy connection: {}", e);
-                }
-            }
+    // TCP connection for a CONNECT tunnel, within the connect timeout
+    async fn dial(&self, addr: &str) -> std::io::Result<TcpStream> {
+        match self.config.timeout.connect() {
+            Some(limit) => tokio::time::timeout(limit, TcpStream::connect(addr)).await
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timeout"))?,
+            None => TcpStream::connect(addr).await,
         }
     }
 
-    async fn handle_connection(&self, mut stream: TcpStream, peer_addr: SocketAddr) -> Result<()> {
-        let mut buffer = vec![0u8; 4096];
-        
-        loop {
-            let n = stream.read(&mut buffer).await?;
-            if n == 0 {
-                break; // Connection closed
-            }
+    async fn tunnel_streams<C>(&self, client_stream: &mut C, mut target_stream: TcpStream) -> Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let idle_timeout = self.config.timeout.idle();
+        let last_activity = Mutex::new(Instant::now());
+
+        let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+        let (mut target_read, mut target_write) = target_stream.split();
+
+        // Drive both directions to completion so a half-close on one side
+        // (e.g. the client finishing its upload) doesn't cut off the other
+        let (client_to_target, target_to_client) = tokio::join!(
+            copy_with_idle_timeout(&mut client_read, &mut target_write, idle_timeout, &last_activity),
+            copy_with_idle_timeout(&mut target_read, &mut client_write, idle_timeout, &last_activity),
+        );
 
-            // Parse HTTP request
-            let request_data = &buffer[..n];
-            let request_str = String::from_utf8_lossy(request_data);
-            
-            // Extract method and URI
-            let lines: Vec<&str> = request_str.lines().collect();
-            if let Some(request_line) = lines.first() {
-                let parts: Vec<&str> = request_line.split_whitespace().collect();
-                if parts.len() >= 2 {
-                    let method = parts[0];
-                    let uri = parts[1];
-                    
-                    info!("Forward proxy request: {} {}", method, uri);
-                    
-                    // Handle CONNECT method
-                    if method == "CONNECT" {
-                        if let Err(e) = self.handle_raw_connect(&mut stream, uri).await {
-                            error!("CONNECT handling failed: {}", e);
-                            break;
-                        }
-                    } else {
-                        // Handle regular HTTP request
-                        if let Err(e) = self.handle_raw_http_request(&mut stream, request_data).await {
-                            error!("HTTP request handling failed: {}", e);
-                            break;
-                        }
-                    }
-                }
-            }
+        match &client_to_target {
+            Ok(bytes) => info!("Client to target: {} bytes", bytes),
+            Err(e) => error!("Client to target error: {}", e),
+        }
+        match &target_to_client {
+            Ok(bytes) => info!("Target to client: {} bytes", bytes),
+            Err(e) => error!("Target to client error: {}", e),
         }
 
+        client_to_target?;
+        target_to_client?;
         Ok(())
     }
+}
 
-    async fn handle_raw_connect(&self, client_stream: &mut TcpStream, target: &str) -> Result<()> {
-        // Connect to target
-        match TcpStream::connect(target).await {
-            Ok(target_stream) => {
-                // Send 200 Connection Established
-                let response = b"HTTP/1.1 200 Connection Established\r\n\r\n";
-                client_stream.write_all(response).await?;
+// 504 for a tunnel target that didn't answer in time, 502 otherwise
+fn dial_status(error: &std::io::Error) -> StatusCode {
+    if proxy_client::is_timeout(error) {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    }
+}
 
-                // Start bidirectional tunneling
-                self.tunnel_streams(client_stream, target_stream).await?;
-            }
-            Err(e) => {
-                error!("Failed to connect to target {}: {}", target, e);
-                let response = b"HTTP/1.1 502 Bad Gateway\r\n\r\n";
-                client_stream.write_all(response).await?;
-            }
-        }
+// host:port of the upstream proxy; the port defaults to 8080
+fn upstream_addr(upstream: &UpstreamProxy) -> Result<String> {
+    let upstream_uri: Uri = upstream.url.parse()?;
+    let upstream_host = upstream_uri.host().unwrap_or("localhost");
+    let upstream_port = upstream_uri.port_u16().unwrap_or(8080);
+    Ok(format!("{}:{}", upstream_host, upstream_port))
+}
 
-        Ok(())
+// Connector that dials the upstream proxy whatever the request's
+// destination. Its connections report themselves as proxied, which makes
+// the client send absolute-form request URIs.
+#[derive(Clone)]
+struct UpstreamConnector {
+    http: HttpConnector,
+    proxy: Uri,
+}
+
+impl tower::Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = <HttpConnector as tower::Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<UpstreamStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.http.poll_ready(cx)
     }
 
-    async fn handle_raw_http_request(&self, client_stream: &mut TcpStream, request_data: &[u8]) -> Result<()> {
-        // Parse request and forward to target
-        // This is a simplified implementation
-        let response = b"HTTP/1.1 501 Not Implemented\r\nContent-Length: 0\r\n\r\n";
-        client_stream.write_all(response).await?;
-        Ok(())
+    fn call(&mut self, _dst: Uri) -> Self::Future {
+        let connecting = self.http.call(self.proxy.clone());
+        Box::pin(async move { Ok(UpstreamStream(connecting.await?)) })
     }
+}
 
-    async fn tunnel_streams(&self, client_stream: &mut TcpStream, target_stream: TcpStream) -> Result<()> {
-        let (mut target_read, mut target_write) = target_stream.into_split();
-        let (mut client_read, mut client_write) = client_stream.split();
+struct UpstreamStream(TokioIo<TcpStream>);
 
-        // Bidirectional copy
-        let client_to_target = tokio::spawn(async move {
-            tokio::io::copy(&mut client_read, &mut target_write).await
-        });
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        self.0.connected().proxy(true)
+    }
+}
 
-        let target_to_client = tokio::spawn(async move {
-            tokio::io::copy(&mut target_read, &mut client_write).await
-        });
+impl hyper::rt::Read for UpstreamStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: hyper::rt::ReadBufCursor<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
 
-        // Wait for either direction to complete
-        tokio::select! {
-            result = client_to_target => {
-                match result {
-                    Ok(Ok(bytes)) => info!("Client to target: {} bytes", bytes),
-                    Ok(Err(e)) => error!("Client to target error: {}", e),
-                    Err(e) => error!("Client to target task error: {}", e),
-                }
+impl hyper::rt::Write for UpstreamStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+// Upper bound on the upstream's reply to CONNECT; a proxy that sends more
+// header than this is broken or hostile
+const MAX_CONNECT_RESPONSE_BYTES: usize = 16 * 1024;
+
+// Read an upstream proxy's reply to CONNECT up to the end of its headers.
+// Returns the status and any bytes that arrived after the headers, which are
+// the first bytes of the tunnel. The upstream keeps the connection open, so
+// the blank line is the only way to know the reply is complete.
+async fn read_connect_response<S>(stream: &mut S, read_timeout: Option<Duration>) -> Result<(StatusCode, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let read = async {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                anyhow::bail!("upstream proxy closed the connection before the end of its response");
             }
-            result = target_to_client => {
-                match result {
-                    Ok(Ok(bytes)) => info!("Target to client: {} bytes", bytes),
-                    Ok(Err(e)) => error!("Target to client error: {}", e),
-                    Err(e) => error!("Target to client task error: {}", e),
-                }
+            // The terminator may straddle two reads
+            let search_from = buf.len().saturating_sub(3);
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = buf[search_from..].windows(4).position(|w| w == b"\r\n\r\n") {
+                return Ok((buf, search_from + pos + 4));
+            }
+            if buf.len() > MAX_CONNECT_RESPONSE_BYTES {
+                anyhow::bail!("upstream proxy response headers exceed {} bytes", MAX_CONNECT_RESPONSE_BYTES);
             }
         }
+    };
+
+    let (mut buf, header_end) = match read_timeout {
+        Some(limit) => tokio::time::timeout(limit, read).await
+            .map_err(|_| anyhow::anyhow!("timed out waiting for upstream proxy response"))??,
+        None => read.await?,
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line.strip_prefix("HTTP/")
+        .and_then(|rest| rest.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| anyhow::anyhow!("malformed upstream proxy status line: {:?}", status_line))?;
+
+    Ok((status, buf.split_off(header_end)))
+}
 
-        Ok(())
+// Copy one direction of a tunnel, shutting down the writer on EOF. The idle
+// timeout is shared between both directions through `last_activity`, so a
+// quiet direction stays open while the other one is still moving data.
+pub(super) async fn copy_with_idle_timeout<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    idle_timeout: Option<Duration>,
+    last_activity: &Mutex<Instant>,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut total = 0u64;
+
+    loop {
+        let read = match idle_timeout {
+            Some(idle) => match tokio::time::timeout(idle, reader.read(&mut buf)).await {
+                Ok(read) => read,
+                Err(_) => {
+                    if last_activity.lock().unwrap().elapsed() >= idle {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "tunnel idle timeout",
+                        ));
+                    }
+                    continue;
+                }
+            },
+            None => reader.read(&mut buf).await,
+        };
+
+        match read? {
+            0 => {
+                writer.shutdown().await?;
+                return Ok(total);
+            }
+            n => {
+                writer.write_all(&buf[..n]).await?;
+                total += n as u64;
+                *last_activity.lock().unwrap() = Instant::now();
+            }
+        }
     }
 }
 
@@ -397,8 +550,127 @@ impl Clone for ForwardProxy {
         ForwardProxy {
             config: self.config.clone(),
             client: self.client.clone(),
+            upstream_client: self.upstream_client.clone(),
+            digest: self.digest.clone(),
         }
     }
 }
 
-use base64;
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    // Upstream proxy that answers the first CONNECT with `reply` and then
+    // holds the connection open, as a keep-alive proxy would
+    async fn fake_upstream(reply: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 256];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            assert!(request.starts_with(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+            stream.write_all(&reply).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_http_request_goes_through_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        let (request_tx, request_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 256];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nupstream").await.unwrap();
+            request_tx.send(String::from_utf8(request).unwrap()).unwrap();
+        });
+
+        let config = ProxyConfig {
+            upstream_proxy: Some(UpstreamProxy {
+                url: format!("http://{}", upstream_addr),
+                auth: Some(ProxyAuth {
+                    auth_type: AuthType::Basic,
+                    username: "user".to_string(),
+                    password: "pass".to_string(),
+                    realm: None,
+                }),
+                use_for_https: false,
+            }),
+            ..Default::default()
+        };
+        let proxy = ForwardProxy::new(config).unwrap();
+
+        // The origin doesn't resolve, so only the upstream can answer
+        let req = Request::builder()
+            .uri("http://origin.invalid/path?q=1")
+            .body(Body::empty())
+            .unwrap();
+        let response = proxy.handle_request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"upstream");
+
+        let request = request_rx.await.unwrap();
+        assert!(request.starts_with("GET http://origin.invalid/path?q=1 HTTP/1.1\r\n"), "{}", request);
+        assert!(request.to_lowercase().contains(&format!("proxy-authorization: basic {}", STANDARD.encode("user:pass"))));
+    }
+
+    async fn send_connect(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").await.unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_connect_response_with_body() {
+        let addr = fake_upstream(b"HTTP/1.1 200 Connection established\r\nProxy-Agent: test\r\n\r\nhello".to_vec()).await;
+        let mut stream = send_connect(addr).await;
+
+        // Returns as soon as the headers are in, even though the upstream
+        // keeps the connection open
+        let (status, early_data) = read_connect_response(&mut stream, Some(Duration::from_secs(5))).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(early_data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_connect_response_rejected() {
+        let addr = fake_upstream(b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n".to_vec()).await;
+        let mut stream = send_connect(addr).await;
+
+        let (status, early_data) = read_connect_response(&mut stream, Some(Duration::from_secs(5))).await.unwrap();
+        assert_eq!(status, StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        assert!(early_data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_response_timeout_and_size_limit() {
+        // Headers never finish
+        let addr = fake_upstream(b"HTTP/1.1 200 OK\r\n".to_vec()).await;
+        let mut stream = send_connect(addr).await;
+        let err = read_connect_response(&mut stream, Some(Duration::from_millis(100))).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+
+        // Headers that never end and keep growing
+        let mut reply = b"HTTP/1.1 200 OK\r\n".to_vec();
+        reply.extend(std::iter::repeat(b'x').take(MAX_CONNECT_RESPONSE_BYTES * 2));
+        let addr = fake_upstream(reply).await;
+        let mut stream = send_connect(addr).await;
+        let err = read_connect_response(&mut stream, Some(Duration::from_secs(5))).await.unwrap_err();
+        assert!(err.to_string().contains("exceed"));
+    }
+}
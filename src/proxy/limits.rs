@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Two separate per-IP limits:
+//  - requests per window: `rate_limit_per_ip` requests every
+//    `rate_limit_window_seconds`, the count resetting when the window ends
+//  - concurrent connections: at most `max_connections_per_ip` open at once,
+//    each held by a ConnectionGuard until it is dropped
+#[derive(Clone)]
+pub struct IpLimiter {
+    requests_per_window: Option<u32>,
+    window: Duration,
+    max_connections: Option<u32>,
+    windows: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+    connections: Arc<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+impl IpLimiter {
+    pub fn new(requests_per_window: Option<u32>, window: Duration, max_connections: Option<u32>) -> Self {
+        Self {
+            requests_per_window,
+            window,
+            max_connections,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn check_request(&self, ip: IpAddr) -> bool {
+        self.check_request_at(ip, Instant::now())
+    }
+
+    fn check_request_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let limit = match self.requests_per_window {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let mut windows = self.windows.lock().unwrap();
+        // Forget clients whose window is over so the map doesn't grow forever
+        if windows.len() > 10_000 {
+            windows.retain(|_, (started, _)| now.duration_since(*started) < self.window);
+        }
+
+        let (started, count) = windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*started) >= self.window {
+            *started = now;
+            *count = 0;
+        }
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    // None when the IP already has max_connections open
+    pub fn acquire_connection(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(ip).or_insert(0);
+        if self.max_connections.map_or(false, |max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard { ip, connections: self.connections.clone() })
+    }
+
+    // (open connections, distinct IPs with a connection open)
+    pub fn connection_stats(&self) -> (u64, u64) {
+        let connections = self.connections.lock().unwrap();
+        (connections.values().map(|c| *c as u64).sum(), connections.len() as u64)
+    }
+}
+
+// Releases the connection slot when the connection is done
+pub struct ConnectionGuard {
+    ip: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, u32>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_recovers_after_window() {
+        let limiter = IpLimiter::new(Some(2), Duration::from_secs(60), None);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.check_request_at(ip, start));
+        assert!(limiter.check_request_at(ip, start + Duration::from_secs(1)));
+        assert!(!limiter.check_request_at(ip, start + Duration::from_secs(59)));
+        assert!(limiter.check_request_at("10.0.0.2".parse().unwrap(), start));
+
+        // Next window
+        assert!(limiter.check_request_at(ip, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_connection_released_on_drop() {
+        let limiter = IpLimiter::new(None, Duration::from_secs(60), Some(1));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let guard = limiter.acquire_connection(ip).unwrap();
+        assert!(limiter.acquire_connection(ip).is_none());
+        assert_eq!(limiter.connection_stats(), (1, 1));
+
+        drop(guard);
+        assert_eq!(limiter.connection_stats(), (0, 0));
+        assert!(limiter.acquire_connection(ip).is_some());
+    }
+}
@@ -1,35 +1,46 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use axum::{
     body::Body,
-    extract::{Request, State},
-    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, Version},
+    extract::Request,
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Response},
 };
-use hyper::client::conn::http1::Builder;
-use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt};
-use tokio::net::{TcpStream, TcpListener};
-use tokio::sync::RwLock;
+use tokio::net::TcpListener;
 use tracing::{debug, error, info, warn};
 
+mod digest;
+mod fastcgi;
 mod forward;
-mod reverse;
+mod limits;
 mod socks;
-mod websocket;
 
+pub use fastcgi::{FastCGIConfig, FastCGIProxy};
 pub use forward::ForwardProxy;
-pub use reverse::ReverseProxy;
 pub use socks::{SocksProxy, SocksVersion};
-pub use websocket::WebSocketProxy;
-
-use crate::config::BackendConfig;
-
+pub use limits::{ConnectionGuard, IpLimiter};
+
+// Forward and SOCKS proxy servers. Reverse proxying to [backends] is
+// route_request in main.rs, on the shared pooled client from proxy_client,
+// WebSocket upgrades included.
+pub use crate::proxy_client::{ConnectionPoolConfig, TimeoutConfig};
+
+// One [[proxy_servers]] entry, e.g.
+//
+//   [[proxy_servers]]
+//   mode = "forward"
+//   bind_addr = "127.0.0.1:3128"
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ProxyConfig {
     pub mode: ProxyMode,
     pub bind_addr: Option<SocketAddr>,
@@ -45,10 +56,8 @@ pub struct ProxyConfig {
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum ProxyMode {
-    Reverse,    // Reverse proxy (default)
-    Forward,    // Forward proxy with CONNECT
-    Transparent,// Transparent proxy
-    Socks4,     // SOCKS4 proxy
+    Forward,    // Forward proxy with CONNECT (default)
+    Socks4,     // SOCKS4 proxy, refused at startup
     Socks5,     // SOCKS5 proxy
 }
 
@@ -77,15 +86,7 @@ pub enum AuthType {
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ConnectionPoolConfig {
-    pub max_idle_per_host: usize,
-    pub idle_timeout_seconds: u64,
-    pub max_lifetime_seconds: u64,
-    pub http2: bool,
-    pub keep_alive: bool,
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct HeaderConfig {
     pub preserve_host: bool,
     pub add_forwarded_headers: bool,
@@ -97,23 +98,21 @@ pub struct HeaderConfig {
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct TimeoutConfig {
-    pub connect_timeout_seconds: u64,
-    pub read_timeout_seconds: u64,
-    pub write_timeout_seconds: u64,
-    pub idle_timeout_seconds: u64,
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ProxyLimits {
     pub max_request_size: u64,
     pub max_response_size: u64,
     pub max_concurrent_connections: usize,
+    // Requests allowed per client IP in each rate_limit_window_seconds
     pub rate_limit_per_ip: Option<u32>,
+    pub rate_limit_window_seconds: u64,
+    // Connections a single client IP may hold open at once
+    pub max_connections_per_ip: Option<u32>,
     pub bandwidth_limit_kbps: Option<u32>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ProxyLogging {
     pub log_requests: bool,
     pub log_responses: bool,
@@ -122,76 +121,103 @@ pub struct ProxyLogging {
     pub max_body_size: usize,
 }
 
+impl ProxyConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.bind_addr.is_none() {
+            bail!("{:?} proxy server needs a bind_addr", self.mode);
+        }
+        match self.mode {
+            ProxyMode::Socks4 => bail!("SOCKS4 is not supported, use mode = \"socks5\""),
+            ProxyMode::Socks5 => {
+                if let Some(auth) = &self.authentication {
+                    if auth.auth_type != AuthType::Basic {
+                        bail!("SOCKS5 only supports basic (username/password) authentication");
+                    }
+                }
+            }
+            ProxyMode::Forward => {
+                if let Some(auth) = &self.authentication {
+                    if !matches!(auth.auth_type, AuthType::Basic | AuthType::Digest) {
+                        bail!("forward proxy authentication must be basic or digest");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Default for ProxyConfig {
     fn default() -> Self {
         ProxyConfig {
-            mode: ProxyMode::Reverse,
+            mode: ProxyMode::Forward,
             bind_addr: None,
             upstream_proxy: None,
             authentication: None,
-            connection_pool: ConnectionPoolConfig {
-                max_idle_per_host: 32,
-                idle_timeout_seconds: 90,
-                max_lifetime_seconds: 3600,
-                http2: true,
-                keep_alive: true,
-            },
-            headers: HeaderConfig {
-                preserve_host: false,
-                add_forwarded_headers: true,
-                add_real_ip: true,
-                add_proxy_headers: true,
-                remove_headers: vec!["Connection".to_string(), "Upgrade".to_string()],
-                add_headers: HashMap::new(),
-                via_header: Some("miwidothttp/1.0".to_string()),
-            },
-            timeout: TimeoutConfig {
-                connect_timeout_seconds: 10,
-                read_timeout_seconds: 30,
-                write_timeout_seconds: 30,
-                idle_timeout_seconds: 90,
-            },
-            limits: ProxyLimits {
-                max_request_size: 100 * 1024 * 1024, // 100MB
-                max_response_size: 100 * 1024 * 1024, // 100MB
-                max_concurrent_connections: 10000,
-                rate_limit_per_ip: Some(1000),
-                bandwidth_limit_kbps: None,
-            },
-            logging: ProxyLogging {
-                log_requests: true,
-                log_responses: false,
-                log_headers: false,
-                log_body: false,
-                max_body_size: 4096,
-            },
+            connection_pool: ConnectionPoolConfig::default(),
+            headers: HeaderConfig::default(),
+            timeout: TimeoutConfig::default(),
+            limits: ProxyLimits::default(),
+            logging: ProxyLogging::default(),
+        }
+    }
+}
+
+impl Default for HeaderConfig {
+    fn default() -> Self {
+        HeaderConfig {
+            preserve_host: false,
+            add_forwarded_headers: true,
+            add_real_ip: true,
+            add_proxy_headers: true,
+            remove_headers: vec!["Connection".to_string(), "Upgrade".to_string()],
+            add_headers: HashMap::new(),
+            via_header: Some("miwidothttp/1.0".to_string()),
+        }
+    }
+}
+
+impl Default for ProxyLimits {
+    fn default() -> Self {
+        ProxyLimits {
+            max_request_size: 100 * 1024 * 1024, // 100MB
+            max_response_size: 100 * 1024 * 1024, // 100MB
+            max_concurrent_connections: 10000,
+            rate_limit_per_ip: Some(1000),
+            rate_limit_window_seconds: 60,
+            max_connections_per_ip: None,
+            bandwidth_limit_kbps: None,
+        }
+    }
+}
+
+impl Default for ProxyLogging {
+    fn default() -> Self {
+        ProxyLogging {
+            log_requests: true,
+            log_responses: false,
+            log_headers: false,
+            log_body: false,
+            max_body_size: 4096,
         }
     }
 }
 
 pub struct ProxyManager {
     config: ProxyConfig,
-    client: Client<hyper_util::client::legacy::connect::HttpConnector, Body>,
     forward_proxy: Option<Arc<ForwardProxy>>,
-    reverse_proxy: Arc<ReverseProxy>,
     socks_proxy: Option<Arc<SocksProxy>>,
-    websocket_proxy: Arc<WebSocketProxy>,
-    connection_count: Arc<RwLock<HashMap<IpAddr, u32>>>,
+    ip_limiter: IpLimiter,
 }
 
 impl ProxyManager {
     pub fn new(config: ProxyConfig) -> Result<Self> {
-        let connector = hyper_util::client::legacy::connect::HttpConnector::new();
-        let client = Client::builder(TokioExecutor::new()).build(connector);
-
         let forward_proxy = if config.mode == ProxyMode::Forward {
             Some(Arc::new(ForwardProxy::new(config.clone())?))
         } else {
             None
         };
 
-        let reverse_proxy = Arc::new(ReverseProxy::new(config.clone())?);
-
         let socks_proxy = if matches!(config.mode, ProxyMode::Socks4 | ProxyMode::Socks5) {
             let version = if config.mode == ProxyMode::Socks4 {
                 SocksVersion::V4
@@ -203,29 +229,22 @@ impl ProxyManager {
             None
         };
 
-        let websocket_proxy = Arc::new(WebSocketProxy::new(config.clone())?);
+        let ip_limiter = IpLimiter::new(
+            config.limits.rate_limit_per_ip,
+            Duration::from_secs(config.limits.rate_limit_window_seconds),
+            config.limits.max_connections_per_ip,
+        );
 
         Ok(ProxyManager {
             config,
-            client,
             forward_proxy,
-            reverse_proxy,
             socks_proxy,
-            websocket_proxy,
-            connection_count: Arc::new(RwLock::new(HashMap::new())),
+            ip_limiter,
         })
     }
 
     pub async fn handle_request(&self, req: Request<Body>) -> Result<Response, StatusCode> {
         let method = req.method();
-        let uri = req.uri();
-        let headers = req.headers();
-
-        // Check for WebSocket upgrade
-        if self.is_websocket_request(headers) {
-            return self.websocket_proxy.handle_upgrade(req).await
-                .map_err(|_| StatusCode::BAD_GATEWAY);
-        }
 
         // Handle different proxy modes
         match self.config.mode {
@@ -236,12 +255,6 @@ impl ProxyManager {
                     self.handle_forward_proxy(req).await
                 }
             }
-            ProxyMode::Reverse => {
-                self.handle_reverse_proxy(req).await
-            }
-            ProxyMode::Transparent => {
-                self.handle_transparent_proxy(req).await
-            }
             ProxyMode::Socks4 | ProxyMode::Socks5 => {
                 Err(StatusCode::METHOD_NOT_ALLOWED) // SOCKS handled separately
             }
@@ -251,7 +264,10 @@ impl ProxyManager {
     async fn handle_connect_method(&self, req: Request<Body>) -> Result<Response, StatusCode> {
         if let Some(forward_proxy) = &self.forward_proxy {
             forward_proxy.handle_connect(req).await
-                .map_err(|_| StatusCode::BAD_GATEWAY)
+                .map_err(|e| {
+                    debug!("Rejected proxy request: {}", e);
+                    StatusCode::BAD_REQUEST
+                })
         } else {
             Err(StatusCode::METHOD_NOT_ALLOWED)
         }
@@ -260,62 +276,95 @@ impl ProxyManager {
     async fn handle_forward_proxy(&self, req: Request<Body>) -> Result<Response, StatusCode> {
         if let Some(forward_proxy) = &self.forward_proxy {
             forward_proxy.handle_request(req).await
-                .map_err(|_| StatusCode::BAD_GATEWAY)
+                .map_err(|e| {
+                    debug!("Rejected proxy request: {}", e);
+                    StatusCode::BAD_REQUEST
+                })
         } else {
             Err(StatusCode::METHOD_NOT_ALLOWED)
         }
     }
 
-    async fn handle_reverse_proxy(&self, req: Request<Body>) -> Result<Response, StatusCode> {
-        self.reverse_proxy.handle_request(req).await
-            .map_err(|_| StatusCode::BAD_GATEWAY)
-    }
-
-    async fn handle_transparent_proxy(&self, req: Request<Body>) -> Result<Response, StatusCode> {
-        // Transparent proxy intercepts traffic at network level
-        // Implementation would depend on iptables/netfilter integration
-        warn!("Transparent proxy not yet implemented");
-        Err(StatusCode::NOT_IMPLEMENTED)
+    // Listen on bind_addr and serve until the process exits
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let bind_addr = self.config.bind_addr
+            .ok_or_else(|| anyhow!("{:?} proxy server needs a bind_addr", self.config.mode))?;
+        let listener = TcpListener::bind(bind_addr).await?;
+        info!("{:?} proxy listening on {}", self.config.mode, bind_addr);
+        self.serve(listener).await
     }
 
-    pub async fn proxy_request(&self, backend: &BackendConfig, req: Request<Body>) -> Result<Response> {
-        self.reverse_proxy.proxy_to_backend(backend, req).await
-    }
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept proxy connection: {}", e);
+                    continue;
+                }
+            };
+            let client_ip = peer_addr.ip();
+            let Some(connection) = self.acquire_connection(client_ip) else {
+                warn!("{} has too many proxy connections open, closing", client_ip);
+                continue;
+            };
+            debug!("New {:?} proxy connection from {}", self.config.mode, peer_addr);
+
+            let manager = self.clone();
+            tokio::spawn(async move {
+                // Held while the connection is served
+                let _connection = connection;
+                if let Some(socks_proxy) = &manager.socks_proxy {
+                    // A SOCKS connection carries a single request
+                    if !manager.check_rate_limit(client_ip).await {
+                        warn!("Rate limited SOCKS5 client {}", client_ip);
+                        return;
+                    }
+                    if let Err(e) = socks_proxy.handle_connection(stream).await {
+                        warn!("SOCKS5 connection from {} failed: {}", peer_addr, e);
+                    }
+                    return;
+                }
 
-    pub async fn start_socks_server(&self) -> Result<()> {
-        if let Some(socks_proxy) = &self.socks_proxy {
-            if let Some(bind_addr) = self.config.bind_addr {
-                socks_proxy.start_server(bind_addr).await?;
-            } else {
-                return Err(anyhow!("SOCKS proxy requires bind_addr"));
-            }
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let manager = manager.clone();
+                    async move {
+                        if !manager.check_rate_limit(client_ip).await {
+                            return Ok::<_, Infallible>(StatusCode::TOO_MANY_REQUESTS.into_response());
+                        }
+                        Ok(manager.respond(req.map(Body::new)).await)
+                    }
+                });
+                // Upgrades let a CONNECT take the connection over once its
+                // 200 has been written
+                let served = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .with_upgrades()
+                    .await;
+                if let Err(e) = served {
+                    debug!("Forward proxy connection from {} ended: {}", peer_addr, e);
+                }
+            });
         }
-        Ok(())
     }
 
-    fn is_websocket_request(&self, headers: &HeaderMap) -> bool {
-        headers.get("upgrade")
-            .and_then(|v| v.to_str().ok())
-            .map(|v| v.to_lowercase() == "websocket")
-            .unwrap_or(false)
+    // handle_request, with failures answered by their status
+    async fn respond(&self, req: Request<Body>) -> Response {
+        match self.handle_request(req).await {
+            Ok(response) => response,
+            Err(status) => status.into_response(),
+        }
     }
 
+    // Requests-per-window limit (rate_limit_per_ip)
     pub async fn check_rate_limit(&self, client_ip: IpAddr) -> bool {
-        if let Some(limit) = self.config.limits.rate_limit_per_ip {
-            let mut counts = self.connection_count.write().await;
-            let count = counts.entry(client_ip).or_insert(0);
-            
-            if *count >= limit {
-                return false;
-            }
-            
-            *count += 1;
-        }
-        true
+        self.ip_limiter.check_request(client_ip)
     }
 
-    pub async fn health_check(&self, backend: &BackendConfig) -> bool {
-        self.reverse_proxy.health_check(backend).await
+    // Concurrent-connection limit (max_connections_per_ip); hold the guard
+    // for as long as the connection is open
+    pub fn acquire_connection(&self, client_ip: IpAddr) -> Option<ConnectionGuard> {
+        self.ip_limiter.acquire_connection(client_ip)
     }
 
     pub fn add_proxy_headers(&self, headers: &mut HeaderMap, client_ip: &str, original_host: &str) {
@@ -358,134 +407,6 @@ impl ProxyManager {
     }
 }
 
-// Proxy protocol support (HAProxy protocol)
-pub struct ProxyProtocol {
-    pub version: u8,
-    pub command: ProxyCommand,
-    pub family: ProxyFamily,
-    pub protocol: ProxyTransport,
-    pub src_addr: SocketAddr,
-    pub dest_addr: SocketAddr,
-}
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum ProxyCommand {
-    Local,
-    Proxy,
-}
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum ProxyFamily {
-    Inet,
-    Inet6,
-    Unix,
-}
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum ProxyTransport {
-    Stream,
-    Dgram,
-}
-
-impl ProxyProtocol {
-    pub async fn parse<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Self>> {
-        let mut buf = [0u8; 108]; // Max proxy protocol v2 header size
-        
-        // Read first 16 bytes to determine version
-        reader.read_exact(&mut buf[..16]).await?;
-        
-        // Check for proxy protocol v2 signature
-        if &buf[..12] == b"\r\n\r\n\0\r\nQUIT\n" {
-            return Self::parse_v2(&buf).await;
-        }
-        
-        // Check for proxy protocol v1
-        let header = String::from_utf8_lossy(&buf[..16]);
-        if header.starts_with("PROXY ") {
-            return Self::parse_v1(&header).await;
-        }
-        
-        Ok(None)
-    }
-
-    async fn parse_v1(header: &str) -> Result<Option<Self>> {
-        // PROXY TCP4 192.168.1.1 192.168.1.2 12345 80\r\n
-        let parts: Vec<&str> = header.trim().split_whitespace().collect();
-        
-        if parts.len() >= 6 && parts[0] == "PROXY" {
-            let family = match parts[1] {
-                "TCP4" => ProxyFamily::Inet,
-                "TCP6" => ProxyFamily::Inet6,
-                _ => return Ok(None),
-            };
-            
-            let src_ip: IpAddr = parts[2].parse()?;
-            let dest_ip: IpAddr = parts[3].parse()?;
-            let src_port: u16 = parts[4].parse()?;
-            let dest_port: u16 = parts[5].parse()?;
-            
-            Ok(Some(ProxyProtocol {
-                version: 1,
-                command: ProxyCommand::Proxy,
-                family,
-                protocol: ProxyTransport::Stream,
-                src_addr: SocketAddr::new(src_ip, src_port),
-                dest_addr: SocketAddr::new(dest_ip, dest_port),
-            }))
-        } else {
-            Ok(None)
-        }
-    }
-
-    async fn parse_v2(buf: &[u8]) -> Result<Option<Self>> {
-        // Proxy protocol v2 binary format
-        if buf.len() < 16 {
-            return Ok(None);
-        }
-        
-        let version = (buf[12] & 0xF0) >> 4;
-        let command = buf[12] & 0x0F;
-        let family = (buf[13] & 0xF0) >> 4;
-        let protocol = buf[13] & 0x0F;
-        let length = u16::from_be_bytes([buf[14], buf[15]]) as usize;
-        
-        if version != 2 || buf.len() < 16 + length {
-            return Ok(None);
-        }
-        
-        // Parse addresses based on family
-        let (src_addr, dest_addr) = match family {
-            1 => { // IPv4
-                if length < 12 { return Ok(None); }
-                let src_ip = IpAddr::V4(std::net::Ipv4Addr::new(
-                    buf[16], buf[17], buf[18], buf[19]
-                ));
-                let dest_ip = IpAddr::V4(std::net::Ipv4Addr::new(
-                    buf[20], buf[21], buf[22], buf[23]
-                ));
-                let src_port = u16::from_be_bytes([buf[24], buf[25]]);
-                let dest_port = u16::from_be_bytes([buf[26], buf[27]]);
-                (SocketAddr::new(src_ip, src_port), SocketAddr::new(dest_ip, dest_port))
-            }
-            2 => { // IPv6
-                if length < 36 { return Ok(None); }
-                // IPv6 parsing implementation
-                return Ok(None); // Simplified for now
-            }
-            _ => return Ok(None),
-        };
-        
-        Ok(Some(ProxyProtocol {
-            version: 2,
-            command: if command == 1 { ProxyCommand::Proxy } else { ProxyCommand::Local },
-            family: ProxyFamily::Inet,
-            protocol: ProxyTransport::Stream,
-            src_addr,
-            dest_addr,
-        }))
-    }
-}
-
 // Connection statistics
 #[derive(Debug, Clone)]
 pub struct ProxyStats {
@@ -499,15 +420,230 @@ pub struct ProxyStats {
 
 impl ProxyManager {
     pub async fn get_stats(&self) -> ProxyStats {
-        let connection_count = self.connection_count.read().await;
-        
+        let (total_connections, active_connections) = self.ip_limiter.connection_stats();
+
         ProxyStats {
-            total_connections: connection_count.values().sum::<u32>() as u64,
-            active_connections: connection_count.len() as u64,
+            total_connections,
+            active_connections,
             bytes_transferred: 0, // Would track in real implementation
             requests_per_second: 0.0, // Would calculate from metrics
             average_response_time: Duration::from_millis(0),
             error_rate: 0.0,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn proxy_server(config: ProxyConfig) -> SocketAddr {
+        let manager = Arc::new(ProxyManager::new(config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(manager.serve(listener));
+        addr
+    }
+
+    // Everything up to the end of the response headers
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnel() {
+        // TCP echo server standing in for a TLS origin
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        let proxy = proxy_server(ProxyConfig::default()).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", echo_addr).as_bytes()).await.unwrap();
+        assert!(read_head(&mut stream).await.starts_with("HTTP/1.1 200"));
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    async fn origin() -> SocketAddr {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = origin.local_addr().unwrap();
+        let app = axum::Router::new()
+            .route("/hello", axum::routing::get(|| async { "from origin" }))
+            .route("/slow", axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_secs(3)).await;
+                "too late"
+            }));
+        tokio::spawn(async move { axum::serve(origin, app).await.unwrap() });
+        addr
+    }
+
+    // GET `url` through the proxy on a fresh connection, returning the raw
+    // response
+    async fn get(proxy: SocketAddr, url: &str, extra_headers: &str) -> String {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: origin\r\nConnection: close\r\n{}\r\n", url, extra_headers);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_forward_http_request() {
+        let origin = origin().await;
+        let proxy = proxy_server(ProxyConfig::default()).await;
+
+        let response = get(proxy, &format!("http://{}/hello", origin), "").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("from origin"));
+    }
+
+    #[tokio::test]
+    async fn test_forward_read_timeout() {
+        let origin = origin().await;
+        let mut config = ProxyConfig::default();
+        config.timeout.read_timeout_seconds = 1;
+        let proxy = proxy_server(config).await;
+
+        let response = get(proxy, &format!("http://{}/slow", origin), "").await;
+        assert!(response.starts_with("HTTP/1.1 504"), "{}", response);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: ProxyConfig = toml::from_str("mode = \"socks5\"\nbind_addr = \"127.0.0.1:1080\"").unwrap();
+        assert!(config.validate().is_ok());
+        assert!(ProxyConfig::default().validate().is_err());
+
+        let socks4: ProxyConfig = toml::from_str("mode = \"socks4\"\nbind_addr = \"127.0.0.1:1080\"").unwrap();
+        assert!(socks4.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_digest_auth() {
+        use md5::{Digest, Md5};
+        let md5 = |data: String| format!("{:x}", Md5::digest(data.as_bytes()));
+
+        let origin = origin().await;
+        let proxy = proxy_server(ProxyConfig {
+            authentication: Some(ProxyAuth {
+                auth_type: AuthType::Digest,
+                username: "user".to_string(),
+                password: "secret".to_string(),
+                realm: Some("Proxy".to_string()),
+            }),
+            ..Default::default()
+        }).await;
+        let url = format!("http://{}/hello", origin);
+
+        // Challenged first, SHA-256 and MD5 offered
+        let response = get(proxy, &url, "").await;
+        assert!(response.starts_with("HTTP/1.1 407"), "{}", response);
+        let challenge = response.lines()
+            .find(|line| line.to_ascii_lowercase().starts_with("proxy-authenticate: digest") && line.contains("MD5"))
+            .expect("MD5 challenge");
+        let nonce = challenge.split("nonce=\"").nth(1).unwrap().split('"').next().unwrap();
+
+        let ha1 = md5("user:Proxy:secret".to_string());
+        let ha2 = md5(format!("GET:{}", url));
+        let answer = md5(format!("{}:{}:00000001:abc:auth:{}", ha1, nonce, ha2));
+        let authorization = format!(
+            "Proxy-Authorization: Digest username=\"user\", realm=\"Proxy\", nonce=\"{}\", uri=\"{}\", \
+             qop=auth, nc=00000001, cnonce=\"abc\", response=\"{}\"\r\n",
+            nonce, url, answer,
+        );
+        let response = get(proxy, &url, &authorization).await;
+        assert!(response.ends_with("from origin"), "{}", response);
+
+        // Replaying the same nonce count is refused
+        assert!(get(proxy, &url, &authorization).await.starts_with("HTTP/1.1 407"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_per_ip() {
+        let origin = origin().await;
+        let mut config = ProxyConfig::default();
+        config.limits.rate_limit_per_ip = Some(2);
+        let proxy = proxy_server(config).await;
+        let url = format!("http://{}/hello", origin);
+
+        assert!(get(proxy, &url, "").await.starts_with("HTTP/1.1 200"));
+        assert!(get(proxy, &url, "").await.starts_with("HTTP/1.1 200"));
+        assert!(get(proxy, &url, "").await.starts_with("HTTP/1.1 429"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_upstream_proxy() {
+        // Upstream proxy that accepts the CONNECT, sends the first tunnel
+        // bytes right behind its headers and then echoes
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            assert!(head.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"), "{}", head);
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello").await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        let proxy = proxy_server(ProxyConfig {
+            upstream_proxy: Some(UpstreamProxy {
+                url: format!("http://{}", upstream_addr),
+                auth: None,
+                use_for_https: true,
+            }),
+            ..Default::default()
+        }).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").await.unwrap();
+        assert!(read_head(&mut stream).await.starts_with("HTTP/1.1 200"));
+
+        let mut early = [0u8; 5];
+        stream.read_exact(&mut early).await.unwrap();
+        assert_eq!(&early, b"hello");
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_http_request_through_upstream_proxy() {
+        // The upstream proxy sees the absolute-form URI and answers itself
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let app = axum::Router::new().route("/hello", axum::routing::get(|uri: axum::http::Uri| async move {
+            format!("upstream saw {}", uri)
+        }));
+        tokio::spawn(async move { axum::serve(upstream, app).await.unwrap() });
+        let proxy = proxy_server(ProxyConfig {
+            upstream_proxy: Some(UpstreamProxy {
+                url: format!("http://{}", upstream_addr),
+                auth: None,
+                use_for_https: false,
+            }),
+            ..Default::default()
+        }).await;
+
+        // The origin doesn't resolve, so only the upstream can answer
+        let response = get(proxy, "http://origin.invalid/hello", "").await;
+        assert!(response.ends_with("upstream saw http://origin.invalid/hello"), "{}", response);
+    }
+}
@@ -0,0 +1,553 @@
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::{debug, info};
+
+use super::forward::copy_with_idle_timeout;
+use super::{AuthType, ProxyConfig};
+
+// RFC 1928 / RFC 1929 constants
+const SOCKS5: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+// Largest UDP payload we relay; anything bigger is truncated by recv_from
+const MAX_DATAGRAM: usize = 65_535;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocksVersion {
+    V4,
+    V5,
+}
+
+// DST.ADDR/DST.PORT from a request or UDP header
+#[derive(Debug, Clone, PartialEq)]
+enum Address {
+    Socket(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    async fn resolve(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Address::Socket(addr) => Ok(*addr),
+            Address::Domain(host, port) => tokio::net::lookup_host((host.as_str(), *port)).await?
+                .next()
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} did not resolve", host))),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Socket(addr) => write!(f, "{}", addr),
+            Address::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+// SOCKS5 server supporting CONNECT and UDP ASSOCIATE, with either no
+// authentication or the username/password from `authentication`
+// (auth_type = "basic")
+#[derive(Clone)]
+pub struct SocksProxy {
+    config: ProxyConfig,
+}
+
+impl SocksProxy {
+    pub fn new(version: SocksVersion, config: ProxyConfig) -> Result<Self> {
+        if version == SocksVersion::V4 {
+            bail!("SOCKS4 is not supported, use mode = \"socks5\"");
+        }
+        if let Some(auth) = &config.authentication {
+            if !matches!(auth.auth_type, AuthType::Basic) {
+                bail!("SOCKS5 only supports basic (username/password) authentication");
+            }
+        }
+        Ok(SocksProxy { config })
+    }
+
+    pub(super) async fn handle_connection(&self, mut client: TcpStream) -> Result<()> {
+        self.negotiate(&mut client).await?;
+
+        // Request: VER CMD RSV ATYP DST.ADDR DST.PORT
+        let mut header = [0u8; 4];
+        client.read_exact(&mut header).await?;
+        if header[0] != SOCKS5 {
+            bail!("unexpected SOCKS version {} in request", header[0]);
+        }
+
+        let target = match header[3] {
+            ATYP_IPV4 => {
+                let mut addr = [0u8; 4];
+                client.read_exact(&mut addr).await?;
+                Address::Socket(SocketAddr::new(Ipv4Addr::from(addr).into(), client.read_u16().await?))
+            }
+            ATYP_IPV6 => {
+                let mut addr = [0u8; 16];
+                client.read_exact(&mut addr).await?;
+                Address::Socket(SocketAddr::new(Ipv6Addr::from(addr).into(), client.read_u16().await?))
+            }
+            ATYP_DOMAIN => {
+                let len = client.read_u8().await? as usize;
+                let mut domain = vec![0u8; len];
+                client.read_exact(&mut domain).await?;
+                let domain = String::from_utf8(domain).map_err(|_| anyhow!("domain name is not valid UTF-8"))?;
+                Address::Domain(domain, client.read_u16().await?)
+            }
+            atyp => {
+                send_reply(&mut client, REPLY_ADDRESS_NOT_SUPPORTED, None).await?;
+                bail!("unsupported address type {}", atyp);
+            }
+        };
+
+        match header[1] {
+            CMD_CONNECT => self.connect(client, target).await,
+            CMD_UDP_ASSOCIATE => self.udp_associate(client, target).await,
+            command => {
+                send_reply(&mut client, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+                bail!("unsupported command {} for {}", command, target);
+            }
+        }
+    }
+
+    async fn connect(&self, mut client: TcpStream, target: Address) -> Result<()> {
+        let target = target.to_string();
+        let connected = match self.config.timeout.connect() {
+            Some(limit) => tokio::time::timeout(limit, TcpStream::connect(&target)).await,
+            None => Ok(TcpStream::connect(&target).await),
+        };
+        let connected = match connected {
+            Ok(connected) => connected,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")),
+        };
+        let upstream = match connected {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                let reply = match e.kind() {
+                    std::io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+                    std::io::ErrorKind::TimedOut => REPLY_HOST_UNREACHABLE,
+                    _ => REPLY_GENERAL_FAILURE,
+                };
+                send_reply(&mut client, reply, None).await?;
+                bail!("failed to connect to {}: {}", target, e);
+            }
+        };
+
+        send_reply(&mut client, REPLY_SUCCEEDED, upstream.local_addr().ok()).await?;
+        info!("SOCKS5 CONNECT to {}", target);
+
+        self.relay(client, upstream).await
+    }
+
+    // UDP ASSOCIATE: relay datagrams between the client and any remote
+    // through one socket, for as long as the control connection stays open.
+    // `requested` is where the client says it will send from; zeros mean
+    // it doesn't know yet, and the first datagram from its IP decides.
+    async fn udp_associate(&self, mut client: TcpStream, requested: Address) -> Result<()> {
+        let client_ip = client.peer_addr()?.ip();
+        let socket = match UdpSocket::bind(SocketAddr::new(client.local_addr()?.ip(), 0)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                send_reply(&mut client, REPLY_GENERAL_FAILURE, None).await?;
+                bail!("failed to bind UDP relay: {}", e);
+            }
+        };
+        let mut client_addr = match requested {
+            Address::Socket(addr) if !addr.ip().is_unspecified() && addr.port() != 0 => Some(addr),
+            _ => None,
+        };
+        let expected_port = match requested {
+            Address::Socket(addr) => addr.port(),
+            Address::Domain(_, port) => port,
+        };
+
+        send_reply(&mut client, REPLY_SUCCEEDED, socket.local_addr().ok()).await?;
+        info!("SOCKS5 UDP ASSOCIATE for {} on {:?}", client_ip, socket.local_addr());
+
+        let mut control = [0u8; 1];
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            tokio::select! {
+                // The client must not send anything else on the control
+                // connection; EOF or an error ends the association
+                read = client.read(&mut control) => {
+                    match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => continue,
+                    }
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            debug!("UDP relay receive failed: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let from_client = match client_addr {
+                        Some(addr) => from == addr,
+                        None => from.ip() == client_ip && (expected_port == 0 || from.port() == expected_port),
+                    };
+
+                    if from_client {
+                        client_addr = Some(from);
+                        let (target, payload) = match parse_udp_datagram(&buf[..len]) {
+                            Some(datagram) => datagram,
+                            None => {
+                                debug!("Dropping malformed or fragmented SOCKS5 datagram from {}", from);
+                                continue;
+                            }
+                        };
+                        match target.resolve().await {
+                            Ok(target) => {
+                                if let Err(e) = socket.send_to(payload, target).await {
+                                    debug!("UDP relay to {} failed: {}", target, e);
+                                }
+                            }
+                            Err(e) => debug!("Dropping datagram for {}: {}", target, e),
+                        }
+                    } else if let Some(client_addr) = client_addr {
+                        let mut datagram = vec![0x00, 0x00, 0x00];
+                        encode_address(&mut datagram, from);
+                        datagram.extend_from_slice(&buf[..len]);
+                        if let Err(e) = socket.send_to(&datagram, client_addr).await {
+                            debug!("UDP relay to client {} failed: {}", client_addr, e);
+                        }
+                    }
+                }
+            }
+        }
+
+        debug!("SOCKS5 UDP association for {} closed", client_ip);
+        Ok(())
+    }
+
+    // Greeting and, when configured, the RFC 1929 username/password exchange
+    async fn negotiate(&self, client: &mut TcpStream) -> Result<()> {
+        let version = client.read_u8().await?;
+        if version != SOCKS5 {
+            bail!("unsupported SOCKS version {}", version);
+        }
+        let count = client.read_u8().await? as usize;
+        let mut methods = vec![0u8; count];
+        client.read_exact(&mut methods).await?;
+
+        let method = match &self.config.authentication {
+            Some(_) => METHOD_USER_PASS,
+            None => METHOD_NO_AUTH,
+        };
+        if !methods.contains(&method) {
+            client.write_all(&[SOCKS5, METHOD_NONE_ACCEPTABLE]).await?;
+            bail!("client offered no acceptable authentication method");
+        }
+        client.write_all(&[SOCKS5, method]).await?;
+
+        let auth = match &self.config.authentication {
+            Some(auth) => auth,
+            None => return Ok(()),
+        };
+
+        // VER ULEN UNAME PLEN PASSWD
+        let version = client.read_u8().await?;
+        if version != AUTH_VERSION {
+            bail!("unsupported authentication version {}", version);
+        }
+        let len = client.read_u8().await? as usize;
+        let mut username = vec![0u8; len];
+        client.read_exact(&mut username).await?;
+        let len = client.read_u8().await? as usize;
+        let mut password = vec![0u8; len];
+        client.read_exact(&mut password).await?;
+
+        if username != auth.username.as_bytes() || password != auth.password.as_bytes() {
+            client.write_all(&[AUTH_VERSION, 0x01]).await?;
+            bail!("authentication failed for user {:?}", String::from_utf8_lossy(&username));
+        }
+        client.write_all(&[AUTH_VERSION, 0x00]).await?;
+        Ok(())
+    }
+
+    async fn relay(&self, mut client: TcpStream, mut upstream: TcpStream) -> Result<()> {
+        let idle_timeout = self.config.timeout.idle();
+        let last_activity = Mutex::new(Instant::now());
+
+        let (mut client_read, mut client_write) = client.split();
+        let (mut upstream_read, mut upstream_write) = upstream.split();
+
+        let (sent, received) = tokio::join!(
+            copy_with_idle_timeout(&mut client_read, &mut upstream_write, idle_timeout, &last_activity),
+            copy_with_idle_timeout(&mut upstream_read, &mut client_write, idle_timeout, &last_activity),
+        );
+        debug!("SOCKS5 relay closed: {:?} bytes sent, {:?} bytes received", sent, received);
+
+        sent?;
+        received?;
+        Ok(())
+    }
+}
+
+// VER REP RSV ATYP BND.ADDR BND.PORT; failures report an unspecified address
+async fn send_reply(client: &mut TcpStream, reply: u8, bound: Option<SocketAddr>) -> Result<()> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut message = vec![SOCKS5, reply, 0x00];
+    encode_address(&mut message, bound);
+    client.write_all(&message).await?;
+    Ok(())
+}
+
+// ATYP ADDR PORT
+fn encode_address(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+// RSV(2) FRAG ATYP DST.ADDR DST.PORT DATA. Fragment reassembly is optional
+// in RFC 1928 and nobody uses it, so fragments (FRAG != 0) are dropped.
+fn parse_udp_datagram(datagram: &[u8]) -> Option<(Address, &[u8])> {
+    if datagram.len() < 4 || datagram[2] != 0x00 {
+        return None;
+    }
+    let rest = &datagram[4..];
+    let (address, rest) = match datagram[3] {
+        ATYP_IPV4 if rest.len() >= 6 => {
+            let ip: [u8; 4] = rest[..4].try_into().ok()?;
+            let port = u16::from_be_bytes([rest[4], rest[5]]);
+            (Address::Socket(SocketAddr::new(Ipv4Addr::from(ip).into(), port)), &rest[6..])
+        }
+        ATYP_IPV6 if rest.len() >= 18 => {
+            let ip: [u8; 16] = rest[..16].try_into().ok()?;
+            let port = u16::from_be_bytes([rest[16], rest[17]]);
+            (Address::Socket(SocketAddr::new(Ipv6Addr::from(ip).into(), port)), &rest[18..])
+        }
+        ATYP_DOMAIN if !rest.is_empty() && rest.len() >= 1 + rest[0] as usize + 2 => {
+            let len = rest[0] as usize;
+            let host = std::str::from_utf8(&rest[1..1 + len]).ok()?.to_string();
+            let port = u16::from_be_bytes([rest[1 + len], rest[2 + len]]);
+            (Address::Domain(host, port), &rest[3 + len..])
+        }
+        _ => return None,
+    };
+    Some((address, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::{ProxyAuth, ProxyManager, ProxyMode};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_socks::tcp::Socks5Stream;
+
+    // Origin answering one HTTP request with "hello"
+    async fn origin() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut chunk = [0u8; 256];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = stream.read(&mut chunk).await.unwrap();
+                        if n == 0 { return; }
+                        request.extend_from_slice(&chunk[..n]);
+                    }
+                    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    async fn socks_server(config: ProxyConfig) -> SocketAddr {
+        let manager = Arc::new(ProxyManager::new(ProxyConfig { mode: ProxyMode::Socks5, ..config }).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(manager.serve(listener));
+        addr
+    }
+
+    async fn fetch(mut stream: Socks5Stream<TcpStream>) -> String {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: origin\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_connect_without_auth() {
+        let origin = origin().await;
+        let proxy = socks_server(ProxyConfig::default()).await;
+
+        // IPv4 address
+        let stream = Socks5Stream::connect(proxy, origin).await.unwrap();
+        let response = fetch(stream).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("hello"));
+
+        // Domain name, resolved by the proxy
+        let stream = Socks5Stream::connect(proxy, ("localhost", origin.port())).await.unwrap();
+        assert!(fetch(stream).await.ends_with("hello"));
+
+        // Nothing listening behind the proxy
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let err = Socks5Stream::connect(proxy, closed).await.unwrap_err();
+        assert!(matches!(err, tokio_socks::Error::ConnectionRefused), "{:?}", err);
+    }
+
+    #[test]
+    fn test_parse_udp_datagram() {
+        let datagram = [0, 0, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 53, b'h', b'i'];
+        let (target, payload) = parse_udp_datagram(&datagram).unwrap();
+        assert_eq!(target, Address::Socket("10.0.0.1:53".parse().unwrap()));
+        assert_eq!(payload, b"hi");
+
+        let mut datagram = vec![0, 0, 0, ATYP_DOMAIN, 7];
+        datagram.extend_from_slice(b"example");
+        datagram.extend_from_slice(&[0x01, 0xbb]);
+        let (target, payload) = parse_udp_datagram(&datagram).unwrap();
+        assert_eq!(target, Address::Domain("example".to_string(), 443));
+        assert!(payload.is_empty());
+
+        // Fragmented, truncated
+        assert!(parse_udp_datagram(&[0, 0, 1, ATYP_IPV4, 10, 0, 0, 1, 0, 53]).is_none());
+        assert!(parse_udp_datagram(&[0, 0, 0, ATYP_IPV4, 10, 0]).is_none());
+        assert!(parse_udp_datagram(&[0, 0, 0, ATYP_DOMAIN, 7, b'e']).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate_echo() {
+        // UDP echo server
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+        let proxy = socks_server(ProxyConfig::default()).await;
+
+        // Control connection: greeting, then UDP ASSOCIATE from 0.0.0.0:0
+        let mut control = TcpStream::connect(proxy).await.unwrap();
+        control.write_all(&[SOCKS5, 1, METHOD_NO_AUTH]).await.unwrap();
+        let mut choice = [0u8; 2];
+        control.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [SOCKS5, METHOD_NO_AUTH]);
+        control.write_all(&[SOCKS5, CMD_UDP_ASSOCIATE, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [SOCKS5, REPLY_SUCCEEDED, 0, ATYP_IPV4]);
+        let relay = SocketAddr::new(
+            Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]).into(),
+            u16::from_be_bytes([reply[8], reply[9]]),
+        );
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = vec![0, 0, 0];
+        encode_address(&mut datagram, echo_addr);
+        datagram.extend_from_slice(b"ping");
+        socket.send_to(&datagram, relay).await.unwrap();
+
+        // The echo comes back wrapped in a header naming the echo server
+        let mut buf = [0u8; 1024];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, relay);
+        let (source, payload) = parse_udp_datagram(&buf[..len]).unwrap();
+        assert_eq!(source, Address::Socket(echo_addr));
+        assert_eq!(payload, b"ping");
+
+        // Closing the control connection tears the relay down
+        drop(control);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        socket.send_to(&datagram, relay).await.unwrap();
+        let echoed = tokio::time::timeout(Duration::from_millis(300), socket.recv_from(&mut buf)).await;
+        assert!(echoed.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_association_holds_connection_slot() {
+        let origin = origin().await;
+        let mut config = ProxyConfig::default();
+        config.limits.max_connections_per_ip = Some(1);
+        let proxy = socks_server(config).await;
+
+        let mut control = TcpStream::connect(proxy).await.unwrap();
+        control.write_all(&[SOCKS5, 1, METHOD_NO_AUTH]).await.unwrap();
+        let mut choice = [0u8; 2];
+        control.read_exact(&mut choice).await.unwrap();
+        control.write_all(&[SOCKS5, CMD_UDP_ASSOCIATE, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], REPLY_SUCCEEDED);
+
+        // The association counts as the client's one connection
+        assert!(Socks5Stream::connect(proxy, origin).await.is_err());
+
+        // Closing the control connection gives the slot back
+        drop(control);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let stream = Socks5Stream::connect(proxy, origin).await.unwrap();
+        assert!(fetch(stream).await.ends_with("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_password() {
+        let origin = origin().await;
+        let config = ProxyConfig {
+            authentication: Some(ProxyAuth {
+                auth_type: AuthType::Basic,
+                username: "user".to_string(),
+                password: "secret".to_string(),
+                realm: None,
+            }),
+            ..Default::default()
+        };
+        let proxy = socks_server(config).await;
+
+        let stream = Socks5Stream::connect_with_password(proxy, origin, "user", "secret").await.unwrap();
+        assert!(fetch(stream).await.ends_with("hello"));
+
+        assert!(Socks5Stream::connect_with_password(proxy, origin, "user", "wrong").await.is_err());
+        // Without credentials there's no method in common
+        assert!(Socks5Stream::connect(proxy, origin).await.is_err());
+    }
+}
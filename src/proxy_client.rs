@@ -0,0 +1,726 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use bytes::Bytes;
+use hyper::body::{Frame, SizeHint};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::client::legacy::connect::{Connect, HttpConnector};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{Instant, Sleep};
+use tracing::{debug, warn};
+
+use crate::body_limit::{is_limit_exceeded, BoxError, LimitedBody};
+
+pub type PooledClient = Client<HttpConnector, Body>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConnectionPoolConfig {
+    pub max_idle_per_host: usize,
+    pub idle_timeout_seconds: u64,
+    // Not enforced: the hyper pool only expires connections by idle time
+    pub max_lifetime_seconds: u64,
+    // Talk HTTP/2 to backends with prior knowledge (h2c); every backend
+    // behind this client must support it
+    pub http2: bool,
+    pub keep_alive: bool,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: 32,
+            idle_timeout_seconds: 90,
+            max_lifetime_seconds: 3600,
+            http2: false,
+            keep_alive: true,
+        }
+    }
+}
+
+// Backend timeouts; 0 turns one off
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    // Opening a backend connection; applied when the client is built
+    pub connect_timeout_seconds: u64,
+    // From the request being sent in full until the response headers
+    pub read_timeout_seconds: u64,
+    // Sending the request body
+    pub write_timeout_seconds: u64,
+    // Longest gap between response body chunks
+    pub idle_timeout_seconds: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_seconds: 10,
+            read_timeout_seconds: 30,
+            write_timeout_seconds: 30,
+            idle_timeout_seconds: 90,
+        }
+    }
+}
+
+fn seconds(value: u64) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_secs(value))
+}
+
+impl TimeoutConfig {
+    pub fn connect(&self) -> Option<Duration> {
+        seconds(self.connect_timeout_seconds)
+    }
+
+    pub fn read(&self) -> Option<Duration> {
+        seconds(self.read_timeout_seconds)
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        seconds(self.write_timeout_seconds)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        seconds(self.idle_timeout_seconds)
+    }
+}
+
+// Trying a backend request again after it failed, e.g.
+//
+//   [backends."api.example.com".retry]
+//   attempts = 2
+//   delay_ms = 100
+//   backoff = true
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    // Tries after the first one
+    pub attempts: u32,
+    pub delay_ms: u64,
+    // Double the delay after every try
+    pub backoff: bool,
+    // POST is not idempotent, so it is only retried on opt-in
+    pub retry_post: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 2,
+            delay_ms: 100,
+            backoff: true,
+            retry_post: false,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn allows(&self, method: &Method) -> bool {
+        [Method::GET, Method::HEAD, Method::PUT, Method::DELETE].contains(method)
+            || (self.retry_post && method == Method::POST)
+    }
+}
+
+// Answers worth another try: the backend, or the proxy on its behalf,
+// couldn't give a real one
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status, StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT)
+}
+
+// Whether the client asks to switch protocols (a WebSocket handshake, say):
+// Connection lists "upgrade" and Upgrade names the protocol
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && headers.get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+// Once the backend has answered 101 Switching Protocols and that answer has
+// reached the client, splice the two connections together until either
+// side closes. Frames pass through untouched, close and ping/pong included.
+pub fn tunnel(client: OnUpgrade, backend: OnUpgrade, target: String) {
+    tokio::spawn(async move {
+        let (client, backend) = match tokio::try_join!(client, backend) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!("Upgrade to {} failed: {}", target, e);
+                return;
+            }
+        };
+        let mut client = TokioIo::new(client);
+        let mut backend = TokioIo::new(backend);
+        match tokio::io::copy_bidirectional(&mut client, &mut backend).await {
+            Ok((sent, received)) => debug!("Upgraded connection to {} closed ({} bytes up, {} down)", target, sent, received),
+            Err(e) => debug!("Upgraded connection to {} ended: {}", target, e),
+        }
+    });
+}
+
+// Backend client whose idle connections are kept and reused per host
+pub fn build_client(config: &ConnectionPoolConfig, timeouts: &TimeoutConfig) -> PooledClient {
+    build_client_with(config, http_connector(config, timeouts))
+}
+
+// TCP connector with the connect timeout and the pool's keepalive, for
+// wrapping in another connector
+pub fn http_connector(config: &ConnectionPoolConfig, timeouts: &TimeoutConfig) -> HttpConnector {
+    let mut connector = HttpConnector::new();
+    connector.set_nodelay(true);
+    connector.set_connect_timeout(timeouts.connect());
+    if config.keep_alive {
+        connector.set_keepalive(Some(Duration::from_secs(config.idle_timeout_seconds)));
+    }
+    connector
+}
+
+// A client pooled like build_client's over any connector
+pub fn build_client_with<C>(config: &ConnectionPoolConfig, connector: C) -> Client<C, Body>
+where
+    C: Connect + Clone,
+{
+    let mut builder = Client::builder(TokioExecutor::new());
+    builder
+        .pool_max_idle_per_host(if config.keep_alive { config.max_idle_per_host } else { 0 })
+        .pool_idle_timeout(Duration::from_secs(config.idle_timeout_seconds))
+        .http2_only(config.http2);
+    builder.build(connector)
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    // The backend took longer than the named timeout ("write" or "read")
+    Timeout(&'static str),
+    Backend(hyper_util::client::legacy::Error),
+}
+
+impl SendError {
+    // Status to answer the client with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SendError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            SendError::Backend(e) if is_limit_exceeded(e) => StatusCode::PAYLOAD_TOO_LARGE,
+            // A connect timeout comes through as a connect error
+            SendError::Backend(e) if is_timeout(e) => StatusCode::GATEWAY_TIMEOUT,
+            SendError::Backend(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Timeout(phase) => write!(f, "backend {} timeout", phase),
+            SendError::Backend(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::Timeout(_) => None,
+            SendError::Backend(e) => Some(e),
+        }
+    }
+}
+
+// Whether a backend error, or anything it was caused by, is a timeout
+pub fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        let timed_out = e.downcast_ref::<std::io::Error>().map_or(false, |e| e.kind() == std::io::ErrorKind::TimedOut)
+            || e.is::<tokio::time::error::Elapsed>()
+            || e.is::<IdleTimeout>();
+        if timed_out {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+// Send a proxied request to a backend. Bodies are limited frame by frame so
+// trailers make it through both ways; gRPC backends (which carry
+// grpc-status there) need an HTTP/2 `client`.
+//
+// If the client goes away first, the handler future and this one with it
+// are dropped. Dropping the pending hyper request (or, once streaming, the
+// response body) closes the backend connection or resets the HTTP/2 stream,
+// so the backend stops working on an answer nobody will read.
+pub async fn send<C>(
+    client: &Client<C, Body>,
+    req: Request<Body>,
+    max_request_size: u64,
+    max_response_size: u64,
+    timeouts: &TimeoutConfig,
+) -> Result<Response<Body>, SendError>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let sent = Arc::new(Notify::new());
+    let mut req = req.map(|body| Body::new(SentBody::new(LimitedBody::new(body, max_request_size), sent.clone())));
+    // The proxy forwards trailers, so it accepts them whatever the client
+    // said; gRPC servers insist on this and HTTP/1 servers only send
+    // trailers when asked
+    req.headers_mut().insert(header::TE, HeaderValue::from_static("trailers"));
+
+    let mut in_flight = InFlight(Some(req.uri().clone()));
+    let response = client.request(req);
+    tokio::pin!(response);
+
+    // The write timeout runs until the body is through, unless the backend
+    // answers early; the read timeout then runs until it answers
+    let body_sent = async {
+        match timeouts.write() {
+            Some(timeout) => tokio::time::timeout(timeout, sent.notified()).await.is_ok(),
+            None => {
+                sent.notified().await;
+                true
+            }
+        }
+    };
+    let early = tokio::select! {
+        response = &mut response => Some(response),
+        in_time = body_sent => if in_time { None } else { return Err(SendError::Timeout("write")) },
+    };
+    let response = match early {
+        Some(response) => response,
+        None => match timeouts.read() {
+            Some(timeout) => tokio::time::timeout(timeout, response).await
+                .map_err(|_| SendError::Timeout("read"))?,
+            None => response.await,
+        },
+    };
+    in_flight.0 = None;
+
+    let idle = timeouts.idle();
+    Ok(response.map_err(SendError::Backend)?.map(|body| {
+        let body = LimitedBody::new(body, max_response_size);
+        match idle {
+            Some(idle) => Body::new(IdleTimeoutBody::new(body, idle)),
+            None => Body::new(body),
+        }
+    }))
+}
+
+// Notes a backend request abandoned before its answer arrived
+struct InFlight(Option<Uri>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(uri) = &self.0 {
+            debug!("Request to {} abandoned before the backend answered", uri);
+        }
+    }
+}
+
+// Request body that signals once it has been sent in full
+struct SentBody<B> {
+    inner: B,
+    sent: Arc<Notify>,
+}
+
+impl<B: hyper::body::Body> SentBody<B> {
+    fn new(inner: B, sent: Arc<Notify>) -> Self {
+        // Empty bodies are never polled
+        if inner.is_end_stream() {
+            sent.notify_one();
+        }
+        Self { inner, sent }
+    }
+}
+
+impl<B> hyper::body::Body for SentBody<B>
+where
+    B: hyper::body::Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        let frame = std::task::ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if frame.is_none() || self.inner.is_end_stream() {
+            self.sent.notify_one();
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Error ending a response body that stalled for longer than the idle timeout
+#[derive(Debug)]
+pub struct IdleTimeout(pub Duration);
+
+impl fmt::Display for IdleTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "backend sent nothing for {:?}", self.0)
+    }
+}
+
+impl std::error::Error for IdleTimeout {}
+
+struct IdleTimeoutBody<B> {
+    inner: B,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<B> IdleTimeoutBody<B> {
+    fn new(inner: B, timeout: Duration) -> Self {
+        Self { inner, timeout, sleep: Box::pin(tokio::time::sleep(timeout)) }
+    }
+}
+
+impl<B> hyper::body::Body for IdleTimeoutBody<B>
+where
+    B: hyper::body::Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                let deadline = Instant::now() + self.timeout;
+                self.sleep.as_mut().reset(deadline);
+                Poll::Ready(frame.map(|frame| frame.map_err(Into::into)))
+            }
+            Poll::Pending => {
+                std::task::ready!(self.sleep.as_mut().poll(cx));
+                Poll::Ready(Some(Err(Box::new(IdleTimeout(self.timeout)))))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::ConnectInfo, http::HeaderMap, routing::get, Router};
+    use bytes::Bytes;
+    use hyper::body::{Body as _, Frame};
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::net::TcpListener;
+
+    // Backend that answers with the client port of the connection it came in on
+    async fn spawn_backend() -> SocketAddr {
+        let app = Router::new().route("/", get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+            peer.port().to_string()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap()
+        });
+        addr
+    }
+
+    async fn client_port(client: &PooledClient, addr: SocketAddr) -> String {
+        let response = client.get(format!("http://{}/", addr).parse().unwrap()).await.unwrap();
+        let body = axum::body::to_bytes(Body::new(response.into_body()), 64).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_second_request_reuses_pooled_connection() {
+        let addr = spawn_backend().await;
+        let client = build_client(&ConnectionPoolConfig::default(), &TimeoutConfig::default());
+
+        let first = client_port(&client, addr).await;
+        let second = client_port(&client, addr).await;
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_pooling_disabled_without_keep_alive() {
+        let addr = spawn_backend().await;
+        let client = build_client(&ConnectionPoolConfig { keep_alive: false, ..Default::default() }, &TimeoutConfig::default());
+
+        let first = client_port(&client, addr).await;
+        let second = client_port(&client, addr).await;
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_http2_prior_knowledge() {
+        let addr = spawn_backend().await;
+        let client = build_client(&ConnectionPoolConfig { http2: true, ..Default::default() }, &TimeoutConfig::default());
+
+        let response = client.get(format!("http://{}/", addr).parse().unwrap()).await.unwrap();
+        assert_eq!(response.version(), axum::http::Version::HTTP_2);
+    }
+
+    // Proxy that forwards everything to `backend` through `send`
+    async fn spawn_proxy(backend: SocketAddr, http2: bool) -> SocketAddr {
+        let client = build_client(&ConnectionPoolConfig { http2, ..Default::default() }, &TimeoutConfig::default());
+        let app = Router::new().fallback(move |req: Request<Body>| {
+            let client = client.clone();
+            async move {
+                let (mut parts, body) = req.into_parts();
+                parts.uri = format!("http://{}{}", backend, parts.uri.path()).parse().unwrap();
+                parts.headers.remove(header::HOST);
+                send(&client, Request::from_parts(parts, body), 1024 * 1024, 1024 * 1024, &TimeoutConfig::default()).await.unwrap()
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    // One data frame, then trailers
+    struct TrailerBody(Vec<Frame<Bytes>>);
+
+    impl hyper::body::Body for TrailerBody {
+        type Data = Bytes;
+        type Error = std::io::Error;
+
+        fn poll_frame(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, std::io::Error>>> {
+            Poll::Ready((!self.0.is_empty()).then(|| Ok(self.0.remove(0))))
+        }
+    }
+
+    fn body_with_trailers(data: &'static str, trailers: &[(&'static str, &str)]) -> Body {
+        let mut map = HeaderMap::new();
+        for (name, value) in trailers {
+            map.insert(*name, value.parse().unwrap());
+        }
+        Body::new(TrailerBody(vec![Frame::data(Bytes::from(data)), Frame::trailers(map)]))
+    }
+
+    // Read a body to the end, returning its data and trailers
+    async fn read_body(mut body: Body) -> (String, Option<HeaderMap>) {
+        let mut data = Vec::new();
+        let mut trailers = None;
+        while let Some(frame) = futures::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+            match frame.unwrap().into_data() {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(frame) => trailers = frame.into_trailers().ok(),
+            }
+        }
+        (String::from_utf8(data).unwrap(), trailers)
+    }
+
+    // Backend answering with trailers, echoing the request's x-checksum one
+    async fn spawn_trailer_backend() -> SocketAddr {
+        let app = Router::new().fallback(|req: Request<Body>| async move {
+            let (_, trailers) = read_body(req.into_body()).await;
+            let checksum = trailers
+                .and_then(|trailers| trailers.get("x-checksum").cloned())
+                .map_or("none".to_string(), |v| v.to_str().unwrap().to_string());
+            Response::builder()
+                .header(header::TRAILER, "x-checksum, x-done")
+                .body(body_with_trailers("payload", &[("x-checksum", checksum.as_str()), ("x-done", "1")]))
+                .unwrap()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    async fn trailer_round_trip(http2: bool) -> (String, HeaderMap) {
+        let proxy = spawn_proxy(spawn_trailer_backend().await, http2).await;
+        let client = build_client(&ConnectionPoolConfig { http2, ..Default::default() }, &TimeoutConfig::default());
+
+        let req = Request::post(format!("http://{}/upload", proxy))
+            .header(header::TE, "trailers")
+            .body(body_with_trailers("request", &[("x-checksum", "abc")]))
+            .unwrap();
+        let response = client.request(req).await.unwrap();
+        let (data, trailers) = read_body(Body::new(response.into_body())).await;
+        (data, trailers.expect("response trailers"))
+    }
+
+    #[tokio::test]
+    async fn test_http2_trailers_forwarded_both_ways() {
+        let (data, trailers) = trailer_round_trip(true).await;
+        assert_eq!(data, "payload");
+        assert_eq!(trailers["x-done"], "1");
+        assert_eq!(trailers["x-checksum"], "abc");
+    }
+
+    #[tokio::test]
+    async fn test_http1_chunked_trailers_forwarded() {
+        let (data, trailers) = trailer_round_trip(false).await;
+        assert_eq!(data, "payload");
+        assert_eq!(trailers["x-done"], "1");
+    }
+
+    // Backend whose /slow answers after 5s and whose /stall sends one chunk
+    // and then nothing
+    async fn spawn_slow_backend() -> SocketAddr {
+        let app = Router::new()
+            .route("/slow", get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                "late"
+            }))
+            .route("/stall", get(|| async {
+                use futures::StreamExt;
+                let chunks = futures::stream::once(async { Ok::<_, std::io::Error>("first") })
+                    .chain(futures::stream::pending());
+                Body::from_stream(chunks)
+            }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_slow_backend_cut_off_at_read_timeout() {
+        let addr = spawn_slow_backend().await;
+        let timeouts = TimeoutConfig { read_timeout_seconds: 1, ..Default::default() };
+        let client = build_client(&ConnectionPoolConfig::default(), &timeouts);
+
+        let started = std::time::Instant::now();
+        let req = Request::get(format!("http://{}/slow", addr)).body(Body::empty()).unwrap();
+        let err = send(&client, req, 1024, 1024, &timeouts).await.unwrap_err();
+        assert!(matches!(err, SendError::Timeout("read")));
+        assert_eq!(err.status(), axum::http::StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_backend_is_bad_gateway() {
+        let client = build_client(&ConnectionPoolConfig::default(), &TimeoutConfig::default());
+
+        // Nothing listens on a port that was just given back
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let req = Request::get(format!("http://{}/", addr)).body(Body::empty()).unwrap();
+        let err = send(&client, req, 1024, 1024, &TimeoutConfig::default()).await.unwrap_err();
+        assert!(matches!(err, SendError::Backend(_)));
+        assert_eq!(err.status(), axum::http::StatusCode::BAD_GATEWAY);
+
+        // .invalid never resolves
+        let req = Request::get("http://backend.invalid/").body(Body::empty()).unwrap();
+        let err = send(&client, req, 1024, 1024, &TimeoutConfig::default()).await.unwrap_err();
+        assert_eq!(err.status(), axum::http::StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn test_retry_only_idempotent_methods_by_default() {
+        let mut retry = RetryConfig::default();
+        assert!(retry.allows(&Method::GET));
+        assert!(retry.allows(&Method::PUT));
+        assert!(!retry.allows(&Method::POST));
+        assert!(!retry.allows(&Method::PATCH));
+
+        retry.retry_post = true;
+        assert!(retry.allows(&Method::POST));
+        assert!(is_retryable_status(StatusCode::GATEWAY_TIMEOUT));
+        assert!(!is_retryable_status(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[test]
+    fn test_is_upgrade_request() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        assert!(!is_upgrade_request(&headers));
+        headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        assert!(is_upgrade_request(&headers));
+        headers.remove(header::UPGRADE);
+        assert!(!is_upgrade_request(&headers));
+    }
+
+    #[test]
+    fn test_is_timeout() {
+        let timed_out = std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timeout");
+        assert!(is_timeout(&timed_out));
+        assert!(is_timeout(&IdleTimeout(Duration::from_secs(1))));
+        assert!(!is_timeout(&std::io::Error::from(std::io::ErrorKind::ConnectionRefused)));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body_hits_idle_timeout() {
+        let addr = spawn_slow_backend().await;
+        let timeouts = TimeoutConfig { idle_timeout_seconds: 1, ..Default::default() };
+        let client = build_client(&ConnectionPoolConfig::default(), &timeouts);
+
+        let req = Request::get(format!("http://{}/stall", addr)).body(Body::empty()).unwrap();
+        let mut body = send(&client, req, 1024, 1024, &timeouts).await.unwrap().into_body();
+        let first = futures::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await.unwrap().unwrap();
+        assert_eq!(first.into_data().unwrap(), "first");
+        let err = futures::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("sent nothing"));
+    }
+
+    mod grpc {
+        use super::*;
+        use crate::cluster::grpc::cluster_rpc::replication_client::ReplicationClient;
+        use crate::cluster::grpc::cluster_rpc::replication_server::{Replication, ReplicationServer};
+        use crate::cluster::grpc::cluster_rpc::{ReplicaAck, ReplicaUpdate};
+        use tonic::{Code, Status};
+
+        // Echoes the pushed version back; an empty key is an error
+        struct Echo;
+
+        #[tonic::async_trait]
+        impl Replication for Echo {
+            async fn push(&self, request: tonic::Request<ReplicaUpdate>) -> Result<tonic::Response<ReplicaAck>, Status> {
+                let update = request.into_inner();
+                if update.key.is_empty() {
+                    return Err(Status::not_found("no key"));
+                }
+                Ok(tonic::Response::new(ReplicaAck { version: update.version, origin_node: update.origin_node }))
+            }
+        }
+
+        #[tokio::test]
+        async fn test_grpc_status_survives_proxy() {
+            let backend: SocketAddr = "127.0.0.1:17991".parse().unwrap();
+            tokio::spawn(tonic::transport::Server::builder().add_service(ReplicationServer::new(Echo)).serve(backend));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let proxy = spawn_proxy(backend, true).await;
+
+            let mut client = ReplicationClient::connect(format!("http://{}", proxy)).await.unwrap();
+            let update = |key: &str| ReplicaUpdate {
+                key: key.to_string(),
+                value: b"v".to_vec(),
+                version: 7,
+                origin_node: "node-1".to_string(),
+                updated_at_ms: 0,
+            };
+
+            // Both outcomes are only known from the grpc-status trailer
+            let ack = client.push(update("a")).await.unwrap().into_inner();
+            assert_eq!(ack.version, 7);
+            assert_eq!(ack.origin_node, "node-1");
+
+            let status = client.push(update("")).await.unwrap_err();
+            assert_eq!(status.code(), Code::NotFound);
+            assert_eq!(status.message(), "no key");
+        }
+    }
+}
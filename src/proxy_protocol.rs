@@ -0,0 +1,337 @@
+use anyhow::{anyhow, Result};
+use axum_server::accept::Accept;
+use futures::future::BoxFuture;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tower_http::add_extension::AddExtension;
+use tracing::debug;
+
+// Proxy protocol support (HAProxy protocol)
+pub struct ProxyProtocol {
+    pub version: u8,
+    pub command: ProxyCommand,
+    pub family: ProxyFamily,
+    pub protocol: ProxyTransport,
+    pub src_addr: SocketAddr,
+    pub dest_addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyCommand {
+    Local,
+    Proxy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyFamily {
+    Inet,
+    Inet6,
+    Unix,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyTransport {
+    Stream,
+    Dgram,
+}
+
+impl ProxyProtocol {
+    pub async fn parse<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Self>> {
+        let mut buf = [0u8; 16];
+        
+        // Read 12 bytes to determine version; the shortest v1 header
+        // ("PROXY UNKNOWN\r\n") is longer, so no request bytes are consumed
+        reader.read_exact(&mut buf[..12]).await?;
+        
+        // Check for proxy protocol v2 signature
+        if &buf[..12] == b"\r\n\r\n\0\r\nQUIT\n" {
+            reader.read_exact(&mut buf[12..16]).await?;
+
+            // The fixed header carries the length of the address block (and
+            // any TLVs) that follows; read all of it so none is left behind
+            let length = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+            let mut header = buf[..16].to_vec();
+            header.resize(16 + length, 0);
+            reader.read_exact(&mut header[16..]).await?;
+            return Self::parse_v2(&header).await;
+        }
+        
+        // Check for proxy protocol v1, a single line of at most 107 bytes
+        if buf.starts_with(b"PROXY ") {
+            let mut line = buf[..12].to_vec();
+            while !line.ends_with(b"\r\n") {
+                if line.len() >= 107 {
+                    return Err(anyhow!("PROXY v1 header too long"));
+                }
+                line.push(reader.read_u8().await?);
+            }
+            return Self::parse_v1(&String::from_utf8_lossy(&line)).await;
+        }
+        
+        Ok(None)
+    }
+
+    async fn parse_v1(header: &str) -> Result<Option<Self>> {
+        // PROXY TCP4 192.168.1.1 192.168.1.2 12345 80\r\n
+        let parts: Vec<&str> = header.trim().split_whitespace().collect();
+        
+        if parts.len() >= 6 && parts[0] == "PROXY" {
+            let family = match parts[1] {
+                "TCP4" => ProxyFamily::Inet,
+                "TCP6" => ProxyFamily::Inet6,
+                _ => return Ok(None),
+            };
+            
+            let src_ip: IpAddr = parts[2].parse()?;
+            let dest_ip: IpAddr = parts[3].parse()?;
+            let src_port: u16 = parts[4].parse()?;
+            let dest_port: u16 = parts[5].parse()?;
+            
+            Ok(Some(ProxyProtocol {
+                version: 1,
+                command: ProxyCommand::Proxy,
+                family,
+                protocol: ProxyTransport::Stream,
+                src_addr: SocketAddr::new(src_ip, src_port),
+                dest_addr: SocketAddr::new(dest_ip, dest_port),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn parse_v2(buf: &[u8]) -> Result<Option<Self>> {
+        // Proxy protocol v2 binary format
+        if buf.len() < 16 {
+            return Ok(None);
+        }
+        
+        let version = (buf[12] & 0xF0) >> 4;
+        let command = buf[12] & 0x0F;
+        let family = (buf[13] & 0xF0) >> 4;
+        let protocol = buf[13] & 0x0F;
+        let length = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        
+        if version != 2 || buf.len() < 16 + length {
+            return Ok(None);
+        }
+        
+        let transport = match protocol {
+            1 => ProxyTransport::Stream,
+            2 => ProxyTransport::Dgram,
+            _ => return Ok(None),
+        };
+        
+        // Parse addresses based on family
+        let addr = &buf[16..16 + length];
+        let (proxy_family, src_addr, dest_addr) = match family {
+            1 => { // IPv4
+                if length < 12 { return Ok(None); }
+                let src_ip = IpAddr::V4(std::net::Ipv4Addr::new(
+                    addr[0], addr[1], addr[2], addr[3]
+                ));
+                let dest_ip = IpAddr::V4(std::net::Ipv4Addr::new(
+                    addr[4], addr[5], addr[6], addr[7]
+                ));
+                let src_port = u16::from_be_bytes([addr[8], addr[9]]);
+                let dest_port = u16::from_be_bytes([addr[10], addr[11]]);
+                (ProxyFamily::Inet, SocketAddr::new(src_ip, src_port), SocketAddr::new(dest_ip, dest_port))
+            }
+            2 => { // IPv6
+                if length < 36 { return Ok(None); }
+                let mut src_octets = [0u8; 16];
+                let mut dest_octets = [0u8; 16];
+                src_octets.copy_from_slice(&addr[0..16]);
+                dest_octets.copy_from_slice(&addr[16..32]);
+                let src_ip = IpAddr::V6(std::net::Ipv6Addr::from(src_octets));
+                let dest_ip = IpAddr::V6(std::net::Ipv6Addr::from(dest_octets));
+                let src_port = u16::from_be_bytes([addr[32], addr[33]]);
+                let dest_port = u16::from_be_bytes([addr[34], addr[35]]);
+                (ProxyFamily::Inet6, SocketAddr::new(src_ip, src_port), SocketAddr::new(dest_ip, dest_port))
+            }
+            3 => { // UNIX
+                // Socket paths can't be expressed as a SocketAddr; the header
+                // has been consumed, so the connection just keeps its peer address
+                debug!("Ignoring PROXY v2 header with AF_UNIX addresses");
+                return Ok(None);
+            }
+            _ => return Ok(None),
+        };
+        
+        Ok(Some(ProxyProtocol {
+            version: 2,
+            command: if command == 1 { ProxyCommand::Proxy } else { ProxyCommand::Local },
+            family: proxy_family,
+            protocol: transport,
+            src_addr,
+            dest_addr,
+        }))
+    }
+}
+
+// Address of the client that opened the connection: the peer address, or
+// the source from the PROXY header when the listener expects one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientAddr(pub SocketAddr);
+
+// How long a connection gets to send its PROXY header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Listener acceptor that records each connection's ClientAddr as a request
+// extension, reading and stripping the PROXY header first when enabled.
+// Runs on the raw TCP stream, so it goes inside the TLS acceptor.
+#[derive(Clone, Copy, Debug)]
+pub struct ProxyProtocolAcceptor {
+    enabled: bool,
+}
+
+impl ProxyProtocolAcceptor {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+}
+
+impl<S: Send + 'static> Accept<TcpStream, S> for ProxyProtocolAcceptor {
+    type Stream = TcpStream;
+    type Service = AddExtension<S, ClientAddr>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        let enabled = self.enabled;
+        Box::pin(async move {
+            let peer = stream.peer_addr()?;
+            let client = if enabled {
+                read_client_addr(&mut stream, peer).await?
+            } else {
+                peer
+            };
+            Ok((stream, AddExtension::new(service, ClientAddr(client))))
+        })
+    }
+}
+
+// With PROXY protocol enabled the header is mandatory: its bytes have already
+// been consumed by the time we know it's missing, so the connection is dropped
+async fn read_client_addr(stream: &mut TcpStream, peer: SocketAddr) -> io::Result<SocketAddr> {
+    let header = tokio::time::timeout(HEADER_TIMEOUT, ProxyProtocol::parse(stream)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for PROXY header"))?
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    match header {
+        Some(header) if header.command == ProxyCommand::Proxy => {
+            debug!("PROXY header from {}: client {}", peer, header.src_addr);
+            Ok(header.src_addr)
+        }
+        // LOCAL connections (load balancer health checks) and unsupported
+        // address families keep the peer address
+        _ => Ok(peer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+    fn v2_header(family_protocol: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x21); // version 2, PROXY
+        header.push(family_protocol);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn test_parse_v2_ipv6() {
+        // 2001:db8::1 port 50000 -> 2001:db8::2 port 443, TCP over IPv6
+        let header: Vec<u8> = vec![
+            0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+            0x21, 0x21, 0x00, 0x24,
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+            0xc3, 0x50, 0x01, 0xbb,
+            // Start of the proxied request, which must be left unread
+            b'G', b'E', b'T',
+        ];
+        let mut reader = &header[..];
+
+        let parsed = ProxyProtocol::parse(&mut reader).await.unwrap().unwrap();
+
+        assert_eq!(parsed.version, 2);
+        assert_eq!(parsed.command, ProxyCommand::Proxy);
+        assert_eq!(parsed.family, ProxyFamily::Inet6);
+        assert_eq!(parsed.protocol, ProxyTransport::Stream);
+        assert_eq!(parsed.src_addr, "[2001:db8::1]:50000".parse().unwrap());
+        assert_eq!(parsed.dest_addr, "[2001:db8::2]:443".parse().unwrap());
+        assert_eq!(reader, b"GET");
+    }
+
+    #[tokio::test]
+    async fn test_parse_v2_ipv4() {
+        let header = v2_header(0x11, &[192, 168, 1, 10, 10, 0, 0, 1, 0x1f, 0x90, 0x00, 0x50]);
+        let mut reader = &header[..];
+
+        let parsed = ProxyProtocol::parse(&mut reader).await.unwrap().unwrap();
+
+        assert_eq!(parsed.family, ProxyFamily::Inet);
+        assert_eq!(parsed.src_addr, "192.168.1.10:8080".parse().unwrap());
+        assert_eq!(parsed.dest_addr, "10.0.0.1:80".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_parse_v2_unix_is_skipped() {
+        // Two 108-byte socket paths
+        let header = v2_header(0x31, &[0u8; 216]);
+        let mut reader = &header[..];
+
+        assert!(ProxyProtocol::parse(&mut reader).await.unwrap().is_none());
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_parse_v1_leaves_request_unread() {
+        let data = b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 80\r\nGET / HTTP/1.1\r\n";
+        let mut reader = &data[..];
+
+        let parsed = ProxyProtocol::parse(&mut reader).await.unwrap().unwrap();
+
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.src_addr, "203.0.113.7:56324".parse().unwrap());
+        assert_eq!(parsed.dest_addr, "10.0.0.1:80".parse().unwrap());
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_acceptor_uses_proxy_source_address() {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 80\r\nGET / HTTP/1.1\r\n\r\n").await.unwrap();
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let service = service_fn(|req: axum::http::Request<()>| async move {
+            Ok::<_, Infallible>(req.extensions().get::<ClientAddr>().copied())
+        });
+        let (mut stream, service) = ProxyProtocolAcceptor::new(true).accept(stream, service).await.unwrap();
+
+        let client = service.oneshot(axum::http::Request::new(())).await.unwrap();
+        assert_eq!(client, Some(ClientAddr("203.0.113.7:56324".parse().unwrap())));
+
+        // The HTTP request itself is still there for the server to read
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1\r\n\r\n");
+    }
+}
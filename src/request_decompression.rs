@@ -0,0 +1,186 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use bytes::Bytes;
+use std::io::Read;
+use tracing::warn;
+
+use crate::body_limit::{is_limit_exceeded, payload_too_large};
+
+#[derive(Debug, Clone, Copy)]
+enum Encoding {
+    Gzip,
+    Deflate,
+    Br,
+}
+
+enum DecodeError {
+    TooLarge,
+    Corrupt(std::io::Error),
+}
+
+// Decode a gzip/deflate/br request body for backends that can't, replacing
+// Content-Encoding with the decoded Content-Length. The body is decoded in
+// memory and may grow to `limit` bytes at most, so a small compressed body
+// can't expand into a huge one. Unknown encodings get a 415.
+pub async fn decompress_body(headers: &mut HeaderMap, body: Body, limit: u64) -> Result<Body, Response> {
+    let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+        return Ok(body);
+    };
+    let encoding = match value.to_str().unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "identity" => return Ok(body),
+        "gzip" | "x-gzip" => Encoding::Gzip,
+        "deflate" => Encoding::Deflate,
+        "br" => Encoding::Br,
+        other => {
+            warn!("Unsupported request Content-Encoding {:?}", other);
+            return Err(unsupported_encoding());
+        }
+    };
+
+    let compressed = match axum::body::to_bytes(body, usize::try_from(limit).unwrap_or(usize::MAX)).await {
+        Ok(compressed) => compressed,
+        Err(e) if is_limit_exceeded(&e) => return Err(payload_too_large()),
+        Err(e) => {
+            warn!("Failed to read compressed request body: {}", e);
+            return Err(bad_request());
+        }
+    };
+    let decoded = tokio::task::spawn_blocking(move || decode(encoding, compressed, limit)).await
+        .unwrap_or_else(|e| Err(DecodeError::Corrupt(std::io::Error::other(e))));
+    let decoded = match decoded {
+        Ok(decoded) => decoded,
+        Err(DecodeError::TooLarge) => {
+            warn!("Decompressed request body over the {} byte limit", limit);
+            return Err(payload_too_large());
+        }
+        Err(DecodeError::Corrupt(e)) => {
+            warn!("Failed to decompress {:?} request body: {}", encoding, e);
+            return Err(bad_request());
+        }
+    };
+
+    headers.remove(header::CONTENT_ENCODING);
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(decoded.len()));
+    Ok(Body::from(decoded))
+}
+
+fn decode(encoding: Encoding, compressed: Bytes, limit: u64) -> Result<Vec<u8>, DecodeError> {
+    let reader: Box<dyn Read> = match encoding {
+        Encoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(&compressed[..])),
+        Encoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(&compressed[..])),
+        Encoding::Br => Box::new(brotli::Decompressor::new(&compressed[..], 4096)),
+    };
+    let mut decoded = Vec::new();
+    reader.take(limit.saturating_add(1)).read_to_end(&mut decoded).map_err(DecodeError::Corrupt)?;
+    if decoded.len() as u64 > limit {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(decoded)
+}
+
+// Bare bodies, so the error pages apply
+fn unsupported_encoding() -> Response {
+    Response::builder()
+        .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .header(header::ACCEPT_ENCODING, "gzip, deflate, br")
+        .body(Body::from("Unsupported Content-Encoding"))
+        .unwrap()
+}
+
+fn bad_request() -> Response {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from("Malformed request body"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_client::{build_client, send, ConnectionPoolConfig, TimeoutConfig};
+    use axum::http::Request;
+    use axum::Router;
+    use std::io::Write;
+    use tokio::net::TcpListener;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn headers(encoding: &str, length: usize) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, encoding.parse().unwrap());
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_backend_receives_decompressed_body() {
+        // Echoes the body with the headers it came with
+        let app = Router::new().fallback(|req: Request<Body>| async move {
+            let (parts, body) = req.into_parts();
+            let body = axum::body::to_bytes(body, 1024).await.unwrap();
+            format!(
+                "{:?} {:?} {}",
+                parts.headers.get(header::CONTENT_ENCODING),
+                parts.headers.get(header::CONTENT_LENGTH),
+                String::from_utf8_lossy(&body),
+            )
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let compressed = gzip(b"{\"order\":42}");
+        let mut headers = headers("gzip", compressed.len());
+        let body = decompress_body(&mut headers, Body::from(compressed), 1024).await.unwrap();
+        let mut req = Request::post(format!("http://{}/orders", addr)).body(body).unwrap();
+        *req.headers_mut() = headers;
+
+        let client = build_client(&ConnectionPoolConfig::default(), &TimeoutConfig::default());
+        let response = send(&client, req, 1024, 1024, &TimeoutConfig::default()).await.unwrap();
+        let echoed = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+        assert_eq!(&echoed[..], b"None Some(\"12\") {\"order\":42}");
+    }
+
+    #[tokio::test]
+    async fn test_encodings_and_limits() {
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        zlib.write_all(b"deflated").unwrap();
+        let zlib = zlib.finish().unwrap();
+        let mut deflate_headers = headers("deflate", zlib.len());
+        let body = decompress_body(&mut deflate_headers, Body::from(zlib), 1024).await.unwrap();
+        assert_eq!(&axum::body::to_bytes(body, 1024).await.unwrap()[..], b"deflated");
+
+        let mut br = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut br, 4096, 5, 22);
+            writer.write_all(b"brotli").unwrap();
+        }
+        let mut br_headers = headers("br", br.len());
+        let body = decompress_body(&mut br_headers, Body::from(br), 1024).await.unwrap();
+        assert_eq!(&axum::body::to_bytes(body, 1024).await.unwrap()[..], b"brotli");
+
+        // A megabyte of zeros is a few hundred bytes compressed
+        let bomb = gzip(&vec![0; 1024 * 1024]);
+        assert!(bomb.len() < 4096);
+        let err = decompress_body(&mut headers("gzip", bomb.len()), Body::from(bomb), 64 * 1024).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let err = decompress_body(&mut headers("gzip", 9), Body::from("not gzip!"), 1024).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        let err = decompress_body(&mut headers("compress", 4), Body::from("data"), 1024).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(err.headers()[header::ACCEPT_ENCODING], "gzip, deflate, br");
+
+        // Plain bodies pass through
+        let mut plain = HeaderMap::new();
+        let body = decompress_body(&mut plain, Body::from("plain"), 1024).await.unwrap();
+        assert_eq!(&axum::body::to_bytes(body, 1024).await.unwrap()[..], b"plain");
+    }
+}
@@ -0,0 +1,638 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+// Shared cache for proxied GET responses:
+//
+//   [cache]
+//   max_size_bytes = 67108864
+//
+//   [backends."example.com"]
+//   cache = true
+//
+// Responses are stored when Cache-Control allows a shared cache to keep
+// them (max-age / s-maxage, no no-store/private/no-cache), or for
+// default_ttl_seconds when the backend says nothing. stale-while-revalidate
+// and stale-if-error (RFC 5861) let an expired copy be served while it is
+// refreshed in the background, or while the backend is failing.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ResponseCacheConfig {
+    // Total body and header bytes kept; least recently used entries go first
+    pub max_size_bytes: u64,
+    // Larger responses, and responses without a Content-Length, are passed
+    // through uncached
+    pub max_entry_bytes: u64,
+    // TTL for responses without freshness information; 0 leaves them uncached
+    pub default_ttl_seconds: u64,
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_size_bytes: 64 * 1024 * 1024,
+            max_entry_bytes: 1024 * 1024,
+            default_ttl_seconds: 0,
+        }
+    }
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+    // How long past `expires` the entry may still be served, from the
+    // stale-while-revalidate and stale-if-error directives
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+    // A background revalidation is in flight
+    revalidating: bool,
+    size: u64,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Vary header names last seen for each URL; they pick the variant key
+    vary: HashMap<String, Vec<HeaderName>>,
+    entries: HashMap<String, Entry>,
+    // LRU order: last-use tick to variant key
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+// Freshness lifetime and stale windows taken from a response's Cache-Control
+struct Freshness {
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+}
+
+enum Lookup {
+    Fresh(Response),
+    // Past its TTL but inside stale-while-revalidate; `revalidate` carries the
+    // variant key and conditional headers when this request should refresh it
+    Stale(Response, Option<(String, HeaderMap)>),
+    // Past stale-while-revalidate but inside stale-if-error: only served when
+    // the backend fails
+    Fallback(Response),
+    Miss,
+}
+
+pub struct ResponseCache {
+    config: ResponseCacheConfig,
+    inner: Mutex<Inner>,
+}
+
+impl ResponseCache {
+    pub fn new(config: ResponseCacheConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn key(host: &str, uri: &Uri) -> String {
+        format!("{}{}", host, uri.path_and_query().map_or("/", |pq| pq.as_str()))
+    }
+
+    // Only plain GETs without credentials go through the cache
+    pub fn is_cacheable_request(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::GET
+            && !headers.contains_key(header::AUTHORIZATION)
+            && !cache_control(headers).iter().any(|d| d == "no-store")
+    }
+
+    // Serve `key` from the cache, or call `fetch` with the headers to send and
+    // keep its response when it is cacheable. Responses carry X-Cache: HIT,
+    // MISS or STALE. A stale entry inside its stale-while-revalidate window is
+    // served immediately while `fetch` revalidates it in the background.
+    pub async fn get_or_fetch<F, Fut>(self: &Arc<Self>, key: &str, request_headers: &HeaderMap, fetch: F) -> Response
+    where
+        F: FnOnce(HeaderMap) -> Fut + Send + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        // Client asked for a fresh copy
+        let revalidate = cache_control(request_headers).iter().any(|d| d == "no-cache");
+        let lookup = if revalidate { Lookup::Miss } else { self.lookup(key, request_headers) };
+        let fallback = match lookup {
+            Lookup::Fresh(response) => {
+                debug!("Response cache hit for {}", key);
+                return response;
+            }
+            Lookup::Stale(response, revalidate) => {
+                if let Some((variant, conditional)) = revalidate {
+                    debug!("Serving stale {} while revalidating", key);
+                    let cache = Arc::clone(self);
+                    let key = key.to_string();
+                    let request_headers = request_headers.clone();
+                    tokio::spawn(async move {
+                        let response = fetch(conditional).await;
+                        cache.revalidated(&key, &request_headers, &variant, response).await;
+                    });
+                }
+                return response;
+            }
+            Lookup::Fallback(response) => Some(response),
+            Lookup::Miss => None,
+        };
+
+        let response = fetch(request_headers.clone()).await;
+        match fallback {
+            Some(stale) if response.status().is_server_error() => {
+                warn!("Backend returned {} for {}, serving stale copy", response.status(), key);
+                stale
+            }
+            _ => self.fill(key, request_headers, response).await,
+        }
+    }
+
+    // Keep `response` when it is cacheable and hand it back marked as a miss
+    async fn fill(&self, key: &str, request_headers: &HeaderMap, response: Response) -> Response {
+        let freshness = match self.freshness(response.status(), response.headers()) {
+            Some(freshness) => freshness,
+            None => return with_cache_status(response, "MISS"),
+        };
+        let length = response.headers().get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if length.map_or(true, |len| len > self.config.max_entry_bytes) {
+            return with_cache_status(response, "MISS");
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = match axum::body::to_bytes(body, self.config.max_entry_bytes as usize).await {
+            Ok(body) => body,
+            Err(e) => {
+                debug!("Failed to buffer response for {}: {}", key, e);
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::from("Backend response failed"))
+                    .unwrap();
+            }
+        };
+
+        self.store(key, request_headers, parts.status, &parts.headers, body.clone(), freshness);
+        with_cache_status(Response::from_parts(parts, Body::from(body)), "MISS")
+    }
+
+    // Apply the outcome of a background revalidation of `variant`
+    async fn revalidated(&self, key: &str, request_headers: &HeaderMap, variant: &str, response: Response) {
+        if response.status() == StatusCode::NOT_MODIFIED {
+            debug!("Revalidated {}, still current", variant);
+            self.refresh(variant, response.headers());
+        } else if response.status().is_server_error() {
+            // Keep the stale copy; the next request past the window retries
+            warn!("Revalidating {} failed with {}", key, response.status());
+            if let Some(entry) = self.inner.lock().unwrap().entries.get_mut(variant) {
+                entry.revalidating = false;
+            }
+        } else {
+            // A full response replaces the entry, or drops it when it is no
+            // longer cacheable
+            self.inner.lock().unwrap().remove(variant);
+            self.fill(key, request_headers, response).await;
+        }
+    }
+
+    // Merge the headers of a 304 into the entry and restart its freshness
+    fn refresh(&self, variant: &str, headers: &HeaderMap) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.entries.get_mut(variant) else {
+            return;
+        };
+        for name in headers.keys() {
+            if *name == header::CONTENT_LENGTH {
+                continue;
+            }
+            entry.headers.remove(name);
+            for value in headers.get_all(name) {
+                entry.headers.append(name.clone(), value.clone());
+            }
+        }
+
+        let Some(freshness) = self.freshness(entry.status, &entry.headers) else {
+            inner.remove(variant);
+            return;
+        };
+        let now = Instant::now();
+        let size = entry_size(variant, &entry.headers, &entry.body);
+        let previous = std::mem::replace(&mut entry.size, size);
+        entry.stored = now;
+        entry.expires = now + freshness.ttl;
+        entry.stale_while_revalidate = freshness.stale_while_revalidate;
+        entry.stale_if_error = freshness.stale_if_error;
+        entry.revalidating = false;
+        inner.size = inner.size - previous + size;
+    }
+
+    fn freshness(&self, status: StatusCode, headers: &HeaderMap) -> Option<Freshness> {
+        if !matches!(status.as_u16(), 200 | 203 | 301 | 404 | 410) {
+            return None;
+        }
+        if headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        if headers.get_all(header::VARY).iter().any(|v| v.to_str().map_or(true, |v| v.contains('*'))) {
+            return None;
+        }
+
+        let directives = cache_control(headers);
+        if directives.iter().any(|d| d == "no-store" || d == "private" || d == "no-cache") {
+            return None;
+        }
+        let seconds = |name: &str| directives.iter()
+            .filter_map(|d| d.strip_prefix(name)?.strip_prefix('=')?.trim_matches('"').parse::<u64>().ok())
+            .next();
+        let freshness = Freshness {
+            ttl: Duration::from_secs(seconds("s-maxage")
+                .or_else(|| seconds("max-age"))
+                .unwrap_or(self.config.default_ttl_seconds)),
+            stale_while_revalidate: Duration::from_secs(seconds("stale-while-revalidate").unwrap_or(0)),
+            stale_if_error: Duration::from_secs(seconds("stale-if-error").unwrap_or(0)),
+        };
+
+        let usable = freshness.ttl + freshness.stale_while_revalidate.max(freshness.stale_if_error);
+        (!usable.is_zero()).then_some(freshness)
+    }
+
+    fn lookup(&self, key: &str, request_headers: &HeaderMap) -> Lookup {
+        let mut inner = self.inner.lock().unwrap();
+        let vary = inner.vary.get(key).cloned().unwrap_or_default();
+        let variant = variant_key(key, &vary, request_headers);
+
+        let now = Instant::now();
+        let Some(entry) = inner.entries.get(&variant) else {
+            return Lookup::Miss;
+        };
+        let stale_for = now.saturating_duration_since(entry.expires);
+        let fresh = now < entry.expires;
+        let in_swr = !fresh && stale_for < entry.stale_while_revalidate;
+        if !fresh && !in_swr && stale_for >= entry.stale_if_error {
+            inner.remove(&variant);
+            return Lookup::Miss;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        let Some(entry) = inner.entries.get_mut(&variant) else {
+            return Lookup::Miss;
+        };
+        let previous = std::mem::replace(&mut entry.tick, tick);
+        let response = entry.to_response(request_headers, now);
+
+        let lookup = if fresh {
+            Lookup::Fresh(with_cache_status(response, "HIT"))
+        } else if in_swr {
+            let revalidate = (!entry.revalidating).then(|| {
+                entry.revalidating = true;
+                (variant.clone(), entry.conditional_headers(request_headers))
+            });
+            Lookup::Stale(with_cache_status(response, "STALE"), revalidate)
+        } else {
+            Lookup::Fallback(with_cache_status(response, "STALE"))
+        };
+
+        inner.lru.remove(&previous);
+        inner.lru.insert(tick, variant);
+        lookup
+    }
+
+    fn store(&self, key: &str, request_headers: &HeaderMap, status: StatusCode, headers: &HeaderMap, body: Bytes, freshness: Freshness) {
+        let vary: Vec<HeaderName> = headers.get_all(header::VARY).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+            .collect();
+        let variant = variant_key(key, &vary, request_headers);
+
+        let size = entry_size(&variant, headers, &body);
+        if size > self.config.max_size_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&variant);
+        while inner.size + size > self.config.max_size_bytes {
+            let oldest = match inner.lru.first_key_value() {
+                Some((_, variant)) => variant.clone(),
+                None => break,
+            };
+            debug!("Evicting {} from the response cache", oldest);
+            inner.remove(&oldest);
+        }
+
+        let now = Instant::now();
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.vary.insert(key.to_string(), vary);
+        inner.lru.insert(tick, variant.clone());
+        inner.size += size;
+        inner.entries.insert(variant, Entry {
+            status,
+            headers: headers.clone(),
+            body,
+            stored: now,
+            expires: now + freshness.ttl,
+            stale_while_revalidate: freshness.stale_while_revalidate,
+            stale_if_error: freshness.stale_if_error,
+            revalidating: false,
+            size,
+            tick,
+        });
+    }
+}
+
+impl Entry {
+    // The cached response, or a 304 when the request's If-None-Match matches
+    fn to_response(&self, request_headers: &HeaderMap, now: Instant) -> Response {
+        let mut builder = Response::builder().status(self.status);
+        let not_modified = match (request_headers.get(header::IF_NONE_MATCH), self.headers.get(header::ETAG)) {
+            (Some(inm), Some(etag)) => inm.to_str().map_or(false, |inm| {
+                inm.split(',').any(|tag| tag.trim() == "*" || tag.trim() == etag.to_str().unwrap_or_default())
+            }),
+            _ => false,
+        };
+        let mut response = if not_modified {
+            builder = builder.status(StatusCode::NOT_MODIFIED);
+            for name in [header::ETAG, header::CACHE_CONTROL, header::VARY] {
+                if let Some(value) = self.headers.get(&name) {
+                    builder = builder.header(name, value.clone());
+                }
+            }
+            builder.body(Body::empty()).unwrap()
+        } else {
+            if let Some(headers) = builder.headers_mut() {
+                headers.extend(self.headers.clone());
+            }
+            builder.body(Body::from(self.body.clone())).unwrap()
+        };
+
+        let age = now.duration_since(self.stored).as_secs();
+        response.headers_mut().insert(header::AGE, HeaderValue::from(age));
+        response
+    }
+
+    // Request headers for revalidating this entry: the client's own
+    // validators are replaced with the ones the cached copy carries
+    fn conditional_headers(&self, request_headers: &HeaderMap) -> HeaderMap {
+        let mut headers = request_headers.clone();
+        headers.remove(header::IF_NONE_MATCH);
+        headers.remove(header::IF_MODIFIED_SINCE);
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(header::LAST_MODIFIED) {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        headers
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, variant: &str) {
+        if let Some(entry) = self.entries.remove(variant) {
+            self.lru.remove(&entry.tick);
+            self.size -= entry.size;
+        }
+    }
+}
+
+fn entry_size(variant: &str, headers: &HeaderMap, body: &Bytes) -> u64 {
+    let header_size: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+    (body.len() + header_size + variant.len()) as u64
+}
+
+// The URL plus the request's values for each header the response varies on
+fn variant_key(key: &str, vary: &[HeaderName], request_headers: &HeaderMap) -> String {
+    let mut variant = key.to_string();
+    for name in vary {
+        variant.push('\n');
+        variant.push_str(name.as_str());
+        variant.push(':');
+        for value in request_headers.get_all(name) {
+            variant.push_str(&String::from_utf8_lossy(value.as_bytes()));
+        }
+    }
+    variant
+}
+
+fn cache_control(headers: &HeaderMap) -> Vec<String> {
+    headers.get_all(header::CACHE_CONTROL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect()
+}
+
+fn with_cache_status(mut response: Response, status: &'static str) -> Response {
+    response.headers_mut().insert("x-cache", HeaderValue::from_static(status));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn backend(cache_control: &str, body: &'static str) -> Response {
+        Response::builder()
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::VARY, "Accept-Encoding")
+            .header(header::ETAG, "\"v1\"")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn get(cache: &Arc<ResponseCache>, calls: &Arc<AtomicUsize>, headers: &HeaderMap, cache_control: &'static str) -> Response {
+        let calls = Arc::clone(calls);
+        cache.get_or_fetch("example.com/page", headers, move |_| async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            backend(cache_control, "hello")
+        }).await
+    }
+
+    fn cache() -> (Arc<ResponseCache>, Arc<AtomicUsize>) {
+        (Arc::new(ResponseCache::new(ResponseCacheConfig::default())), Arc::new(AtomicUsize::new(0)))
+    }
+
+    // Move every entry `by` past its expiry
+    fn expire(cache: &ResponseCache, by: Duration) {
+        for entry in cache.inner.lock().unwrap().entries.values_mut() {
+            entry.expires = Instant::now() - by;
+        }
+    }
+
+    async fn body_of(response: Response) -> Bytes {
+        axum::body::to_bytes(response.into_body(), 1024).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_miss_then_hit() {
+        let (cache, calls) = cache();
+        let headers = HeaderMap::new();
+
+        let first = get(&cache, &calls, &headers, "public, max-age=60").await;
+        assert_eq!(first.headers()["x-cache"], "MISS");
+        assert_eq!(body_of(first).await, "hello");
+
+        let second = get(&cache, &calls, &headers, "public, max-age=60").await;
+        assert_eq!(second.headers()["x-cache"], "HIT");
+        assert_eq!(second.headers()[header::ETAG], "\"v1\"");
+        assert_eq!(body_of(second).await, "hello");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // A matching validator gets a 304 from the cache
+        let mut conditional = HeaderMap::new();
+        conditional.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"v1\""));
+        let not_modified = get(&cache, &calls, &conditional, "public, max-age=60").await;
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_store_and_private_bypass() {
+        let (cache, calls) = cache();
+        let headers = HeaderMap::new();
+
+        for cache_control in ["no-store", "private, max-age=60"] {
+            assert_eq!(get(&cache, &calls, &headers, cache_control).await.headers()["x-cache"], "MISS");
+            assert_eq!(get(&cache, &calls, &headers, cache_control).await.headers()["x-cache"], "MISS");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let mut no_store = HeaderMap::new();
+        no_store.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        assert!(!ResponseCache::is_cacheable_request(&Method::GET, &no_store));
+        assert!(!ResponseCache::is_cacheable_request(&Method::POST, &HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn test_vary_and_expiry() {
+        let (cache, calls) = cache();
+
+        let mut gzip = HeaderMap::new();
+        gzip.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        get(&cache, &calls, &gzip, "max-age=60").await;
+        assert_eq!(get(&cache, &calls, &gzip, "max-age=60").await.headers()["x-cache"], "HIT");
+        // Different Accept-Encoding is a different variant
+        assert_eq!(get(&cache, &calls, &HeaderMap::new(), "max-age=60").await.headers()["x-cache"], "MISS");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        expire(&cache, Duration::ZERO);
+        assert_eq!(get(&cache, &calls, &gzip, "max-age=60").await.headers()["x-cache"], "MISS");
+    }
+
+    #[tokio::test]
+    async fn test_lru_eviction_by_size() {
+        let cache = Arc::new(ResponseCache::new(ResponseCacheConfig { max_size_bytes: 300, ..Default::default() }));
+        let headers = HeaderMap::new();
+        let fetch = |_| async { backend("max-age=60", "0123456789012345678901234567890123456789") };
+
+        cache.get_or_fetch("a", &headers, fetch).await;
+        cache.get_or_fetch("b", &headers, fetch).await;
+        // Touch "a" so "b" is the least recently used
+        assert_eq!(cache.get_or_fetch("a", &headers, fetch).await.headers()["x-cache"], "HIT");
+        cache.get_or_fetch("c", &headers, fetch).await;
+
+        let inner = cache.inner.lock().unwrap();
+        assert!(inner.size <= 300);
+        assert!(inner.entries.contains_key("a\naccept-encoding:"));
+        assert!(!inner.entries.contains_key("b\naccept-encoding:"));
+        assert!(inner.entries.contains_key("c\naccept-encoding:"));
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate_serves_stale_promptly() {
+        let (cache, calls) = cache();
+        let headers = HeaderMap::new();
+        get(&cache, &calls, &headers, "max-age=60, stale-while-revalidate=60").await;
+        expire(&cache, Duration::from_secs(1));
+
+        // The backend is slow now, but the stale copy comes straight back
+        let slow = |_| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            backend("max-age=60, stale-while-revalidate=60", "fresh")
+        };
+        let stale = tokio::time::timeout(Duration::from_millis(100), cache.get_or_fetch("example.com/page", &headers, slow))
+            .await
+            .expect("stale response should not wait for the backend");
+        assert_eq!(stale.headers()["x-cache"], "STALE");
+        assert_eq!(body_of(stale).await, "hello");
+
+        // Only one revalidation runs at a time
+        let again = get(&cache, &calls, &headers, "max-age=60").await;
+        assert_eq!(again.headers()["x-cache"], "STALE");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let refreshed = get(&cache, &calls, &headers, "max-age=60").await;
+        assert_eq!(refreshed.headers()["x-cache"], "HIT");
+        assert_eq!(body_of(refreshed).await, "fresh");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_revalidation_not_modified_refreshes_entry() {
+        let (cache, calls) = cache();
+        let headers = HeaderMap::new();
+        get(&cache, &calls, &headers, "max-age=60, stale-while-revalidate=60").await;
+        expire(&cache, Duration::from_secs(1));
+
+        let (sent, received) = tokio::sync::oneshot::channel();
+        let not_modified = move |headers: HeaderMap| async move {
+            let _ = sent.send(headers.get(header::IF_NONE_MATCH).cloned());
+            Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::CACHE_CONTROL, "max-age=120")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let stale = cache.get_or_fetch("example.com/page", &headers, not_modified).await;
+        assert_eq!(stale.headers()["x-cache"], "STALE");
+        assert_eq!(received.await.unwrap().unwrap(), "\"v1\"");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let hit = get(&cache, &calls, &headers, "max-age=60").await;
+        assert_eq!(hit.headers()["x-cache"], "HIT");
+        assert_eq!(hit.headers()[header::CACHE_CONTROL], "max-age=120");
+        assert_eq!(body_of(hit).await, "hello");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error() {
+        let (cache, calls) = cache();
+        let headers = HeaderMap::new();
+        get(&cache, &calls, &headers, "max-age=60, stale-if-error=600").await;
+        expire(&cache, Duration::from_secs(10));
+
+        let failing = |_| async {
+            Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::empty()).unwrap()
+        };
+        let stale = cache.get_or_fetch("example.com/page", &headers, failing).await;
+        assert_eq!(stale.headers()["x-cache"], "STALE");
+        assert_eq!(body_of(stale).await, "hello");
+
+        // A working backend replaces the stale copy
+        assert_eq!(get(&cache, &calls, &headers, "max-age=60").await.headers()["x-cache"], "MISS");
+
+        // Past the stale-if-error window the error goes through
+        expire(&cache, Duration::from_secs(61));
+        let error = cache.get_or_fetch("example.com/page", &headers, failing).await;
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.headers()["x-cache"], "MISS");
+    }
+}
@@ -1,6 +1,7 @@
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
 use anyhow::{Result, anyhow};
 use tracing::{info, debug};
 
@@ -22,6 +23,7 @@ pub enum RewriteFlag {
     G,     // Gone - return 410
     NC,    // No Case - case insensitive
     QSA,   // Query String Append
+    QSD,   // Query String Discard
     R301,  // Permanent redirect
     R302,  // Temporary redirect
 }
@@ -30,6 +32,8 @@ pub enum RewriteFlag {
 pub struct RewriteConfig {
     pub rules: Vec<RewriteRule>,
     pub conditions: Vec<RewriteCondition>,
+    // Root of the site; file/dir conditions resolve against it
+    pub document_root: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug)]
@@ -44,11 +48,145 @@ pub struct RewriteCondition {
 pub enum ConditionFlag {
     NC,    // No Case
     OR,    // OR next condition (default is AND)
+    F,     // Test string is a file (-f)
+    D,     // Test string is a directory (-d)
+    NOT,   // Negate the result (!)
 }
 
 pub struct RewriteEngine {
     rules: Vec<RewriteRule>,
     conditions: Vec<RewriteCondition>,
+    document_root: Option<PathBuf>,
+    index: Option<PrefixIndex>,
+}
+
+// Narrows each request down to the rules that can possibly match it. Rules
+// anchored on a literal path prefix (`^/api/v1/...`) live in a byte trie
+// keyed by that prefix; everything else is tested on every request.
+struct PrefixIndex {
+    trie: Vec<TrieNode>,
+    unindexed: Vec<usize>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: HashMap<u8, usize>,
+    rules: Vec<usize>,
+}
+
+impl PrefixIndex {
+    fn build(rules: &[RewriteRule]) -> Self {
+        let mut index = PrefixIndex {
+            trie: vec![TrieNode::default()],
+            unindexed: Vec::new(),
+        };
+
+        for (i, rule) in rules.iter().enumerate() {
+            match literal_prefix(&rule.pattern) {
+                Some(prefix) if !rule.flags.contains(&RewriteFlag::NC) => index.insert(&prefix, i),
+                _ => index.unindexed.push(i),
+            }
+        }
+
+        index
+    }
+
+    fn insert(&mut self, prefix: &str, rule: usize) {
+        let mut node = 0;
+        for byte in prefix.bytes() {
+            node = match self.trie[node].children.get(&byte) {
+                Some(&next) => next,
+                None => {
+                    self.trie.push(TrieNode::default());
+                    let next = self.trie.len() - 1;
+                    self.trie[node].children.insert(byte, next);
+                    next
+                }
+            };
+        }
+        self.trie[node].rules.push(rule);
+    }
+
+    // Indices of the rules worth testing against `url`, in configuration order
+    fn candidates(&self, url: &str) -> Vec<usize> {
+        let mut candidates = self.unindexed.clone();
+        let mut node = 0;
+        for byte in url.bytes() {
+            match self.trie[node].children.get(&byte) {
+                Some(&next) => node = next,
+                None => break,
+            }
+            candidates.extend_from_slice(&self.trie[node].rules);
+        }
+        candidates.sort_unstable();
+        candidates
+    }
+}
+
+// The literal text every match of `pattern` must start with, if it is
+// anchored and begins with plain characters. Conservative: anything that
+// isn't obviously literal ends the prefix.
+fn literal_prefix(pattern: &str) -> Option<String> {
+    let rest = pattern.strip_prefix('^')?;
+    if has_top_level_alternation(rest) {
+        return None;
+    }
+
+    let mut prefix = String::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        let literal = match c {
+            '\\' => match chars.next() {
+                // \< and \> are word boundaries, other escaped punctuation is literal
+                Some(escaped) if escaped.is_ascii_punctuation() && escaped != '<' && escaped != '>' => escaped,
+                _ => break,
+            },
+            '.' | '^' | '$' | '*' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' => break,
+            c => c,
+        };
+        // A following quantifier makes this character optional or repeated
+        if matches!(chars.peek(), Some('*' | '?' | '{')) {
+            break;
+        }
+        prefix.push(literal);
+    }
+
+    if prefix.is_empty() {
+        None
+    } else {
+        Some(prefix)
+    }
+}
+
+fn has_top_level_alternation(pattern: &str) -> bool {
+    let mut depth = 0usize;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' => {
+                // Skip the class; a leading ']' (after an optional '^') is literal
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        ']' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            '|' if depth == 0 => return true,
+            _ => {}
+        }
+    }
+    false
 }
 
 impl RewriteEngine {
@@ -76,16 +214,30 @@ impl RewriteEngine {
                 .map_err(|e| anyhow!("Invalid condition pattern '{}': {}", pattern, e))?);
         }
         
+        let index = Some(PrefixIndex::build(&config.rules));
         Ok(Self {
             rules: config.rules,
             conditions: config.conditions,
+            document_root: config.document_root,
+            index,
         })
     }
 
+    // Test every rule on every request instead of using the prefix index
+    pub fn without_prefix_index(mut self) -> Self {
+        self.index = None;
+        self
+    }
+
     pub fn process_url(&self, url: &str, query_string: Option<&str>, headers: &HashMap<String, String>) -> RewriteResult {
         debug!("Processing URL rewrite for: {}", url);
         
-        for rule in &self.rules {
+        let candidates = match &self.index {
+            Some(index) => index.candidates(url),
+            None => (0..self.rules.len()).collect(),
+        };
+        
+        for rule in candidates.into_iter().map(|i| &self.rules[i]) {
             // Check conditions first
             if !self.check_conditions(url, headers) {
                 continue;
@@ -103,14 +255,7 @@ impl RewriteEngine {
                     }
                     
                     // Handle query string
-                    if rule.flags.contains(&RewriteFlag::QSA) && query_string.is_some() {
-                        if replacement.contains('?') {
-                            replacement.push('&');
-                        } else {
-                            replacement.push('?');
-                        }
-                        replacement.push_str(query_string.unwrap());
-                    }
+                    let replacement = with_query(replacement, query_string, &rule.flags);
                     
                     info!("URL rewritten from '{}' to '{}'", url, replacement);
                     
@@ -150,8 +295,17 @@ impl RewriteEngine {
                         return result;
                     }
                     
-                    // Continue processing with rewritten URL
-                    return self.process_url(&result.get_url(), query_string, headers);
+                    // Continue processing with the rewritten path and query,
+                    // keeping this rewrite if no later rule matches
+                    let url = result.get_url();
+                    let (path, query) = match url.split_once('?') {
+                        Some((path, query)) => (path, Some(query)),
+                        None => (url.as_str(), None),
+                    };
+                    return match self.process_url(path, query, headers) {
+                        RewriteResult::NoMatch => result,
+                        rewritten => rewritten,
+                    };
                 }
             }
         }
@@ -170,12 +324,23 @@ impl RewriteEngine {
         for condition in &self.conditions {
             let test_value = self.expand_variables(&condition.test_string, url, headers);
             
-            let matches = if let Some(regex) = &condition.regex {
+            // File system checks replace the pattern match
+            let matches = if condition.flags.contains(&ConditionFlag::F) {
+                self.resolve_fs_path(&test_value).map_or(false, |p| p.is_file())
+            } else if condition.flags.contains(&ConditionFlag::D) {
+                self.resolve_fs_path(&test_value).map_or(false, |p| p.is_dir())
+            } else if let Some(regex) = &condition.regex {
                 regex.is_match(&test_value)
             } else {
                 false
             };
             
+            let matches = if condition.flags.contains(&ConditionFlag::NOT) {
+                !matches
+            } else {
+                matches
+            };
+            
             if use_or {
                 result = result || matches;
                 use_or = false;
@@ -191,6 +356,30 @@ impl RewriteEngine {
         result
     }
 
+    // Map a condition's test string (usually %{REQUEST_URI}) onto the
+    // filesystem under the document root. Strings that already name a path
+    // inside the root are used as-is. Returns None for anything that would
+    // climb out of the root.
+    fn resolve_fs_path(&self, test_value: &str) -> Option<PathBuf> {
+        let root = match &self.document_root {
+            Some(root) => root,
+            None => return Some(PathBuf::from(test_value)),
+        };
+        
+        let test_path = Path::new(test_value);
+        let relative = test_path.strip_prefix(root).unwrap_or(test_path);
+        
+        let mut path = root.clone();
+        for component in relative.components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+        Some(path)
+    }
+
     fn expand_variables(&self, template: &str, url: &str, headers: &HashMap<String, String>) -> String {
         let mut result = template.to_string();
         
@@ -214,6 +403,25 @@ impl RewriteEngine {
     }
 }
 
+// Carry the original query string over to a rewrite target. By default it is
+// kept unless the target has a query of its own; QSA merges the two and QSD
+// drops the original.
+fn with_query(target: String, original_query: Option<&str>, flags: &[RewriteFlag]) -> String {
+    let original_query = match original_query {
+        Some(query) if !query.is_empty() && !flags.contains(&RewriteFlag::QSD) => query,
+        _ => return target,
+    };
+
+    if !target.contains('?') {
+        format!("{}?{}", target, original_query)
+    } else if flags.contains(&RewriteFlag::QSA) {
+        let separator = if target.ends_with('?') || target.ends_with('&') { "" } else { "&" };
+        format!("{}{}{}", target, separator, original_query)
+    } else {
+        target
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum RewriteResult {
     NoMatch,
@@ -241,6 +449,7 @@ impl RewriteConfig {
         Self {
             rules: Vec::new(),
             conditions: Vec::new(),
+            document_root: None,
         }
     }
 
@@ -351,4 +560,152 @@ mod tests {
             _ => panic!("Expected redirect result"),
         }
     }
-}
\ No newline at end of file
+
+    fn rule(pattern: &str, replacement: &str, flags: Vec<RewriteFlag>) -> RewriteRule {
+        RewriteRule {
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            flags,
+            regex: None,
+        }
+    }
+
+    fn engine(rules: Vec<RewriteRule>) -> RewriteEngine {
+        let mut config = RewriteConfig::new();
+        config.rules = rules;
+        RewriteEngine::new(config).unwrap()
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(literal_prefix(r"^/old/(.*)$"), Some("/old/".to_string()));
+        assert_eq!(literal_prefix(r"^/files/report\.pdf$"), Some("/files/report.pdf".to_string()));
+        assert_eq!(literal_prefix(r"^/blog/?$"), Some("/blog".to_string()));
+        assert_eq!(literal_prefix(r"^/api/v\d+/"), Some("/api/v".to_string()));
+        assert_eq!(literal_prefix(r"^/a|^/b"), None);
+        assert_eq!(literal_prefix(r"^/(a|b)/x"), Some("/".to_string()));
+        assert_eq!(literal_prefix(r"^/[|]/x"), Some("/".to_string()));
+        assert_eq!(literal_prefix(r"/old/"), None);
+        assert_eq!(literal_prefix(r"^(.*)$"), None);
+    }
+
+    #[test]
+    fn test_prefix_index_matches_linear_scan() {
+        let rules = vec![
+            rule(r"^/old/(.*)$", "/new/$1", vec![]),
+            rule(r"^/new/(.*)$", "/newer/$1", vec![RewriteFlag::L]),
+            rule(r"^/shop/cart$", "/cart", vec![RewriteFlag::R301]),
+            rule(r"\.php$", "/gone", vec![RewriteFlag::G]),
+            rule(r"^/CASE/(.*)$", "/case/$1", vec![RewriteFlag::NC, RewriteFlag::L]),
+            rule(r"^/a|^/b", "/ab", vec![RewriteFlag::L]),
+        ];
+        let indexed = engine(rules.clone());
+        let linear = engine(rules).without_prefix_index();
+
+        for url in ["/old/page", "/new/page", "/shop/cart", "/index.php", "/case/x", "/b", "/nothing"] {
+            let indexed_result = indexed.process_url(url, None, &HashMap::new());
+            let linear_result = linear.process_url(url, None, &HashMap::new());
+            assert_eq!(format!("{:?}", indexed_result), format!("{:?}", linear_result), "{}", url);
+        }
+    }
+
+    // cargo test --release bench_500_rules -- --ignored
+    #[test]
+    #[ignore]
+    fn bench_500_rules() {
+        let rules: Vec<RewriteRule> = (0..500)
+            .map(|i| rule(&format!(r"^/section{}/(.*)$", i), &format!("/s/{}/$1", i), vec![RewriteFlag::L]))
+            .collect();
+        let indexed = engine(rules.clone());
+        let linear = engine(rules).without_prefix_index();
+
+        let iterations = 10_000;
+        let time = |engine: &RewriteEngine| {
+            let start = std::time::Instant::now();
+            for i in 0..iterations {
+                let url = format!("/section{}/page", i % 500);
+                assert!(!matches!(engine.process_url(&url, None, &HashMap::new()), RewriteResult::NoMatch));
+            }
+            start.elapsed() / iterations
+        };
+
+        assert!(time(&indexed) < time(&linear));
+    }
+
+    fn rewritten_url(engine: &RewriteEngine, url: &str, query_string: Option<&str>) -> String {
+        match engine.process_url(url, query_string, &HashMap::new()) {
+            RewriteResult::NoMatch => panic!("Expected a match for {}", url),
+            result => result.get_url(),
+        }
+    }
+
+    #[test]
+    fn test_query_string_preserved_by_default() {
+        let engine = engine(vec![
+            rule(r"^/old/(.*)$", "/new/$1", vec![]),
+            rule(r"^/search$", "/find?source=legacy", vec![RewriteFlag::L]),
+        ]);
+
+        assert_eq!(rewritten_url(&engine, "/old/page", Some("id=7")), "/new/page?id=7");
+        // A target with its own query replaces the original one
+        assert_eq!(rewritten_url(&engine, "/search", Some("q=rust")), "/find?source=legacy");
+    }
+
+    #[test]
+    fn test_query_string_append() {
+        let engine = engine(vec![
+            rule(r"^/search$", "/find?source=legacy", vec![RewriteFlag::QSA, RewriteFlag::L]),
+            rule(r"^/plain$", "/other", vec![RewriteFlag::QSA, RewriteFlag::L]),
+        ]);
+
+        assert_eq!(rewritten_url(&engine, "/search", Some("q=rust&page=2")), "/find?source=legacy&q=rust&page=2");
+        assert_eq!(rewritten_url(&engine, "/plain", Some("a=1")), "/other?a=1");
+    }
+
+    #[test]
+    fn test_query_string_discard() {
+        let engine = engine(vec![
+            rule(r"^/old$", "/new", vec![RewriteFlag::QSD, RewriteFlag::R301]),
+            rule(r"^/tracked$", "/clean?ref=internal", vec![RewriteFlag::QSD, RewriteFlag::L]),
+        ]);
+
+        match engine.process_url("/old", Some("utm_source=mail"), &HashMap::new()) {
+            RewriteResult::Redirect { url, .. } => assert_eq!(url, "/new"),
+            other => panic!("Expected redirect, got {:?}", other),
+        }
+        assert_eq!(rewritten_url(&engine, "/tracked", Some("utm_source=mail")), "/clean?ref=internal");
+    }
+
+    #[test]
+    fn test_file_conditions_resolve_against_document_root() {
+        let root = std::env::temp_dir().join(format!("miwidothttp-rewrite-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("assets/app.js"), "console.log(1)").unwrap();
+
+        // try_files: anything that isn't a real file or directory goes to the front controller
+        let not = |flag| RewriteCondition {
+            test_string: "%{REQUEST_URI}".to_string(),
+            pattern: String::new(),
+            flags: vec![flag, ConditionFlag::NOT],
+            regex: None,
+        };
+        let mut config = RewriteConfig::new();
+        config.document_root = Some(root.clone());
+        config.conditions = vec![not(ConditionFlag::F), not(ConditionFlag::D)];
+        config.rules.push(rule(r"^/(.*)$", "/index.php", vec![RewriteFlag::L]));
+        let engine = RewriteEngine::new(config).unwrap();
+
+        let process = |url: &str| engine.process_url(url, None, &HashMap::new()).get_url();
+        assert_eq!(process("/assets/app.js"), "");
+        assert_eq!(process("/assets"), "");
+        assert_eq!(process("/blog/hello-world"), "/index.php");
+        // Traversal never reaches files outside the root
+        assert_eq!(process("/../../etc/passwd"), "/index.php");
+
+        assert_eq!(engine.resolve_fs_path("/assets/app.js"), Some(root.join("assets/app.js")));
+        assert_eq!(engine.resolve_fs_path(root.join("assets").to_str().unwrap()), Some(root.join("assets")));
+        assert_eq!(engine.resolve_fs_path("/a/../../b"), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
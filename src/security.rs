@@ -1,27 +1,43 @@
 use axum::{
     body::Body,
     extract::{Request, State},
-    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use tokio::sync::RwLock;
 use tracing::{warn, info};
 use std::net::IpAddr;
 use serde::{Deserialize, Serialize};
+use crate::admin_allowlist::AdminBypass;
+use crate::error::AppError;
+use crate::logging::VirtualHost;
+use crate::proxy_protocol::ClientAddr;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct SecurityConfig {
-    pub enable_hsts: bool,
-    pub hsts_max_age: u32,
-    pub enable_csp: bool,
-    pub csp_policy: String,
+    // Response headers; backends override single fields per host
+    pub headers: SecurityHeaders,
     pub enable_rate_limiting: bool,
     pub rate_limit_requests: u32,
+    #[serde(with = "duration_secs")]
     pub rate_limit_window: Duration,
+    pub rate_limit_algorithm: RateLimitAlgorithm,
+    // Token bucket capacity, i.e. how many requests may arrive at once.
+    // Defaults to rate_limit_requests.
+    pub rate_limit_burst: Option<u32>,
+    // Per-route overrides; the longest matching prefix replaces the global
+    // limit for that path, counted separately per client IP
+    pub route_rate_limits: Vec<RouteRateLimit>,
+    // "redis" shares counters between nodes through rate_limit_redis_url;
+    // requests fall back to local counting while Redis is unreachable
+    pub rate_limit_backend: RateLimitBackend,
+    pub rate_limit_redis_url: Option<String>,
+    pub rate_limit_redis_prefix: String,
     pub max_body_size: usize,
     pub max_header_size: usize,
 }
@@ -29,127 +45,509 @@ pub struct SecurityConfig {
 impl Default for SecurityConfig {
     fn default() -> Self {
         Self {
-            enable_hsts: true,
-            hsts_max_age: 31536000, // 1 year
-            enable_csp: true,
-            csp_policy: "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'".to_string(),
+            headers: SecurityHeaders::default(),
             enable_rate_limiting: true,
             rate_limit_requests: 100,
             rate_limit_window: Duration::from_secs(60),
+            rate_limit_algorithm: RateLimitAlgorithm::SlidingWindow,
+            rate_limit_burst: None,
+            route_rate_limits: Vec::new(),
+            rate_limit_backend: RateLimitBackend::Local,
+            rate_limit_redis_url: None,
+            rate_limit_redis_prefix: "ratelimit".to_string(),
             max_body_size: 10 * 1024 * 1024, // 10MB
             max_header_size: 8192, // 8KB
         }
     }
 }
 
+// [security.headers]
+// hsts_enabled = true           # only ever sent over HTTPS
+// hsts_max_age = 31536000
+// hsts_include_subdomains = true
+// hsts_preload = false
+// csp_enabled = true
+// csp_policy = "default-src 'self'"
+// referrer_policy = "strict-origin-when-cross-origin"   # "" to leave out
+// permissions_policy = "geolocation=(), microphone=(), camera=()"
+// content_type_options = true   # X-Content-Type-Options: nosniff
+// frame_options = "DENY"
+// Headers the response already carries (e.g. a backend's own CSP) are kept.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    pub hsts_enabled: bool,
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    pub hsts_preload: bool,
+    pub csp_enabled: bool,
+    pub csp_policy: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub content_type_options: bool,
+    pub frame_options: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            hsts_enabled: true,
+            hsts_max_age: 31536000, // 1 year
+            hsts_include_subdomains: true,
+            hsts_preload: false,
+            csp_enabled: true,
+            csp_policy: "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'".to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            permissions_policy: "geolocation=(), microphone=(), camera=()".to_string(),
+            content_type_options: true,
+            frame_options: "DENY".to_string(),
+        }
+    }
+}
+
+// Per-host changes to [security.headers]; unset fields keep the global value
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct SecurityHeaderOverrides {
+    pub hsts_enabled: Option<bool>,
+    pub hsts_max_age: Option<u64>,
+    pub hsts_include_subdomains: Option<bool>,
+    pub hsts_preload: Option<bool>,
+    pub csp_enabled: Option<bool>,
+    pub csp_policy: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    pub content_type_options: Option<bool>,
+    pub frame_options: Option<String>,
+}
+
+impl SecurityHeaderOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl SecurityHeaders {
+    pub fn with_overrides(&self, overrides: &SecurityHeaderOverrides) -> SecurityHeaders {
+        let o = overrides.clone();
+        SecurityHeaders {
+            hsts_enabled: o.hsts_enabled.unwrap_or(self.hsts_enabled),
+            hsts_max_age: o.hsts_max_age.unwrap_or(self.hsts_max_age),
+            hsts_include_subdomains: o.hsts_include_subdomains.unwrap_or(self.hsts_include_subdomains),
+            hsts_preload: o.hsts_preload.unwrap_or(self.hsts_preload),
+            csp_enabled: o.csp_enabled.unwrap_or(self.csp_enabled),
+            csp_policy: o.csp_policy.unwrap_or_else(|| self.csp_policy.clone()),
+            referrer_policy: o.referrer_policy.unwrap_or_else(|| self.referrer_policy.clone()),
+            permissions_policy: o.permissions_policy.unwrap_or_else(|| self.permissions_policy.clone()),
+            content_type_options: o.content_type_options.unwrap_or(self.content_type_options),
+            frame_options: o.frame_options.unwrap_or_else(|| self.frame_options.clone()),
+        }
+    }
+
+    fn hsts_value(&self) -> String {
+        let mut value = format!("max-age={}", self.hsts_max_age);
+        if self.hsts_include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.hsts_preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+
+    // Header values for a response; HSTS is left out on plain HTTP, where
+    // browsers ignore it anyway
+    fn headers(&self, https: bool) -> Vec<(HeaderName, String)> {
+        let mut headers = Vec::new();
+        if https && self.hsts_enabled {
+            headers.push((header::STRICT_TRANSPORT_SECURITY, self.hsts_value()));
+        }
+        if self.csp_enabled {
+            headers.push((header::CONTENT_SECURITY_POLICY, self.csp_policy.clone()));
+        }
+        if self.content_type_options {
+            headers.push((header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()));
+        }
+        headers.push((header::REFERRER_POLICY, self.referrer_policy.clone()));
+        headers.push((HeaderName::from_static("permissions-policy"), self.permissions_policy.clone()));
+        headers.push((header::X_FRAME_OPTIONS, self.frame_options.clone()));
+        headers.retain(|(_, value)| !value.is_empty());
+        headers
+    }
+}
+
+// State for security_headers_middleware; one per listener
+#[derive(Clone)]
+pub struct SecurityHeadersState {
+    pub headers: Arc<SecurityHeaders>,
+    // Global headers with each host's overrides already applied
+    pub host_headers: Arc<HashMap<String, SecurityHeaders>>,
+    pub https: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    // `requests` per window, the count resetting at each window boundary
+    FixedWindow,
+    // At most `requests` within any `window` (timestamp log)
+    SlidingWindow,
+    // Bursts up to `burst`, refilled at `requests` per `window`
+    TokenBucket,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    Local,
+    Redis,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RouteRateLimit {
+    pub path_prefix: String,
+    pub requests: u32,
+    #[serde(with = "duration_secs")]
+    pub window: Duration,
+    pub algorithm: Option<RateLimitAlgorithm>,
+    pub burst: Option<u32>,
+}
+
+// Durations are written as whole seconds in the config file
+mod duration_secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RateLimitRule {
+    algorithm: RateLimitAlgorithm,
+    requests: u32,
+    window: Duration,
+    burst: u32,
+}
+
+enum LimiterState {
+    FixedWindow { started: Instant, count: u32 },
+    SlidingWindow(VecDeque<Instant>),
+    TokenBucket { tokens: f64, updated: Instant },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // How long until the next request would be allowed; zero when allowed
+    pub retry_after: Duration,
+}
+
+impl RateLimitRule {
+    fn new_state(&self, now: Instant) -> LimiterState {
+        match self.algorithm {
+            RateLimitAlgorithm::FixedWindow => LimiterState::FixedWindow { started: now, count: 0 },
+            RateLimitAlgorithm::SlidingWindow => LimiterState::SlidingWindow(VecDeque::new()),
+            RateLimitAlgorithm::TokenBucket => LimiterState::TokenBucket { tokens: self.burst as f64, updated: now },
+        }
+    }
+
+    fn check(&self, state: &mut LimiterState, now: Instant) -> RateLimitDecision {
+        let allow = |limit: u32, remaining: u32| RateLimitDecision { allowed: true, limit, remaining, retry_after: Duration::ZERO };
+        let deny = |limit: u32, retry_after: Duration| RateLimitDecision { allowed: false, limit, remaining: 0, retry_after };
+
+        match state {
+            LimiterState::FixedWindow { started, count } => {
+                if now.duration_since(*started) >= self.window {
+                    *started = now;
+                    *count = 0;
+                }
+                if *count < self.requests {
+                    *count += 1;
+                    allow(self.requests, self.requests - *count)
+                } else {
+                    deny(self.requests, self.window - now.duration_since(*started))
+                }
+            }
+            LimiterState::SlidingWindow(timestamps) => {
+                while timestamps.front().map_or(false, |t| now.duration_since(*t) >= self.window) {
+                    timestamps.pop_front();
+                }
+                if timestamps.len() < self.requests as usize {
+                    timestamps.push_back(now);
+                    allow(self.requests, self.requests - timestamps.len() as u32)
+                } else {
+                    let oldest = timestamps.front().copied().unwrap_or(now);
+                    deny(self.requests, self.window - now.duration_since(oldest))
+                }
+            }
+            LimiterState::TokenBucket { tokens, updated } => {
+                let per_second = self.requests as f64 / self.window.as_secs_f64().max(f64::EPSILON);
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * per_second).min(self.burst as f64);
+                *updated = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    allow(self.burst, *tokens as u32)
+                } else {
+                    deny(self.burst, Duration::from_secs_f64((1.0 - *tokens) / per_second))
+                }
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct RateLimiter {
-    requests: Arc<RwLock<HashMap<IpAddr, Vec<Instant>>>>,
+    // Keyed by client IP and rule (0 is the global limit, n the nth route)
+    requests: Arc<RwLock<HashMap<(IpAddr, usize), LimiterState>>>,
+    redis: Option<Arc<RedisRateLimiter>>,
     config: SecurityConfig,
 }
 
 impl RateLimiter {
     pub fn new(config: SecurityConfig) -> Self {
+        let redis = match (config.rate_limit_backend, &config.rate_limit_redis_url) {
+            (RateLimitBackend::Redis, Some(url)) => match RedisRateLimiter::new(url, &config.rate_limit_redis_prefix) {
+                Ok(redis) => Some(Arc::new(redis)),
+                Err(e) => {
+                    warn!("Invalid rate limit Redis URL, limiting locally: {}", e);
+                    None
+                }
+            },
+            (RateLimitBackend::Redis, None) => {
+                warn!("rate_limit_backend = \"redis\" without rate_limit_redis_url, limiting locally");
+                None
+            }
+            (RateLimitBackend::Local, _) => None,
+        };
+
         Self {
             requests: Arc::new(RwLock::new(HashMap::new())),
+            redis,
             config,
         }
     }
 
-    pub async fn check_rate_limit(&self, ip: IpAddr) -> bool {
-        if !self.config.enable_rate_limiting {
-            return true;
+    pub fn enabled(&self) -> bool {
+        self.config.enable_rate_limiting
+    }
+
+    // Longest matching route prefix, falling back to the global limit
+    fn rule_for(&self, path: &str) -> (usize, RateLimitRule) {
+        let route = self.config.route_rate_limits.iter()
+            .enumerate()
+            .filter(|(_, route)| path.starts_with(&route.path_prefix))
+            .max_by_key(|(_, route)| route.path_prefix.len());
+
+        match route {
+            Some((i, route)) => (i + 1, RateLimitRule {
+                algorithm: route.algorithm.unwrap_or(self.config.rate_limit_algorithm),
+                requests: route.requests,
+                window: route.window,
+                burst: route.burst.unwrap_or(route.requests),
+            }),
+            None => (0, RateLimitRule {
+                algorithm: self.config.rate_limit_algorithm,
+                requests: self.config.rate_limit_requests,
+                window: self.config.rate_limit_window,
+                burst: self.config.rate_limit_burst.unwrap_or(self.config.rate_limit_requests),
+            }),
         }
+    }
+
+    pub async fn check(&self, ip: IpAddr, path: &str) -> RateLimitDecision {
+        if let Some(redis) = &self.redis {
+            let (index, rule) = self.rule_for(path);
+            if let Some(decision) = redis.check(ip, index, &rule).await {
+                if !decision.allowed {
+                    warn!("Rate limit exceeded for IP: {} on {}", ip, path);
+                }
+                return decision;
+            }
+        }
+        self.check_at(ip, path, Instant::now()).await
+    }
 
+    async fn check_at(&self, ip: IpAddr, path: &str, now: Instant) -> RateLimitDecision {
+        let (index, rule) = self.rule_for(path);
         let mut requests = self.requests.write().await;
-        let now = Instant::now();
-        
-        let timestamps = requests.entry(ip).or_insert_with(Vec::new);
-        
-        // Remove old timestamps outside the window
-        timestamps.retain(|t| now.duration_since(*t) < self.config.rate_limit_window);
-        
-        if timestamps.len() >= self.config.rate_limit_requests as usize {
-            warn!("Rate limit exceeded for IP: {}", ip);
-            false
-        } else {
-            timestamps.push(now);
-            true
+        let state = requests.entry((ip, index)).or_insert_with(|| rule.new_state(now));
+
+        let decision = rule.check(state, now);
+        if !decision.allowed {
+            warn!("Rate limit exceeded for IP: {} on {}", ip, path);
         }
+        decision
     }
 }
 
-pub async fn security_headers_middleware(
-    request: Request,
-    next: Next,
-) -> Result<Response, StatusCode> {
-    let mut response = next.run(request).await;
-    let headers = response.headers_mut();
-    
-    // Security headers
-    headers.insert(
-        HeaderName::from_static("x-frame-options"),
-        HeaderValue::from_static("DENY"),
-    );
-    
-    headers.insert(
-        HeaderName::from_static("x-content-type-options"),
-        HeaderValue::from_static("nosniff"),
-    );
-    
-    headers.insert(
-        HeaderName::from_static("x-xss-protection"),
-        HeaderValue::from_static("1; mode=block"),
-    );
-    
-    headers.insert(
-        HeaderName::from_static("referrer-policy"),
-        HeaderValue::from_static("strict-origin-when-cross-origin"),
-    );
-    
-    headers.insert(
-        HeaderName::from_static("permissions-policy"),
-        HeaderValue::from_static("geolocation=(), microphone=(), camera=()"),
-    );
-    
-    Ok(response)
+// Give up on a Redis call after this long and count locally instead
+const REDIS_TIMEOUT: Duration = Duration::from_millis(250);
+// After a failure Redis is skipped for this long, so an outage doesn't add
+// REDIS_TIMEOUT to every request
+const REDIS_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+// Each script returns {allowed, remaining, retry_after_ms} and reads the
+// clock from Redis so all nodes agree on the time
+const FIXED_WINDOW_SCRIPT: &str = r#"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+local limit = tonumber(ARGV[1])
+if count > limit then
+    return {0, 0, redis.call('PTTL', KEYS[1])}
+end
+return {1, limit - count, 0}
+"#;
+
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+local limit = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+local count = redis.call('ZCARD', KEYS[1])
+if count < limit then
+    redis.call('ZADD', KEYS[1], now, ARGV[3])
+    redis.call('PEXPIRE', KEYS[1], window)
+    return {1, limit - count - 1, 0}
+end
+local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+return {0, 0, tonumber(oldest[2]) + window - now}
+"#;
+
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+local capacity = tonumber(ARGV[1])
+local per_ms = tonumber(ARGV[2])
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or capacity
+local updated = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - updated) * per_ms)
+local allowed, retry = 0, 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+else
+    retry = math.ceil((1 - tokens) / per_ms)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity / per_ms) + 1000)
+return {allowed, math.floor(tokens), retry}
+"#;
+
+// Counters shared by every node through Redis. check() returns None when
+// Redis can't answer, and the caller limits locally.
+struct RedisRateLimiter {
+    client: redis::Client,
+    prefix: String,
+    connection: tokio::sync::OnceCell<redis::aio::ConnectionManager>,
+    unavailable_until: std::sync::Mutex<Option<Instant>>,
+    fixed_window: redis::Script,
+    sliding_window: redis::Script,
+    token_bucket: redis::Script,
 }
 
-pub async fn hsts_middleware(
-    State(config): State<Arc<SecurityConfig>>,
-    request: Request,
-    next: Next,
-) -> Result<Response, StatusCode> {
-    let mut response = next.run(request).await;
-    
-    if config.enable_hsts {
-        response.headers_mut().insert(
-            HeaderName::from_static("strict-transport-security"),
-            HeaderValue::from_str(&format!(
-                "max-age={}; includeSubDomains; preload",
-                config.hsts_max_age
-            )).unwrap(),
-        );
+impl RedisRateLimiter {
+    fn new(url: &str, prefix: &str) -> redis::RedisResult<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            prefix: prefix.to_string(),
+            connection: tokio::sync::OnceCell::new(),
+            unavailable_until: std::sync::Mutex::new(None),
+            fixed_window: redis::Script::new(FIXED_WINDOW_SCRIPT),
+            sliding_window: redis::Script::new(SLIDING_WINDOW_SCRIPT),
+            token_bucket: redis::Script::new(TOKEN_BUCKET_SCRIPT),
+        })
+    }
+
+    async fn check(&self, ip: IpAddr, rule_index: usize, rule: &RateLimitRule) -> Option<RateLimitDecision> {
+        if self.unavailable_until.lock().unwrap().map_or(false, |until| Instant::now() < until) {
+            return None;
+        }
+
+        match tokio::time::timeout(REDIS_TIMEOUT, self.run(ip, rule_index, rule)).await {
+            Ok(Ok(decision)) => Some(decision),
+            Ok(Err(e)) => self.mark_unavailable(&e.to_string()),
+            Err(_) => self.mark_unavailable("timed out"),
+        }
+    }
+
+    fn mark_unavailable(&self, reason: &str) -> Option<RateLimitDecision> {
+        warn!("Rate limit Redis unavailable ({}), limiting locally for {:?}", reason, REDIS_RETRY_AFTER);
+        *self.unavailable_until.lock().unwrap() = Some(Instant::now() + REDIS_RETRY_AFTER);
+        None
+    }
+
+    async fn run(&self, ip: IpAddr, rule_index: usize, rule: &RateLimitRule) -> redis::RedisResult<RateLimitDecision> {
+        let mut connection = self.connection
+            .get_or_try_init(|| redis::aio::ConnectionManager::new(self.client.clone()))
+            .await?
+            .clone();
+
+        let key = format!("{}:{}:{}", self.prefix, rule_index, ip);
+        let window_ms = rule.window.as_millis().max(1) as u64;
+        let (limit, result): (u32, Vec<i64>) = match rule.algorithm {
+            RateLimitAlgorithm::FixedWindow => (rule.requests, self.fixed_window.key(&key)
+                .arg(rule.requests).arg(window_ms)
+                .invoke_async(&mut connection).await?),
+            RateLimitAlgorithm::SlidingWindow => (rule.requests, self.sliding_window.key(&key)
+                .arg(rule.requests).arg(window_ms).arg(uuid::Uuid::new_v4().to_string())
+                .invoke_async(&mut connection).await?),
+            RateLimitAlgorithm::TokenBucket => {
+                let per_ms = rule.requests as f64 / window_ms as f64;
+                (rule.burst, self.token_bucket.key(&key)
+                    .arg(rule.burst).arg(per_ms.to_string())
+                    .invoke_async(&mut connection).await?)
+            }
+        };
+
+        match result.as_slice() {
+            [allowed, remaining, retry_after_ms] => Ok(RateLimitDecision {
+                allowed: *allowed == 1,
+                limit,
+                remaining: (*remaining).max(0) as u32,
+                retry_after: Duration::from_millis((*retry_after_ms).max(0) as u64),
+            }),
+            _ => Err(redis::RedisError::from((redis::ErrorKind::TypeError, "unexpected rate limit script reply"))),
+        }
     }
-    
-    Ok(response)
 }
 
-pub async fn csp_middleware(
-    State(config): State<Arc<SecurityConfig>>,
+pub async fn security_headers_middleware(
+    State(state): State<SecurityHeadersState>,
     request: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
+) -> Response {
     let mut response = next.run(request).await;
-    
-    if config.enable_csp {
-        response.headers_mut().insert(
-            HeaderName::from_static("content-security-policy"),
-            HeaderValue::from_str(&config.csp_policy).unwrap(),
-        );
+
+    let policy = response.extensions()
+        .get::<VirtualHost>()
+        .and_then(|VirtualHost(host)| state.host_headers.get(host))
+        .unwrap_or(&state.headers);
+    let values = policy.headers(state.https);
+
+    let headers = response.headers_mut();
+    for (name, value) in values {
+        if headers.contains_key(&name) {
+            continue;
+        }
+        match HeaderValue::from_str(&value) {
+            Ok(value) => {
+                headers.insert(name, value);
+            }
+            Err(_) => warn!("Invalid {} value in security headers: {:?}", name, value),
+        }
     }
-    
-    Ok(response)
+    response
 }
 
 pub async fn rate_limit_middleware(
@@ -157,22 +555,37 @@ pub async fn rate_limit_middleware(
     request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    // Extract client IP (in real implementation, handle X-Forwarded-For)
+    if !limiter.enabled() {
+        return Ok(next.run(request).await);
+    }
+
+    // Set by the listener, already resolved through the PROXY header if any
     let ip = request
         .extensions()
-        .get::<std::net::SocketAddr>()
-        .map(|addr| addr.ip())
+        .get::<ClientAddr>()
+        .map(|addr| addr.0.ip())
         .unwrap_or_else(|| "127.0.0.1".parse().unwrap());
-    
-    if !limiter.check_rate_limit(ip).await {
-        return Ok(Response::builder()
-            .status(StatusCode::TOO_MANY_REQUESTS)
-            .header("Retry-After", "60")
-            .body(Body::from("Rate limit exceeded"))
-            .unwrap());
+
+    if let Some(AdminBypass(reason)) = request.extensions().get::<AdminBypass>() {
+        info!("Rate limit bypassed for {} {} from {} ({})", request.method(), request.uri().path(), ip, reason);
+        return Ok(next.run(request).await);
     }
-    
-    Ok(next.run(request).await)
+
+    let decision = limiter.check(ip, request.uri().path()).await;
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        let mut response = AppError::rate_limit_exceeded().into_response();
+        // Whole seconds, rounded up so clients don't retry too early
+        let retry_after = decision.retry_after.as_secs() + u64::from(decision.retry_after.subsec_nanos() > 0);
+        response.headers_mut().insert("retry-after", HeaderValue::from(retry_after.max(1)));
+        response
+    };
+
+    let headers = response.headers_mut();
+    headers.insert("x-ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("x-ratelimit-remaining", HeaderValue::from(decision.remaining));
+    Ok(response)
 }
 
 // Request size limiting
@@ -304,4 +717,238 @@ pub async fn slowloris_protection(
     // In production, you'd want to track slow clients and disconnect them
     
     Ok(next.run(request).await)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(algorithm: RateLimitAlgorithm, requests: u32, window_secs: u64, burst: Option<u32>) -> RateLimiter {
+        RateLimiter::new(SecurityConfig {
+            rate_limit_algorithm: algorithm,
+            rate_limit_requests: requests,
+            rate_limit_window: Duration::from_secs(window_secs),
+            rate_limit_burst: burst,
+            ..Default::default()
+        })
+    }
+
+    const IP: &str = "10.0.0.1";
+
+    #[tokio::test]
+    async fn test_token_bucket_burst_then_refill() {
+        // Bursts of 5, refilled at one token per second
+        let limiter = limiter(RateLimitAlgorithm::TokenBucket, 60, 60, Some(5));
+        let ip = IP.parse().unwrap();
+        let start = Instant::now();
+
+        for remaining in (0..5).rev() {
+            let decision = limiter.check_at(ip, "/", start).await;
+            assert!(decision.allowed);
+            assert_eq!((decision.limit, decision.remaining), (5, remaining));
+        }
+        let throttled = limiter.check_at(ip, "/", start).await;
+        assert!(!throttled.allowed);
+        assert_eq!(throttled.retry_after, Duration::from_secs(1));
+
+        // Two seconds later two tokens are back, not the whole burst
+        let later = start + Duration::from_secs(2);
+        assert!(limiter.check_at(ip, "/", later).await.allowed);
+        assert!(limiter.check_at(ip, "/", later).await.allowed);
+        assert!(!limiter.check_at(ip, "/", later).await.allowed);
+
+        // Other clients have their own bucket
+        assert!(limiter.check_at("10.0.0.2".parse().unwrap(), "/", start).await.allowed);
+    }
+
+    #[tokio::test]
+    async fn test_fixed_and_sliding_windows() {
+        let ip = IP.parse().unwrap();
+        let start = Instant::now();
+
+        let fixed = limiter(RateLimitAlgorithm::FixedWindow, 2, 10, None);
+        assert!(fixed.check_at(ip, "/", start).await.allowed);
+        assert!(fixed.check_at(ip, "/", start + Duration::from_secs(9)).await.allowed);
+        assert!(!fixed.check_at(ip, "/", start + Duration::from_secs(9)).await.allowed);
+        // New window, full allowance again
+        assert!(fixed.check_at(ip, "/", start + Duration::from_secs(10)).await.allowed);
+        assert!(fixed.check_at(ip, "/", start + Duration::from_secs(10)).await.allowed);
+
+        let sliding = limiter(RateLimitAlgorithm::SlidingWindow, 2, 10, None);
+        assert!(sliding.check_at(ip, "/", start).await.allowed);
+        assert!(sliding.check_at(ip, "/", start + Duration::from_secs(9)).await.allowed);
+        // Only the first request has left the window at 10s
+        assert!(sliding.check_at(ip, "/", start + Duration::from_secs(10)).await.allowed);
+        let denied = sliding.check_at(ip, "/", start + Duration::from_secs(10)).await;
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_secs(9));
+    }
+
+    #[tokio::test]
+    async fn test_route_limit_overrides_global() {
+        let limiter = RateLimiter::new(SecurityConfig {
+            rate_limit_requests: 100,
+            route_rate_limits: vec![RouteRateLimit {
+                path_prefix: "/login".to_string(),
+                requests: 1,
+                window: Duration::from_secs(60),
+                algorithm: Some(RateLimitAlgorithm::FixedWindow),
+                burst: None,
+            }],
+            ..Default::default()
+        });
+        let ip = IP.parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(ip, "/login", now).await.allowed);
+        assert!(!limiter.check_at(ip, "/login", now).await.allowed);
+        let other = limiter.check_at(ip, "/", now).await;
+        assert!(other.allowed);
+        assert_eq!(other.remaining, 99);
+    }
+
+    #[tokio::test]
+    async fn test_redis_unreachable_falls_back_to_local() {
+        let limiter = RateLimiter::new(SecurityConfig {
+            rate_limit_backend: RateLimitBackend::Redis,
+            // Nothing listens on port 1
+            rate_limit_redis_url: Some("redis://127.0.0.1:1/".to_string()),
+            rate_limit_algorithm: RateLimitAlgorithm::FixedWindow,
+            rate_limit_requests: 2,
+            ..Default::default()
+        });
+        let ip = IP.parse().unwrap();
+
+        assert!(limiter.check(ip, "/").await.allowed);
+        assert!(limiter.check(ip, "/").await.allowed);
+        assert!(!limiter.check(ip, "/").await.allowed);
+    }
+
+    #[test]
+    fn test_config_from_toml() {
+        let config: SecurityConfig = toml::from_str(r#"
+            rate_limit_algorithm = "token_bucket"
+            rate_limit_window = 60
+            rate_limit_burst = 20
+
+            [[route_rate_limits]]
+            path_prefix = "/api/"
+            requests = 10
+            window = 1
+        "#).unwrap();
+        assert_eq!(config.rate_limit_algorithm, RateLimitAlgorithm::TokenBucket);
+        assert_eq!(config.rate_limit_window, Duration::from_secs(60));
+        assert_eq!(config.route_rate_limits[0].window, Duration::from_secs(1));
+    }
+
+    fn headers_app(https: bool) -> axum::Router {
+        let headers = SecurityHeaders { hsts_preload: true, ..Default::default() };
+        let api = headers.with_overrides(&SecurityHeaderOverrides {
+            hsts_max_age: Some(60),
+            csp_enabled: Some(false),
+            frame_options: Some(String::new()),
+            ..Default::default()
+        });
+        let state = SecurityHeadersState {
+            headers: Arc::new(headers),
+            host_headers: Arc::new(HashMap::from([("api.example.com".to_string(), api)])),
+            https,
+        };
+        axum::Router::new()
+            .route("/", axum::routing::get(|| async { "home" }))
+            .route("/api", axum::routing::get(|| async {
+                let mut response = "api".into_response();
+                response.extensions_mut().insert(VirtualHost("api.example.com".to_string()));
+                response
+            }))
+            .layer(axum::middleware::from_fn_with_state(state, security_headers_middleware))
+    }
+
+    async fn response_headers(app: axum::Router, uri: &str) -> HeaderMap {
+        use tower::ServiceExt;
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap().headers().clone()
+    }
+
+    #[tokio::test]
+    async fn test_hsts_only_over_https() {
+        let https = response_headers(headers_app(true), "/").await;
+        assert_eq!(https[header::STRICT_TRANSPORT_SECURITY], "max-age=31536000; includeSubDomains; preload");
+        assert_eq!(https[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(https[header::X_FRAME_OPTIONS], "DENY");
+
+        let http = response_headers(headers_app(false), "/").await;
+        assert!(!http.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert!(http.contains_key(header::CONTENT_SECURITY_POLICY));
+        assert_eq!(http[header::REFERRER_POLICY], "strict-origin-when-cross-origin");
+    }
+
+    #[tokio::test]
+    async fn test_host_overrides_layer_on_global_headers() {
+        let api = response_headers(headers_app(true), "/api").await;
+        assert_eq!(api[header::STRICT_TRANSPORT_SECURITY], "max-age=60; includeSubDomains; preload");
+        assert!(!api.contains_key(header::CONTENT_SECURITY_POLICY));
+        assert!(!api.contains_key(header::X_FRAME_OPTIONS));
+        // Untouched fields come from [security.headers]
+        assert_eq!(api["permissions-policy"], "geolocation=(), microphone=(), camera=()");
+    }
+}
+
+// Needs a Redis server: MIWIDOTHTTP_TEST_REDIS_URL, or one on localhost.
+// Run with `cargo test --features redis-tests`.
+#[cfg(all(test, feature = "redis-tests"))]
+mod redis_tests {
+    use super::*;
+
+    // Two limiters stand in for two nodes sharing one Redis
+    fn nodes(algorithm: RateLimitAlgorithm) -> (RateLimiter, RateLimiter) {
+        let config = SecurityConfig {
+            rate_limit_backend: RateLimitBackend::Redis,
+            rate_limit_redis_url: Some(std::env::var("MIWIDOTHTTP_TEST_REDIS_URL")
+                .unwrap_or_else(|_| "redis://127.0.0.1:6379/".to_string())),
+            rate_limit_redis_prefix: format!("ratelimit-test-{}", uuid::Uuid::new_v4()),
+            rate_limit_algorithm: algorithm,
+            rate_limit_requests: 3,
+            ..Default::default()
+        };
+        (RateLimiter::new(config.clone()), RateLimiter::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_limit_is_shared_between_nodes() {
+        for algorithm in [RateLimitAlgorithm::FixedWindow, RateLimitAlgorithm::SlidingWindow, RateLimitAlgorithm::TokenBucket] {
+            let (a, b) = nodes(algorithm);
+            let ip = "10.0.0.1".parse().unwrap();
+
+            assert_eq!(a.check(ip, "/").await.remaining, 2);
+            assert_eq!(b.check(ip, "/").await.remaining, 1);
+            assert!(a.check(ip, "/").await.allowed);
+
+            let denied = b.check(ip, "/").await;
+            assert!(!denied.allowed, "{:?}", algorithm);
+            assert!(denied.retry_after > Duration::ZERO);
+        }
+    }
+    #[tokio::test]
+    async fn test_middleware_limits_across_nodes() {
+        use tower::ServiceExt;
+
+        let (a, b) = nodes(RateLimitAlgorithm::FixedWindow);
+        let app = |limiter: RateLimiter| axum::Router::new()
+            .route("/", axum::routing::get(|| async { "OK" }))
+            .layer(axum::middleware::from_fn_with_state(Arc::new(limiter), rate_limit_middleware));
+        let (a, b) = (app(a), app(b));
+        let request = || {
+            let mut req = Request::get("/").body(Body::empty()).unwrap();
+            req.extensions_mut().insert(ClientAddr("10.0.0.2:40000".parse().unwrap()));
+            req
+        };
+
+        for node in [&a, &b, &a] {
+            assert_eq!(node.clone().oneshot(request()).await.unwrap().status(), StatusCode::OK);
+        }
+        let response = b.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::{anyhow, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use rustls_pemfile::{certs, private_key};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufReader;
+use std::sync::Arc;
+use tracing::debug;
+
+// A backend's own certificate, served to clients asking for its host, e.g.
+//
+//   [backends."api.example.com"]
+//   tls = { cert_path = "./certs/api.crt", key_path = "./certs/api.key" }
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HostCertificate {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+// Selects a certificate by the server name sent in the ClientHello
+#[derive(Debug, Default)]
+pub struct SniCertResolver {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    // Keyed by the parent domain, so "*.example.com" is stored as "example.com"
+    wildcards: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniCertResolver {
+    pub fn new(default: Option<Arc<CertifiedKey>>) -> Self {
+        Self {
+            default,
+            ..Self::default()
+        }
+    }
+
+    pub fn add(&mut self, domain: &str, key: Arc<CertifiedKey>) {
+        let domain = domain.to_lowercase();
+        match domain.strip_prefix("*.") {
+            Some(parent) => self.wildcards.insert(parent.to_string(), key),
+            None => self.exact.insert(domain, key),
+        };
+    }
+
+    pub fn resolve_name(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let name = match server_name {
+            Some(name) => name.to_lowercase(),
+            None => return self.default.clone(),
+        };
+
+        if let Some(key) = self.exact.get(&name) {
+            return Some(key.clone());
+        }
+
+        // A wildcard covers exactly one label, as in certificate matching
+        if let Some((_, parent)) = name.split_once('.') {
+            if let Some(key) = self.wildcards.get(parent) {
+                return Some(key.clone());
+            }
+        }
+
+        debug!("No certificate for {}, using default", name);
+        self.default.clone()
+    }
+
+    // Listener config serving certificates from this resolver, with the
+    // same ALPN protocols as RustlsConfig::from_pem_file
+    pub fn into_rustls_config(self) -> Result<RustlsConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(self));
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(RustlsConfig::from_config(Arc::new(server_config)))
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.resolve_name(client_hello.server_name())
+    }
+}
+
+pub fn load_certified_key(cert_path: &str, key_path: &str) -> Result<Arc<CertifiedKey>> {
+    let cert_file = fs::File::open(cert_path).map_err(|e| anyhow!("Failed to open {}: {}", cert_path, e))?;
+    let cert_chain: Vec<CertificateDer<'static>> = certs(&mut BufReader::new(cert_file))
+        .collect::<Result<_, _>>()
+        .map_err(|e| anyhow!("Failed to parse {}: {}", cert_path, e))?;
+    if cert_chain.is_empty() {
+        return Err(anyhow!("No certificates found in {}", cert_path));
+    }
+
+    let key_file = fs::File::open(key_path).map_err(|e| anyhow!("Failed to open {}: {}", key_path, e))?;
+    let key = private_key(&mut BufReader::new(key_file))
+        .map_err(|e| anyhow!("Failed to parse {}: {}", key_path, e))?
+        .ok_or_else(|| anyhow!("No private key found in {}", key_path))?;
+
+    certified_key(cert_chain, key)
+}
+
+fn certified_key(cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Arc<CertifiedKey>> {
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported private key: {}", e))?;
+    Ok(Arc::new(CertifiedKey::new(cert_chain, signing_key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn self_signed(domain: &str) -> (Arc<CertifiedKey>, CertificateDer<'static>) {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec![domain.to_string()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+        let der = cert.der().clone();
+        (certified_key(vec![der.clone()], key).unwrap(), der)
+    }
+
+    #[test]
+    fn test_sni_selects_cert_per_host() {
+        let (first, _) = self_signed("first.example.com");
+        let (second, _) = self_signed("second.example.org");
+        let (wildcard, _) = self_signed("*.apps.example.com");
+        let (fallback, _) = self_signed("localhost");
+
+        let mut resolver = SniCertResolver::new(Some(fallback.clone()));
+        resolver.add("first.example.com", first.clone());
+        resolver.add("second.example.org", second.clone());
+        resolver.add("*.apps.example.com", wildcard.clone());
+
+        let pick = |name: Option<&str>| resolver.resolve_name(name).unwrap();
+        assert!(Arc::ptr_eq(&pick(Some("first.example.com")), &first));
+        assert!(Arc::ptr_eq(&pick(Some("SECOND.example.org")), &second));
+        assert!(Arc::ptr_eq(&pick(Some("api.apps.example.com")), &wildcard));
+        // Wildcards don't span more than one label
+        assert!(Arc::ptr_eq(&pick(Some("a.b.apps.example.com")), &fallback));
+        assert!(Arc::ptr_eq(&pick(Some("unknown.example.net")), &fallback));
+        assert!(Arc::ptr_eq(&pick(None), &fallback));
+    }
+
+    #[test]
+    fn test_sni_without_default() {
+        let resolver = SniCertResolver::new(None);
+        assert!(resolver.resolve_name(Some("example.com")).is_none());
+    }
+
+    // The certificate a TLS client gets when asking for `server_name`
+    async fn handshake(tls: &RustlsConfig, roots: &[CertificateDer<'static>], server_name: &str) -> CertificateDer<'static> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(tls.get_inner());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut root_store = rustls::RootCertStore::empty();
+        for root in roots {
+            root_store.add(root.clone()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"ok");
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn test_listener_serves_cert_by_sni() {
+        let (fallback, fallback_der) = self_signed("localhost");
+        let (api, api_der) = self_signed("api.example.com");
+        let mut resolver = SniCertResolver::new(Some(fallback));
+        resolver.add("api.example.com", api);
+        let tls = resolver.into_rustls_config().unwrap();
+        let roots = [fallback_der.clone(), api_der.clone()];
+
+        assert_eq!(handshake(&tls, &roots, "api.example.com").await, api_der);
+        assert_eq!(handshake(&tls, &roots, "localhost").await, fallback_der);
+    }
+}
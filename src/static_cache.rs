@@ -1,4 +1,4 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::collections::HashMap;
 use tokio::sync::RwLock;
@@ -7,18 +7,27 @@ use std::fs::File;
 use std::time::{SystemTime, UNIX_EPOCH};
 use bytes::Bytes;
 use mime_guess::MimeGuess;
+use percent_encoding::percent_decode_str;
 use axum::response::{Response, IntoResponse};
-use axum::http::{StatusCode, header};
+use axum::http::{StatusCode, header, HeaderMap};
 use axum::body::Body;
 
+use crate::compression::encoding_quality;
+
 #[derive(Clone)]
 pub struct CachedFile {
     pub content: Bytes,
     pub mime_type: String,
     pub etag: String,
     pub last_modified: u64,
+    // Set for precompressed variants (file.zst / file.br / file.gz)
+    pub encoding: Option<&'static str>,
 }
 
+// Precompressed siblings, like nginx's brotli_static and gzip_static. The
+// client's q-values decide; on a tie the earlier one wins.
+const PRECOMPRESSED: &[(&str, &str)] = &[("zstd", "zst"), ("br", "br"), ("gzip", "gz")];
+
 pub struct StaticCache {
     cache: Arc<RwLock<HashMap<PathBuf, Arc<CachedFile>>>>,
     use_mmap: bool,
@@ -33,27 +42,60 @@ impl StaticCache {
     }
 
     pub async fn serve_file(&self, path: &Path) -> Response {
-        // Check cache first
-        {
-            let cache = self.cache.read().await;
-            if let Some(cached) = cache.get(path) {
-                return Self::build_response(cached.clone());
+        self.serve_variant(path, path, None, &HeaderMap::new()).await
+    }
+
+    // Serve `path`, or a precompressed `path.zst`/`path.br`/`path.gz` next
+    // to it when the client accepts that encoding, so assets aren't
+    // recompressed on every request. Conditional request headers are honored.
+    // Variants are canonicalized like the file itself, so a symlinked
+    // `app.js.gz` can't serve something from outside `root`.
+    pub async fn serve_precompressed(&self, root: &Path, path: &Path, request_headers: &HeaderMap) -> Response {
+        let accepted = request_headers.get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        let root = root.canonicalize().ok();
+
+        let mut best: Option<(PathBuf, &'static str, f32)> = None;
+        for (encoding, extension) in PRECOMPRESSED {
+            let quality = encoding_quality(accepted, encoding);
+            if quality <= 0.0 || best.as_ref().map_or(false, |(_, _, q)| quality <= *q) {
+                continue;
+            }
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(extension);
+            let variant = match (PathBuf::from(variant).canonicalize(), &root) {
+                (Ok(variant), Some(root)) if variant.starts_with(root) => variant,
+                _ => continue,
+            };
+            if variant.is_file() {
+                best = Some((variant, encoding, quality));
             }
         }
 
-        // Load file
-        match self.load_file(path).await {
-            Ok(cached_file) => {
-                let cached = Arc::new(cached_file);
-                
-                // Store in cache
-                {
-                    let mut cache = self.cache.write().await;
-                    cache.insert(path.to_path_buf(), cached.clone());
-                }
-                
-                Self::build_response(cached)
-            }
+        let mut response = match best {
+            Some((variant, encoding, _)) => self.serve_variant(&variant, path, Some(encoding), request_headers).await,
+            None => self.serve_variant(path, path, None, request_headers).await,
+        };
+        // Which representation is sent depends on Accept-Encoding, even
+        // when it's the uncompressed one
+        if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+            response.headers_mut().insert(header::VARY, header::HeaderValue::from_static("Accept-Encoding"));
+        }
+        response
+    }
+
+    async fn serve_variant(
+        &self,
+        path: &Path,
+        original: &Path,
+        encoding: Option<&'static str>,
+        request_headers: &HeaderMap,
+    ) -> Response {
+        match self.cached_file(path, original, encoding).await {
+            Ok(cached) if is_not_modified(&cached, request_headers) => Self::not_modified_response(&cached),
+            Ok(cached) => Self::build_response(cached),
             Err(_) => {
                 Response::builder()
                     .status(StatusCode::NOT_FOUND)
@@ -63,7 +105,36 @@ impl StaticCache {
         }
     }
 
-    async fn load_file(&self, path: &Path) -> Result<CachedFile, std::io::Error> {
+    // Cached entries are revalidated against the file's size and mtime on
+    // every request, so edited files are picked up without a restart
+    async fn cached_file(&self, path: &Path, original: &Path, encoding: Option<&'static str>) -> Result<Arc<CachedFile>, std::io::Error> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let etag = etag_for(&metadata)?;
+
+        // Check cache first
+        {
+            let cache = self.cache.read().await;
+            if let Some(cached) = cache.get(path) {
+                if cached.etag == etag {
+                    return Ok(cached.clone());
+                }
+            }
+        }
+
+        // Load file
+        let cached = Arc::new(self.load_file(path, original, encoding).await?);
+        
+        // Store in cache
+        {
+            let mut cache = self.cache.write().await;
+            cache.insert(path.to_path_buf(), cached.clone());
+        }
+        
+        Ok(cached)
+    }
+
+    // `original` is the uncompressed file the content type comes from
+    async fn load_file(&self, path: &Path, original: &Path, encoding: Option<&'static str>) -> Result<CachedFile, std::io::Error> {
         let file = File::open(path)?;
         let metadata = file.metadata()?;
         let modified = metadata.modified()?
@@ -82,34 +153,289 @@ impl StaticCache {
             Bytes::from(std::fs::read(path)?)
         };
 
-        let mime_type = MimeGuess::from_path(path)
+        let mime_type = MimeGuess::from_path(original)
             .first_or_octet_stream()
             .to_string();
 
-        // Simple ETag based on size and modification time
-        let etag = format!("\"{:x}-{:x}\"", metadata.len(), modified);
+        let etag = etag_for(&metadata)?;
 
         Ok(CachedFile {
             content,
             mime_type,
             etag,
             last_modified: modified,
+            encoding,
         })
     }
 
     fn build_response(cached: Arc<CachedFile>) -> Response {
-        Response::builder()
+        let mut response = Response::builder()
             .status(StatusCode::OK)
             .header(header::CONTENT_TYPE, &cached.mime_type)
             .header(header::ETAG, &cached.etag)
             .header(header::CACHE_CONTROL, "public, max-age=3600")
-            .header("Last-Modified", format!("{}", cached.last_modified))
-            .body(Body::from(cached.content.clone()))
-            .unwrap()
+            .header(header::LAST_MODIFIED, http_date(cached.last_modified));
+        if let Some(encoding) = cached.encoding {
+            response = response
+                .header(header::CONTENT_ENCODING, encoding)
+                .header(header::VARY, "Accept-Encoding");
+        }
+        response.body(Body::from(cached.content.clone())).unwrap()
+    }
+
+    // 304 keeps the validators and caching headers but carries no body
+    fn not_modified_response(cached: &CachedFile) -> Response {
+        let mut response = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, &cached.etag)
+            .header(header::CACHE_CONTROL, "public, max-age=3600")
+            .header(header::LAST_MODIFIED, http_date(cached.last_modified));
+        if cached.encoding.is_some() {
+            response = response.header(header::VARY, "Accept-Encoding");
+        }
+        response.body(Body::empty()).unwrap()
     }
 
     pub async fn clear_cache(&self) {
         let mut cache = self.cache.write().await;
         cache.clear();
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StaticPath {
+    Found(PathBuf),
+    Missing,
+    Forbidden,
+}
+
+// Map a request path onto a file under `root`. The path is percent-decoded
+// first, so encoded `%2e%2e` is caught with plain `..`, and the result is
+// canonicalized so symlinks can't lead outside the root either.
+pub fn resolve_static_path(root: &Path, uri_path: &str) -> StaticPath {
+    let decoded = match percent_decode_str(uri_path).decode_utf8() {
+        Ok(decoded) => decoded,
+        Err(_) => return StaticPath::Forbidden,
+    };
+    if decoded.contains('\0') || decoded.contains('\\') {
+        return StaticPath::Forbidden;
+    }
+
+    let mut relative = PathBuf::new();
+    for component in Path::new(decoded.as_ref()).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return StaticPath::Forbidden,
+        }
+    }
+
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(_) => return StaticPath::Missing,
+    };
+    match root.join(relative).canonicalize() {
+        Ok(path) if path.starts_with(&root) => StaticPath::Found(path),
+        Ok(_) => StaticPath::Forbidden,
+        Err(_) => StaticPath::Missing,
+    }
+}
+
+// Strong ETag from size and modification time (with sub-second precision,
+// so quick successive edits still change it)
+fn etag_for(metadata: &std::fs::Metadata) -> Result<String, std::io::Error> {
+    let modified = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    Ok(format!("\"{:x}-{:x}\"", metadata.len(), modified))
+}
+
+fn http_date(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+// If-None-Match takes precedence; If-Modified-Since is only consulted when
+// the client sent no ETags
+fn is_not_modified(cached: &CachedFile, request_headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH) {
+        let if_none_match = if_none_match.to_str().unwrap_or("");
+        let etag = cached.etag.trim_start_matches("W/");
+        return if_none_match.split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+
+    request_headers.get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+        .map_or(false, |since| cached.last_modified as i64 <= since.timestamp())
+}
+
+// Whether an Accept-Encoding value allows `encoding`; "q=0" rules it out
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn fixture_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("miwidothttp-static-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.js"), "console.log('plain')").unwrap();
+        std::fs::write(dir.join("app.js.gz"), "gzip bytes").unwrap();
+        std::fs::write(dir.join("app.js.br"), "brotli bytes").unwrap();
+        std::fs::write(dir.join("app.js.zst"), "zstd bytes").unwrap();
+        std::fs::write(dir.join("style.css"), "body {}").unwrap();
+        std::fs::write(dir.join("style.css.gz"), "gzip css").unwrap();
+        dir
+    }
+
+    async fn fetch(cache: &StaticCache, path: &Path, accept_encoding: Option<&'static str>) -> (Option<String>, String, Bytes) {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = accept_encoding {
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        }
+        let response = cache.serve_precompressed(path.parent().unwrap(), path, &headers).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::VARY], "Accept-Encoding");
+        let encoding = response.headers().get(header::CONTENT_ENCODING)
+            .map(|v| v.to_str().unwrap().to_string());
+        let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (encoding, content_type, body)
+    }
+
+    #[tokio::test]
+    async fn test_serves_precompressed_variants() {
+        let dir = fixture_dir();
+        let cache = StaticCache::new(false);
+        let js = dir.join("app.js");
+        let js_type = MimeGuess::from_path(&js).first_or_octet_stream().to_string();
+
+        let (encoding, content_type, body) = fetch(&cache, &js, Some("gzip, deflate, br, zstd")).await;
+        assert_eq!(encoding.as_deref(), Some("zstd"));
+        assert_eq!(content_type, js_type);
+        assert_eq!(&body[..], b"zstd bytes");
+
+        // A lower q-value for zstd lets brotli win
+        let (encoding, _, body) = fetch(&cache, &js, Some("zstd;q=0.5, br")).await;
+        assert_eq!(encoding.as_deref(), Some("br"));
+        assert_eq!(&body[..], b"brotli bytes");
+
+        let (encoding, content_type, body) = fetch(&cache, &js, Some("gzip, deflate, br")).await;
+        assert_eq!(encoding.as_deref(), Some("br"));
+        assert_eq!(content_type, js_type);
+        assert_eq!(&body[..], b"brotli bytes");
+
+        let (encoding, content_type, body) = fetch(&cache, &js, Some("br;q=0, gzip")).await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        assert_eq!(content_type, js_type);
+        assert_eq!(&body[..], b"gzip bytes");
+
+        let (encoding, _, body) = fetch(&cache, &js, None).await;
+        assert_eq!(encoding, None);
+        assert_eq!(&body[..], b"console.log('plain')");
+
+        // No .br next to style.css, so gzip is the best match
+        let (encoding, content_type, _) = fetch(&cache, &dir.join("style.css"), Some("br, gzip")).await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        assert_eq!(content_type, "text/css");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_precompressed_variant_outside_root() {
+        let base = std::env::temp_dir().join(format!("miwidothttp-variant-{}", uuid::Uuid::new_v4()));
+        let root = base.join("public");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.js"), "console.log('plain')").unwrap();
+        std::fs::write(base.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(base.join("secret.txt"), root.join("app.js.gz")).unwrap();
+
+        let cache = StaticCache::new(false);
+        let (encoding, _, body) = fetch(&cache, &root.join("app.js"), Some("gzip")).await;
+        assert_eq!(encoding, None);
+        assert_eq!(&body[..], b"console.log('plain')");
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        let dir = fixture_dir();
+        let cache = StaticCache::new(false);
+        let css = dir.join("style.css");
+
+        let first = cache.serve_file(&css).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers()[header::ETAG].clone();
+        let last_modified = first.headers()[header::LAST_MODIFIED].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+        let response = cache.serve_precompressed(&dir, &css, &headers).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        assert_eq!(cache.serve_precompressed(&dir, &css, &headers).await.status(), StatusCode::NOT_MODIFIED);
+
+        // Touching the file invalidates both validators
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        File::options().write(true).open(&css).unwrap().set_modified(later).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+        let response = cache.serve_precompressed(&dir, &css, &headers).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], etag);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MODIFIED_SINCE, last_modified);
+        assert_eq!(cache.serve_precompressed(&dir, &css, &headers).await.status(), StatusCode::OK);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_static_path_blocks_traversal() {
+        let base = std::env::temp_dir().join(format!("miwidothttp-traversal-{}", uuid::Uuid::new_v4()));
+        let root = base.join("public");
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        std::fs::write(root.join("css/site.css"), "body {}").unwrap();
+        std::fs::write(root.join("my file.txt"), "spaces").unwrap();
+        std::fs::write(base.join("secret.txt"), "secret").unwrap();
+        let canonical_root = root.canonicalize().unwrap();
+
+        assert_eq!(resolve_static_path(&root, "/css/site.css"), StaticPath::Found(canonical_root.join("css/site.css")));
+        assert_eq!(resolve_static_path(&root, "/my%20file.txt"), StaticPath::Found(canonical_root.join("my file.txt")));
+        assert_eq!(resolve_static_path(&root, "/missing.css"), StaticPath::Missing);
+
+        // Plain and encoded parent references
+        assert_eq!(resolve_static_path(&root, "/../secret.txt"), StaticPath::Forbidden);
+        assert_eq!(resolve_static_path(&root, "/css/../../secret.txt"), StaticPath::Forbidden);
+        assert_eq!(resolve_static_path(&root, "/%2e%2e/secret.txt"), StaticPath::Forbidden);
+        assert_eq!(resolve_static_path(&root, "/%2E%2E%2Fsecret.txt"), StaticPath::Forbidden);
+        assert_eq!(resolve_static_path(&root, "/..%5csecret.txt"), StaticPath::Forbidden);
+        assert_eq!(resolve_static_path(&root, "/secret.txt%00.css"), StaticPath::Forbidden);
+
+        // A symlink inside the root that points outside of it
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+            std::os::unix::fs::symlink(&base, root.join("up")).unwrap();
+            assert_eq!(resolve_static_path(&root, "/link.txt"), StaticPath::Forbidden);
+            assert_eq!(resolve_static_path(&root, "/up/secret.txt"), StaticPath::Forbidden);
+        }
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap};
+use bytes::{Bytes, BytesMut};
+use hyper::body::{Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tracing::debug;
+
+// nginx sub_filter for proxied responses, e.g. after moving a domain:
+//
+//   [[backends."example.com".response_substitutions]]
+//   from = "http://old.example.com/"
+//   to = "https://example.com/"
+//   content_types = ["text/html", "application/json"]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Substitution {
+    pub from: String,
+    pub to: String,
+    // Content-type prefixes the substitution applies to
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+}
+
+fn default_content_types() -> Vec<String> {
+    vec!["text/html".to_string()]
+}
+
+pub fn validate(substitutions: &[Substitution]) -> anyhow::Result<()> {
+    if substitutions.iter().any(|s| s.from.is_empty()) {
+        anyhow::bail!("response_substitutions: from must not be empty");
+    }
+    Ok(())
+}
+
+// Wrap a response body with the substitutions for its content type. Bodies
+// that are encoded (gzip and the like) or of other types pass through
+// untouched; when something applies, Content-Length is dropped since the
+// length changes.
+pub fn apply(substitutions: &[Substitution], headers: &mut HeaderMap, body: Body) -> Body {
+    if substitutions.is_empty() {
+        return body;
+    }
+    let content_type = headers.get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let rules: Vec<(Bytes, Bytes)> = substitutions.iter()
+        .filter(|s| s.content_types.iter().any(|prefix| content_type.starts_with(&prefix.to_ascii_lowercase())))
+        .map(|s| (Bytes::from(s.from.clone()), Bytes::from(s.to.clone())))
+        .collect();
+    if rules.is_empty() {
+        return body;
+    }
+    let encoded = headers.get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| !v.eq_ignore_ascii_case("identity"));
+    if encoded {
+        debug!("Skipping response substitutions for an encoded {} body", content_type);
+        return body;
+    }
+
+    headers.remove(header::CONTENT_LENGTH);
+    Body::new(SubstitutingBody::new(body, rules))
+}
+
+// Replaces as the body streams through. The last longest-pattern-minus-one
+// bytes of each chunk are held back, so a match split across chunks is
+// still found, and nothing more is buffered.
+struct SubstitutingBody {
+    inner: Body,
+    rules: Vec<(Bytes, Bytes)>,
+    longest: usize,
+    carry: BytesMut,
+    // Trailers waiting behind the flushed carry
+    queued: Option<Frame<Bytes>>,
+    done: bool,
+}
+
+impl SubstitutingBody {
+    fn new(inner: Body, rules: Vec<(Bytes, Bytes)>) -> Self {
+        let longest = rules.iter().map(|(from, _)| from.len()).max().unwrap_or(0);
+        Self { inner, rules, longest, carry: BytesMut::new(), queued: None, done: false }
+    }
+
+    // Substitute in carry + data, returning what's safe to send; with
+    // `last` set nothing is held back
+    fn process(&mut self, data: &[u8], last: bool) -> Bytes {
+        self.carry.extend_from_slice(data);
+        let pending = self.carry.split().freeze();
+        let hold_from = if last { pending.len() } else { pending.len().saturating_sub(self.longest.saturating_sub(1)) };
+
+        let mut out = BytesMut::with_capacity(pending.len());
+        let mut i = 0;
+        'scan: while i < pending.len() {
+            for (from, to) in &self.rules {
+                if pending[i..].starts_with(from) {
+                    out.extend_from_slice(to);
+                    i += from.len();
+                    continue 'scan;
+                }
+            }
+            // Could be the start of a match that the next chunk completes
+            if i >= hold_from {
+                break;
+            }
+            out.extend_from_slice(&pending[i..i + 1]);
+            i += 1;
+        }
+        self.carry.extend_from_slice(&pending[i..]);
+        out.freeze()
+    }
+}
+
+impl hyper::body::Body for SubstitutingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        loop {
+            if let Some(frame) = self.queued.take() {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            if self.done {
+                return Poll::Ready(None);
+            }
+            let out = match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => self.process(&data, false),
+                    Err(trailers) => {
+                        self.queued = Some(trailers);
+                        self.process(&[], true)
+                    }
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    self.done = true;
+                    self.process(&[], true)
+                }
+            };
+            if !out.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(out))));
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done && self.queued.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn substitution(from: &str, to: &str) -> Substitution {
+        Substitution { from: from.to_string(), to: to.to_string(), content_types: default_content_types() }
+    }
+
+    fn chunked(chunks: &[&'static str]) -> Body {
+        let chunks: Vec<Result<&'static str, std::io::Error>> = chunks.iter().map(|c| Ok(*c)).collect();
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    fn html_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+        headers.insert(header::CONTENT_LENGTH, "42".parse().unwrap());
+        headers
+    }
+
+    async fn read(body: Body) -> String {
+        String::from_utf8(axum::body::to_bytes(body, usize::MAX).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_substitution_across_chunk_boundary() {
+        let rules = [substitution("http://old/", "https://new/")];
+        let mut headers = html_headers();
+        let body = apply(&rules, &mut headers, chunked(&["<a href=\"http://o", "ld/page\">http://old/", "</a> http://ol"]));
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+        assert_eq!(read(body).await, "<a href=\"https://new/page\">https://new/</a> http://ol");
+    }
+
+    #[tokio::test]
+    async fn test_several_rules_in_one_pass() {
+        let rules = [substitution("cat", "dog"), substitution("dog", "cat")];
+        let mut headers = html_headers();
+        let body = apply(&rules, &mut headers, chunked(&["a ca", "t and a d", "og"]));
+        assert_eq!(read(body).await, "a dog and a cat");
+    }
+
+    #[tokio::test]
+    async fn test_other_types_and_encoded_bodies_untouched() {
+        let rules = [substitution("http://old/", "https://new/")];
+
+        let mut headers = html_headers();
+        headers.insert(header::CONTENT_TYPE, "image/png".parse().unwrap());
+        let body = apply(&rules, &mut headers, chunked(&["http://old/"]));
+        assert_eq!(read(body).await, "http://old/");
+        assert!(headers.contains_key(header::CONTENT_LENGTH));
+
+        let mut headers = html_headers();
+        headers.insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+        let body = apply(&rules, &mut headers, chunked(&["http://old/"]));
+        assert_eq!(read(body).await, "http://old/");
+
+        let json_rule = [Substitution { content_types: vec!["application/json".to_string()], ..substitution("old", "new") }];
+        let mut headers = html_headers();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        assert_eq!(read(apply(&json_rule, &mut headers, chunked(&["{\"host\":\"old\"}"]))).await, "{\"host\":\"new\"}");
+    }
+}
@@ -0,0 +1,291 @@
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{field, info_span, Instrument, Span};
+
+// Distributed tracing over OTLP (needs the `otel` feature):
+//
+//   [telemetry]
+//   enabled = true
+//   endpoint = "http://otel-collector:4317"
+//   sampling_ratio = 0.1
+//
+// Requests continue the trace of an incoming `traceparent`, and proxied
+// requests carry the proxy span's `traceparent` to the backend. Read at
+// startup.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    // OTLP/gRPC collector
+    pub endpoint: String,
+    pub service_name: String,
+    // Share of new traces to sample, 0.0-1.0; traces started upstream
+    // follow the caller's decision
+    pub sampling_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "http://localhost:4317".to_string(),
+            service_name: "miwidothttp".to_string(),
+            sampling_ratio: 1.0,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.sampling_ratio) {
+            anyhow::bail!("telemetry sampling_ratio must be between 0 and 1, not {}", self.sampling_ratio);
+        }
+        if self.enabled && reqwest::Url::parse(&self.endpoint).is_err() {
+            anyhow::bail!("telemetry has invalid endpoint {:?}", self.endpoint);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use super::TelemetryConfig;
+    use axum::http::HeaderMap;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::{self, Sampler};
+    use opentelemetry_sdk::Resource;
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::registry::LookupSpan;
+
+    pub fn layer<S>(config: &TelemetryConfig) -> anyhow::Result<impl tracing_subscriber::Layer<S>>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(&config.endpoint);
+        let provider = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(trace::Config::default()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sampling_ratio))))
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", config.service_name.clone())])))
+            .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+        let tracer = provider.tracer("miwidothttp");
+        global::set_tracer_provider(provider);
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+    }
+
+    pub fn set_parent(span: &Span, headers: &HeaderMap) {
+        let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+        span.set_parent(parent);
+    }
+
+    pub fn inject(span: &Span, headers: &mut HeaderMap) {
+        let context = span.context();
+        global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut HeaderInjector(headers)));
+    }
+
+    pub fn shutdown() {
+        global::shutdown_tracer_provider();
+    }
+}
+
+// The exporting layer when [telemetry] is enabled; None otherwise, or when
+// built without the `otel` feature
+#[cfg(feature = "otel")]
+pub fn layer<S>(config: &TelemetryConfig) -> Option<impl tracing_subscriber::Layer<S>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    if !config.enabled {
+        return None;
+    }
+    match otel::layer(config) {
+        Ok(layer) => Some(layer),
+        Err(e) => {
+            eprintln!("Failed to start the OpenTelemetry exporter: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn layer<S>(config: &TelemetryConfig) -> Option<tracing_subscriber::layer::Identity>
+where
+    S: tracing::Subscriber,
+{
+    if config.enabled {
+        eprintln!("[telemetry] is enabled but this build lacks the `otel` feature; spans aren't exported");
+    }
+    None
+}
+
+// Flush spans still queued for export
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+}
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+// version-traceid-parentid-flags, lowercase hex, ids not all zeros
+fn is_valid_traceparent(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    let hex = |part: &str, len: usize| {
+        part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    parts.len() == 4
+        && hex(parts[0], 2) && parts[0] != "ff"
+        && hex(parts[1], 32) && parts[1].bytes().any(|b| b != b'0')
+        && hex(parts[2], 16) && parts[2].bytes().any(|b| b != b'0')
+        && hex(parts[3], 2)
+}
+
+// A new sampled trace, for requests that didn't bring one
+fn new_traceparent() -> String {
+    format!("00-{:032x}-{:016x}-01", rand::random::<u128>() | 1, rand::random::<u64>() | 1)
+}
+
+// Wraps each request in a server span, continuing an incoming traceparent.
+// Requests without a valid one get a new trace, so the backend can still
+// correlate its logs with ours.
+pub async fn trace_middleware(mut req: Request, next: Next) -> Response {
+    let span = info_span!(
+        "request",
+        otel.kind = "server",
+        http.request.method = %req.method(),
+        url.path = %req.uri().path(),
+        request_id = field::Empty,
+        http.response.status_code = field::Empty,
+    );
+    #[cfg(feature = "otel")]
+    otel::set_parent(&span, req.headers());
+
+    let valid = req.headers().get(TRACEPARENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, is_valid_traceparent);
+    if !valid {
+        let traceparent = HeaderValue::from_str(&new_traceparent()).expect("traceparent is ASCII");
+        req.headers_mut().insert(TRACEPARENT_HEADER, traceparent);
+    }
+
+    let response = next.run(req).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    response
+}
+
+// Client span for a backend request
+pub fn proxy_span(target_url: &str) -> Span {
+    info_span!(
+        "proxy",
+        otel.kind = "client",
+        url.full = %target_url,
+        http.response.status_code = field::Empty,
+    )
+}
+
+// Point the backend request's traceparent at `span`. Without the `otel`
+// feature the client's own traceparent, copied with the other headers, is
+// passed on as it is.
+pub fn inject(span: &Span, headers: &mut HeaderMap) {
+    #[cfg(feature = "otel")]
+    otel::inject(span, headers);
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, headers);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_client::{build_client, send, ConnectionPoolConfig, TimeoutConfig};
+    use axum::body::Body;
+    use axum::Router;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    #[tokio::test]
+    async fn test_traceparent_reaches_backend() {
+        #[cfg(feature = "otel")]
+        let _subscriber = {
+            use opentelemetry::trace::TracerProvider as _;
+            use tracing_subscriber::layer::SubscriberExt;
+
+            let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+            opentelemetry::global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+            let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(layer))
+        };
+
+        // Backend that answers with the traceparent it got
+        let backend = Router::new().fallback(|req: Request| async move {
+            req.headers().get("traceparent").map_or_else(String::new, |v| v.to_str().unwrap().to_string())
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, backend).await.unwrap() });
+
+        // Forwards like send_to_backend: headers copied, then injected
+        let app = Router::new()
+            .fallback(move |req: Request| async move {
+                let target = format!("http://{}/", addr);
+                let mut proxy_req = axum::http::Request::get(&target).body(Body::empty()).unwrap();
+                *proxy_req.headers_mut() = req.headers().clone();
+                let span = proxy_span(&target);
+                inject(&span, proxy_req.headers_mut());
+                let timeouts = TimeoutConfig::default();
+                let client = build_client(&ConnectionPoolConfig::default(), &timeouts);
+                let response = send(&client, proxy_req, 1024, 1024, &timeouts).instrument(span).await.unwrap();
+                Body::new(response.into_body())
+            })
+            .layer(axum::middleware::from_fn(trace_middleware));
+
+        let incoming = format!("00-{}-00f067aa0ba902b7-01", TRACE_ID);
+        let request = axum::http::Request::get("/").header("traceparent", &incoming).body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+        let forwarded = String::from_utf8(body.to_vec()).unwrap();
+
+        // Same trace; with spans exported the parent is the proxy span
+        let parts: Vec<&str> = forwarded.split('-').collect();
+        assert_eq!(parts.len(), 4, "{}", forwarded);
+        assert_eq!(parts[1], TRACE_ID);
+        #[cfg(feature = "otel")]
+        assert_ne!(parts[2], "00f067aa0ba902b7");
+        #[cfg(not(feature = "otel"))]
+        assert_eq!(forwarded, incoming);
+    }
+
+    #[test]
+    fn test_traceparent_format() {
+        assert!(is_valid_traceparent(&format!("00-{}-00f067aa0ba902b7-01", TRACE_ID)));
+        assert!(is_valid_traceparent(&new_traceparent()));
+        assert!(!is_valid_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"));
+        assert!(!is_valid_traceparent(&format!("00-{}-00f067aa0ba902b7", TRACE_ID)));
+        assert!(!is_valid_traceparent(&format!("00-{}-00F067AA0BA902B7-01", TRACE_ID)));
+        assert!(!is_valid_traceparent(&format!("ff-{}-00f067aa0ba902b7-01", TRACE_ID)));
+        assert_ne!(new_traceparent(), new_traceparent());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(TelemetryConfig::default().validate().is_ok());
+        let config: TelemetryConfig = toml::from_str("enabled = true\nsampling_ratio = 1.5").unwrap();
+        assert!(config.validate().is_err());
+        let config: TelemetryConfig = toml::from_str("enabled = true\nendpoint = \"not a url\"").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::static_cache::{resolve_static_path, StaticPath};
+
+// nginx-style `try_files $uri $uri/ /index.html`: candidates are tried in
+// order against the document root, then the fallback applies. `$uri` expands
+// to the request path. A candidate ending in `/` matches a directory that
+// holds one of the index files.
+//
+// The fallback is either a path to rewrite to ("/index.html"), a named
+// backend ("@app") or a status code ("=404").
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TryFiles {
+    pub files: Vec<String>,
+    pub fallback: String,
+    #[serde(default = "default_index_files")]
+    pub index: Vec<String>,
+}
+
+fn default_index_files() -> Vec<String> {
+    vec!["index.html".to_string(), "index.htm".to_string()]
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TryFilesResult {
+    File(PathBuf),
+    Rewrite(String),
+    Named(String),
+    Status(StatusCode),
+}
+
+impl TryFiles {
+    pub fn resolve(&self, root: &Path, uri_path: &str) -> TryFilesResult {
+        for candidate in &self.files {
+            let expanded = candidate.replace("$uri", uri_path);
+            let path = match resolve_static_path(root, &expanded) {
+                StaticPath::Found(path) => path,
+                StaticPath::Missing | StaticPath::Forbidden => continue,
+            };
+
+            if expanded.ends_with('/') {
+                if path.is_dir() {
+                    let index = self.index.iter()
+                        .filter_map(|i| match resolve_static_path(root, &format!("{}{}", expanded, i)) {
+                            StaticPath::Found(index) => Some(index),
+                            _ => None,
+                        })
+                        .find(|p| p.is_file());
+                    if let Some(index) = index {
+                        return TryFilesResult::File(index);
+                    }
+                }
+            } else if path.is_file() {
+                return TryFilesResult::File(path);
+            }
+        }
+
+        if let Some(code) = self.fallback.strip_prefix('=') {
+            let status = code.parse::<u16>().ok()
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .unwrap_or_else(|| {
+                    warn!("Invalid try_files status fallback {:?}, using 404", self.fallback);
+                    StatusCode::NOT_FOUND
+                });
+            TryFilesResult::Status(status)
+        } else if let Some(name) = self.fallback.strip_prefix('@') {
+            TryFilesResult::Named(name.to_string())
+        } else {
+            TryFilesResult::Rewrite(self.fallback.replace("$uri", uri_path))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_files(files: &[&str], fallback: &str) -> TryFiles {
+        TryFiles {
+            files: files.iter().map(|f| f.to_string()).collect(),
+            fallback: fallback.to_string(),
+            index: default_index_files(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_candidates_and_fallbacks() {
+        let root = std::env::temp_dir().join(format!("miwidothttp-try-files-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::create_dir_all(root.join("empty")).unwrap();
+        std::fs::write(root.join("app.js"), "").unwrap();
+        std::fs::write(root.join("docs/index.html"), "").unwrap();
+
+        let spa = try_files(&["$uri", "$uri/"], "/index.html");
+        // Found files come back canonicalized
+        let root = root.canonicalize().unwrap();
+        assert_eq!(spa.resolve(&root, "/app.js"), TryFilesResult::File(root.join("app.js")));
+        assert_eq!(spa.resolve(&root, "/docs"), TryFilesResult::File(root.join("docs/index.html")));
+        assert_eq!(spa.resolve(&root, "/empty"), TryFilesResult::Rewrite("/index.html".to_string()));
+        assert_eq!(spa.resolve(&root, "/../etc/passwd"), TryFilesResult::Rewrite("/index.html".to_string()));
+
+        assert_eq!(try_files(&["$uri"], "=404").resolve(&root, "/missing"), TryFilesResult::Status(StatusCode::NOT_FOUND));
+        assert_eq!(try_files(&["$uri"], "@app").resolve(&root, "/missing"), TryFilesResult::Named("app".to_string()));
+        assert_eq!(try_files(&["$uri"], "=abc").resolve(&root, "/missing"), TryFilesResult::Status(StatusCode::NOT_FOUND));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
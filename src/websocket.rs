@@ -1,32 +1,123 @@
 use anyhow::Result;
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
         State, Path, Query,
     },
     response::Response,
 };
-use futures::{sink::SinkExt, stream::StreamExt};
+use futures::{sink::{Sink, SinkExt}, stream::StreamExt};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, RwLock};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
+use crate::http_auth::{AuthConfig, JwtClaims};
+
+// Sender id of messages published by the server itself
+const SERVER_SENDER: &str = "server";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    // Broadcasts queued per connection before a slow one starts missing them
+    pub queue_size: usize,
+    // A send taking longer than this drops the client
+    pub send_timeout_ms: u64,
+    // Messages a client may miss in total before it is disconnected
+    pub max_missed_messages: u64,
+    // Tell clients how many messages they missed
+    pub notify_missed: bool,
+    // How often to ping each client (0 disables heartbeats)
+    pub heartbeat_interval_ms: u64,
+    // Close connections that send nothing, not even a pong, for this long
+    pub idle_timeout_ms: u64,
+    // Serve /ws, /ws/stats and /ws/publish; off so backends keep their own /ws
+    pub enabled: bool,
+    // Credentials for POST /ws/publish; without them there is no publish endpoint
+    pub publish_auth: Option<AuthConfig>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 1000,
+            send_timeout_ms: 10_000,
+            max_missed_messages: 1000,
+            notify_missed: true,
+            heartbeat_interval_ms: 30_000,
+            idle_timeout_ms: 90_000,
+            enabled: false,
+            publish_auth: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct WebSocketManager {
+    config: WebSocketConfig,
     connections: Arc<RwLock<HashMap<String, WebSocketConnection>>>,
     broadcast_tx: broadcast::Sender<BroadcastMessage>,
     rooms: Arc<RwLock<HashMap<String, Room>>>,
+    // Connections closed for going quiet
+    reaped: Arc<AtomicU64>,
+}
+
+// What the delivery loop got next for a connection
+#[derive(Debug)]
+enum Delivery {
+    Message(BroadcastMessage),
+    // The connection fell this many messages behind and they were dropped
+    Missed(u64),
+    // A broadcast not addressed to this connection
+    Skip,
+    // The channel closed or the connection is gone
+    Closed,
+}
+
+// Why a connection's delivery loop stopped
+#[derive(Debug, PartialEq)]
+enum Disconnect {
+    Closed,
+    SendFailed,
+    SendTimeout,
+    TooSlow(u64),
+    Idle,
 }
 
 #[derive(Debug)]
 struct WebSocketConnection {
     id: String,
     user_id: Option<String>,
+    // The room this connection is in; joining another room leaves it
     room_id: Option<String>,
     metadata: HashMap<String, String>,
+    // Last time any frame arrived from the client
+    last_seen: Instant,
+}
+
+impl WebSocketConnection {
+    // Room messages go to the room's members, private messages to the
+    // connection (or user) they name, everything else to everyone. Nobody
+    // gets their own messages back.
+    fn should_receive(&self, msg: &BroadcastMessage) -> bool {
+        if msg.sender_id == self.id {
+            return false;
+        }
+        if let MessageType::Private = msg.msg_type {
+            return msg.target_id.as_deref().map_or(false, |target| {
+                target == self.id || self.user_id.as_deref() == Some(target)
+            });
+        }
+        match &msg.room_id {
+            Some(room_id) => self.room_id.as_ref() == Some(room_id),
+            None => true,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -42,6 +133,9 @@ pub struct BroadcastMessage {
     pub msg_type: MessageType,
     pub sender_id: String,
     pub room_id: Option<String>,
+    // Recipient of a private message: a connection id or user id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_id: Option<String>,
     pub data: serde_json::Value,
     pub timestamp: chrono::DateTime<chrono::Utc>,
 }
@@ -64,24 +158,33 @@ pub enum MessageType {
 
 impl WebSocketManager {
     pub fn new() -> Self {
-        let (broadcast_tx, _) = broadcast::channel(1000);
+        Self::with_config(WebSocketConfig::default())
+    }
+    
+    pub fn with_config(config: WebSocketConfig) -> Self {
+        let (broadcast_tx, _) = broadcast::channel(config.queue_size.max(1));
         
         Self {
+            config,
             connections: Arc::new(RwLock::new(HashMap::new())),
             broadcast_tx,
             rooms: Arc::new(RwLock::new(HashMap::new())),
+            reaped: Arc::new(AtomicU64::new(0)),
         }
     }
     
+    // `user_id` is who the connection belongs to, if known; publish_to_user
+    // reaches every connection of that user
     pub async fn handle_upgrade(
         &self,
         ws: WebSocketUpgrade,
         user_agent: Option<String>,
+        user_id: Option<String>,
     ) -> Response {
         let manager = self.clone();
         
         ws.on_upgrade(move |socket| async move {
-            if let Err(e) = manager.handle_socket(socket, user_agent).await {
+            if let Err(e) = manager.handle_socket(socket, user_agent, user_id).await {
                 error!("WebSocket error: {}", e);
             }
         })
@@ -91,47 +194,47 @@ impl WebSocketManager {
         &self,
         socket: WebSocket,
         user_agent: Option<String>,
+        user_id: Option<String>,
     ) -> Result<()> {
         let conn_id = Uuid::new_v4().to_string();
-        info!("New WebSocket connection: {} (UA: {:?})", conn_id, user_agent);
+        info!("New WebSocket connection: {} (UA: {:?}, user: {:?})", conn_id, user_agent, user_id);
         
-        // Register connection
-        let connection = WebSocketConnection {
-            id: conn_id.clone(),
-            user_id: None,
-            room_id: None,
-            metadata: HashMap::new(),
-        };
-        
-        self.connections.write().await.insert(conn_id.clone(), connection);
+        // Register connection and subscribe to broadcasts
+        let broadcast_rx = self.register(&conn_id).await;
+        if let Some(user_id) = user_id {
+            self.identify(&conn_id, &user_id).await;
+        }
         
         // Split the WebSocket
-        let (mut sender, mut receiver) = socket.split();
+        let (sender, mut receiver) = socket.split();
         
-        // Subscribe to broadcasts
-        let mut broadcast_rx = self.broadcast_tx.subscribe();
+        // Spawn task to deliver the broadcasts meant for this connection
+        let manager = self.clone();
         let conn_id_clone = conn_id.clone();
+        let mut broadcast_task = tokio::spawn(async move {
+            manager.deliver(&conn_id_clone, broadcast_rx, sender).await
+        });
         
-        // Spawn task to handle broadcasts
-        let broadcast_task = tokio::spawn(async move {
-            while let Ok(msg) = broadcast_rx.recv().await {
-                // Filter messages based on room membership
-                let should_send = msg.room_id.is_none() || {
-                    // Check if connection is in the target room
-                    true // TODO: Implement room filtering
-                };
-                
-                if should_send && msg.sender_id != conn_id_clone {
-                    let json = serde_json::to_string(&msg).unwrap();
-                    if sender.send(Message::Text(json)).await.is_err() {
-                        break;
+        // Handle incoming messages until either side gives up
+        loop {
+            let msg = tokio::select! {
+                msg = receiver.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                reason = &mut broadcast_task => {
+                    match reason {
+                        Ok(Disconnect::TooSlow(missed)) => warn!("Disconnecting {}: missed {} messages", conn_id, missed),
+                        Ok(Disconnect::SendTimeout) => warn!("Disconnecting {}: send timed out", conn_id),
+                        Ok(Disconnect::Idle) => info!("Disconnecting {}: no response to pings", conn_id),
+                        _ => debug!("Delivery to {} stopped", conn_id),
                     }
+                    break;
                 }
+            };
+            if msg.is_ok() {
+                self.touch(&conn_id).await;
             }
-        });
-        
-        // Handle incoming messages
-        while let Some(msg) = receiver.next().await {
             match msg {
                 Ok(Message::Text(text)) => {
                     self.handle_text_message(&conn_id, text).await?;
@@ -159,13 +262,171 @@ impl WebSocketManager {
         
         // Cleanup
         broadcast_task.abort();
-        self.connections.write().await.remove(&conn_id);
+        self.unregister(&conn_id).await;
         self.broadcast_leave(&conn_id).await?;
         
         info!("WebSocket {} disconnected", conn_id);
         Ok(())
     }
     
+    async fn register(&self, conn_id: &str) -> broadcast::Receiver<BroadcastMessage> {
+        let connection = WebSocketConnection {
+            id: conn_id.to_string(),
+            user_id: None,
+            room_id: None,
+            metadata: HashMap::new(),
+            last_seen: Instant::now(),
+        };
+        
+        // Subscribe first so nothing sent once we're visible is missed
+        let broadcast_rx = self.broadcast_tx.subscribe();
+        self.connections.write().await.insert(conn_id.to_string(), connection);
+        broadcast_rx
+    }
+    
+    async fn unregister(&self, conn_id: &str) {
+        let room_id = self.connections.read().await
+            .get(conn_id)
+            .and_then(|conn| conn.room_id.clone());
+        if let Some(room_id) = room_id {
+            let _ = self.leave_room(conn_id, &room_id).await;
+        }
+        self.connections.write().await.remove(conn_id);
+    }
+    
+    async fn identify(&self, conn_id: &str, user_id: &str) {
+        if let Some(conn) = self.connections.write().await.get_mut(conn_id) {
+            conn.user_id = Some(user_id.to_string());
+        }
+    }
+    
+    async fn touch(&self, conn_id: &str) {
+        if let Some(conn) = self.connections.write().await.get_mut(conn_id) {
+            conn.last_seen = Instant::now();
+        }
+    }
+    
+    // What a broadcast received for `conn_id` means to it
+    async fn route(
+        &self,
+        conn_id: &str,
+        received: Result<BroadcastMessage, broadcast::error::RecvError>,
+    ) -> Delivery {
+        let msg = match received {
+            Ok(msg) => msg,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => return Delivery::Missed(skipped),
+            Err(broadcast::error::RecvError::Closed) => return Delivery::Closed,
+        };
+        
+        match self.connections.read().await.get(conn_id) {
+            Some(conn) if conn.should_receive(&msg) => Delivery::Message(msg),
+            Some(_) => Delivery::Skip,
+            None => Delivery::Closed,
+        }
+    }
+    
+    // Next broadcast addressed to `conn_id`, or None once the channel is
+    // closed or the connection is gone
+    async fn next_delivery(
+        &self,
+        conn_id: &str,
+        broadcast_rx: &mut broadcast::Receiver<BroadcastMessage>,
+    ) -> Option<Delivery> {
+        loop {
+            match self.route(conn_id, broadcast_rx.recv().await).await {
+                Delivery::Skip => continue,
+                Delivery::Closed => return None,
+                delivery => return Some(delivery),
+            }
+        }
+    }
+    
+    // Send a connection its broadcasts and heartbeat pings until it goes
+    // away, a send fails or times out, it has missed more than
+    // max_missed_messages in total, or it has been quiet past idle_timeout
+    async fn deliver<S>(
+        &self,
+        conn_id: &str,
+        mut broadcast_rx: broadcast::Receiver<BroadcastMessage>,
+        mut sink: S,
+    ) -> Disconnect
+    where
+        S: Sink<Message> + Unpin,
+    {
+        let send_timeout = Duration::from_millis(self.config.send_timeout_ms);
+        let idle_timeout = Duration::from_millis(self.config.idle_timeout_ms);
+        let mut heartbeat = (self.config.heartbeat_interval_ms > 0).then(|| {
+            let period = Duration::from_millis(self.config.heartbeat_interval_ms);
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+        let mut missed = 0u64;
+        
+        loop {
+            // Only recv() races the heartbeat, and it is cancel safe
+            let received = tokio::select! {
+                received = broadcast_rx.recv() => received,
+                _ = next_tick(&mut heartbeat) => {
+                    let last_seen = match self.connections.read().await.get(conn_id) {
+                        Some(conn) => conn.last_seen,
+                        None => return Disconnect::Closed,
+                    };
+                    if !idle_timeout.is_zero() && last_seen.elapsed() > idle_timeout {
+                        self.reaped.fetch_add(1, Ordering::Relaxed);
+                        let close = Message::Close(Some(CloseFrame {
+                            code: axum::extract::ws::close_code::AWAY,
+                            reason: "idle timeout".into(),
+                        }));
+                        let _ = tokio::time::timeout(send_timeout, sink.send(close)).await;
+                        return Disconnect::Idle;
+                    }
+                    match tokio::time::timeout(send_timeout, sink.send(Message::Ping(Vec::new()))).await {
+                        Ok(Ok(())) => continue,
+                        Ok(Err(_)) => return Disconnect::SendFailed,
+                        Err(_) => return Disconnect::SendTimeout,
+                    }
+                }
+            };
+            
+            let msg = match self.route(conn_id, received).await {
+                Delivery::Message(msg) => msg,
+                Delivery::Skip => continue,
+                Delivery::Missed(skipped) => {
+                    missed += skipped;
+                    if missed > self.config.max_missed_messages {
+                        let close = Message::Close(Some(CloseFrame {
+                            code: axum::extract::ws::close_code::POLICY,
+                            reason: "too slow".into(),
+                        }));
+                        let _ = tokio::time::timeout(send_timeout, sink.send(close)).await;
+                        return Disconnect::TooSlow(missed);
+                    }
+                    debug!("WebSocket {} missed {} messages", conn_id, skipped);
+                    if !self.config.notify_missed {
+                        continue;
+                    }
+                    BroadcastMessage {
+                        msg_type: MessageType::SystemNotification,
+                        sender_id: SERVER_SENDER.to_string(),
+                        room_id: None,
+                        target_id: Some(conn_id.to_string()),
+                        data: serde_json::json!({ "missed": skipped }),
+                        timestamp: chrono::Utc::now(),
+                    }
+                }
+                Delivery::Closed => return Disconnect::Closed,
+            };
+            
+            let json = serde_json::to_string(&msg).unwrap();
+            match tokio::time::timeout(send_timeout, sink.send(Message::Text(json))).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => return Disconnect::SendFailed,
+                Err(_) => return Disconnect::SendTimeout,
+            }
+        }
+    }
+    
     async fn handle_text_message(&self, conn_id: &str, text: String) -> Result<()> {
         // Parse JSON message
         match serde_json::from_str::<ClientMessage>(&text) {
@@ -210,6 +471,7 @@ impl WebSocketManager {
             msg_type: MessageType::Binary,
             sender_id: conn_id.to_string(),
             room_id: None,
+            target_id: None,
             data: serde_json::json!({
                 "size": data.len(),
                 "data": base64::encode(&data),
@@ -222,6 +484,14 @@ impl WebSocketManager {
     }
     
     async fn join_room(&self, conn_id: &str, room_id: &str) -> Result<()> {
+        // A connection is in one room at a time
+        let current = self.connections.read().await
+            .get(conn_id)
+            .and_then(|conn| conn.room_id.clone());
+        if let Some(current) = current.filter(|current| current != room_id) {
+            self.leave_room(conn_id, &current).await?;
+        }
+        
         let mut rooms = self.rooms.write().await;
         let room = rooms.entry(room_id.to_string()).or_insert_with(|| Room {
             id: room_id.to_string(),
@@ -244,6 +514,7 @@ impl WebSocketManager {
                 msg_type: MessageType::Join,
                 sender_id: conn_id.to_string(),
                 room_id: Some(room_id.to_string()),
+                target_id: None,
                 data: serde_json::json!({
                     "user_id": conn_id,
                     "room_id": room_id,
@@ -287,6 +558,7 @@ impl WebSocketManager {
             msg_type: MessageType::Broadcast,
             sender_id: sender_id.to_string(),
             room_id: sender_room,
+            target_id: None,
             data,
             timestamp: chrono::Utc::now(),
         };
@@ -304,7 +576,8 @@ impl WebSocketManager {
         let msg = BroadcastMessage {
             msg_type: MessageType::Private,
             sender_id: sender_id.to_string(),
-            room_id: Some(target_id.to_string()), // Use room_id as target
+            room_id: None,
+            target_id: Some(target_id.to_string()),
             data,
             timestamp: chrono::Utc::now(),
         };
@@ -313,11 +586,50 @@ impl WebSocketManager {
         Ok(())
     }
     
+    // Push `data` to every connection in `room_id`; returns how many
+    // connections it was addressed to
+    pub async fn publish_to_room(&self, room_id: &str, data: serde_json::Value) -> usize {
+        let recipients = self.rooms.read().await
+            .get(room_id)
+            .map_or(0, |room| room.members.len());
+        
+        let msg = BroadcastMessage {
+            msg_type: MessageType::RoomMessage,
+            sender_id: SERVER_SENDER.to_string(),
+            room_id: Some(room_id.to_string()),
+            target_id: None,
+            data,
+            timestamp: chrono::Utc::now(),
+        };
+        let _ = self.broadcast_tx.send(msg);
+        recipients
+    }
+    
+    // Push `data` to every connection of `user_id`; returns how many there are
+    pub async fn publish_to_user(&self, user_id: &str, data: serde_json::Value) -> usize {
+        let recipients = self.connections.read().await
+            .values()
+            .filter(|conn| conn.user_id.as_deref() == Some(user_id))
+            .count();
+        
+        let msg = BroadcastMessage {
+            msg_type: MessageType::Private,
+            sender_id: SERVER_SENDER.to_string(),
+            room_id: None,
+            target_id: Some(user_id.to_string()),
+            data,
+            timestamp: chrono::Utc::now(),
+        };
+        let _ = self.broadcast_tx.send(msg);
+        recipients
+    }
+    
     async fn broadcast_leave(&self, conn_id: &str) -> Result<()> {
         let msg = BroadcastMessage {
             msg_type: MessageType::Leave,
             sender_id: conn_id.to_string(),
             room_id: None,
+            target_id: None,
             data: serde_json::json!({
                 "user_id": conn_id,
             }),
@@ -332,6 +644,10 @@ impl WebSocketManager {
         self.connections.read().await.len()
     }
     
+    pub fn get_reaped_count(&self) -> u64 {
+        self.reaped.load(Ordering::Relaxed)
+    }
+    
     pub async fn get_room_count(&self) -> usize {
         self.rooms.read().await.len()
     }
@@ -347,6 +663,16 @@ impl WebSocketManager {
     }
 }
 
+// Resolves on the next heartbeat, or never when heartbeats are off
+async fn next_tick(heartbeat: &mut Option<tokio::time::Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ClientMessage {
     action: String,
@@ -363,29 +689,78 @@ pub struct RoomInfo {
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
-// WebSocket routes
-pub fn websocket_routes() -> axum::Router {
-    use axum::routing::get;
-    
-    let manager = Arc::new(WebSocketManager::new());
+// Body of POST /ws/publish; exactly one of room_id and user_id
+#[derive(Debug, Deserialize)]
+struct PublishRequest {
+    room_id: Option<String>,
+    user_id: Option<String>,
+    data: serde_json::Value,
+}
+
+// WebSocket routes. POST /ws/publish is only served when `publish_auth` is
+// set, and requires those credentials. Generic over the state of the router
+// it gets merged into.
+pub fn websocket_routes<S>(manager: Arc<WebSocketManager>, publish_auth: Option<AuthConfig>) -> axum::Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    use axum::routing::{get, post};
     
-    axum::Router::new()
+    let router = axum::Router::new()
         .route("/ws", get(websocket_handler))
         .route("/ws/stats", get(websocket_stats))
-        .with_state(manager)
+        .with_state(manager.clone());
+    
+    match publish_auth {
+        Some(auth) => router.merge(
+            axum::Router::new()
+                .route("/ws/publish", post(publish_handler))
+                .with_state((manager, Arc::new(auth))),
+        ),
+        None => router,
+    }
 }
 
 async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(manager): State<Arc<WebSocketManager>>,
     headers: axum::http::HeaderMap,
+    claims: Option<axum::Extension<JwtClaims>>,
 ) -> Response {
     let user_agent = headers
         .get(axum::http::header::USER_AGENT)
         .and_then(|v| v.to_str().ok())
         .map(|s| s.to_string());
+    // Connections authenticated by JWT belong to the token's subject
+    let user_id = claims
+        .and_then(|axum::Extension(claims)| claims.get("sub").and_then(|sub| sub.as_str()).map(str::to_string));
     
-    manager.handle_upgrade(ws, user_agent).await
+    manager.handle_upgrade(ws, user_agent, user_id).await
+}
+
+async fn publish_handler(
+    State((manager, auth)): State<(Arc<WebSocketManager>, Arc<AuthConfig>)>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> Response {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    
+    if let Err(challenge) = auth.authorize(&headers).await {
+        return challenge;
+    }
+    
+    let request: PublishRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid publish request: {}", e)).into_response(),
+    };
+    let recipients = match (request.room_id, request.user_id) {
+        (Some(room_id), None) => manager.publish_to_room(&room_id, request.data).await,
+        (None, Some(user_id)) => manager.publish_to_user(&user_id, request.data).await,
+        _ => return (StatusCode::BAD_REQUEST, "Exactly one of room_id and user_id is required").into_response(),
+    };
+    
+    axum::Json(serde_json::json!({ "recipients": recipients })).into_response()
 }
 
 async fn websocket_stats(
@@ -394,6 +769,269 @@ async fn websocket_stats(
     axum::Json(serde_json::json!({
         "connections": manager.get_connection_count().await,
         "rooms": manager.get_room_count().await,
+        "reaped_connections": manager.get_reaped_count(),
         "room_list": manager.get_rooms().await,
     }))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Drains whatever `conn_id` has been sent, waiting briefly for more
+    async fn received(
+        manager: &WebSocketManager,
+        conn_id: &str,
+        rx: &mut broadcast::Receiver<BroadcastMessage>,
+    ) -> Vec<BroadcastMessage> {
+        let mut messages = Vec::new();
+        while let Ok(Some(delivery)) = tokio::time::timeout(
+            Duration::from_millis(50),
+            manager.next_delivery(conn_id, rx),
+        ).await {
+            match delivery {
+                Delivery::Message(msg) => messages.push(msg),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_private_message_reaches_only_target() {
+        let manager = WebSocketManager::new();
+        let mut a = manager.register("a").await;
+        let mut b = manager.register("b").await;
+        let mut c = manager.register("c").await;
+
+        manager.send_private_message("a", "b", serde_json::json!("secret")).await.unwrap();
+
+        let to_b = received(&manager, "b", &mut b).await;
+        assert_eq!(to_b.len(), 1);
+        assert!(matches!(to_b[0].msg_type, MessageType::Private));
+        assert_eq!(to_b[0].data, "secret");
+        assert!(received(&manager, "a", &mut a).await.is_empty());
+        assert!(received(&manager, "c", &mut c).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_room_message_reaches_only_members() {
+        let manager = WebSocketManager::new();
+        let mut a = manager.register("a").await;
+        let mut b = manager.register("b").await;
+        let mut c = manager.register("c").await;
+
+        manager.join_room("a", "lobby").await.unwrap();
+        manager.join_room("b", "lobby").await.unwrap();
+        manager.join_room("c", "other").await.unwrap();
+        // Join notices only go to the room
+        let joins = received(&manager, "a", &mut a).await;
+        assert_eq!(joins.len(), 1);
+        assert_eq!(joins[0].sender_id, "b");
+        assert!(received(&manager, "b", &mut b).await.is_empty());
+        assert!(received(&manager, "c", &mut c).await.is_empty());
+
+        manager.broadcast_message("a", serde_json::json!("hi room")).await.unwrap();
+        let to_b = received(&manager, "b", &mut b).await;
+        assert_eq!(to_b.len(), 1);
+        assert_eq!(to_b[0].data, "hi room");
+        assert!(received(&manager, "a", &mut a).await.is_empty());
+        assert!(received(&manager, "c", &mut c).await.is_empty());
+
+        // Moving rooms leaves the old one
+        manager.join_room("b", "other").await.unwrap();
+        manager.broadcast_message("a", serde_json::json!("anyone?")).await.unwrap();
+        assert!(received(&manager, "b", &mut b).await.iter().all(|msg| msg.data != "anyone?"));
+        assert_eq!(manager.rooms.read().await["lobby"].members, vec!["a".to_string()]);
+
+        // Disconnecting empties the room
+        manager.unregister("a").await;
+        assert!(!manager.rooms.read().await.contains_key("lobby"));
+    }
+
+    // Sink that takes `delay` per message and records what it got
+    fn slow_client(delay: Duration, sent: Arc<Mutex<Vec<Message>>>) -> impl Sink<Message, Error = std::convert::Infallible> + Unpin {
+        Box::pin(futures::sink::unfold(sent, move |sent, msg: Message| async move {
+            tokio::time::sleep(delay).await;
+            sent.lock().unwrap().push(msg);
+            Ok::<_, std::convert::Infallible>(sent)
+        }))
+    }
+
+    async fn flood(manager: &WebSocketManager, count: usize, interval: Duration) {
+        for i in 0..count {
+            manager.broadcast_message("sender", serde_json::json!(i)).await.unwrap();
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_is_disconnected() {
+        let manager = WebSocketManager::with_config(WebSocketConfig {
+            queue_size: 4,
+            send_timeout_ms: 5_000,
+            max_missed_messages: 30,
+            notify_missed: true,
+            ..Default::default()
+        });
+        let rx = manager.register("slow").await;
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sink = slow_client(Duration::from_millis(50), sent.clone());
+
+        let delivery = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.deliver("slow", rx, sink).await })
+        };
+        // Roughly 25 messages arrive during each send, but only 4 fit the queue
+        flood(&manager, 200, Duration::from_millis(2)).await;
+
+        let reason = tokio::time::timeout(Duration::from_secs(10), delivery).await.unwrap().unwrap();
+        assert!(matches!(reason, Disconnect::TooSlow(missed) if missed > 30), "{:?}", reason);
+
+        // It was told about the first gap, and closed with a policy error
+        let sent = sent.lock().unwrap();
+        assert!(sent.iter().any(|msg| matches!(msg, Message::Text(text) if text.contains("\"missed\""))));
+        assert!(matches!(sent.last(), Some(Message::Close(Some(frame))) if frame.code == axum::extract::ws::close_code::POLICY));
+    }
+
+    #[tokio::test]
+    async fn test_hung_client_times_out() {
+        let manager = WebSocketManager::with_config(WebSocketConfig {
+            send_timeout_ms: 100,
+            ..Default::default()
+        });
+        let rx = manager.register("hung").await;
+        let sink = slow_client(Duration::from_secs(3600), Arc::new(Mutex::new(Vec::new())));
+
+        let delivery = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.deliver("hung", rx, sink).await })
+        };
+        flood(&manager, 1, Duration::ZERO).await;
+
+        let reason = tokio::time::timeout(Duration::from_secs(5), delivery).await.unwrap().unwrap();
+        assert_eq!(reason, Disconnect::SendTimeout);
+    }
+
+    #[tokio::test]
+    async fn test_fast_client_gets_everything() {
+        let manager = WebSocketManager::with_config(WebSocketConfig {
+            queue_size: 4,
+            ..Default::default()
+        });
+        let rx = manager.register("fast").await;
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sink = slow_client(Duration::ZERO, sent.clone());
+
+        let delivery = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.deliver("fast", rx, sink).await })
+        };
+        // Pace the messages so the client keeps up
+        flood(&manager, 20, Duration::from_millis(5)).await;
+        manager.unregister("fast").await;
+        manager.broadcast_message("sender", serde_json::json!("last")).await.unwrap();
+
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), delivery).await.unwrap().unwrap(), Disconnect::Closed);
+        assert_eq!(sent.lock().unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_client_is_reaped() {
+        let manager = WebSocketManager::with_config(WebSocketConfig {
+            heartbeat_interval_ms: 50,
+            idle_timeout_ms: 200,
+            ..Default::default()
+        });
+        let rx = manager.register("quiet").await;
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sink = slow_client(Duration::ZERO, sent.clone());
+
+        let mut delivery = {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.deliver("quiet", rx, sink).await })
+        };
+
+        // Answering keeps it alive well past the idle timeout
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(40)).await;
+            manager.touch("quiet").await;
+        }
+        assert!(!delivery.is_finished());
+        assert!(sent.lock().unwrap().iter().any(|msg| matches!(msg, Message::Ping(_))));
+        assert_eq!(manager.get_reaped_count(), 0);
+
+        // Going quiet gets it closed
+        let reason = tokio::time::timeout(Duration::from_secs(5), &mut delivery).await.unwrap().unwrap();
+        assert_eq!(reason, Disconnect::Idle);
+        assert_eq!(manager.get_reaped_count(), 1);
+        assert!(matches!(sent.lock().unwrap().last(), Some(Message::Close(Some(frame))) if frame.code == axum::extract::ws::close_code::AWAY));
+    }
+
+    #[tokio::test]
+    async fn test_publish_reaches_room_and_user() {
+        let manager = WebSocketManager::new();
+        let mut a = manager.register("a").await;
+        let mut b = manager.register("b").await;
+        manager.identify("a", "alice").await;
+        manager.join_room("b", "news").await.unwrap();
+
+        assert_eq!(manager.publish_to_room("news", serde_json::json!("extra")).await, 1);
+        let to_b = received(&manager, "b", &mut b).await;
+        assert_eq!(to_b.len(), 1);
+        assert_eq!(to_b[0].sender_id, SERVER_SENDER);
+        assert_eq!(to_b[0].data, "extra");
+        assert!(received(&manager, "a", &mut a).await.is_empty());
+
+        assert_eq!(manager.publish_to_user("alice", serde_json::json!("hi alice")).await, 1);
+        let to_a = received(&manager, "a", &mut a).await;
+        assert_eq!(to_a.len(), 1);
+        assert_eq!(to_a[0].data, "hi alice");
+        assert!(received(&manager, "b", &mut b).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_publish_endpoint_requires_auth() {
+        use axum::body::Body;
+        use axum::http::{header, Request, StatusCode};
+        use tower::ServiceExt;
+
+        let manager = Arc::new(WebSocketManager::new());
+        let mut b = manager.register("b").await;
+        manager.join_room("b", "news").await.unwrap();
+        let auth: AuthConfig = serde_json::from_value(serde_json::json!({
+            "auth_type": "bearer",
+            "realm": "publish",
+            "tokens": ["s3cret"],
+        })).unwrap();
+        let app: axum::Router = websocket_routes(manager.clone(), Some(auth));
+
+        let publish = |token: Option<&str>| {
+            let mut request = Request::post("/ws/publish").header(header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            request.body(Body::from(r#"{"room_id":"news","data":{"headline":"up"}}"#)).unwrap()
+        };
+
+        let response = app.clone().oneshot(publish(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(publish(Some("wrong"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(received(&manager, "b", &mut b).await.is_empty());
+
+        let response = app.clone().oneshot(publish(Some("s3cret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["recipients"], 1);
+        let to_b = received(&manager, "b", &mut b).await;
+        assert_eq!(to_b.len(), 1);
+        assert_eq!(to_b[0].data["headline"], "up");
+
+        // Without auth configured there is no publish endpoint
+        let open: axum::Router = websocket_routes(manager, None);
+        let response = open.oneshot(publish(Some("s3cret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}